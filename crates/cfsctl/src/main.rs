@@ -5,7 +5,7 @@
 //! repository maintenance operations like garbage collection.
 
 use std::{
-    fs::create_dir_all,
+    collections::BTreeMap,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -13,13 +13,24 @@ use std::{
 use anyhow::Result;
 use clap::{Parser, Subcommand, ValueEnum};
 
-use rustix::fs::CWD;
+use rustix::fs::{fstat, CWD};
+use serde::Serialize;
 
-use composefs_boot::{write_boot, BootOps};
+use composefs_boot::{
+    bootloader::{BootEntry, PEType, Type2Entry},
+    cmdline::get_booted_composefs,
+    deploy::{Deployment, Deployments},
+    entries::{BootDir, EntryType, InstalledEntry},
+    state, BootOps,
+};
 
 use composefs::{
+    diff::{diff_images, Change, ChangeKind, MetadataChange},
+    erofs::format::FormatVersion,
     fsverity::{FsVerityHashValue, Sha256HashValue, Sha512HashValue},
     repository::Repository,
+    splitstream::SplitStreamData,
+    tree::FileSystem,
 };
 
 /// cfsctl
@@ -82,6 +93,8 @@ enum OciCommand {
         config_verity: Option<String>,
         #[clap(long)]
         bootable: bool,
+        #[clap(flatten)]
+        format_opts: ImageFormatOptions,
     },
     CreateImage {
         config_name: String,
@@ -90,6 +103,8 @@ enum OciCommand {
         bootable: bool,
         #[clap(long)]
         image_name: Option<String>,
+        #[clap(flatten)]
+        format_opts: ImageFormatOptions,
     },
     Seal {
         config_name: String,
@@ -99,18 +114,160 @@ enum OciCommand {
         name: String,
         mountpoint: String,
     },
+    /// Deploys an OCI image and makes it the default: the same as `deploy stage` followed by
+    /// `deploy finalize`
     PrepareBoot {
         config_name: String,
         config_verity: Option<String>,
         #[clap(long, default_value = "/boot")]
         bootdir: PathBuf,
+        #[clap(flatten)]
+        boot_opts: BootEntryOptions,
+    },
+}
+
+/// Common options for writing the boot entry of an OCI image
+#[cfg(feature = "oci")]
+#[derive(Debug, Parser)]
+struct BootEntryOptions {
+    #[clap(long)]
+    entry_id: Option<String>,
+    #[clap(long)]
+    cmdline: Vec<String>,
+    /// Enable boot assessment, allowing this many attempts to boot the new entry
+    #[clap(long)]
+    boot_tries: Option<u32>,
+    /// Build a UKI from the kernel in /usr/lib/modules using this systemd EFI stub
+    #[clap(long)]
+    uki_stub: Option<PathBuf>,
+    /// Refuse to install UKIs which aren't signed by a certificate in this PEM file
+    #[clap(long)]
+    secure_boot_db: Option<PathBuf>,
+    /// Boot with this devicetree (relative to /usr/lib/modules/<kver>/dtb)
+    #[clap(long)]
+    devicetree: Option<String>,
+    /// Apply this devicetree overlay (relative to /usr/lib/modules/<kver>/dtb)
+    #[clap(long)]
+    devicetree_overlay: Vec<String>,
+    /// Give the new deployment its own /var instead of sharing it with the others
+    #[clap(long)]
+    private_var: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum BootCommand {
+    /// Lists the installed boot entries in boot menu order
+    List {
+        /// Output in JSON format
+        #[clap(long)]
+        json: bool,
+    },
+    /// Makes an entry the default in loader.conf
+    SetDefault {
+        /// the entry identifier or filename
+        id: String,
+    },
+    /// Removes an entry and any files that only it referenced
+    Remove {
+        /// the entry identifier or filename
+        id: String,
+        /// Also remove entries which don't boot a composefs image
+        #[clap(long)]
+        force: bool,
+    },
+    /// Marks the entries for the currently-booted image as good, ending boot assessment
+    MarkGood,
+    /// Removes all but the newest composefs entries
+    Prune {
+        /// The number of composefs entries to keep (the default and booted entries are always kept)
+        #[clap(long)]
+        keep: usize,
+    },
+    /// Prints GRUB menu entries for the composefs entries, for bootloaders without Boot Loader
+    /// Specification support
+    GrubCfg,
+}
+
+#[derive(Debug, Subcommand)]
+enum StateCommand {
+    /// Discards the local changes to /etc and/or the contents of /var of a deployment (both if
+    /// neither is given).  The booted deployment can only be reset at boot, with --next-boot or
+    /// the composefs.reset kernel command line argument.
+    Reset {
+        /// Reset /etc
+        #[clap(long)]
+        etc: bool,
+        /// Reset /var (for all deployments, if it is shared)
         #[clap(long)]
-        entry_id: Option<String>,
+        var: bool,
+        /// Reset the deployment the next time it boots, rather than now
         #[clap(long)]
-        cmdline: Vec<String>,
+        next_boot: bool,
+        /// The fs-verity digest of the deployed image
+        deployment: String,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum DeployCommand {
+    /// Deploys an OCI image next to the existing deployments, to be booted after `finalize`.  If
+    /// the image is deployed already, its existing boot entry is kept and the entry options are
+    /// ignored.
+    #[cfg(feature = "oci")]
+    Stage {
+        config_name: String,
+        config_verity: Option<String>,
+        #[clap(flatten)]
+        boot_opts: BootEntryOptions,
+    },
+    /// Makes the staged deployment the default
+    Finalize,
+    /// Makes the previous deployment the default again
+    Rollback,
+    /// Lists the deployments
+    Status {
+        /// Output in JSON format
+        #[clap(long)]
+        json: bool,
+    },
+    /// Removes a deployment: its boot entries, its state and its ref in the repository
+    Remove {
+        /// The fs-verity digest of the deployed image
+        deployment: String,
+    },
+}
+
+/// Common options for the format of created images
+#[derive(Debug, Parser)]
+struct ImageFormatOptions {
+    /// Store files of up to 4096 bytes which compress well DEFLATE-compressed in the image,
+    /// instead of as separate objects.  Mounting the image requires Linux 6.6 or later with EROFS
+    /// DEFLATE support, and the image ID differs from the uncompressed one.
+    #[clap(long)]
+    compress: bool,
+}
+
+impl ImageFormatOptions {
+    fn version(&self) -> FormatVersion {
+        match self.compress {
+            true => FormatVersion::V1_1Compressed,
+            false => FormatVersion::default(),
+        }
+    }
+
+    /// Prepares `fs` for being written in this format, reading file content from `repo`.
+    fn prepare<ObjectID: FsVerityHashValue>(
+        &self,
+        fs: &mut FileSystem<ObjectID>,
+        repo: &Repository<ObjectID>,
+    ) -> Result<()> {
+        if self.compress {
+            fs.inline_compressible_files(|_, id| Ok(Some(repo.read_object(id)?)))?;
+        }
+        Ok(())
+    }
+}
+
 /// Common options for reading a filesystem from a path
 #[derive(Debug, Parser)]
 struct FsReadOptions {
@@ -134,6 +291,17 @@ enum Command {
         /// the name of the stream to cat, either a content identifier or prefixed with 'ref/'
         name: String,
     },
+    /// Shows the internal structure of a split stream
+    StreamInfo {
+        /// the name of the stream to inspect, either a content identifier or prefixed with 'ref/'
+        name: String,
+        /// List every chunk in the stream
+        #[clap(long, short = 'v')]
+        verbose: bool,
+        /// Output in JSON format
+        #[clap(long)]
+        json: bool,
+    },
     /// Perform garbage collection
     GC {
         /// Additional roots to keep (image or stream names)
@@ -151,6 +319,27 @@ enum Command {
         #[clap(subcommand)]
         cmd: OciCommand,
     },
+    /// Commands for managing installed boot entries
+    Boot {
+        /// The boot partition/directory
+        #[clap(long, default_value = "/boot")]
+        bootdir: PathBuf,
+        #[clap(subcommand)]
+        cmd: BootCommand,
+    },
+    /// Commands for staging, finalizing and rolling back deployments
+    Deploy {
+        /// The boot partition/directory
+        #[clap(long, default_value = "/boot")]
+        bootdir: PathBuf,
+        #[clap(subcommand)]
+        cmd: DeployCommand,
+    },
+    /// Commands for managing the state (/etc and /var) of deployments
+    State {
+        #[clap(subcommand)]
+        cmd: StateCommand,
+    },
     /// Mounts a composefs, possibly enforcing fsverity of the image
     Mount {
         /// the name of the image to mount, either an fs-verity hash or prefixed with 'ref/'
@@ -158,16 +347,59 @@ enum Command {
         /// the mountpoint
         mountpoint: String,
     },
+    /// Serves an image, the filesystem of an OCI config, or a dumpfile with FUSE
+    #[cfg(feature = "fuse")]
+    FuseMount {
+        /// the name of the image to mount, either an fs-verity hash or prefixed with 'ref/' (or
+        /// the OCI config name with --oci, or the path of the dumpfile with --dumpfile)
+        source: String,
+        /// the mountpoint
+        mountpoint: PathBuf,
+        /// Mount the filesystem of an OCI config
+        #[cfg(feature = "oci")]
+        #[clap(long, conflicts_with = "dumpfile")]
+        oci: bool,
+        /// The fs-verity digest of the OCI config (with --oci)
+        #[cfg(feature = "oci")]
+        #[clap(long, requires = "oci")]
+        config_verity: Option<String>,
+        /// Mount the filesystem described by a dumpfile
+        #[clap(long)]
+        dumpfile: bool,
+        /// Allow other users to access the filesystem
+        #[clap(long)]
+        allow_other: bool,
+        /// Make the filesystem writable, keeping changes in memory until it's unmounted
+        #[clap(long)]
+        writable: bool,
+        /// Verify object content against its fs-verity digest in userspace (for objects without
+        /// fs-verity enabled, as in insecure repositories)
+        #[clap(long)]
+        verify: bool,
+        /// The number of threads serving reads [default: one per CPU]
+        #[clap(long)]
+        threads: Option<usize>,
+        /// Go to the background once the filesystem is mounted
+        #[clap(long)]
+        daemon: bool,
+        /// Print "ready" once the filesystem is mounted (used by --daemon)
+        #[clap(long, hide = true)]
+        notify_ready: bool,
+    },
     /// Creates a composefs image from a filesystem
     CreateImage {
         #[clap(flatten)]
         fs_opts: FsReadOptions,
         image_name: Option<String>,
+        #[clap(flatten)]
+        format_opts: ImageFormatOptions,
     },
     /// Computes the composefs image ID for a filesystem
     ComputeId {
         #[clap(flatten)]
         fs_opts: FsReadOptions,
+        #[clap(flatten)]
+        format_opts: ImageFormatOptions,
     },
     /// Outputs the composefs dumpfile format for a filesystem
     CreateDumpfile {
@@ -176,6 +408,16 @@ enum Command {
     },
     /// Lists all object IDs referenced by an image
     ImageObjects { name: String },
+    /// Shows the differences between two images
+    ImageDiff {
+        /// the name of the old image, either an fs-verity hash or prefixed with 'refs/'
+        old: String,
+        /// the name of the new image, either an fs-verity hash or prefixed with 'refs/'
+        new: String,
+        /// Output in JSON format
+        #[clap(long)]
+        json: bool,
+    },
     #[cfg(feature = "http")]
     Fetch { url: String, name: String },
 }
@@ -190,6 +432,475 @@ where
     })
 }
 
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StreamChunk {
+    Inline {
+        size: u64,
+    },
+    /// The size is `None` if the object is missing or can't be read.
+    External {
+        object: String,
+        size: Option<u64>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct StreamInfo {
+    algorithm: &'static str,
+    block_size: u64,
+    content_type: u64,
+    total_size: u64,
+    named_refs: BTreeMap<String, String>,
+    object_refs: Vec<String>,
+    inline_bytes: u64,
+    external_bytes: u64,
+    missing_objects: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<Vec<StreamChunk>>,
+}
+
+fn stream_info<ObjectID>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+    verbose: bool,
+) -> Result<StreamInfo>
+where
+    ObjectID: FsVerityHashValue,
+{
+    let mut reader = repo.open_stream(name, None, None)?;
+
+    let named_refs = reader
+        .iter_named_refs()
+        .map(|(name, id)| (name.to_string(), id.to_hex()))
+        .collect();
+    let mut object_refs = vec![];
+    reader.get_object_refs(|id| object_refs.push(id.to_hex()))?;
+
+    let mut info = StreamInfo {
+        algorithm: ObjectID::ID,
+        block_size: 1 << reader.lg_blocksize,
+        content_type: reader.content_type,
+        total_size: reader.total_size,
+        named_refs,
+        object_refs,
+        inline_bytes: 0,
+        external_bytes: 0,
+        missing_objects: 0,
+        chunks: None,
+    };
+
+    let mut chunks = vec![];
+    while let Some(chunk) = reader.next_chunk()? {
+        let chunk = match chunk {
+            SplitStreamData::Inline(data) => {
+                let size = data.len() as u64;
+                info.inline_bytes += size;
+                StreamChunk::Inline { size }
+            }
+            SplitStreamData::External(id) => {
+                // Keep going without the size, so the output shows what's missing
+                let size = repo
+                    .open_object(&id)
+                    .and_then(|fd| Ok(fstat(fd)?.st_size as u64))
+                    .ok();
+                match size {
+                    Some(size) => info.external_bytes += size,
+                    None => info.missing_objects += 1,
+                }
+                StreamChunk::External {
+                    object: id.to_hex(),
+                    size,
+                }
+            }
+        };
+        if verbose {
+            chunks.push(chunk);
+        }
+    }
+    if verbose {
+        info.chunks = Some(chunks);
+    }
+
+    Ok(info)
+}
+
+fn print_stream_info(info: &StreamInfo) {
+    println!("algorithm: {}", info.algorithm);
+    println!("block size: {}", info.block_size);
+    println!("content type: {:#018x}", info.content_type);
+    println!("total size: {}", info.total_size);
+    println!("inline bytes: {}", info.inline_bytes);
+    println!("external bytes: {}", info.external_bytes);
+    println!("missing objects: {}", info.missing_objects);
+    println!("named refs: {}", info.named_refs.len());
+    for (name, id) in &info.named_refs {
+        println!("  {name} {id}");
+    }
+    println!("object refs: {}", info.object_refs.len());
+    for id in &info.object_refs {
+        println!("  {id}");
+    }
+    if let Some(chunks) = &info.chunks {
+        println!("chunks: {}", chunks.len());
+        for chunk in chunks {
+            match chunk {
+                StreamChunk::Inline { size } => println!("  inline {size}"),
+                StreamChunk::External { object, size } => match size {
+                    Some(size) => println!("  external {size} {object}"),
+                    None => println!("  external ? {object}"),
+                },
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImageChange {
+    path: PathBuf,
+    change: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    metadata: Vec<&'static str>,
+}
+
+impl From<Change> for ImageChange {
+    fn from(change: Change) -> Self {
+        let (kind, content, metadata) = match change.kind {
+            ChangeKind::Added => ("added", None, vec![]),
+            ChangeKind::Removed => ("removed", None, vec![]),
+            ChangeKind::Modified { content, metadata } => (
+                "modified",
+                Some(content),
+                metadata.iter().map(MetadataChange::as_str).collect(),
+            ),
+        };
+        Self {
+            path: change.path,
+            change: kind,
+            content,
+            metadata,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BootEntryInfo {
+    id: String,
+    filename: String,
+    #[serde(rename = "type")]
+    entry_type: &'static str,
+    title: Option<String>,
+    version: Option<String>,
+    sort_key: Option<String>,
+    image: Option<String>,
+    insecure: bool,
+    default: bool,
+    booted: bool,
+    cmdline: Option<String>,
+}
+
+impl BootEntryInfo {
+    fn new<ObjectID: FsVerityHashValue>(
+        entry: &InstalledEntry<ObjectID>,
+        booted: Option<&ObjectID>,
+    ) -> Self {
+        Self {
+            id: entry.id.clone(),
+            filename: entry.filename.clone(),
+            entry_type: match entry.entry_type {
+                EntryType::Type1 => "type1",
+                EntryType::Type2 => "type2",
+            },
+            title: entry.title.clone(),
+            version: entry.version.clone(),
+            sort_key: entry.sort_key.clone(),
+            image: entry.image.as_ref().map(FsVerityHashValue::to_hex),
+            insecure: entry.insecure,
+            default: entry.is_default,
+            booted: entry.image.is_some() && entry.image.as_ref() == booted,
+            cmdline: entry.cmdline.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DeploymentInfo {
+    image: String,
+    entries: Vec<String>,
+    state: Option<PathBuf>,
+    default: bool,
+    booted: bool,
+    staged: bool,
+}
+
+impl<ObjectID: FsVerityHashValue> From<Deployment<ObjectID>> for DeploymentInfo {
+    fn from(deployment: Deployment<ObjectID>) -> Self {
+        Self {
+            image: deployment.image.to_hex(),
+            entries: deployment.entries.into_iter().map(|e| e.filename).collect(),
+            state: deployment.state,
+            default: deployment.default,
+            booted: deployment.booted,
+            staged: deployment.staged,
+        }
+    }
+}
+
+/// Creates the boot image of an OCI image, returning its ID, its boot entry and the extra kernel
+/// command line arguments to write to the entry.
+#[cfg(feature = "oci")]
+fn prepare_oci_boot<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    config_name: &str,
+    config_verity: &Option<String>,
+    opts: &BootEntryOptions,
+    insecure: bool,
+) -> Result<(ObjectID, BootEntry<ObjectID>, Vec<String>)> {
+    let verity = verity_opt(config_verity)?;
+    let mut fs = composefs_oci::image::create_filesystem(repo, config_name, verity.as_ref())?;
+    let entries = fs.transform_for_boot(repo)?;
+    let id = fs.commit_image(repo, None)?;
+
+    if let Some(BootEntry::Type2(
+        uki @ Type2Entry {
+            pe_type: PEType::Uki,
+            ..
+        },
+    )) = entries.first()
+    {
+        let t2_entries = entries.iter().filter_map(|entry| match entry {
+            BootEntry::Type2(t2) => Some(t2),
+            _ => None,
+        });
+        let cmdline = uki.effective_cmdline(t2_entries, repo)?;
+        println!("Kernel command line: {cmdline}");
+    }
+
+    let Some(mut entry) = entries.into_iter().next() else {
+        anyhow::bail!("No boot entries!");
+    };
+
+    if opts.devicetree.is_some() || !opts.devicetree_overlay.is_empty() {
+        let BootEntry::UsrLibModulesVmLinuz(vmlinuz) = &mut entry else {
+            anyhow::bail!("--devicetree requires a kernel in /usr/lib/modules");
+        };
+        let overlays: Vec<&str> = opts.devicetree_overlay.iter().map(String::as_str).collect();
+        vmlinuz.select_devicetree(opts.devicetree.as_deref(), &overlays)?;
+    }
+
+    let mut cmdline = opts.cmdline.clone();
+    let entry = match (&opts.uki_stub, entry) {
+        (Some(stub), BootEntry::UsrLibModulesVmLinuz(entry)) => {
+            let cmdline_refs: Vec<&str> = cmdline.iter().map(String::as_str).collect();
+            let t2 = entry.into_type2(
+                &std::fs::read(stub)?,
+                &id,
+                insecure,
+                &cmdline_refs,
+                opts.entry_id.as_deref(),
+                repo,
+            )?;
+            // The command line is part of the UKI now
+            cmdline.clear();
+            BootEntry::Type2(t2)
+        }
+        (Some(_), _) => {
+            anyhow::bail!("--uki-stub requires a kernel in /usr/lib/modules")
+        }
+        (None, entry) => entry,
+    };
+
+    Ok((id, entry, cmdline))
+}
+
+/// Creates the boot image of an OCI image and stages its deployment, reporting `/etc` conflicts.
+#[cfg(feature = "oci")]
+fn stage_oci_boot<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    deployments: &Deployments<ObjectID>,
+    config_name: &str,
+    config_verity: &Option<String>,
+    boot_opts: &BootEntryOptions,
+    insecure: bool,
+) -> Result<ObjectID> {
+    let trusted = boot_opts.trusted()?;
+    let (id, entry, cmdline) =
+        prepare_oci_boot(repo, config_name, config_verity, boot_opts, insecure)?;
+    let cmdline: Vec<&str> = cmdline.iter().map(String::as_str).collect();
+    let options = composefs_boot::deploy::StageOptions {
+        entry_id: boot_opts.entry_id.as_deref(),
+        cmdline: &cmdline,
+        boot_tries: boot_opts.boot_tries,
+        insecure,
+        trusted: trusted.as_ref(),
+        state: state::StateOptions {
+            share_var: !boot_opts.private_var,
+        },
+    };
+    let merge = deployments.stage(&id, entry, &options)?;
+    for conflict in &merge.conflicts {
+        println!("Conflict in {conflict}");
+    }
+    Ok(id)
+}
+
+#[cfg(feature = "oci")]
+impl BootEntryOptions {
+    fn trusted(&self) -> Result<Option<composefs_boot::uki::TrustedCerts>> {
+        Ok(match &self.secure_boot_db {
+            Some(path) => Some(composefs_boot::uki::TrustedCerts::from_pem(
+                &std::fs::read(path)?,
+            )?),
+            None => None,
+        })
+    }
+}
+
+/// Runs the current command again in the background, with --daemon replaced by --notify-ready,
+/// and waits until it has mounted the filesystem.
+#[cfg(feature = "fuse")]
+fn spawn_fuse_daemon() -> Result<()> {
+    use std::{
+        io::BufRead,
+        os::unix::process::CommandExt,
+        process::{Command, Stdio},
+    };
+
+    let args = std::env::args_os().skip(1).map(|arg| match arg.to_str() {
+        Some("--daemon") => "--notify-ready".into(),
+        _ => arg,
+    });
+    let (reader, writer) = std::io::pipe()?;
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(writer)
+        .process_group(0)
+        .spawn()?;
+
+    // We get EOF without "ready" if the daemon fails before mounting the filesystem
+    let mut line = String::new();
+    std::io::BufReader::new(reader).read_line(&mut line)?;
+    if line != "ready\n" {
+        anyhow::bail!("fuse-mount daemon failed: {}", child.wait()?);
+    }
+    println!("{}", child.id());
+    Ok(())
+}
+
+/// What to serve with `cfsctl fuse-mount`
+#[cfg(feature = "fuse")]
+#[derive(Debug)]
+enum FuseSource {
+    /// A committed EROFS image, by name
+    Image(String),
+    /// The filesystem described by a dumpfile
+    Dumpfile(PathBuf),
+    /// The filesystem of an OCI config, by name, with its optional fs-verity digest
+    #[cfg(feature = "oci")]
+    Oci(String, Option<String>),
+}
+
+/// Reads `source`, mounts it with FUSE on `mountpoint` and serves it until the filesystem is
+/// unmounted.  This blocks for as long as the filesystem is mounted.
+#[cfg(feature = "fuse")]
+fn serve_fuse<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    source: &FuseSource,
+    mountpoint: &Path,
+    mount_options: &composefs_fuse::MountOptions,
+    fuse_options: composefs_fuse::FuseOptions,
+    notify_ready: bool,
+) -> Result<()> {
+    use composefs::{dumpfile::dumpfile_to_filesystem, erofs::reader::image_to_filesystem};
+    use composefs_fuse::{serve_image_fuse, serve_tree_fuse, serve_tree_fuse_overlay, UpperLayer};
+
+    let mount = || -> Result<std::os::fd::OwnedFd> {
+        let dev_fuse = composefs_fuse::open_fuse()?;
+        let mnt = composefs_fuse::mount_fuse_with_options(&dev_fuse, mount_options)?;
+        composefs::mount::mount_at(mnt, CWD, mountpoint)?;
+        if notify_ready {
+            println!("ready");
+        }
+        Ok(dev_fuse)
+    };
+
+    // Read the source before mounting anything, so that errors get reported right away
+    let fs = match source {
+        FuseSource::Image(name) => {
+            let image = repo.read_image(name)?;
+            if mount_options.readonly {
+                serve_image_fuse(mount()?, &image, repo, fuse_options)?;
+                return Ok(());
+            }
+            image_to_filesystem(&image)?
+        }
+        FuseSource::Dumpfile(path) => dumpfile_to_filesystem(&std::fs::read_to_string(path)?)?,
+        #[cfg(feature = "oci")]
+        FuseSource::Oci(name, config_verity) => {
+            let verity = verity_opt(config_verity)?;
+            composefs_oci::image::create_filesystem(repo, name, verity.as_ref())?
+        }
+    };
+
+    let dev_fuse = mount()?;
+    if mount_options.readonly {
+        serve_tree_fuse(dev_fuse, &fs.root, repo, fuse_options)?;
+    } else {
+        serve_tree_fuse_overlay(dev_fuse, &fs.root, repo, UpperLayer::Memory, fuse_options)?;
+    }
+    Ok(())
+}
+
+/// Mounts `source` with FUSE on `mountpoint` and serves it until the filesystem is unmounted or
+/// we get SIGTERM or SIGINT.  The FUSE session runs on a blocking thread, outside of the runtime.
+#[cfg(feature = "fuse")]
+async fn fuse_mount<ObjectID: FsVerityHashValue>(
+    repo: Repository<ObjectID>,
+    source: FuseSource,
+    mountpoint: PathBuf,
+    mount_options: composefs_fuse::MountOptions,
+    fuse_options: composefs_fuse::FuseOptions,
+    notify_ready: bool,
+) -> Result<()> {
+    use anyhow::Context;
+    use rustix::mount::{unmount, UnmountFlags};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let target = mountpoint.clone();
+    let mut server = tokio::task::spawn_blocking(move || {
+        serve_fuse(
+            &repo,
+            &source,
+            &mountpoint,
+            &mount_options,
+            fuse_options,
+            notify_ready,
+        )
+    });
+
+    tokio::select! {
+        result = &mut server => return result?,
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+
+    // Unmounting the filesystem makes the server return
+    unmount(&target, UnmountFlags::DETACH)
+        .with_context(|| format!("Failed to unmount {}", target.display()))?;
+    server.await?
+}
+
+/// The sysroot containing the repository, which has the state directories of the deployments.
+fn sysroot_path(repo: Option<&Path>) -> &Path {
+    repo.map(|p| p.parent().unwrap())
+        .unwrap_or(Path::new("/sysroot"))
+}
+
 fn open_repo<ObjectID>(args: &App) -> Result<Repository<ObjectID>>
 where
     ObjectID: FsVerityHashValue,
@@ -213,7 +924,11 @@ where
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    // Show warnings about boot entries (like skipped microcode) unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("composefs_boot=warn"),
+    )
+    .init();
 
     let args = App::parse();
 
@@ -237,6 +952,19 @@ where
         Command::Cat { name } => {
             repo.merge_splitstream(&name, None, None, &mut std::io::stdout())?;
         }
+        Command::StreamInfo {
+            ref name,
+            verbose,
+            json,
+        } => {
+            let info = stream_info(&repo, name, verbose)?;
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout(), &info)?;
+                println!();
+            } else {
+                print_stream_info(&info);
+            }
+        }
         Command::ImportImage { reference } => {
             let image_id = repo.import_image(&reference, &mut std::io::stdin())?;
             println!("{}", image_id.to_id());
@@ -272,6 +1000,7 @@ where
                 ref config_name,
                 ref config_verity,
                 bootable,
+                ref format_opts,
             } => {
                 let verity = verity_opt(config_verity)?;
                 let mut fs =
@@ -279,7 +1008,8 @@ where
                 if bootable {
                     fs.transform_for_boot(&repo)?;
                 }
-                let id = fs.compute_image_id();
+                format_opts.prepare(&mut fs, &repo)?;
+                let id = fs.compute_image_id_with_format(format_opts.version());
                 println!("{}", id.to_hex());
             }
             OciCommand::CreateImage {
@@ -287,6 +1017,7 @@ where
                 ref config_verity,
                 bootable,
                 ref image_name,
+                ref format_opts,
             } => {
                 let verity = verity_opt(config_verity)?;
                 let mut fs =
@@ -294,7 +1025,12 @@ where
                 if bootable {
                     fs.transform_for_boot(&repo)?;
                 }
-                let image_id = fs.commit_image(&repo, image_name.as_deref())?;
+                format_opts.prepare(&mut fs, &repo)?;
+                let image_id = fs.commit_image_with_format(
+                    &repo,
+                    image_name.as_deref(),
+                    format_opts.version(),
+                )?;
                 println!("{}", image_id.to_id());
             }
             OciCommand::Pull { ref image, name } => {
@@ -324,45 +1060,25 @@ where
                 ref config_name,
                 ref config_verity,
                 ref bootdir,
-                ref entry_id,
-                ref cmdline,
+                ref boot_opts,
             } => {
-                let verity = verity_opt(config_verity)?;
-                let mut fs =
-                    composefs_oci::image::create_filesystem(&repo, config_name, verity.as_ref())?;
-                let entries = fs.transform_for_boot(&repo)?;
-                let id = fs.commit_image(&repo, None)?;
-
-                let Some(entry) = entries.into_iter().next() else {
-                    anyhow::bail!("No boot entries!");
-                };
-
-                let cmdline_refs: Vec<&str> = cmdline.iter().map(String::as_str).collect();
-                write_boot::write_boot_simple(
+                let sysroot = sysroot_path(args.repo.as_deref());
+                let deployments = Deployments::new(&repo, sysroot, bootdir, None)?;
+                stage_oci_boot(
                     &repo,
-                    entry,
-                    &id,
+                    &deployments,
+                    config_name,
+                    config_verity,
+                    boot_opts,
                     args.insecure,
-                    bootdir,
-                    None,
-                    entry_id.as_deref(),
-                    &cmdline_refs,
                 )?;
-
-                let state = args
-                    .repo
-                    .as_ref()
-                    .map(|p: &PathBuf| p.parent().unwrap())
-                    .unwrap_or(Path::new("/sysroot"))
-                    .join("state/deploy")
-                    .join(id.to_hex());
-
-                create_dir_all(state.join("var"))?;
-                create_dir_all(state.join("etc/upper"))?;
-                create_dir_all(state.join("etc/work"))?;
+                deployments.finalize()?;
             }
         },
-        Command::ComputeId { fs_opts } => {
+        Command::ComputeId {
+            fs_opts,
+            format_opts,
+        } => {
             let mut fs = if fs_opts.no_propagate_usr_to_root {
                 composefs::fs::read_filesystem(CWD, &fs_opts.path, Some(&repo))?
             } else {
@@ -371,12 +1087,14 @@ where
             if fs_opts.bootable {
                 fs.transform_for_boot(&repo)?;
             }
-            let id = fs.compute_image_id();
+            format_opts.prepare(&mut fs, &repo)?;
+            let id = fs.compute_image_id_with_format(format_opts.version());
             println!("{}", id.to_hex());
         }
         Command::CreateImage {
             fs_opts,
             ref image_name,
+            format_opts,
         } => {
             let mut fs = if fs_opts.no_propagate_usr_to_root {
                 composefs::fs::read_filesystem(CWD, &fs_opts.path, Some(&repo))?
@@ -386,7 +1104,9 @@ where
             if fs_opts.bootable {
                 fs.transform_for_boot(&repo)?;
             }
-            let id = fs.commit_image(&repo, image_name.as_deref())?;
+            format_opts.prepare(&mut fs, &repo)?;
+            let id =
+                fs.commit_image_with_format(&repo, image_name.as_deref(), format_opts.version())?;
             println!("{}", id.to_id());
         }
         Command::CreateDumpfile { fs_opts } => {
@@ -400,15 +1120,224 @@ where
             }
             fs.print_dumpfile()?;
         }
+        Command::Boot { ref bootdir, cmd } => {
+            let boot = BootDir::new(bootdir, None);
+            let booted = get_booted_composefs::<ObjectID>().unwrap_or_default();
+            match cmd {
+                BootCommand::List { json } => {
+                    let entries: Vec<BootEntryInfo> = boot
+                        .list::<ObjectID>()?
+                        .iter()
+                        .map(|entry| BootEntryInfo::new(entry, booted.as_ref()))
+                        .collect();
+                    if json {
+                        serde_json::to_writer_pretty(std::io::stdout(), &entries)?;
+                        println!();
+                    } else {
+                        for entry in entries {
+                            println!(
+                                "{}{} {} {} {} {}",
+                                if entry.default { '*' } else { ' ' },
+                                if entry.booted { '>' } else { ' ' },
+                                entry.id,
+                                entry.entry_type,
+                                entry.image.as_deref().unwrap_or("-"),
+                                entry.title.as_deref().unwrap_or(""),
+                            );
+                        }
+                    }
+                }
+                BootCommand::SetDefault { id } => boot.set_default::<ObjectID>(&id)?,
+                BootCommand::Remove { id, force } => {
+                    let entries = boot.list::<ObjectID>()?;
+                    if let Some(entry) = entries.iter().find(|e| e.id == id || e.filename == id) {
+                        anyhow::ensure!(
+                            entry.image.is_none() || entry.image != booted,
+                            "Refusing to remove the entry for the currently-booted image"
+                        );
+                    }
+                    let removed = boot.remove::<ObjectID>(&id, force)?;
+                    println!("Removed {}", removed.filename);
+                }
+                BootCommand::MarkGood => {
+                    for path in boot.mark_booted_good::<ObjectID>()? {
+                        println!("Marked {} as good", path.display());
+                    }
+                }
+                BootCommand::Prune { keep } => {
+                    let protect: Vec<ObjectID> = booted.into_iter().collect();
+                    for removed in boot.prune(keep, &protect)? {
+                        println!("Removed {}", removed.filename);
+                    }
+                }
+                BootCommand::GrubCfg => {
+                    print!(
+                        "{}",
+                        composefs_boot::grub::installed_grub_cfg::<ObjectID>(&boot)?
+                    );
+                }
+            }
+        }
+        Command::Deploy { ref bootdir, cmd } => {
+            let deployments =
+                Deployments::new(&repo, sysroot_path(args.repo.as_deref()), bootdir, None)?;
+            match cmd {
+                #[cfg(feature = "oci")]
+                DeployCommand::Stage {
+                    config_name,
+                    config_verity,
+                    boot_opts,
+                } => {
+                    let id = stage_oci_boot(
+                        &repo,
+                        &deployments,
+                        &config_name,
+                        &config_verity,
+                        &boot_opts,
+                        args.insecure,
+                    )?;
+                    println!("Staged {}", id.to_hex());
+                }
+                DeployCommand::Finalize => {
+                    let id = deployments.finalize()?;
+                    println!("{} is now the default", id.to_hex());
+                }
+                DeployCommand::Rollback => {
+                    let id = deployments.rollback()?;
+                    println!("{} is now the default", id.to_hex());
+                }
+                DeployCommand::Status { json } => {
+                    let status: Vec<DeploymentInfo> =
+                        deployments.status()?.into_iter().map(Into::into).collect();
+                    if json {
+                        serde_json::to_writer_pretty(std::io::stdout(), &status)?;
+                        println!();
+                    } else {
+                        for deployment in status {
+                            println!(
+                                "{}{}{} {} {}",
+                                if deployment.default { '*' } else { ' ' },
+                                if deployment.booted { '>' } else { ' ' },
+                                if deployment.staged { '+' } else { ' ' },
+                                deployment.image,
+                                deployment.entries.join(" "),
+                            );
+                        }
+                    }
+                }
+                DeployCommand::Remove { deployment } => {
+                    deployments.remove(&ObjectID::from_hex(&deployment)?)?;
+                }
+            }
+        }
+        Command::State { ref cmd } => match cmd {
+            StateCommand::Reset {
+                etc,
+                var,
+                next_boot,
+                deployment,
+            } => {
+                let id = ObjectID::from_hex(deployment)?;
+                let state = state::state_dir(sysroot_path(args.repo.as_deref()), &id);
+                anyhow::ensure!(state.is_dir(), "No state directory at {state:?}");
+                let options = match (etc, var) {
+                    (false, false) => state::ResetOptions {
+                        etc: true,
+                        var: true,
+                    },
+                    (&etc, &var) => state::ResetOptions { etc, var },
+                };
+
+                if *next_boot {
+                    state::request_reset(&state, &options)?;
+                } else {
+                    anyhow::ensure!(
+                        get_booted_composefs::<ObjectID>()? != Some(id),
+                        "Can't reset the booted deployment while it's running: use --next-boot"
+                    );
+                    state::reset_state(&state, &options)?;
+                }
+            }
+        },
         Command::Mount { name, mountpoint } => {
             repo.mount_at(&name, &mountpoint)?;
         }
+        #[cfg(feature = "fuse")]
+        Command::FuseMount {
+            ref source,
+            ref mountpoint,
+            #[cfg(feature = "oci")]
+            oci,
+            #[cfg(feature = "oci")]
+            ref config_verity,
+            dumpfile,
+            allow_other,
+            writable,
+            verify,
+            threads,
+            daemon,
+            notify_ready,
+        } => {
+            if daemon {
+                return spawn_fuse_daemon();
+            }
+
+            #[cfg(feature = "oci")]
+            let oci_source = oci.then(|| FuseSource::Oci(source.clone(), config_verity.clone()));
+            #[cfg(not(feature = "oci"))]
+            let oci_source = None;
+
+            let source = if let Some(source) = oci_source {
+                source
+            } else if dumpfile {
+                FuseSource::Dumpfile(source.into())
+            } else {
+                FuseSource::Image(source.clone())
+            };
+            let threads = match threads {
+                Some(threads) => threads,
+                None => std::thread::available_parallelism()?.get(),
+            };
+            let mount_options = composefs_fuse::MountOptions {
+                readonly: !writable,
+                allow_other,
+            };
+            let fuse_options = composefs_fuse::FuseOptions {
+                verify_objects: verify,
+                threads,
+            };
+            fuse_mount(
+                repo,
+                source,
+                mountpoint.clone(),
+                mount_options,
+                fuse_options,
+                notify_ready,
+            )
+            .await?;
+        }
         Command::ImageObjects { name } => {
             let objects = repo.objects_for_image(&name)?;
             for object in objects {
                 println!("{}", object.to_id());
             }
         }
+        Command::ImageDiff {
+            ref old,
+            ref new,
+            json,
+        } => {
+            let changes = diff_images::<ObjectID>(&repo.read_image(old)?, &repo.read_image(new)?)?;
+            if json {
+                let changes: Vec<ImageChange> = changes.into_iter().map(Into::into).collect();
+                serde_json::to_writer_pretty(std::io::stdout(), &changes)?;
+                println!();
+            } else {
+                for change in changes {
+                    println!("{change}");
+                }
+            }
+        }
         Command::GC { root, dry_run } => {
             let roots: Vec<&str> = root.iter().map(|s| s.as_str()).collect();
             let result = if dry_run {
@@ -439,3 +1368,52 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use composefs::fsverity::compute_verity;
+
+    use super::*;
+
+    #[test]
+    fn test_stream_info_missing_object() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        repo.set_insecure(true);
+        let repo = Arc::new(repo);
+
+        let present = vec![0x5a; 8192];
+        let missing = vec![0xa5; 4096];
+        let mut writer = repo.create_stream(0);
+        writer.write_inline(b"header");
+        writer.write_external(&missing).unwrap();
+        writer.write_external(&present).unwrap();
+        repo.write_stream(writer, "test", None).unwrap();
+
+        let missing_id: Sha256HashValue = compute_verity(&missing);
+        let hex = missing_id.to_hex();
+        std::fs::remove_file(tmp.path().join("objects").join(&hex[..2]).join(&hex[2..])).unwrap();
+
+        let info = stream_info(&repo, "test", true).unwrap();
+        assert_eq!(info.external_bytes, 8192);
+        assert_eq!(info.missing_objects, 1);
+        let chunks = info.chunks.as_ref().unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(matches!(
+            &chunks[1],
+            StreamChunk::External { object, size: None } if *object == hex
+        ));
+        assert!(matches!(
+            &chunks[2],
+            StreamChunk::External {
+                size: Some(8192),
+                ..
+            }
+        ));
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["missing_objects"], 1);
+        assert_eq!(json["chunks"][1]["size"], serde_json::Value::Null);
+        assert_eq!(json["chunks"][1]["object"], hex.as_str());
+    }
+}
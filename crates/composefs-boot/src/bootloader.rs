@@ -6,12 +6,16 @@
 //! from /usr/lib/modules. Key types include `BootLoaderEntryFile` for parsing BLS
 //! configuration files and `BootEntry` enum for representing different boot entry types.
 
-use core::ops::Range;
+use core::{fmt::Write, ops::Range};
 use std::{
-    collections::HashMap, ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf, str::from_utf8,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    str::from_utf8,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Context, Result};
 
 use composefs::{
     fsverity::FsVerityHashValue,
@@ -19,7 +23,11 @@ use composefs::{
     tree::{Directory, FileSystem, ImageError, Inode, LeafContent, RegularFile},
 };
 
-use crate::cmdline::{make_cmdline_composefs, split_cmdline};
+use crate::{
+    cmdline::{make_cmdline_composefs, split_cmdline},
+    microcode::{build_early_microcode, find_microcode, VendorMicrocode},
+    uki,
+};
 
 /// Strips the key (if it matches) plus the following whitespace from a single line in a "Type #1
 /// Boot Loader Specification Entry" file.
@@ -103,6 +111,37 @@ impl BootLoaderEntryFile {
         self.lines.iter().find_map(|line| strip_ble_key(line, key))
     }
 
+    /// Sets the value for a given key in the entry file.
+    ///
+    /// The first line with the key is replaced (and any further ones are removed) or, if the key
+    /// isn't present, a new line is appended.  If `value` is None, all lines with the key are
+    /// removed.
+    pub fn set_value(&mut self, key: &str, value: Option<&str>) {
+        let new = value.map(|value| format!("{key} {value}"));
+        let mut found = false;
+
+        self.lines.retain_mut(|line| {
+            if strip_ble_key(line, key).is_none() {
+                return true;
+            }
+            if found {
+                return false;
+            }
+            found = true;
+            match &new {
+                Some(new) => {
+                    line.clone_from(new);
+                    true
+                }
+                None => false,
+            }
+        });
+
+        if let (false, Some(new)) = (found, new) {
+            self.lines.push(new);
+        }
+    }
+
     /// Adds a kernel command-line argument, possibly replacing a previous value.
     ///
     /// arg can be something like "composefs=xyz" but it can also be something like "rw".  In
@@ -180,29 +219,41 @@ impl<ObjectID: FsVerityHashValue> Type1Entry<ObjectID> {
     pub fn relocate(&mut self, boot_subdir: Option<&str>, entry_id: &str) {
         self.filename = Box::from(format!("{entry_id}.conf").as_ref());
         for line in &mut self.entry.lines {
-            for key in ["linux", "initrd", "efi"] {
+            for key in T1_FILE_KEYS {
                 let Some(value) = strip_ble_key(line, key) else {
                     continue;
                 };
-                let Some((_dir, basename)) = value.rsplit_once("/") else {
-                    continue;
-                };
 
-                let file = self.files.remove(value);
+                // devicetree-overlay can have several paths: replace them back to front, so that
+                // the ranges of the ones we haven't done yet stay valid
+                let paths: Vec<(Range<usize>, &str)> = value
+                    .split_ascii_whitespace()
+                    .map(|path| (substr_range(line, path).unwrap(), path))
+                    .collect();
+                let mut replacements = vec![];
+                for (range, path) in paths {
+                    let Some((_dir, basename)) = path.rsplit_once("/") else {
+                        continue;
+                    };
 
-                let new = format!("/{entry_id}/{basename}");
-                let range = substr_range(line, value).unwrap();
+                    let file = self.files.remove(path);
 
-                let final_entry_path = if let Some(boot_subdir) = boot_subdir {
-                    format!("/{boot_subdir}{new}")
-                } else {
-                    new.clone()
-                };
+                    let new = format!("/{entry_id}/{basename}");
+
+                    let final_entry_path = if let Some(boot_subdir) = boot_subdir {
+                        format!("/{boot_subdir}{new}")
+                    } else {
+                        new.clone()
+                    };
+                    replacements.push((range, final_entry_path));
 
-                line.replace_range(range, &final_entry_path);
+                    if let Some(file) = file {
+                        self.files.insert(new.into_boxed_str(), file);
+                    }
+                }
 
-                if let Some(file) = file {
-                    self.files.insert(new.into_boxed_str(), file);
+                for (range, path) in replacements.into_iter().rev() {
+                    line.replace_range(range, &path);
                 }
             }
         }
@@ -232,8 +283,8 @@ impl<ObjectID: FsVerityHashValue> Type1Entry<ObjectID> {
         let entry = BootLoaderEntryFile::new(from_utf8(&composefs::fs::read_file(file, repo)?)?);
 
         let mut files = HashMap::new();
-        for key in ["linux", "initrd", "efi"] {
-            for pathname in entry.get_values(key) {
+        for key in T1_FILE_KEYS {
+            for pathname in entry.get_values(key).flat_map(str::split_ascii_whitespace) {
                 let (dir, filename) = root.split(pathname.as_ref())?;
                 files.insert(Box::from(pathname), dir.get_file(filename)?.clone());
             }
@@ -285,6 +336,12 @@ impl<ObjectID: FsVerityHashValue> Type1Entry<ObjectID> {
     }
 }
 
+/// The keys in a Type 1 entry which refer to files on the boot partition.
+///
+/// `devicetree-overlay` can list several files, separated by spaces.
+pub(crate) const T1_FILE_KEYS: &[&str] =
+    &["linux", "initrd", "efi", "devicetree", "devicetree-overlay"];
+
 /// File extension for EFI executables
 pub const EFI_EXT: &str = ".efi";
 /// Directory extension for UKI addon directories
@@ -320,10 +377,23 @@ pub struct Type2Entry<ObjectID: FsVerityHashValue> {
 impl<ObjectID: FsVerityHashValue> Type2Entry<ObjectID> {
     /// Renames the UKI file to a new name.
     ///
+    /// For addons, the `.efi.extra.d` directory they're in is renamed instead, so that they stay
+    /// with the renamed UKI.
+    ///
     /// # Arguments
     ///
     /// * `name` - New base name (without .efi extension)
     pub fn rename(&mut self, name: &str) {
+        if let PEType::UkiAddon = self.pe_type {
+            if let (Some(dir), Some(filename)) =
+                (self.file_path.parent(), self.file_path.file_name())
+            {
+                let dir = dir.with_file_name(format!("{name}{EFI_ADDON_DIR_EXT}"));
+                self.file_path = dir.join(filename);
+            }
+            return;
+        }
+
         let new_name = format!("{name}.efi");
 
         if let Some(parent) = self.file_path.parent() {
@@ -333,6 +403,59 @@ impl<ObjectID: FsVerityHashValue> Type2Entry<ObjectID> {
         }
     }
 
+    /// Returns the addons for this UKI among `entries`, in the order that systemd-stub applies
+    /// them.
+    ///
+    /// These are the `*.addon.efi` files in the `<name>.efi.extra.d` directory next to the UKI.
+    pub fn addons<'a>(&self, entries: impl IntoIterator<Item = &'a Self>) -> Vec<&'a Self> {
+        let stem = self.file_path.file_stem().unwrap_or_default();
+        let mut dir = stem.to_os_string();
+        dir.push(EFI_ADDON_DIR_EXT);
+        let dir = self.file_path.with_file_name(dir);
+
+        let mut addons: Vec<&Self> = entries
+            .into_iter()
+            .filter(|entry| matches!(entry.pe_type, PEType::UkiAddon) && entry.kver == self.kver)
+            .filter(|entry| entry.file_path.parent() == Some(&dir))
+            .filter(|entry| {
+                let filename = entry.file_path.as_os_str().as_bytes();
+                filename.ends_with(EFI_ADDON_FILE_EXT.as_bytes())
+            })
+            .collect();
+        addons.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        addons
+    }
+
+    /// Computes the kernel command line that this UKI boots with, including its addons.
+    ///
+    /// `entries` are all of the Type 2 entries of the image, as returned by [`Self::load_all`].
+    /// Fails if any of the addons tries to set composefs=.
+    pub fn effective_cmdline<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a Self>,
+        repo: &Repository<ObjectID>,
+    ) -> Result<String> {
+        let content = composefs::fs::read_file(&self.file, repo)?;
+        let mut addon_contents = vec![];
+        for addon in self.addons(entries) {
+            addon_contents.push((addon, composefs::fs::read_file(&addon.file, repo)?));
+        }
+
+        let mut addon_cmdlines = vec![];
+        for (addon, content) in &addon_contents {
+            if let Some(cmdline) = uki::get_addon_cmdline(content)? {
+                crate::cmdline::check_addon_cmdline(cmdline)
+                    .with_context(|| format!("Invalid addon {:?}", addon.file_path))?;
+                addon_cmdlines.push(cmdline);
+            }
+        }
+
+        Ok(crate::cmdline::effective_cmdline(
+            uki::get_cmdline(&content)?,
+            &addon_cmdlines,
+        ))
+    }
+
     // Find UKI components, the UKI PE binary and other UKI addons,
     // if any, in the provided directory
     fn find_uki_components(
@@ -370,7 +493,9 @@ impl<ObjectID: FsVerityHashValue> Type2Entry<ObjectID> {
                 kver: kver.clone(),
                 file_path: path.clone(),
                 file: file.clone(),
-                pe_type: if path.components().count() == 1 {
+                pe_type: if path.components().count() == 1
+                    && !filename.as_bytes().ends_with(EFI_ADDON_FILE_EXT.as_bytes())
+                {
                     PEType::Uki
                 } else {
                     PEType::UkiAddon
@@ -440,49 +565,217 @@ pub struct UsrLibModulesVmlinuz<ObjectID: FsVerityHashValue> {
     pub initramfs: Option<RegularFile<ObjectID>>,
     /// Optional os-release file from /usr/lib/os-release
     pub os_release: Option<RegularFile<ObjectID>>,
+    /// The devicetree blobs and overlays in /usr/lib/modules/{kver}/dtb, by their path relative to
+    /// that directory (like `rockchip/rk3588-rock-5b.dtb`)
+    pub dtbs: BTreeMap<Box<str>, RegularFile<ObjectID>>,
+    /// The devicetree to boot with, as a key in `dtbs`.  See [`Self::select_devicetree`].
+    pub devicetree: Option<Box<str>>,
+    /// The devicetree overlays to apply, as keys in `dtbs`
+    pub devicetree_overlays: Vec<Box<str>>,
+    /// The microcode in /usr/lib/firmware.  An early microcode initrd is built from it, to be
+    /// loaded before the initramfs.  See [`crate::microcode`].
+    pub microcode: Vec<VendorMicrocode<ObjectID>>,
 }
 
 impl<ObjectID: FsVerityHashValue> UsrLibModulesVmlinuz<ObjectID> {
+    /// Selects the devicetree and overlays to boot with, from the ones in `dtbs`.
+    ///
+    /// Most systems get their devicetree from the firmware, so none is selected by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `devicetree` - The devicetree blob to use, relative to /usr/lib/modules/{kver}/dtb
+    /// * `overlays` - Overlays to apply on top of it, relative to the same directory
+    pub fn select_devicetree(&mut self, devicetree: Option<&str>, overlays: &[&str]) -> Result<()> {
+        let mut basenames = HashSet::new();
+        for name in devicetree.iter().chain(overlays) {
+            ensure!(
+                self.dtbs.contains_key(*name),
+                "No devicetree {name} in /usr/lib/modules/{}/dtb",
+                self.kver
+            );
+            // They all get copied to the same directory on the boot partition
+            let basename = name
+                .rsplit_once('/')
+                .map_or(*name, |(_, basename)| basename);
+            ensure!(
+                basenames.insert(basename),
+                "Duplicate devicetree name {basename}"
+            );
+        }
+
+        self.devicetree = devicetree.map(Box::from);
+        self.devicetree_overlays = overlays.iter().copied().map(Box::from).collect();
+        Ok(())
+    }
+
     /// Converts this vmlinuz entry into a Type 1 BLS entry.
     ///
+    /// The early microcode initrd (if any) is added before the initramfs, and the selected
+    /// devicetree and overlays are copied next to the kernel.
+    ///
     /// # Arguments
     ///
     /// * `entry_id` - Optional entry ID to use; defaults to kernel version
+    /// * `repo` - The composefs repository, to read the microcode from
     ///
     /// # Returns
     ///
     /// A Type1Entry with generated BLS configuration
-    pub fn into_type1(self, entry_id: Option<&str>) -> Type1Entry<ObjectID> {
+    pub fn into_type1(
+        mut self,
+        entry_id: Option<&str>,
+        repo: &Repository<ObjectID>,
+    ) -> Result<Type1Entry<ObjectID>> {
+        let microcode = build_early_microcode(&self.microcode, repo)?;
         let id = entry_id.unwrap_or(&self.kver);
+        let mut files = HashMap::new();
+        let mut add_file = |name: &str, file| {
+            let path = format!("/{id}/{name}");
+            files.insert(Box::from(path.as_str()), file);
+            path
+        };
 
         let title = "todoOS";
         let version = "0-todo";
-        let entry = BootLoaderEntryFile::new(&format!(
+        let mut entry = format!(
             r#"# File created by composefs
 title {title}
 version {version}
-linux /{id}/vmlinuz
-initrd /{id}/initramfs.img
-"#
-        ));
+linux {}
+"#,
+            add_file("vmlinuz", self.vmlinuz)
+        );
+        if let Some(cpio) = microcode {
+            let microcode = RegularFile::Inline(cpio.into_boxed_slice());
+            writeln!(entry, "initrd {}", add_file("microcode.img", microcode)).unwrap();
+        }
+        if let Some(initramfs) = self.initramfs {
+            writeln!(entry, "initrd {}", add_file("initramfs.img", initramfs)).unwrap();
+        }
 
-        let filename = Box::from(format!("{id}.conf").as_ref());
+        let mut dtb = |name: &str| {
+            let basename = name.rsplit_once('/').map_or(name, |(_, basename)| basename);
+            // SAFETY: select_devicetree() checked that it's there
+            add_file(basename, self.dtbs.remove(name).unwrap())
+        };
+        if let Some(devicetree) = &self.devicetree {
+            writeln!(entry, "devicetree {}", dtb(devicetree)).unwrap();
+        }
+        if !self.devicetree_overlays.is_empty() {
+            let overlays: Vec<String> = self.devicetree_overlays.iter().map(|o| dtb(o)).collect();
+            writeln!(entry, "devicetree-overlay {}", overlays.join(" ")).unwrap();
+        }
 
-        Type1Entry {
-            filename,
-            entry,
-            files: HashMap::from([
-                (Box::from(format!("/{id}/vmlinuz")), self.vmlinuz),
-                (
-                    Box::from(format!("/{id}/initramfs.img")),
-                    self.initramfs.unwrap(),
-                ),
-            ]),
+        Ok(Type1Entry {
+            filename: Box::from(format!("{id}.conf").as_ref()),
+            entry: BootLoaderEntryFile::new(&entry),
+            files,
+        })
+    }
+
+    /// Converts this vmlinuz entry into a Type 2 entry by assembling a Unified Kernel Image.
+    ///
+    /// The UKI is built from the given EFI stub, the kernel, the initramfs (if any, preceded by the
+    /// early microcode), the selected devicetree and the os-release file, with a command line
+    /// containing the composefs= argument for `root_id`.  Devicetree overlays can't be used with
+    /// UKIs.
+    ///
+    /// # Arguments
+    ///
+    /// * `stub` - The systemd EFI stub to build the UKI from
+    /// * `root_id` - The composefs root object ID
+    /// * `insecure` - Whether to allow optional fs-verity verification
+    /// * `cmdline_extra` - Additional kernel command line arguments
+    /// * `entry_id` - Optional name for the UKI (without .efi); defaults to kernel version
+    /// * `repo` - The composefs repository
+    ///
+    /// # Returns
+    ///
+    /// A Type2Entry containing the assembled UKI
+    pub fn into_type2(
+        self,
+        stub: &[u8],
+        root_id: &ObjectID,
+        insecure: bool,
+        cmdline_extra: &[&str],
+        entry_id: Option<&str>,
+        repo: &Repository<ObjectID>,
+    ) -> Result<Type2Entry<ObjectID>> {
+        let Some(os_release) = &self.os_release else {
+            bail!("Can't build a UKI without /usr/lib/os-release");
+        };
+        ensure!(
+            self.devicetree_overlays.is_empty(),
+            "Can't use devicetree overlays with a UKI"
+        );
+
+        let mut cmdline = make_cmdline_composefs(&root_id.to_hex(), insecure);
+        for arg in cmdline_extra {
+            cmdline.push(' ');
+            cmdline.push_str(arg);
+        }
+
+        let linux = composefs::fs::read_file(&self.vmlinuz, repo)?;
+        // The kernel expects the microcode archive at the start of the initrd
+        let mut initrd = build_early_microcode(&self.microcode, repo)?.unwrap_or_default();
+        if let Some(initramfs) = &self.initramfs {
+            initrd.extend_from_slice(&composefs::fs::read_file(initramfs, repo)?);
+        }
+        let devicetree = match &self.devicetree {
+            // SAFETY: select_devicetree() checked that it's there
+            Some(name) => Some(composefs::fs::read_file(&self.dtbs[name], repo)?),
+            None => None,
+        };
+        let os_release = composefs::fs::read_file(os_release, repo)?;
+
+        let uki = uki::build_uki(
+            stub,
+            &uki::UkiComponents {
+                linux: &linux,
+                initrd: (!initrd.is_empty()).then_some(&initrd),
+                devicetree: devicetree.as_deref(),
+                os_release: &os_release,
+                cmdline: &cmdline,
+                uname: Some(&self.kver),
+            },
+        )?;
+
+        let name = entry_id.unwrap_or(&self.kver);
+        Ok(Type2Entry {
+            kver: Some(Box::from(OsStr::new(self.kver.as_ref()))),
+            file_path: PathBuf::from(format!("{name}{EFI_EXT}")),
+            file: RegularFile::Inline(uki.into_boxed_slice()),
+            pe_type: PEType::Uki,
+        })
+    }
+
+    /// Collects the devicetree blobs and overlays below `dir`.
+    fn find_dtbs(
+        dir: &Directory<ObjectID>,
+        prefix: &str,
+        dtbs: &mut BTreeMap<Box<str>, RegularFile<ObjectID>>,
+    ) -> Result<()> {
+        for (name, inode) in dir.entries() {
+            let name = format!("{prefix}{}", std::str::from_utf8(name.as_bytes())?);
+            match inode {
+                Inode::Directory(subdir) => Self::find_dtbs(subdir, &format!("{name}/"), dtbs)?,
+                Inode::Leaf(leaf) => {
+                    if let LeafContent::Regular(file) = &leaf.content {
+                        if name.ends_with(".dtb") || name.ends_with(".dtbo") {
+                            dtbs.insert(name.into(), file.clone());
+                        }
+                    }
+                }
+            }
         }
+        Ok(())
     }
 
     /// Loads all vmlinuz entries from /usr/lib/modules.
     ///
+    /// This also finds the devicetrees for each kernel, and the microcode in /usr/lib/firmware.
+    ///
     /// # Arguments
     ///
     /// * `root` - Root directory of the filesystem
@@ -492,6 +785,7 @@ initrd /{id}/initramfs.img
     /// A vector of all UsrLibModulesVmlinuz entries found
     pub fn load_all(root: &Directory<ObjectID>) -> Result<Vec<Self>> {
         let mut entries = vec![];
+        let mut microcode = None;
 
         match root.get_directory("/usr/lib/modules".as_ref()) {
             Ok(modules_dir) => {
@@ -505,11 +799,22 @@ initrd /{id}/initramfs.img
                         // without it
                         let initramfs = dir.get_file("initramfs.img".as_ref()).ok();
                         let os_release = root.get_file("/usr/lib/os-release".as_ref()).ok();
+                        let mut dtbs = BTreeMap::new();
+                        if let Some(dir) = dir.get_directory_opt("dtb".as_ref())? {
+                            Self::find_dtbs(dir, "", &mut dtbs)?;
+                        }
+                        if microcode.is_none() {
+                            microcode = Some(find_microcode(root)?);
+                        }
                         entries.push(Self {
                             kver: Box::from(std::str::from_utf8(kver.as_bytes())?),
                             vmlinuz: vmlinuz.clone(),
                             initramfs: initramfs.cloned(),
                             os_release: os_release.cloned(),
+                            dtbs,
+                            devicetree: None,
+                            devicetree_overlays: vec![],
+                            microcode: microcode.clone().unwrap_or_default(),
                         });
                     }
                 }
@@ -658,6 +963,23 @@ mod tests {
         assert_eq!(nonexistent_values, Vec::<&str>::new());
     }
 
+    #[test]
+    fn test_set_value() {
+        let mut entry = BootLoaderEntryFile::new("title A\nversion 1\nlinux /vmlinuz\nversion 2\n");
+        entry.set_value("version", Some("3"));
+        assert_eq!(entry.lines, ["title A", "version 3", "linux /vmlinuz"]);
+        entry.set_value("sort-key", Some("fedora"));
+        assert_eq!(
+            entry.lines,
+            ["title A", "version 3", "linux /vmlinuz", "sort-key fedora"]
+        );
+        entry.set_value("version", None);
+        assert_eq!(
+            entry.lines,
+            ["title A", "linux /vmlinuz", "sort-key fedora"]
+        );
+    }
+
     #[test]
     fn test_add_cmdline_new_options_line() {
         let mut entry = BootLoaderEntryFile::new("title Test Entry\nlinux /vmlinuz\n");
@@ -794,4 +1116,226 @@ mod tests {
         let separate_string = String::from("world");
         assert_eq!(substr_range(parent, &separate_string), None);
     }
+
+    #[test]
+    fn test_into_type2() {
+        use composefs::fsverity::Sha256HashValue;
+
+        let tmp = tempfile::TempDir::new().unwrap();
+        let repo = Repository::open_path(rustix::fs::CWD, tmp.path()).unwrap();
+        let file = |content: &[u8]| RegularFile::<Sha256HashValue>::Inline(content.into());
+
+        let mut entry = UsrLibModulesVmlinuz {
+            kver: Box::from("6.12.0"),
+            vmlinuz: file(b"kernel"),
+            initramfs: Some(file(b"initrd")),
+            os_release: Some(file(b"PRETTY_NAME=\"Test OS\"\n")),
+            dtbs: Default::default(),
+            devicetree: None,
+            devicetree_overlays: vec![],
+            microcode: vec![],
+        };
+        let root_id = Sha256HashValue::EMPTY;
+
+        let t2 = entry
+            .into_type2(
+                &uki::test::stub(0x400),
+                &root_id,
+                true,
+                &["quiet"],
+                None,
+                &repo,
+            )
+            .unwrap();
+        assert_eq!(t2.file_path, PathBuf::from("6.12.0.efi"));
+        let RegularFile::Inline(content) = &t2.file else {
+            panic!("UKI should be inline");
+        };
+        assert_eq!(
+            uki::get_cmdline(content).unwrap(),
+            format!("composefs=?{} quiet", root_id.to_hex())
+        );
+        assert_eq!(uki::get_boot_label(content).unwrap(), "Test OS");
+        assert_eq!(uki::get_text_section(content, ".uname").unwrap(), "6.12.0");
+        assert_eq!(
+            uki::get_section(content, ".initrd").unwrap().unwrap(),
+            b"initrd"
+        );
+
+        entry = UsrLibModulesVmlinuz {
+            kver: Box::from("6.12.0"),
+            vmlinuz: file(b"kernel"),
+            initramfs: None,
+            os_release: None,
+            dtbs: Default::default(),
+            devicetree: None,
+            devicetree_overlays: vec![],
+            microcode: vec![],
+        };
+        let err = entry
+            .into_type2(&uki::test::stub(0x400), &root_id, false, &[], None, &repo)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't build a UKI without /usr/lib/os-release"
+        );
+    }
+
+    #[test]
+    fn test_addon_cmdline() {
+        use composefs::fsverity::Sha256HashValue;
+
+        let tmp = tempfile::TempDir::new().unwrap();
+        let repo = Repository::open_path(rustix::fs::CWD, tmp.path()).unwrap();
+        let pe = |path: &str, pe_type, cmdline: &str| {
+            let sections: &[(&str, &[u8])] = &[(".cmdline", cmdline.as_bytes())];
+            let image = uki::add_sections(&uki::test::stub(0x400), sections).unwrap();
+            Type2Entry::<Sha256HashValue> {
+                kver: None,
+                file_path: PathBuf::from(path),
+                file: RegularFile::Inline(image.into()),
+                pe_type,
+            }
+        };
+
+        let mut entries = vec![
+            pe("foo.efi", PEType::Uki, "composefs=abc rw"),
+            pe(
+                "foo.efi.extra.d/b.addon.efi",
+                PEType::UkiAddon,
+                "console=ttyS0\n",
+            ),
+            pe("foo.efi.extra.d/a.addon.efi", PEType::UkiAddon, "quiet"),
+            pe("bar.efi.extra.d/c.addon.efi", PEType::UkiAddon, "debug"),
+        ];
+        assert_eq!(
+            entries[0].effective_cmdline(&entries, &repo).unwrap(),
+            "composefs=abc rw quiet console=ttyS0"
+        );
+
+        // An addon must not change the image
+        entries.push(pe(
+            "foo.efi.extra.d/c.addon.efi",
+            PEType::UkiAddon,
+            "composefs=?abc",
+        ));
+        let err = entries[0].effective_cmdline(&entries, &repo).unwrap_err();
+        assert!(format!("{err:#}").contains("must not set composefs="));
+
+        // Renaming an addon moves it along with its UKI
+        entries[1].rename("new");
+        assert_eq!(
+            entries[1].file_path,
+            std::path::Path::new("new.efi.extra.d/b.addon.efi")
+        );
+    }
+
+    #[test]
+    fn test_devicetree_microcode() {
+        use composefs::fsverity::Sha256HashValue;
+
+        let file = |content: &[u8]| RegularFile::<Sha256HashValue>::Inline(content.into());
+        let entry = || UsrLibModulesVmlinuz {
+            kver: Box::from("6.12.0"),
+            vmlinuz: file(b"kernel"),
+            initramfs: Some(file(b"initrd")),
+            os_release: Some(file(b"ID=test\n")),
+            dtbs: BTreeMap::from([
+                (Box::from("vendor/board.dtb"), file(b"dtb")),
+                (Box::from("vendor/overlays/fix.dtbo"), file(b"dtbo")),
+                (Box::from("other/board.dtb"), file(b"other dtb")),
+            ]),
+            devicetree: None,
+            devicetree_overlays: vec![],
+            microcode: vec![VendorMicrocode {
+                filename: "GenuineIntel.bin",
+                files: vec![file(b"uc"), file(b"ode")],
+            }],
+        };
+        let tmp = tempfile::TempDir::new().unwrap();
+        let repo = Repository::open_path(rustix::fs::CWD, tmp.path()).unwrap();
+
+        let mut vmlinuz = entry();
+        vmlinuz
+            .select_devicetree(Some("vendor/missing.dtb"), &[])
+            .unwrap_err();
+        vmlinuz
+            .select_devicetree(Some("vendor/board.dtb"), &["other/board.dtb"])
+            .unwrap_err();
+        vmlinuz
+            .select_devicetree(Some("vendor/board.dtb"), &["vendor/overlays/fix.dtbo"])
+            .unwrap();
+
+        let mut t1 = vmlinuz.into_type1(None, &repo).unwrap();
+        assert_eq!(
+            t1.entry.lines[3..],
+            [
+                "linux /6.12.0/vmlinuz",
+                "initrd /6.12.0/microcode.img",
+                "initrd /6.12.0/initramfs.img",
+                "devicetree /6.12.0/board.dtb",
+                "devicetree-overlay /6.12.0/fix.dtbo",
+            ]
+        );
+        assert_eq!(t1.files.len(), 5);
+        let RegularFile::Inline(cpio) = &t1.files["/6.12.0/microcode.img"] else {
+            panic!("The microcode should be inline");
+        };
+        let ucode = crate::microcode::early_microcode_cpio(&[("GenuineIntel.bin", b"ucode")]);
+        assert_eq!(**cpio, *ucode);
+
+        // Relocation moves all of the files, including multiple overlays on the same line
+        t1.entry.lines[7] = "devicetree-overlay /6.12.0/fix.dtbo  /6.12.0/fix.dtbo".into();
+        t1.relocate(Some("boot"), "new");
+        assert_eq!(
+            t1.entry.lines[3..],
+            [
+                "linux /boot/new/vmlinuz",
+                "initrd /boot/new/microcode.img",
+                "initrd /boot/new/initramfs.img",
+                "devicetree /boot/new/board.dtb",
+                "devicetree-overlay /boot/new/fix.dtbo  /boot/new/fix.dtbo",
+            ]
+        );
+        let mut files: Vec<&str> = t1.files.keys().map(AsRef::as_ref).collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "/new/board.dtb",
+                "/new/fix.dtbo",
+                "/new/initramfs.img",
+                "/new/microcode.img",
+                "/new/vmlinuz"
+            ]
+        );
+
+        // In a UKI, the microcode goes in front of the initramfs
+        let stub = uki::test::stub(0x400);
+        let mut vmlinuz = entry();
+        vmlinuz
+            .select_devicetree(None, &["vendor/overlays/fix.dtbo"])
+            .unwrap();
+        let root_id = &Sha256HashValue::EMPTY;
+        let err = vmlinuz
+            .into_type2(&stub, root_id, false, &[], None, &repo)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Can't use devicetree overlays with a UKI");
+
+        let mut vmlinuz = entry();
+        vmlinuz
+            .select_devicetree(Some("vendor/board.dtb"), &[])
+            .unwrap();
+        let t2 = vmlinuz
+            .into_type2(&stub, root_id, false, &[], None, &repo)
+            .unwrap();
+        let RegularFile::Inline(content) = &t2.file else {
+            panic!("UKI should be inline");
+        };
+        assert_eq!(
+            uki::get_section(content, ".initrd").unwrap().unwrap(),
+            [ucode.as_slice(), b"initrd"].concat()
+        );
+        assert_eq!(uki::get_section(content, ".dtb").unwrap().unwrap(), b"dtb");
+    }
 }
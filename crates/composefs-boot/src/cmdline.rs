@@ -56,6 +56,50 @@ pub fn get_cmdline_composefs<ObjectID: FsVerityHashValue>(
     }
 }
 
+/// Gets the composefs image that the running system was booted from, according to the
+/// composefs= parameter in /proc/cmdline.
+///
+/// Returns None if the system wasn't booted with a composefs= parameter.
+pub fn get_booted_composefs<ObjectID: FsVerityHashValue>() -> Result<Option<ObjectID>> {
+    let cmdline =
+        std::fs::read_to_string("/proc/cmdline").context("Failed to read /proc/cmdline")?;
+    if get_cmdline_value(&cmdline, "composefs=").is_none() {
+        return Ok(None);
+    }
+    let (id, _) = get_cmdline_composefs(&cmdline)?;
+    Ok(Some(id))
+}
+
+/// Checks that the command line of a UKI addon doesn't set composefs=.
+///
+/// systemd-stub appends the command lines of addons to the one in the UKI.  An addon with a
+/// composefs= argument could therefore change the image that gets mounted (or disable fs-verity
+/// checking of it with `composefs=?`) without touching the UKI itself, so these are rejected.
+pub fn check_addon_cmdline(cmdline: &str) -> Result<()> {
+    if let Some(value) = get_cmdline_value(cmdline, "composefs=") {
+        anyhow::bail!("UKI addons must not set composefs= (found composefs={value})");
+    }
+    Ok(())
+}
+
+/// Computes the kernel command line that a UKI boots with, given the command lines of its addons.
+///
+/// The addon command lines are appended in the order given, as systemd-stub does.  They are not
+/// checked here: see [`check_addon_cmdline`].
+pub fn effective_cmdline(uki_cmdline: &str, addon_cmdlines: &[&str]) -> String {
+    let mut cmdline = uki_cmdline.trim().to_string();
+    for addon in addon_cmdlines {
+        let addon = addon.trim();
+        if !addon.is_empty() {
+            if !cmdline.is_empty() {
+                cmdline.push(' ');
+            }
+            cmdline.push_str(addon);
+        }
+    }
+    cmdline
+}
+
 /// Creates a composefs= kernel command line argument.
 ///
 /// # Arguments
@@ -0,0 +1,647 @@
+//! Deployments of composefs images.
+//!
+//! A deployment ties together everything that's needed to boot an image from a sysroot:
+//!
+//! - the ref `images/refs/deploy/<image id>` in the repository, which keeps the image from being
+//!   garbage collected,
+//! - one or more boot entries with `composefs=<image id>` on the boot partition, and
+//! - the state directory `state/deploy/<image id>` in the sysroot (see [`crate::state`]).
+//!
+//! Updates happen in two steps.  [`Deployments::stage`] installs a new image alongside the
+//! existing deployments without changing what gets booted.  [`Deployments::finalize`] then makes
+//! the staged deployment the default in `loader.conf`, so that it's booted next time.  If the new
+//! deployment turns out to be broken, [`Deployments::rollback`] makes the previous one the default
+//! again.  The staged deployment is recorded in `state/staged` in the sysroot.
+
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use composefs::{fsverity::FsVerityHashValue, repository::Repository};
+
+use crate::{
+    bootloader::BootEntry,
+    cmdline::get_booted_composefs,
+    entries::{BootDir, InstalledEntry},
+    state::{self, EtcMerge, StateOptions},
+    uki::TrustedCerts,
+    write_boot::write_boot_simple,
+};
+
+/// Options for staging a deployment.
+#[derive(Debug, Default)]
+pub struct StageOptions<'a> {
+    /// The name of the boot entry.  By default, this is the image ID, so that the entries of
+    /// different deployments never collide.
+    pub entry_id: Option<&'a str>,
+    /// Extra kernel command line arguments (Type 1 entries only)
+    pub cmdline: &'a [&'a str],
+    /// Enable boot assessment, allowing this many attempts to boot the new entry
+    pub boot_tries: Option<u32>,
+    /// Don't require fs-verity when mounting the image (`composefs=?...`)
+    pub insecure: bool,
+    /// If given, UKIs must be signed by one of these certificates
+    pub trusted: Option<&'a TrustedCerts>,
+    /// How to create the state directory
+    pub state: StateOptions,
+}
+
+/// A deployment, as reported by [`Deployments::status`].
+#[derive(Debug)]
+pub struct Deployment<ObjectID: FsVerityHashValue> {
+    /// The deployed image
+    pub image: ObjectID,
+    /// The boot entries for the image, in boot menu order
+    pub entries: Vec<InstalledEntry<ObjectID>>,
+    /// The state directory of the deployment, if it has one
+    pub state: Option<PathBuf>,
+    /// Whether this is the currently-booted deployment
+    pub booted: bool,
+    /// Whether the bootloader will boot this deployment by default
+    pub default: bool,
+    /// Whether this deployment is staged, waiting for [`Deployments::finalize`]
+    pub staged: bool,
+}
+
+/// The name of the ref which keeps a deployed image in the repository.
+fn deploy_ref<ObjectID: FsVerityHashValue>(image: &ObjectID) -> String {
+    format!("deploy/{}", image.to_hex())
+}
+
+/// The deployments of a sysroot.
+#[derive(Debug)]
+pub struct Deployments<'repo, ObjectID: FsVerityHashValue> {
+    repo: &'repo Repository<ObjectID>,
+    sysroot: PathBuf,
+    bootdir: PathBuf,
+    boot_subdir: Option<String>,
+    booted: Option<ObjectID>,
+}
+
+impl<'repo, ObjectID: FsVerityHashValue> Deployments<'repo, ObjectID> {
+    /// Opens the deployments of a sysroot.
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The repository of the sysroot, which holds the deployed images
+    /// * `sysroot` - The sysroot, which holds the state directories
+    /// * `bootdir` - Path to the boot partition/directory
+    /// * `boot_subdir` - The subdirectory to write entries to, as for [`write_boot_simple`]
+    ///
+    /// The currently-booted deployment is determined from `/proc/cmdline`; see
+    /// [`Self::set_booted`] for operating on a sysroot other than the running one.
+    pub fn new(
+        repo: &'repo Repository<ObjectID>,
+        sysroot: impl Into<PathBuf>,
+        bootdir: impl Into<PathBuf>,
+        boot_subdir: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            repo,
+            sysroot: sysroot.into(),
+            bootdir: bootdir.into(),
+            boot_subdir: boot_subdir.map(String::from),
+            booted: get_booted_composefs()?,
+        })
+    }
+
+    /// Sets the image which is considered to be booted, or None if none of the deployments is.
+    pub fn set_booted(&mut self, booted: Option<ObjectID>) -> &mut Self {
+        self.booted = booted;
+        self
+    }
+
+    /// Returns the boot directory containing the entries of the deployments.
+    pub fn boot_dir(&self) -> BootDir {
+        BootDir::new(&self.bootdir, self.boot_subdir.as_deref())
+    }
+
+    fn staged_path(&self) -> PathBuf {
+        self.sysroot.join("state/staged")
+    }
+
+    /// Returns the image of the staged deployment, if there is one.
+    pub fn staged(&self) -> Result<Option<ObjectID>> {
+        let path = self.staged_path();
+        match fs::read_to_string(&path) {
+            Ok(hex) => Ok(Some(ObjectID::from_hex(hex.trim())?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Reading {path:?}")),
+        }
+    }
+
+    fn set_staged(&self, image: Option<&ObjectID>) -> Result<()> {
+        let path = self.staged_path();
+        match image {
+            Some(image) => {
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, format!("{}\n", image.to_hex()))?;
+                fs::rename(&tmp, &path)?;
+            }
+            None => match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => Err(err).with_context(|| format!("Removing {path:?}"))?,
+            },
+        }
+        Ok(())
+    }
+
+    /// Stages a deployment of `image`, which must already be in the repository, booting it with
+    /// `entry`.
+    ///
+    /// This gives the image its deployment ref, creates its state directory (merging the `/etc`
+    /// changes of the booted deployment, see [`state::create_state`]) and writes its boot entry,
+    /// but doesn't make the new entry the default.  If `loader.conf` doesn't have a `default`
+    /// yet, the entry which the bootloader currently picks is made the default explicitly, so
+    /// that the new entry doesn't take over just by sorting first.
+    ///
+    /// Staging an image which is already deployed reuses its state directory and boot entry: in
+    /// that case, `entry` (and the entry options) are ignored.  Remove the deployment first to
+    /// change its entry.  It's an error if a different deployment is staged already.  If staging
+    /// fails, the ref and the state directory are removed again, unless they were there before.
+    ///
+    /// Returns the result of the `/etc` merge.
+    pub fn stage(
+        &self,
+        image: &ObjectID,
+        entry: BootEntry<ObjectID>,
+        options: &StageOptions,
+    ) -> Result<EtcMerge> {
+        if let Some(staged) = self.staged()? {
+            ensure!(
+                &staged == image,
+                "A deployment is already staged ({}): finalize or remove it first",
+                staged.to_hex()
+            );
+        }
+
+        let name = deploy_ref(image);
+        let had_ref = self.repo.has_named_image(&name)?;
+        let state = state::state_dir(&self.sysroot, image);
+        let had_state = state.exists();
+
+        let result = self.try_stage(image, entry, options, had_state);
+        if result.is_err() {
+            if !had_state {
+                let _ = fs::remove_dir_all(&state);
+            }
+            if !had_ref {
+                let _ = self.repo.remove_image_ref(&name);
+            }
+        }
+        result
+    }
+
+    fn try_stage(
+        &self,
+        image: &ObjectID,
+        entry: BootEntry<ObjectID>,
+        options: &StageOptions,
+        had_state: bool,
+    ) -> Result<EtcMerge> {
+        self.repo.name_image(image, &deploy_ref(image))?;
+
+        let merge = if had_state {
+            EtcMerge::default()
+        } else {
+            state::create_state(
+                self.repo,
+                &self.sysroot,
+                image,
+                self.booted.as_ref(),
+                &options.state,
+            )?
+        };
+
+        let boot = self.boot_dir();
+        let entries = boot.list::<ObjectID>()?;
+        if boot.get_default()?.is_none() {
+            if let Some(current) = entries.iter().find(|e| e.is_default) {
+                boot.set_default::<ObjectID>(&current.filename)?;
+            }
+        }
+
+        if !entries.iter().any(|e| e.image.as_ref() == Some(image)) {
+            let hex = image.to_hex();
+            write_boot_simple(
+                self.repo,
+                entry,
+                image,
+                options.insecure,
+                &self.bootdir,
+                self.boot_subdir.as_deref(),
+                Some(options.entry_id.unwrap_or(&hex)),
+                options.cmdline,
+                options.boot_tries,
+                options.trusted,
+            )?;
+        }
+
+        self.set_staged(Some(image))?;
+        Ok(merge)
+    }
+
+    /// Makes the staged deployment the default, so that it gets booted next time.
+    ///
+    /// Returns the image of the deployment.
+    pub fn finalize(&self) -> Result<ObjectID> {
+        let Some(image) = self.staged()? else {
+            bail!("No deployment is staged");
+        };
+
+        let boot = self.boot_dir();
+        let Some(entry) = boot
+            .list::<ObjectID>()?
+            .into_iter()
+            .find(|e| e.image.as_ref() == Some(&image))
+        else {
+            bail!("The staged deployment {} has no boot entry", image.to_hex());
+        };
+        boot.set_default::<ObjectID>(&entry.filename)?;
+
+        self.set_staged(None)?;
+        Ok(image)
+    }
+
+    /// Makes the previous deployment the default again.
+    ///
+    /// If the default deployment isn't the booted one (because it was finalized since booting),
+    /// the booted deployment is the previous one.  Otherwise, the previous deployment is the next
+    /// one after the default in boot menu order.
+    ///
+    /// Returns the image of the new default deployment.
+    pub fn rollback(&self) -> Result<ObjectID> {
+        let boot = self.boot_dir();
+        let entries = boot.list::<ObjectID>()?;
+        let Some(current) = entries.iter().position(|e| e.is_default) else {
+            bail!("There are no boot entries");
+        };
+
+        let other =
+            |e: &&InstalledEntry<ObjectID>| e.image.is_some() && e.image != entries[current].image;
+        let target = match entries
+            .iter()
+            .filter(other)
+            .find(|e| e.image == self.booted)
+        {
+            Some(booted) => booted,
+            None => entries[current + 1..]
+                .iter()
+                .find(other)
+                .context("There is no previous deployment to roll back to")?,
+        };
+        boot.set_default::<ObjectID>(&target.filename)?;
+
+        // SAFETY: `other` only matches entries with an image
+        Ok(target.image.clone().unwrap())
+    }
+
+    /// Removes the deployment of `image`: its boot entries, its state directory and its ref in
+    /// the repository.  The image itself is removed by the next garbage collection, unless
+    /// something else refers to it.
+    ///
+    /// The booted and the default deployment can't be removed.
+    pub fn remove(&self, image: &ObjectID) -> Result<()> {
+        ensure!(
+            self.booted.as_ref() != Some(image),
+            "Can't remove the booted deployment"
+        );
+
+        let boot = self.boot_dir();
+        let entries: Vec<_> = boot
+            .list::<ObjectID>()?
+            .into_iter()
+            .filter(|e| e.image.as_ref() == Some(image))
+            .collect();
+        ensure!(
+            !entries.iter().any(|e| e.is_default),
+            "Can't remove the default deployment"
+        );
+
+        if self.staged()?.as_ref() == Some(image) {
+            self.set_staged(None)?;
+        }
+
+        // The entries go first, so that nothing boots a deployment without its state
+        for entry in &entries {
+            boot.remove::<ObjectID>(&entry.filename, false)?;
+        }
+
+        let state = state::state_dir(&self.sysroot, image);
+        let had_state = match fs::remove_dir_all(&state) {
+            Ok(()) => true,
+            Err(err) if err.kind() == ErrorKind::NotFound => false,
+            Err(err) => Err(err).with_context(|| format!("Removing {state:?}"))?,
+        };
+
+        let had_ref = self.repo.remove_image_ref(&deploy_ref(image))?;
+        ensure!(
+            had_ref || had_state || !entries.is_empty(),
+            "{} isn't deployed",
+            image.to_hex()
+        );
+        Ok(())
+    }
+
+    fn deployment(&self, image: ObjectID, staged: Option<&ObjectID>) -> Deployment<ObjectID> {
+        let state = state::state_dir(&self.sysroot, &image);
+        Deployment {
+            entries: vec![],
+            state: state.is_dir().then_some(state),
+            booted: self.booted.as_ref() == Some(&image),
+            default: false,
+            staged: staged == Some(&image),
+            image,
+        }
+    }
+
+    /// Lists the deployments: first the ones with boot entries, in boot menu order, and then the
+    /// ones which only have a state directory.
+    pub fn status(&self) -> Result<Vec<Deployment<ObjectID>>> {
+        let staged = self.staged()?;
+        let mut deployments: Vec<Deployment<ObjectID>> = vec![];
+
+        for entry in self.boot_dir().list::<ObjectID>()? {
+            let Some(image) = &entry.image else {
+                continue;
+            };
+            let idx = match deployments.iter().position(|d| &d.image == image) {
+                Some(idx) => idx,
+                None => {
+                    deployments.push(self.deployment(image.clone(), staged.as_ref()));
+                    deployments.len() - 1
+                }
+            };
+            deployments[idx].default |= entry.is_default;
+            deployments[idx].entries.push(entry);
+        }
+
+        let deploy_dir = self.sysroot.join("state/deploy");
+        let mut others = vec![];
+        match fs::read_dir(&deploy_dir) {
+            Ok(readdir) => {
+                for dirent in readdir {
+                    // Skips temporary directories, and those of other hash types
+                    let Some(image) = dirent?
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| ObjectID::from_hex(name).ok())
+                    else {
+                        continue;
+                    };
+                    if !deployments.iter().any(|d| d.image == image) {
+                        others.push(image);
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => Err(err).with_context(|| format!("Failed to read {deploy_dir:?}"))?,
+        }
+        others.sort_by_key(|image| image.to_hex());
+        deployments.extend(
+            others
+                .into_iter()
+                .map(|image| self.deployment(image, staged.as_ref())),
+        );
+
+        Ok(deployments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use composefs::{fsverity::Sha256HashValue, tree::RegularFile};
+    use similar_asserts::assert_eq;
+
+    use super::*;
+    use crate::{
+        bootloader::{BootLoaderEntryFile, Type1Entry},
+        test,
+    };
+
+    fn commit(repo: &Repository<Sha256HashValue>, version: &str) -> Sha256HashValue {
+        let etc = format!(
+            "/etc/version {} 100644 1 0 0 0 0.0 - {version} -\n",
+            version.len()
+        );
+        test::commit(repo, &etc)
+    }
+
+    fn entry(version: &str) -> BootEntry<Sha256HashValue> {
+        BootEntry::Type1(Type1Entry {
+            filename: Box::from(format!("{version}.conf").as_ref()),
+            entry: BootLoaderEntryFile::new(&format!(
+                "title Test\nversion {version}\nlinux /{version}/vmlinuz\n"
+            )),
+            files: HashMap::from([(
+                Box::from(format!("/{version}/vmlinuz")),
+                RegularFile::Inline(version.as_bytes().into()),
+            )]),
+        })
+    }
+
+    fn short(image: &Sha256HashValue) -> String {
+        image.to_hex()[..4].to_string()
+    }
+
+    /// Returns the deployed images, in order, marked with `*` for the default one
+    fn summary(deployments: &Deployments<Sha256HashValue>) -> Vec<String> {
+        deployments
+            .status()
+            .unwrap()
+            .iter()
+            .map(|d| {
+                let mut s = short(&d.image);
+                if d.default {
+                    s.push('*');
+                }
+                if d.booted {
+                    s.push('>');
+                }
+                if d.staged {
+                    s.push('+');
+                }
+                s
+            })
+            .collect()
+    }
+
+    /// Stages and finalizes the deployment of `image`
+    fn deploy(deployments: &Deployments<Sha256HashValue>, image: &Sha256HashValue, version: &str) {
+        deployments
+            .stage(image, entry(version), &StageOptions::default())
+            .unwrap();
+        assert_eq!(&deployments.finalize().unwrap(), image);
+    }
+
+    #[test]
+    fn test_stage() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let v1 = commit(&repo, "1");
+        let v2 = commit(&repo, "2");
+        let v3 = commit(&repo, "3");
+
+        let mut deployments = Deployments::new(&repo, sysroot, sysroot.join("boot"), None).unwrap();
+        deployments.set_booted(None);
+        assert!(deployments.status().unwrap().is_empty());
+
+        // A failed stage is undone
+        let boot_entries = sysroot.join("boot/loader/entries");
+        fs::create_dir_all(boot_entries.parent().unwrap()).unwrap();
+        fs::write(&boot_entries, "").unwrap();
+        assert!(deployments
+            .stage(&v1, entry("1"), &StageOptions::default())
+            .is_err());
+        assert_eq!(deployments.staged().unwrap(), None);
+        assert!(!state::state_dir(sysroot, &v1).exists());
+        assert!(!repo.has_named_image(&deploy_ref(&v1)).unwrap());
+        fs::remove_file(&boot_entries).unwrap();
+
+        deployments
+            .stage(&v1, entry("1"), &StageOptions::default())
+            .unwrap();
+        assert_eq!(deployments.staged().unwrap(), Some(v1.clone()));
+        let status = deployments.status().unwrap();
+        assert_eq!(status.len(), 1);
+        assert!(status[0].staged);
+        assert_eq!(status[0].entries[0].id, v1.to_hex());
+        assert_eq!(status[0].state, Some(state::state_dir(sysroot, &v1)));
+
+        // The images are kept by their refs
+        repo.gc(&[]).unwrap();
+        repo.read_image(&v1.to_hex()).unwrap();
+        assert!(repo.read_image(&v3.to_hex()).is_err());
+
+        // Another deployment can't be staged over a staged one
+        let err = deployments.stage(&v2, entry("2"), &StageOptions::default());
+        assert!(err.unwrap_err().to_string().contains("already staged"));
+    }
+
+    #[test]
+    fn test_finalize() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let v1 = commit(&repo, "1");
+        let v2 = commit(&repo, "2");
+
+        let mut deployments = Deployments::new(&repo, sysroot, sysroot.join("boot"), None).unwrap();
+        deployments.set_booted(None);
+        assert!(deployments.finalize().is_err());
+
+        deploy(&deployments, &v1, "1");
+        assert_eq!(deployments.staged().unwrap(), None);
+        deployments.set_booted(Some(v1.clone()));
+
+        // Staging v2 doesn't change the default, even though it sorts first
+        let merge = deployments
+            .stage(&v2, entry("2"), &StageOptions::default())
+            .unwrap();
+        assert_eq!(merge, EtcMerge::default());
+        assert_eq!(
+            summary(&deployments),
+            [format!("{}+", short(&v2)), format!("{}*>", short(&v1))]
+        );
+        assert_eq!(
+            deployments.boot_dir().get_default().unwrap(),
+            Some(format!("{}.conf", v1.to_hex()))
+        );
+
+        // Finalizing makes it the default
+        assert_eq!(deployments.finalize().unwrap(), v2);
+        assert_eq!(
+            summary(&deployments),
+            [format!("{}*", short(&v2)), format!("{}>", short(&v1))]
+        );
+        assert!(deployments.finalize().is_err());
+    }
+
+    #[test]
+    fn test_rollback() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let v1 = commit(&repo, "1");
+        let v2 = commit(&repo, "2");
+
+        let mut deployments = Deployments::new(&repo, sysroot, sysroot.join("boot"), None).unwrap();
+        deployments.set_booted(None);
+        assert!(deployments.rollback().is_err());
+
+        deploy(&deployments, &v1, "1");
+        deployments.set_booted(Some(v1.clone()));
+        deploy(&deployments, &v2, "2");
+
+        // Rolling back before rebooting goes back to v1
+        assert_eq!(deployments.rollback().unwrap(), v1);
+        assert_eq!(
+            summary(&deployments),
+            [short(&v2), format!("{}*>", short(&v1))]
+        );
+
+        // After booting v2, rolling back goes to the next entry in boot menu order, and rolling
+        // back again returns to the booted deployment
+        deployments
+            .boot_dir()
+            .set_default::<Sha256HashValue>(&v2.to_hex())
+            .unwrap();
+        deployments.set_booted(Some(v2.clone()));
+        assert_eq!(deployments.rollback().unwrap(), v1);
+        assert_eq!(
+            summary(&deployments),
+            [format!("{}>", short(&v2)), format!("{}*", short(&v1))]
+        );
+        assert_eq!(deployments.rollback().unwrap(), v2);
+        assert_eq!(
+            summary(&deployments),
+            [format!("{}*>", short(&v2)), short(&v1)]
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let v1 = commit(&repo, "1");
+        let v2 = commit(&repo, "2");
+        let v3 = commit(&repo, "3");
+
+        let mut deployments = Deployments::new(&repo, sysroot, sysroot.join("boot"), None).unwrap();
+        deploy(&deployments, &v1, "1");
+        deploy(&deployments, &v2, "2");
+        deployments.set_booted(Some(v2.clone()));
+
+        // The booted and default deployments can't be removed, the others can
+        assert_eq!(deployments.rollback().unwrap(), v1);
+        assert!(deployments.remove(&v2).is_err());
+        assert!(deployments.remove(&v1).is_err());
+        assert_eq!(deployments.rollback().unwrap(), v2);
+        deployments.remove(&v1).unwrap();
+        assert!(deployments.remove(&v1).is_err());
+        assert_eq!(summary(&deployments), [format!("{}*>", short(&v2))]);
+        assert!(!state::state_dir(sysroot, &v1).exists());
+        assert!(!sysroot.join(format!("boot/{}", v1.to_hex())).exists());
+        repo.gc(&[]).unwrap();
+        assert!(repo.read_image(&v1.to_hex()).is_err());
+        repo.read_image(&v2.to_hex()).unwrap();
+
+        // Removing the staged deployment unstages it
+        assert_eq!(commit(&repo, "3"), v3);
+        deployments
+            .stage(&v3, entry("3"), &StageOptions::default())
+            .unwrap();
+        deployments.remove(&v3).unwrap();
+        assert_eq!(deployments.staged().unwrap(), None);
+        assert!(!repo.has_named_image(&deploy_ref(&v3)).unwrap());
+
+        // A deployment with only a state directory is listed after the others
+        fs::create_dir_all(state::state_dir(sysroot, &v3)).unwrap();
+        fs::create_dir_all(sysroot.join("state/deploy/junk.tmp")).unwrap();
+        assert_eq!(
+            summary(&deployments),
+            [format!("{}*>", short(&v2)), short(&v3)]
+        );
+    }
+}
@@ -0,0 +1,894 @@
+//! Management of installed boot entries.
+//!
+//! This module provides functionality to inspect and maintain the boot entries that were written
+//! to a boot partition by [`crate::write_boot`]: listing them in boot menu order, mapping them
+//! back to the composefs images that they boot, changing the default entry in `loader.conf`, and
+//! removing old entries along with any kernel and initrd files that are no longer referenced.
+
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fs,
+    io::{ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use composefs::fsverity::FsVerityHashValue;
+
+use crate::{
+    bootloader::{
+        BootLoaderEntryFile, EFI_ADDON_DIR_EXT, EFI_ADDON_FILE_EXT, EFI_EXT, T1_FILE_KEYS,
+    },
+    cmdline::{effective_cmdline, get_booted_composefs, get_cmdline_composefs},
+    os_release::OsReleaseInfo,
+    uki,
+};
+
+/// The kind of an installed boot entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    /// A Boot Loader Specification Type 1 entry (`loader/entries/*.conf`)
+    Type1,
+    /// A Type 2 Unified Kernel Image (`EFI/Linux/*.efi`)
+    Type2,
+}
+
+/// A boot entry found on the boot partition.
+#[derive(Debug)]
+pub struct InstalledEntry<ObjectID: FsVerityHashValue> {
+    /// The entry identifier: the filename without its `.conf` or `.efi` suffix
+    pub id: String,
+    /// The filename of the entry without any boot counter, as matched against the `default` key
+    /// in `loader.conf`
+    pub filename: String,
+    /// The full path to the `.conf` or `.efi` file
+    pub path: PathBuf,
+    /// The boot counter in the filename, if boot assessment is in progress for this entry
+    pub boot_counter: Option<BootCounter>,
+    /// Whether this is a Type 1 or Type 2 entry
+    pub entry_type: EntryType,
+    /// The title shown in the boot menu, if known
+    pub title: Option<String>,
+    /// The version used for ordering entries, if known
+    pub version: Option<String>,
+    /// The sort key used for ordering entries, if known
+    pub sort_key: Option<String>,
+    /// The kernel command line of the entry, if known
+    pub cmdline: Option<String>,
+    /// The composefs image booted by this entry, or None if the entry has no `composefs=`
+    pub image: Option<ObjectID>,
+    /// Whether fs-verity verification of the image is optional (`composefs=?...`)
+    pub insecure: bool,
+    /// Whether this is the entry that the bootloader will select by default
+    pub is_default: bool,
+    /// Other files and directories on the boot partition that belong to this entry
+    pub files: Vec<PathBuf>,
+}
+
+/// A systemd-boot boot counter, from the `+LEFT[-DONE]` suffix of an entry filename.
+///
+/// See <https://systemd.io/AUTOMATIC_BOOT_ASSESSMENT/>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootCounter {
+    /// The number of boot attempts left before the entry is considered bad
+    pub tries_left: u32,
+    /// The number of boot attempts that have already failed
+    pub tries_done: u32,
+}
+
+impl BootCounter {
+    /// Splits the boot counter off the filename of an entry (without its `.conf` or `.efi`
+    /// extension), returning the remaining name and the counter, if there was one.
+    pub fn parse(stem: &str) -> (&str, Option<Self>) {
+        let Some((name, counter)) = stem.rsplit_once('+') else {
+            return (stem, None);
+        };
+        let (left, done) = counter.split_once('-').unwrap_or((counter, "0"));
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+        if !is_number(left) || !is_number(done) {
+            return (stem, None);
+        }
+        match (left.parse(), done.parse()) {
+            (Ok(tries_left), Ok(tries_done)) => (
+                name,
+                Some(Self {
+                    tries_left,
+                    tries_done,
+                }),
+            ),
+            _ => (stem, None),
+        }
+    }
+
+    /// Whether the entry has run out of tries and will only be booted as a last resort.
+    pub fn is_bad(&self) -> bool {
+        self.tries_left == 0
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let n = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[n..]
+}
+
+/// Compares two version strings in the way that systemd-boot does.
+///
+/// Runs of digits are compared numerically, `~` sorts before everything (including the end of
+/// the string) and everything else is compared bytewise.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+
+    loop {
+        match (a.first(), b.first()) {
+            (Some(b'~'), Some(b'~')) => {
+                a = &a[1..];
+                b = &b[1..];
+            }
+            (Some(b'~'), _) => return Ordering::Less,
+            (_, Some(b'~')) => return Ordering::Greater,
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let a_num = trim_zeros(&a[..a_len]);
+                let b_num = trim_zeros(&b[..b_len]);
+                let ordering = a_num.len().cmp(&b_num.len()).then(a_num.cmp(b_num));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = &a[a_len..];
+                b = &b[b_len..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+/// Compares two entries according to the boot menu order used by systemd-boot: entries which
+/// have run out of boot attempts go last, entries with a sort key come first (in ascending order
+/// of the key), then entries are ordered by descending version and finally by descending
+/// identifier.
+fn boot_menu_order<ObjectID: FsVerityHashValue>(
+    a: &InstalledEntry<ObjectID>,
+    b: &InstalledEntry<ObjectID>,
+) -> Ordering {
+    fn some_first(a: &Option<String>, b: &Option<String>) -> Ordering {
+        b.is_some().cmp(&a.is_some())
+    }
+
+    let is_bad = |e: &InstalledEntry<ObjectID>| e.boot_counter.is_some_and(|c| c.is_bad());
+
+    is_bad(a)
+        .cmp(&is_bad(b))
+        .then_with(|| some_first(&a.sort_key, &b.sort_key))
+        .then_with(|| a.sort_key.cmp(&b.sort_key))
+        .then_with(|| some_first(&a.version, &b.version))
+        .then_with(|| match (&a.version, &b.version) {
+            (Some(a), Some(b)) => compare_versions(b, a),
+            _ => Ordering::Equal,
+        })
+        .then_with(|| compare_versions(&b.id, &a.id))
+}
+
+/// Matches a filename against a `loader.conf` `default` pattern, which may contain `*` and `?`
+/// wildcards.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|n| glob_match(rest, &name[n..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+/// Reads a key-value file like a Type 1 entry or `loader.conf`, treating a missing file as
+/// empty.
+fn read_config(path: &Path) -> Result<BootLoaderEntryFile> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(BootLoaderEntryFile::new(&content)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(BootLoaderEntryFile::new("")),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
+
+/// Replaces a key-value file atomically: the content is written to a temporary file in the same
+/// directory and synced before being renamed into place, so that a crash never leaves a truncated
+/// `loader.conf` or entry behind.
+fn write_config(path: &Path, config: &BootLoaderEntryFile) -> Result<()> {
+    let content = config.lines.join("\n") + "\n";
+    // SAFETY: config files always have a parent directory and a filename
+    let dir = path.parent().unwrap();
+    let tmp = dir.join(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+
+    let result = fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("Failed to write {tmp:?}"));
+    }
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {path:?}"))?;
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {dir:?}"))
+}
+
+/// A boot partition (or directory) containing composefs boot entries.
+///
+/// The layout matches what [`crate::write_boot::write_boot_simple`] produces: Type 1 entries in
+/// `loader/entries/` (below `boot_subdir`, if one is used), the files they reference relative to
+/// the top of the boot partition, and Type 2 entries in `EFI/Linux/`.
+#[derive(Debug)]
+pub struct BootDir {
+    bootdir: PathBuf,
+    entries_root: PathBuf,
+}
+
+impl BootDir {
+    /// Opens a boot directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `bootdir` - Path to the boot partition/directory
+    /// * `boot_subdir` - The subdirectory that entries were written to, as passed to
+    ///   [`crate::write_boot::write_boot_simple`]
+    pub fn new(bootdir: impl Into<PathBuf>, boot_subdir: Option<&str>) -> Self {
+        let bootdir = bootdir.into();
+        let entries_root = match boot_subdir {
+            Some(subdir) => bootdir.join(subdir.trim_start_matches('/')),
+            None => bootdir.clone(),
+        };
+        Self {
+            bootdir,
+            entries_root,
+        }
+    }
+
+    fn loader_conf(&self) -> PathBuf {
+        self.entries_root.join("loader/loader.conf")
+    }
+
+    /// Resolves a path from a Type 1 entry relative to the boot partition, refusing anything
+    /// that might point outside of it.
+    fn resolve(&self, value: &str) -> Option<PathBuf> {
+        let path = Path::new(value.strip_prefix('/').unwrap_or(value));
+        path.components()
+            .all(|c| matches!(c, Component::Normal(_)))
+            .then(|| self.bootdir.join(path))
+    }
+
+    fn load_type1<ObjectID: FsVerityHashValue>(
+        &self,
+        path: PathBuf,
+        filename: &str,
+        boot_counter: Option<BootCounter>,
+    ) -> Result<InstalledEntry<ObjectID>> {
+        let entry = read_config(&path)?;
+
+        let options: Vec<&str> = entry.get_values("options").collect();
+        let cmdline = (!options.is_empty()).then(|| options.join(" "));
+        let (image, insecure) = match cmdline.as_deref().map(get_cmdline_composefs) {
+            Some(Ok((image, insecure))) => (Some(image), insecure),
+            _ => (None, false),
+        };
+
+        let files = T1_FILE_KEYS
+            .iter()
+            .flat_map(|key| entry.get_values(key))
+            .flat_map(str::split_ascii_whitespace)
+            .filter_map(|value| self.resolve(value))
+            .collect();
+
+        Ok(InstalledEntry {
+            id: filename.strip_suffix(".conf").unwrap_or(filename).into(),
+            filename: filename.into(),
+            path,
+            boot_counter,
+            entry_type: EntryType::Type1,
+            title: entry.get_value("title").map(String::from),
+            version: entry.get_value("version").map(String::from),
+            sort_key: entry.get_value("sort-key").map(String::from),
+            cmdline,
+            image,
+            insecure,
+            is_default: false,
+            files,
+        })
+    }
+
+    fn load_type2<ObjectID: FsVerityHashValue>(
+        &self,
+        path: PathBuf,
+        filename: &str,
+        boot_counter: Option<BootCounter>,
+    ) -> Result<InstalledEntry<ObjectID>> {
+        let content = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+
+        // The effective command line includes the addons in the .efi.extra.d directory
+        let id = filename.strip_suffix(EFI_EXT).unwrap_or(filename);
+        let addon_dir = path.with_file_name(format!("{id}{EFI_ADDON_DIR_EXT}"));
+        let mut addons = vec![];
+        match fs::read_dir(&addon_dir) {
+            Ok(dir) => {
+                for dirent in dir {
+                    let addon = dirent?.path();
+                    if addon.to_string_lossy().ends_with(EFI_ADDON_FILE_EXT) {
+                        addons.push(addon);
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => Err(err).with_context(|| format!("Failed to read {addon_dir:?}"))?,
+        }
+        addons.sort();
+        let addons = addons
+            .iter()
+            .map(|addon| fs::read(addon).with_context(|| format!("Failed to read {addon:?}")))
+            .collect::<Result<Vec<_>>>()?;
+        let addon_cmdlines: Vec<&str> = addons
+            .iter()
+            .filter_map(|addon| uki::get_addon_cmdline(addon).ok().flatten())
+            .collect();
+
+        // The image is determined by the UKI itself: addons can't change it (see
+        // write_boot::write_t2_simple())
+        let uki_cmdline = uki::get_cmdline(&content).ok();
+        let cmdline = uki_cmdline.map(|cmdline| effective_cmdline(cmdline, &addon_cmdlines));
+        let (image, insecure) = match uki_cmdline.map(get_cmdline_composefs) {
+            Some(Ok((image, insecure))) => (Some(image), insecure),
+            _ => (None, false),
+        };
+
+        let osrel = uki::get_text_section(&content, ".osrel").ok();
+        let osrel = osrel.map(OsReleaseInfo::parse);
+
+        Ok(InstalledEntry {
+            id: id.into(),
+            filename: filename.into(),
+            files: vec![addon_dir],
+            path,
+            boot_counter,
+            entry_type: EntryType::Type2,
+            title: osrel.as_ref().and_then(OsReleaseInfo::get_boot_label),
+            version: osrel
+                .as_ref()
+                .and_then(|o| o.get_value(&["IMAGE_VERSION", "VERSION_ID", "VERSION"])),
+            sort_key: osrel
+                .as_ref()
+                .and_then(|o| o.get_value(&["IMAGE_ID", "ID"])),
+            cmdline,
+            image,
+            insecure,
+            is_default: false,
+        })
+    }
+
+    /// Returns the `default` pattern from `loader.conf`, if one is set.
+    pub fn get_default(&self) -> Result<Option<String>> {
+        Ok(read_config(&self.loader_conf())?
+            .get_value("default")
+            .map(String::from))
+    }
+
+    /// Lists all Type 1 and Type 2 entries in boot menu order.
+    ///
+    /// The entry that the bootloader will pick by default (the first one matching the `default`
+    /// pattern in `loader.conf`, or else the first one) is marked with `is_default`.
+    pub fn list<ObjectID: FsVerityHashValue>(&self) -> Result<Vec<InstalledEntry<ObjectID>>> {
+        let mut entries = vec![];
+
+        for (dir, ext) in [
+            (self.entries_root.join("loader/entries"), ".conf"),
+            (self.bootdir.join("EFI/Linux"), EFI_EXT),
+        ] {
+            let readdir = match fs::read_dir(&dir) {
+                Ok(readdir) => readdir,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => Err(err).with_context(|| format!("Failed to read {dir:?}"))?,
+            };
+
+            for dirent in readdir {
+                let dirent = dirent?;
+                let filename = dirent.file_name();
+                let Some(stem) = filename.to_str().and_then(|f| f.strip_suffix(ext)) else {
+                    continue;
+                };
+                if stem.ends_with(".addon") || !dirent.file_type()?.is_file() {
+                    continue;
+                }
+
+                let (name, counter) = BootCounter::parse(stem);
+                let name = format!("{name}{ext}");
+                entries.push(match ext {
+                    EFI_EXT => self.load_type2(dirent.path(), &name, counter)?,
+                    _ => self.load_type1(dirent.path(), &name, counter)?,
+                });
+            }
+        }
+
+        entries.sort_by(boot_menu_order);
+
+        let pattern = self.get_default()?;
+        let default = pattern
+            .and_then(|p| {
+                entries
+                    .iter()
+                    .position(|e| glob_match(p.as_bytes(), e.filename.as_bytes()))
+            })
+            .unwrap_or(0);
+        if let Some(entry) = entries.get_mut(default) {
+            entry.is_default = true;
+        }
+
+        Ok(entries)
+    }
+
+    fn find<ObjectID: FsVerityHashValue>(
+        entries: &[InstalledEntry<ObjectID>],
+        id: &str,
+    ) -> Result<usize> {
+        match entries.iter().position(|e| e.id == id || e.filename == id) {
+            Some(idx) => Ok(idx),
+            None => bail!("No boot entry named '{id}'"),
+        }
+    }
+
+    /// Makes the named entry the default by setting the `default` key in `loader.conf`.
+    ///
+    /// The entry can be named by its identifier or by its full filename.
+    pub fn set_default<ObjectID: FsVerityHashValue>(&self, id: &str) -> Result<()> {
+        let entries = self.list::<ObjectID>()?;
+        let entry = &entries[Self::find(&entries, id)?];
+
+        let path = self.loader_conf();
+        let mut config = read_config(&path)?;
+        config.set_value("default", Some(&entry.filename));
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_config(&path, &config)
+    }
+
+    /// Sets (or, with None, removes) the `sort-key` and `version` keys of a Type 1 entry, which
+    /// control where it appears in the boot menu.
+    pub fn set_order<ObjectID: FsVerityHashValue>(
+        &self,
+        id: &str,
+        sort_key: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<()> {
+        let entries = self.list::<ObjectID>()?;
+        let entry = &entries[Self::find(&entries, id)?];
+        if entry.entry_type != EntryType::Type1 {
+            bail!("Can't change the ordering of Type 2 entry '{id}'");
+        }
+
+        let mut config = read_config(&entry.path)?;
+        config.set_value("sort-key", sort_key);
+        config.set_value("version", version);
+        write_config(&entry.path, &config)
+    }
+
+    /// Marks the entries booting `image` as good by removing their boot counters, so that they
+    /// are no longer subject to boot assessment.
+    ///
+    /// Returns the paths of the renamed entries.
+    pub fn mark_good<ObjectID: FsVerityHashValue>(&self, image: &ObjectID) -> Result<Vec<PathBuf>> {
+        let mut renamed = vec![];
+
+        for entry in self.list::<ObjectID>()? {
+            if entry.boot_counter.is_none() || entry.image.as_ref() != Some(image) {
+                continue;
+            }
+
+            let new = entry.path.with_file_name(&entry.filename);
+            fs::rename(&entry.path, &new)
+                .with_context(|| format!("Failed to rename {:?} to {new:?}", entry.path))?;
+            // SAFETY: entries always have a parent directory
+            fs::File::open(new.parent().unwrap())?.sync_all()?;
+            renamed.push(new);
+        }
+
+        Ok(renamed)
+    }
+
+    /// Marks the entries booting the currently-running composefs image (according to
+    /// `/proc/cmdline`) as good.  See [`Self::mark_good`].
+    pub fn mark_booted_good<ObjectID: FsVerityHashValue>(&self) -> Result<Vec<PathBuf>> {
+        match get_booted_composefs::<ObjectID>()? {
+            Some(image) => self.mark_good(&image),
+            None => bail!("The running system wasn't booted from a composefs image"),
+        }
+    }
+
+    /// Removes the given entries and then deletes any of their files which aren't referenced by
+    /// the remaining entries, along with directories that become empty as a result.
+    fn remove_entries<ObjectID: FsVerityHashValue>(
+        &self,
+        entries: Vec<InstalledEntry<ObjectID>>,
+        doomed: impl Fn(usize, &InstalledEntry<ObjectID>) -> bool,
+    ) -> Result<Vec<InstalledEntry<ObjectID>>> {
+        let (removed, kept): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .enumerate()
+            .partition(|(idx, entry)| doomed(*idx, entry));
+        let removed: Vec<_> = removed.into_iter().map(|(_, entry)| entry).collect();
+
+        // Remove the entries themselves first so that the bootloader never sees an entry
+        // referring to missing files.
+        for entry in &removed {
+            fs::remove_file(&entry.path)
+                .with_context(|| format!("Failed to remove {:?}", entry.path))?;
+        }
+
+        if let Some(pattern) = self.get_default()? {
+            if removed.iter().any(|e| e.filename == pattern) {
+                let path = self.loader_conf();
+                let mut config = read_config(&path)?;
+                config.set_value("default", None);
+                write_config(&path, &config)?;
+            }
+        }
+
+        let referenced: HashSet<&Path> = kept
+            .iter()
+            .flat_map(|(_, e)| &e.files)
+            .map(PathBuf::as_path)
+            .collect();
+
+        for file in removed.iter().flat_map(|e| &e.files) {
+            if referenced.contains(file.as_path()) {
+                continue;
+            }
+
+            let result = if file.is_dir() {
+                fs::remove_dir_all(file)
+            } else {
+                fs::remove_file(file)
+            };
+            match result {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => Err(err).with_context(|| format!("Failed to remove {file:?}"))?,
+            }
+
+            let mut dir = file.parent();
+            while let Some(parent) = dir {
+                if parent == self.bootdir || parent == self.entries_root {
+                    break;
+                }
+                // This fails if the directory isn't empty, which is exactly what we want
+                if fs::remove_dir(parent).is_err() {
+                    break;
+                }
+                dir = parent.parent();
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes the named entry and garbage-collects the files that it referenced.
+    ///
+    /// Entries that don't boot a composefs image belong to the rest of the system, and are only
+    /// removed with `force`.  If the entry was explicitly set as the default in `loader.conf`, the
+    /// default is cleared.
+    pub fn remove<ObjectID: FsVerityHashValue>(
+        &self,
+        id: &str,
+        force: bool,
+    ) -> Result<InstalledEntry<ObjectID>> {
+        let entries = self.list()?;
+        let idx = Self::find(&entries, id)?;
+        if entries[idx].image.is_none() && !force {
+            bail!("Boot entry '{id}' doesn't boot a composefs image, refusing to remove it");
+        }
+        let mut removed = self.remove_entries(entries, |i, _| i == idx)?;
+        Ok(removed.remove(0))
+    }
+
+    /// Removes old composefs entries, keeping only the first `keep` of them in boot menu order.
+    ///
+    /// Entries that don't boot a composefs image are never removed.  Neither is the default entry
+    /// or any entry booting one of the images in `protect` (typically the currently-booted one),
+    /// although these do count towards `keep`.
+    ///
+    /// Returns the removed entries.
+    pub fn prune<ObjectID: FsVerityHashValue>(
+        &self,
+        keep: usize,
+        protect: &[ObjectID],
+    ) -> Result<Vec<InstalledEntry<ObjectID>>> {
+        let entries = self.list::<ObjectID>()?;
+
+        let mut seen = 0;
+        let doomed: HashSet<usize> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                let Some(image) = &entry.image else {
+                    return false;
+                };
+                seen += 1;
+                seen > keep && !entry.is_default && !protect.contains(image)
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+
+        self.remove_entries(entries, |idx, _| doomed.contains(&idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use composefs::fsverity::Sha256HashValue;
+    use similar_asserts::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn image_id(n: u8) -> Sha256HashValue {
+        Sha256HashValue::from_hex(hex::encode([n; 32])).unwrap()
+    }
+
+    fn write_entry(boot: &Path, id: &str, version: &str, image: u8) {
+        let image = image_id(image).to_hex();
+        fs::create_dir_all(boot.join("loader/entries")).unwrap();
+        fs::create_dir_all(boot.join(id)).unwrap();
+        fs::write(boot.join(id).join("vmlinuz"), "kernel").unwrap();
+        fs::write(boot.join(id).join("initramfs.img"), "initrd").unwrap();
+        fs::write(
+            boot.join(format!("loader/entries/{id}.conf")),
+            format!(
+                "title Test\nversion {version}\nlinux /{id}/vmlinuz\ninitrd /{id}/initramfs.img\n\
+                 options rw composefs={image}\n"
+            ),
+        )
+        .unwrap();
+    }
+
+    fn ids(entries: &[InstalledEntry<Sha256HashValue>]) -> Vec<&str> {
+        entries.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    fn setup() -> (TempDir, BootDir) {
+        let tmp = TempDir::new().unwrap();
+        write_entry(tmp.path(), "a", "1.9", 1);
+        write_entry(tmp.path(), "b", "1.10", 2);
+        write_entry(tmp.path(), "c", "1.2", 3);
+        let boot = BootDir::new(tmp.path(), None);
+        (tmp, boot)
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.010", "1.10"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0", "1.0a"), Ordering::Less);
+        assert_eq!(compare_versions("abc", "abd"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"a.conf", b"a.conf"));
+        assert!(glob_match(b"*.conf", b"a.conf"));
+        assert!(glob_match(b"?.conf", b"a.conf"));
+        assert!(!glob_match(b"*.efi", b"a.conf"));
+        assert!(!glob_match(b"a", b"a.conf"));
+    }
+
+    #[test]
+    fn test_list() {
+        let (tmp, boot) = setup();
+        fs::write(
+            tmp.path().join("loader/entries/foreign.conf"),
+            "title Other\nsort-key other\nlinux /vmlinuz\n",
+        )
+        .unwrap();
+
+        let entries = boot.list::<Sha256HashValue>().unwrap();
+        assert_eq!(ids(&entries), ["foreign", "b", "a", "c"]);
+        assert!(entries[0].is_default);
+        assert_eq!(entries[0].image, None);
+        assert_eq!(entries[1].image, Some(image_id(2)));
+        assert_eq!(entries[1].entry_type, EntryType::Type1);
+        assert_eq!(
+            entries[1].files,
+            [
+                tmp.path().join("b/vmlinuz"),
+                tmp.path().join("b/initramfs.img")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_uki() {
+        let tmp = TempDir::new().unwrap();
+        let boot = BootDir::new(tmp.path(), None);
+        let pe = |path: &str, cmdline: &str| {
+            let sections: &[(&str, &[u8])] = &[
+                (".osrel", b"ID=test\nVERSION_ID=1\n"),
+                (".cmdline", cmdline.as_bytes()),
+            ];
+            let path = tmp.path().join("EFI/Linux").join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(
+                path,
+                uki::add_sections(&uki::test::stub(0x400), sections).unwrap(),
+            )
+            .unwrap();
+        };
+        let image = image_id(1).to_hex();
+        pe("foo.efi", &format!("composefs={image} rw"));
+        pe("foo.efi.extra.d/console.addon.efi", "console=ttyS0");
+
+        let entries = boot.list::<Sha256HashValue>().unwrap();
+        assert_eq!(ids(&entries), ["foo"]);
+        assert_eq!(entries[0].image, Some(image_id(1)));
+        assert_eq!(
+            entries[0].cmdline.as_deref(),
+            Some(format!("composefs={image} rw console=ttyS0").as_str())
+        );
+
+        // The addons go along with the UKI
+        boot.remove::<Sha256HashValue>("foo", false).unwrap();
+        assert!(!tmp.path().join("EFI/Linux/foo.efi.extra.d").exists());
+    }
+
+    #[test]
+    fn test_set_default() {
+        let (tmp, boot) = setup();
+        fs::create_dir_all(tmp.path().join("loader")).unwrap();
+        fs::write(
+            tmp.path().join("loader/loader.conf"),
+            "timeout 3\ndefault b.conf\n",
+        )
+        .unwrap();
+        assert_eq!(boot.get_default().unwrap().as_deref(), Some("b.conf"));
+
+        boot.set_default::<Sha256HashValue>("c").unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("loader/loader.conf")).unwrap(),
+            "timeout 3\ndefault c.conf\n"
+        );
+        let entries = boot.list::<Sha256HashValue>().unwrap();
+        let default: Vec<_> = entries.iter().filter(|e| e.is_default).collect();
+        assert_eq!(default.len(), 1);
+        assert_eq!(default[0].id, "c");
+
+        assert!(boot.set_default::<Sha256HashValue>("missing").is_err());
+    }
+
+    #[test]
+    fn test_boot_counter() {
+        assert_eq!(
+            BootCounter::parse("a+3"),
+            (
+                "a",
+                Some(BootCounter {
+                    tries_left: 3,
+                    tries_done: 0
+                })
+            )
+        );
+        assert_eq!(
+            BootCounter::parse("a+b+0-2"),
+            (
+                "a+b",
+                Some(BootCounter {
+                    tries_left: 0,
+                    tries_done: 2
+                })
+            )
+        );
+        assert_eq!(BootCounter::parse("a+"), ("a+", None));
+        assert_eq!(BootCounter::parse("a+1-"), ("a+1-", None));
+        assert_eq!(BootCounter::parse("a+x"), ("a+x", None));
+        assert_eq!(BootCounter::parse("a"), ("a", None));
+    }
+
+    #[test]
+    fn test_boot_counting() {
+        let (tmp, boot) = setup();
+        let entries = tmp.path().join("loader/entries");
+        fs::rename(entries.join("a.conf"), entries.join("a+2-1.conf")).unwrap();
+        fs::rename(entries.join("b.conf"), entries.join("b+0-3.conf")).unwrap();
+
+        // "b" has run out of tries and goes to the end
+        let listed = boot.list::<Sha256HashValue>().unwrap();
+        assert_eq!(ids(&listed), ["a", "c", "b"]);
+        assert_eq!(listed[0].filename, "a.conf");
+        assert!(listed[0].is_default);
+        assert_eq!(
+            listed[0].boot_counter,
+            Some(BootCounter {
+                tries_left: 2,
+                tries_done: 1
+            })
+        );
+
+        assert_eq!(boot.mark_good(&image_id(3)).unwrap(), Vec::<PathBuf>::new());
+        assert_eq!(
+            boot.mark_good(&image_id(1)).unwrap(),
+            [entries.join("a.conf")]
+        );
+        let listed = boot.list::<Sha256HashValue>().unwrap();
+        assert_eq!(listed[0].boot_counter, None);
+        assert!(entries.join("b+0-3.conf").exists());
+    }
+
+    #[test]
+    fn test_set_order() {
+        let (_tmp, boot) = setup();
+        boot.set_order::<Sha256HashValue>("c", Some("aaa"), Some("2.0"))
+            .unwrap();
+        let entries = boot.list::<Sha256HashValue>().unwrap();
+        assert_eq!(ids(&entries), ["c", "b", "a"]);
+        assert_eq!(entries[0].version.as_deref(), Some("2.0"));
+    }
+
+    #[test]
+    fn test_remove_gc() {
+        let (tmp, boot) = setup();
+        // Make "c" share the kernel directory of "a"
+        let conf = tmp.path().join("loader/entries/c.conf");
+        let content = fs::read_to_string(&conf).unwrap().replace("/c/", "/a/");
+        fs::write(&conf, content).unwrap();
+
+        let removed = boot.remove::<Sha256HashValue>("a", false).unwrap();
+        assert_eq!(removed.id, "a");
+        assert!(!tmp.path().join("loader/entries/a.conf").exists());
+        assert!(tmp.path().join("a/vmlinuz").exists());
+
+        boot.remove::<Sha256HashValue>("c.conf", false).unwrap();
+        assert!(!tmp.path().join("a").exists());
+        // Not referenced by any entry, but not ours to remove either
+        assert!(tmp.path().join("c/vmlinuz").exists());
+        assert!(tmp.path().join("b/vmlinuz").exists());
+
+        assert!(boot.remove::<Sha256HashValue>("a", false).is_err());
+
+        // Entries which don't boot composefs images are only removed with force
+        let foreign = tmp.path().join("loader/entries/foreign.conf");
+        fs::write(&foreign, "linux /b/vmlinuz\n").unwrap();
+        let err = boot
+            .remove::<Sha256HashValue>("foreign", false)
+            .unwrap_err();
+        assert!(err.to_string().contains("doesn't boot a composefs image"));
+        assert!(foreign.exists());
+        boot.remove::<Sha256HashValue>("foreign", true).unwrap();
+        assert!(!foreign.exists());
+        // ...which still doesn't remove files that other entries use
+        assert!(tmp.path().join("b/vmlinuz").exists());
+    }
+
+    #[test]
+    fn test_prune() {
+        let (tmp, boot) = setup();
+        write_entry(tmp.path(), "d", "1.0", 4);
+        fs::write(tmp.path().join("loader/entries/foreign.conf"), "linux /x\n").unwrap();
+        fs::create_dir_all(tmp.path().join("loader")).unwrap();
+        fs::write(tmp.path().join("loader/loader.conf"), "default d.conf\n").unwrap();
+
+        // b, a, c, d in menu order: keep b, plus d (default) and c (protected)
+        let removed = boot.prune(1, &[image_id(3)]).unwrap();
+        assert_eq!(ids(&removed), ["a"]);
+        assert!(!tmp.path().join("a").exists());
+
+        let entries = boot.list::<Sha256HashValue>().unwrap();
+        assert_eq!(ids(&entries), ["b", "c", "d", "foreign"]);
+    }
+}
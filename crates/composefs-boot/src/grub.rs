@@ -0,0 +1,210 @@
+//! GRUB configuration generation for bootloaders without Boot Loader Specification support.
+//!
+//! Some systems use a plain GRUB which doesn't understand `loader/entries/*.conf`.  This module
+//! renders `menuentry` blocks for such systems from the same [`BootEntry`] values that are passed
+//! to [`crate::write_boot::write_boot_simple`], applying the same relocation and command line
+//! adjustments, so that the result refers to the files written by it.  The entries that are
+//! already installed on a boot partition can be rendered with [`installed_grub_cfg`].  The output
+//! is meant to be included from (or appended to) the system's `grub.cfg`.
+
+use std::{fmt::Write, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use composefs::{fsverity::FsVerityHashValue, repository::Repository};
+
+use crate::{
+    bootloader::{BootEntry, BootLoaderEntryFile, Type1Entry},
+    entries::{BootDir, EntryType},
+};
+
+/// Quotes a word for the GRUB configuration language, if necessary.
+///
+/// GRUB uses shell-like quoting: inside of single quotes, everything is literal except for the
+/// closing quote itself.
+fn quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_.,/:=+@".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Renders a single `menuentry` block.
+fn write_menuentry(
+    output: &mut String,
+    title: &str,
+    id: &str,
+    commands: &[(&str, Vec<&str>)],
+) -> std::fmt::Result {
+    writeln!(output, "menuentry {} --id {} {{", quote(title), quote(id))?;
+    for (command, args) in commands {
+        write!(output, "\t{command}")?;
+        for arg in args {
+            write!(output, " {}", quote(arg))?;
+        }
+        writeln!(output)?;
+    }
+    writeln!(output, "}}")
+}
+
+fn type1_menuentry(entry: &BootLoaderEntryFile, id: &str) -> Result<String> {
+    let title = entry.get_value("title").unwrap_or(id);
+
+    let Some(linux) = entry.get_value("linux") else {
+        anyhow::bail!("Boot entry {id} has no linux key");
+    };
+    let mut linux_args = vec![linux];
+    for options in entry.get_values("options") {
+        linux_args.extend(crate::cmdline::split_cmdline(options).filter(|s| !s.is_empty()));
+    }
+
+    let mut commands = vec![("linux", linux_args)];
+    let initrds: Vec<&str> = entry
+        .get_values("initrd")
+        .flat_map(str::split_ascii_whitespace)
+        .collect();
+    if !initrds.is_empty() {
+        commands.push(("initrd", initrds));
+    }
+    for devicetree in entry.get_values("devicetree") {
+        commands.push(("devicetree", vec![devicetree]));
+    }
+    if entry.get_value("devicetree-overlay").is_some() {
+        log::warn!("Boot entry {id}: GRUB can't apply devicetree overlays, ignoring them");
+    }
+
+    let mut output = String::new();
+    write_menuentry(&mut output, title, &format!("composefs-{id}"), &commands)?;
+    Ok(output)
+}
+
+fn uki_menuentry(title: &str, id: &str, filename: &Path) -> Result<String> {
+    let path = Path::new("/EFI/Linux").join(filename);
+    let mut output = String::new();
+    write_menuentry(
+        &mut output,
+        title,
+        &format!("composefs-{id}"),
+        &[("chainloader", vec![&path.to_string_lossy()])],
+    )?;
+    Ok(output)
+}
+
+/// Renders a GRUB `menuentry` block for a boot entry.
+///
+/// The arguments have the same meaning as for [`crate::write_boot::write_boot_simple`], and the
+/// rendered entry refers to the files at the locations where that function would write them.
+/// Type 1 entries become `linux`/`initrd` commands with the kernel command line (including the
+/// `composefs=` argument) appended to `linux`, and UKIs are chainloaded from `/EFI/Linux`.
+///
+/// The menu entry gets the GRUB id `composefs-<entry_id>`, which can be used to select it as the
+/// default.
+pub fn menuentry<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    entry: BootEntry<ObjectID>,
+    root_id: &ObjectID,
+    insecure: bool,
+    boot_subdir: Option<&str>,
+    entry_id: Option<&str>,
+    cmdline_extra: &[&str],
+) -> Result<String> {
+    let mut t1: Type1Entry<ObjectID> = match entry {
+        BootEntry::Type1(t1) => t1,
+        BootEntry::UsrLibModulesVmLinuz(entry) => entry.into_type1(entry_id, repo)?,
+        BootEntry::Type2(mut t2) => {
+            anyhow::ensure!(cmdline_extra.is_empty(), "Can't add --cmdline args to UKIs");
+            if let Some(name) = entry_id {
+                t2.rename(name);
+            }
+            let name = t2
+                .file_path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            return uki_menuentry(&name, &name, &t2.file_path);
+        }
+    };
+
+    if let Some(name) = entry_id {
+        t1.relocate(boot_subdir, name);
+    }
+    t1.entry
+        .adjust_cmdline(Some(&root_id.to_hex()), insecure, cmdline_extra);
+
+    let filename = t1.filename.to_string_lossy();
+    type1_menuentry(
+        &t1.entry,
+        filename.strip_suffix(".conf").unwrap_or(&filename),
+    )
+}
+
+/// Renders a complete GRUB configuration snippet from a list of `menuentry` blocks as returned
+/// by [`menuentry`].
+///
+/// If `default` is given, the snippet also sets it as the default entry (it should be the
+/// `composefs-<entry_id>` id of one of the entries).
+pub fn grub_cfg(menuentries: &[String], default: Option<&str>) -> String {
+    let mut output = String::from("# File created by composefs\n");
+    if let Some(default) = default {
+        output.push_str(&format!("set default={}\n", quote(default)));
+    }
+    for entry in menuentries {
+        output.push('\n');
+        output.push_str(entry);
+    }
+    output
+}
+
+/// Renders a GRUB configuration snippet for the composefs entries installed in `boot`.
+///
+/// Type 1 entries are rendered from their `.conf` files, so the result refers to exactly the
+/// files that they do, and UKIs are chainloaded.  Entries without a `composefs=` argument are
+/// skipped.  If the default entry (according to `loader.conf`) is a composefs entry, it also
+/// becomes the default in GRUB.  The menu entries get the GRUB id `composefs-<id>`, with the ids
+/// shown by [`BootDir::list`].
+pub fn installed_grub_cfg<ObjectID: FsVerityHashValue>(boot: &BootDir) -> Result<String> {
+    let mut menuentries = vec![];
+    let mut default = None;
+
+    for entry in boot.list::<ObjectID>()? {
+        if entry.image.is_none() {
+            continue;
+        }
+        menuentries.push(match entry.entry_type {
+            EntryType::Type1 => {
+                let content = fs::read_to_string(&entry.path)
+                    .with_context(|| format!("Failed to read {:?}", entry.path))?;
+                type1_menuentry(&BootLoaderEntryFile::new(&content), &entry.id)?
+            }
+            EntryType::Type2 => {
+                let title = entry.title.as_deref().unwrap_or(&entry.id);
+                // SAFETY: list() only returns paths with a filename
+                uki_menuentry(title, &entry.id, entry.path.file_name().unwrap().as_ref())?
+            }
+        });
+        if entry.is_default {
+            default = Some(format!("composefs-{}", entry.id));
+        }
+    }
+
+    Ok(grub_cfg(&menuentries, default.as_deref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("composefs=abc"), "composefs=abc");
+        assert_eq!(quote("composefs=?abc"), "'composefs=?abc'");
+        assert_eq!(quote("/boot/vmlinuz"), "/boot/vmlinuz");
+        assert_eq!(quote("Fedora Linux"), "'Fedora Linux'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote("a=\"b c\""), "'a=\"b c\"'");
+        assert_eq!(quote("$root"), "'$root'");
+        assert_eq!(quote(""), "''");
+    }
+}
@@ -9,8 +9,16 @@
 
 pub mod bootloader;
 pub mod cmdline;
+pub mod deploy;
+pub mod entries;
+pub mod grub;
+pub mod microcode;
 pub mod os_release;
 pub mod selabel;
+pub mod signature;
+pub mod state;
+#[cfg(test)]
+mod test;
 pub mod uki;
 pub mod write_boot;
 
@@ -0,0 +1,236 @@
+//! Early microcode initrd generation.
+//!
+//! On x86, CPU microcode updates should be applied as early as possible, before the kernel starts
+//! using any of the affected features.  The kernel looks for them in an uncompressed cpio archive
+//! at the start of the initrd, at `kernel/x86/microcode/GenuineIntel.bin` and
+//! `kernel/x86/microcode/AuthenticAMD.bin`.  This module builds such an archive from the microcode
+//! files in `/usr/lib/firmware`, in the same way as `dracut --early-microcode` does.
+//!
+//! Finding the microcode files ([`find_microcode`]) is cheap, and the archive is only built when
+//! a boot entry is written ([`build_early_microcode`]).  Compressed (`.xz`) microcode files can't
+//! be decompressed yet, so they're skipped with a warning.
+
+use anyhow::Result;
+
+use composefs::{
+    fsverity::FsVerityHashValue,
+    repository::Repository,
+    tree::{Directory, Inode, LeafContent, RegularFile},
+};
+
+/// The microcode directories in /usr/lib/firmware, and the file that the kernel expects each
+/// vendor's microcode in.
+const VENDORS: &[(&str, &str)] = &[
+    ("/usr/lib/firmware/intel-ucode", "GenuineIntel.bin"),
+    ("/usr/lib/firmware/amd-ucode", "AuthenticAMD.bin"),
+];
+
+/// Checks if a file in one of the microcode directories contains microcode.
+///
+/// Intel microcode files are named after the CPU family, model and stepping (like `06-8e-09`),
+/// and AMD ones like `microcode_amd_fam17h.bin`.  Everything else (signatures, compressed files,
+/// READMEs) is ignored.  See [`is_compressed_microcode`] for compressed files.
+fn is_microcode(filename: &str) -> bool {
+    let is_intel = filename.len() == 8
+        && filename
+            .split('-')
+            .all(|part| part.len() == 2 && part.bytes().all(|c| c.is_ascii_hexdigit()));
+    let is_amd = filename.starts_with("microcode_amd") && filename.ends_with(".bin");
+    is_intel || is_amd
+}
+
+/// Checks if a file in one of the microcode directories contains xz-compressed microcode, as
+/// shipped by distributions which compress their firmware.
+fn is_compressed_microcode(filename: &str) -> bool {
+    filename.strip_suffix(".xz").is_some_and(is_microcode)
+}
+
+/// Appends a single entry to a "newc" format cpio archive.
+fn cpio_entry(archive: &mut Vec<u8>, ino: u32, name: &str, mode: u32, data: &[u8]) {
+    let nlink = if mode & 0o040000 != 0 { 2 } else { 1 };
+    archive.extend_from_slice(b"070701");
+    for field in [
+        ino,
+        mode,
+        0, // uid
+        0, // gid
+        nlink,
+        0, // mtime
+        data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        0, // rdevmajor
+        0, // rdevminor
+        name.len() as u32 + 1,
+        0, // check
+    ] {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+/// Builds an early microcode cpio archive.
+///
+/// `microcode` is a list of pairs of the filename that the kernel expects (like
+/// `GenuineIntel.bin`) and its content.
+pub fn early_microcode_cpio(microcode: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = vec![];
+    let mut ino = 0;
+    let mut next_ino = || {
+        ino += 1;
+        ino
+    };
+
+    for dir in ["kernel", "kernel/x86", "kernel/x86/microcode"] {
+        cpio_entry(&mut archive, next_ino(), dir, 0o040755, b"");
+    }
+    for (filename, data) in microcode {
+        let name = format!("kernel/x86/microcode/{filename}");
+        cpio_entry(&mut archive, next_ino(), &name, 0o100644, data);
+    }
+    cpio_entry(&mut archive, 0, "TRAILER!!!", 0, b"");
+
+    // The kernel finds the next archive in the initrd by skipping NUL bytes, but some tools
+    // expect archives to be padded to a full block
+    archive.resize(archive.len().next_multiple_of(512), 0);
+    archive
+}
+
+/// The microcode files of one CPU vendor.
+#[derive(Debug, Clone)]
+pub struct VendorMicrocode<ObjectID: FsVerityHashValue> {
+    /// The file that the kernel expects the microcode in, like `GenuineIntel.bin`
+    pub filename: &'static str,
+    /// The microcode files, in the order in which they're concatenated
+    pub files: Vec<RegularFile<ObjectID>>,
+}
+
+/// Finds the microcode files in `/usr/lib/firmware` in the given filesystem.
+///
+/// Returns an empty list if there are no microcode files (for example on non-x86 systems).
+pub fn find_microcode<ObjectID: FsVerityHashValue>(
+    root: &Directory<ObjectID>,
+) -> Result<Vec<VendorMicrocode<ObjectID>>> {
+    let mut microcode = vec![];
+
+    for (dirname, filename) in VENDORS {
+        let Some(dir) = root.get_directory_opt(dirname.as_ref())? else {
+            continue;
+        };
+
+        let mut files = vec![];
+        for (name, inode) in dir.sorted_entries() {
+            let Inode::Leaf(leaf) = inode else {
+                continue;
+            };
+            let LeafContent::Regular(file) = &leaf.content else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            if is_microcode(&name) {
+                files.push(file.clone());
+            } else if is_compressed_microcode(&name) {
+                log::warn!("Skipping compressed microcode {dirname}/{name}: xz isn't supported");
+            }
+        }
+
+        if !files.is_empty() {
+            microcode.push(VendorMicrocode { filename, files });
+        }
+    }
+
+    Ok(microcode)
+}
+
+/// Builds an early microcode cpio archive from microcode files found with [`find_microcode`].
+///
+/// Returns `None` if there aren't any.
+pub fn build_early_microcode<ObjectID: FsVerityHashValue>(
+    microcode: &[VendorMicrocode<ObjectID>],
+    repo: &Repository<ObjectID>,
+) -> Result<Option<Vec<u8>>> {
+    if microcode.is_empty() {
+        return Ok(None);
+    }
+
+    let mut contents = vec![];
+    for vendor in microcode {
+        let mut content = vec![];
+        for file in &vendor.files {
+            content.extend_from_slice(&composefs::fs::read_file(file, repo)?);
+        }
+        contents.push((vendor.filename, content));
+    }
+
+    let contents: Vec<(&str, &[u8])> = contents
+        .iter()
+        .map(|(name, content)| (*name, content.as_slice()))
+        .collect();
+    Ok(Some(early_microcode_cpio(&contents)))
+}
+
+#[cfg(test)]
+mod tests {
+    use similar_asserts::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_is_microcode() {
+        assert!(is_microcode("06-8e-09"));
+        assert!(is_microcode("microcode_amd_fam17h.bin"));
+        assert!(!is_microcode("06-8e-09.xz"));
+        assert!(is_compressed_microcode("06-8e-09.xz"));
+        assert!(is_compressed_microcode("microcode_amd_fam17h.bin.xz"));
+        assert!(!is_compressed_microcode("06-8e-09"));
+        assert!(!is_compressed_microcode("README.xz"));
+        assert!(!is_microcode("06-8e-09.initramfs"));
+        assert!(!is_microcode("microcode_amd_fam17h.bin.asc"));
+        assert!(!is_microcode("README"));
+    }
+
+    #[test]
+    fn test_early_microcode_cpio() {
+        let archive = early_microcode_cpio(&[("GenuineIntel.bin", b"intel")]);
+        assert_eq!(archive.len() % 512, 0);
+
+        // Walk the archive again to check the structure
+        let mut names = vec![];
+        let mut offset = 0;
+        loop {
+            let header = std::str::from_utf8(&archive[offset..offset + 110]).unwrap();
+            assert_eq!(&header[..6], "070701");
+            let field = |n: usize| {
+                u32::from_str_radix(&header[6 + n * 8..14 + n * 8], 16).unwrap() as usize
+            };
+            let (filesize, namesize) = (field(6), field(11));
+            let name = &archive[offset + 110..offset + 110 + namesize - 1];
+            let name = std::str::from_utf8(name).unwrap();
+            let data_start = (offset + 110 + namesize).next_multiple_of(4);
+            let data = &archive[data_start..data_start + filesize];
+            offset = (data_start + filesize).next_multiple_of(4);
+
+            if name == "TRAILER!!!" {
+                break;
+            }
+            names.push(name);
+            if name.ends_with(".bin") {
+                assert_eq!(data, b"intel");
+            }
+        }
+        assert_eq!(
+            names,
+            [
+                "kernel",
+                "kernel/x86",
+                "kernel/x86/microcode",
+                "kernel/x86/microcode/GenuineIntel.bin"
+            ]
+        );
+        assert!(archive[offset..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,134 @@
+//! Detached signatures of composefs image digests.
+//!
+//! With a signed UKI, the `composefs=` digest on the kernel command line is covered by the Secure
+//! Boot signature, so the root image can be trusted.  Type 1 entries have no such protection: the
+//! command line lives in an unsigned file on the boot partition.  In that case the digest can be
+//! signed separately, and the signature checked in the initramfs against a public key built into
+//! it.
+//!
+//! The signed message is the hex digest of the image, as ASCII, without a trailing newline.  RSA
+//! and EC signatures use SHA-256; Ed25519 and Ed448 signatures are over the message itself.  This
+//! makes it possible to sign images with the openssl command-line tools:
+//!
+//! ```text
+//! printf %s $digest > message
+//! openssl dgst -sha256 -sign key.pem -out $digest.sig message                # RSA, EC
+//! openssl pkeyutl -sign -rawin -inkey key.pem -in message -out $digest.sig   # EdDSA
+//! ```
+
+use core::fmt;
+
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::{Id, PKey, PKeyRef, Private, Public},
+    sign::{Signer, Verifier},
+};
+use thiserror::Error;
+
+/// Errors that can occur while signing or verifying image digests.
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    /// The signature doesn't match the image digest and key
+    #[error("Invalid signature for image {0}")]
+    BadSignature(String),
+    /// An error from OpenSSL
+    #[error("OpenSSL error: {0}")]
+    Openssl(#[from] ErrorStack),
+}
+
+/// Returns the message digest to use with a key, or `None` for algorithms which sign the message
+/// directly.
+fn message_digest<T>(key: &PKeyRef<T>) -> Option<MessageDigest> {
+    match key.id() {
+        Id::ED25519 | Id::ED448 => None,
+        _ => Some(MessageDigest::sha256()),
+    }
+}
+
+/// A public key which is trusted to sign composefs images.
+pub struct TrustedKey {
+    key: PKey<Public>,
+}
+
+impl fmt::Debug for TrustedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrustedKey")
+            .field("id", &self.key.id())
+            .field("bits", &self.key.bits())
+            .finish()
+    }
+}
+
+impl TrustedKey {
+    /// Loads a public key from a PEM file.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, SignatureError> {
+        Ok(Self {
+            key: PKey::public_key_from_pem(pem)?,
+        })
+    }
+
+    /// Verifies a detached signature of an image, given as its hex digest.
+    pub fn verify(&self, image: &str, signature: &[u8]) -> Result<(), SignatureError> {
+        let mut verifier = match message_digest(&self.key) {
+            Some(digest) => Verifier::new(digest, &self.key)?,
+            None => Verifier::new_without_digest(&self.key)?,
+        };
+        // A malformed signature gets reported as an error by some algorithms, rather than false
+        match verifier.verify_oneshot(signature, image.as_bytes()) {
+            Ok(true) => Ok(()),
+            Ok(false) | Err(_) => Err(SignatureError::BadSignature(image.to_string())),
+        }
+    }
+}
+
+/// Creates a detached signature of an image, given as its hex digest.
+pub fn sign_image(image: &str, key: &PKey<Private>) -> Result<Vec<u8>, SignatureError> {
+    let mut signer = match message_digest(key) {
+        Some(digest) => Signer::new(digest, key)?,
+        None => Signer::new_without_digest(key)?,
+    };
+    Ok(signer.sign_oneshot_to_vec(image.as_bytes())?)
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::{ec::EcGroup, ec::EcKey, nid::Nid, rsa::Rsa};
+
+    use super::*;
+
+    const IMAGE: &str = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52";
+    const OTHER: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn trusted(key: &PKey<Private>) -> TrustedKey {
+        TrustedKey::from_pem(&key.public_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let keys = [
+            PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(),
+            PKey::generate_ed25519().unwrap(),
+        ];
+
+        for key in &keys {
+            let signature = sign_image(IMAGE, key).unwrap();
+            let trusted = trusted(key);
+            trusted.verify(IMAGE, &signature).unwrap();
+            assert!(matches!(
+                trusted.verify(OTHER, &signature),
+                Err(SignatureError::BadSignature(..))
+            ));
+            assert!(trusted.verify(IMAGE, b"garbage").is_err());
+        }
+
+        // A signature by a different key
+        let signature = sign_image(IMAGE, &keys[2]).unwrap();
+        let other = trusted(&PKey::generate_ed25519().unwrap());
+        assert!(other.verify(IMAGE, &signature).is_err());
+
+        assert!(TrustedKey::from_pem(b"not a key").is_err());
+    }
+}
@@ -0,0 +1,968 @@
+//! State directories of deployments.
+//!
+//! Each deployed image has a state directory at `state/deploy/<image id>` in the sysroot, which
+//! `composefs-setup-root` uses at boot: `etc/upper` and `etc/work` are the upper and work
+//! directories of an overlay on top of the image's `/etc`, and `var` is bind mounted on `/var`.
+//!
+//! [`create_state`] creates the state directory for a new deployment.  The changes made to `/etc`
+//! in the previous deployment are carried forward with a three-way merge, in the style of ostree:
+//! the local modifications (the upper directory of the previous deployment) are applied on top of
+//! the `/etc` of the new image.  Files which were modified locally and also changed between the
+//! previous and the new image are reported as conflicts, and the local version wins.  Local
+//! modifications which are identical to the new image are dropped, so that future updates of those
+//! files aren't hidden.
+//!
+//! By default, `/var` is shared between all deployments: it lives in `state/var`, and the `var`
+//! of each deployment is a symlink to it.
+//!
+//! [`reset_state`] returns a deployment to the pristine state of its image by discarding the
+//! local changes to `/etc` and/or the contents of `/var`.  This can't be done while the
+//! deployment is running, so `composefs-setup-root` does it at boot (see [`reset_at_boot`]), when
+//! it was requested with [`request_reset`] or with `composefs.reset` on the kernel command line
+//! (see [`get_cmdline_reset`]).  Either way, the reset only happens once.
+
+use std::{
+    ffi::OsStr,
+    fmt,
+    fs::{self, DirBuilder},
+    io::ErrorKind,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, symlink, DirBuilderExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use rustix::fs::{
+    lgetxattr, llistxattr, lsetxattr, mknodat, renameat_with, utimensat, AtFlags, FileType, Mode,
+    RenameFlags, Timespec, Timestamps, XattrFlags, CWD,
+};
+
+use composefs::{
+    erofs::reader::image_to_filesystem,
+    fs::read_filesystem,
+    fsverity::FsVerityHashValue,
+    repository::Repository,
+    tree::{Directory, Inode, Leaf, LeafContent, RegularFile, Stat},
+};
+
+use crate::cmdline::split_cmdline;
+
+/// Options for creating the state directory of a deployment.
+#[derive(Debug, Clone)]
+pub struct StateOptions {
+    /// Share `/var` with the other deployments.  This is the default.  Otherwise, the new
+    /// deployment gets an empty `/var` of its own.
+    pub share_var: bool,
+}
+
+impl Default for StateOptions {
+    fn default() -> Self {
+        Self { share_var: true }
+    }
+}
+
+/// The ways in which local changes to `/etc` can conflict with changes in the new image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// The file was added locally, and the new image added a different version of it.
+    Added,
+    /// The file was modified locally, and changed between the previous and the new image.
+    Modified,
+    /// The file was deleted locally, and changed between the previous and the new image.
+    Deleted,
+}
+
+/// A file in `/etc` which was changed both locally and in the new image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EtcConflict {
+    /// The absolute path of the file, like `/etc/fstab`
+    pub path: PathBuf,
+    /// What happened to the file locally
+    pub kind: ConflictKind,
+}
+
+impl fmt::Display for EtcConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            ConflictKind::Added => "added locally and in the new image",
+            ConflictKind::Modified => "modified locally and changed in the new image",
+            ConflictKind::Deleted => "deleted locally and changed in the new image",
+        };
+        write!(
+            f,
+            "{}: {what}, keeping the local version",
+            self.path.display()
+        )
+    }
+}
+
+/// The result of merging the `/etc` changes of the previous deployment into a new one.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EtcMerge {
+    /// Local changes which were dropped because the new image has the same content
+    pub dropped: Vec<PathBuf>,
+    /// Local changes which conflict with changes in the new image.  They are kept.
+    pub conflicts: Vec<EtcConflict>,
+}
+
+/// Returns the state directory of the deployment of `image`.
+pub fn state_dir<ObjectID: FsVerityHashValue>(sysroot: &Path, image: &ObjectID) -> PathBuf {
+    sysroot.join("state/deploy").join(image.to_hex())
+}
+
+/// Creates the state directory for deploying `image`.
+///
+/// If `previous` is given, the changes made to `/etc` in its deployment are merged into the new
+/// one, and the conflicts are returned.  Both images need to be in `repo`.  The state directory is
+/// created atomically: it's built under a temporary name and renamed into place at the end.  It's
+/// an error if it already exists.
+pub fn create_state<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    sysroot: &Path,
+    image: &ObjectID,
+    previous: Option<&ObjectID>,
+    options: &StateOptions,
+) -> Result<EtcMerge> {
+    let target = state_dir(sysroot, image);
+    ensure!(
+        fs::symlink_metadata(&target).is_err(),
+        "State directory {target:?} already exists"
+    );
+
+    // Clean up after an earlier attempt which got interrupted
+    let tmp = target.with_extension("tmp");
+    remove_all(&tmp)?;
+
+    let mut builder = DirBuilder::new();
+    builder.mode(0o755).recursive(true);
+    builder.create(tmp.join("etc"))?;
+    DirBuilder::new().mode(0o700).create(tmp.join("etc/work"))?;
+
+    let previous_upper = previous.map(|id| state_dir(sysroot, id).join("etc/upper"));
+    let merge = match (previous, &previous_upper) {
+        (Some(old), Some(upper)) if upper.is_dir() => {
+            merge_etc(repo, old, image, upper, &tmp.join("etc/upper"))
+                .with_context(|| format!("Merging the /etc changes in {upper:?}"))?
+        }
+        _ => {
+            builder.create(tmp.join("etc/upper"))?;
+            EtcMerge::default()
+        }
+    };
+
+    if options.share_var {
+        let shared = sysroot.join("state/var");
+        if let Some(id) = previous {
+            migrate_var(&state_dir(sysroot, id).join("var"), &shared)?;
+        }
+        if fs::symlink_metadata(&shared).is_err() {
+            builder.create(&shared)?;
+        }
+        symlink("../../var", tmp.join("var"))?;
+    } else {
+        builder.create(tmp.join("var"))?;
+    }
+
+    fs::rename(&tmp, &target)?;
+    Ok(merge)
+}
+
+/// Makes `var`, the `/var` of an existing deployment, the shared `/var` at `shared`, unless there
+/// already is one.
+///
+/// `var` is exchanged with a symlink to the shared `/var` and then moved into place, so it's
+/// always reachable from either path.  An earlier migration which got interrupted is finished.
+fn migrate_var(var: &Path, shared: &Path) -> Result<()> {
+    let moved = var.with_extension("shared");
+    let is_dir = |path: &Path| fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+    let exists = |path: &Path| fs::symlink_metadata(path).is_ok();
+
+    if !exists(shared) {
+        if is_dir(var) {
+            remove_file(&moved)?;
+            symlink("../../var", &moved)?;
+            renameat_with(CWD, &moved, CWD, var, RenameFlags::EXCHANGE)
+                .with_context(|| format!("Exchanging {var:?} with a symlink to {shared:?}"))?;
+        }
+        if is_dir(&moved) {
+            fs::rename(&moved, shared)
+                .with_context(|| format!("Moving {moved:?} to {shared:?}"))?;
+        }
+    }
+
+    // Repair the symlink if the deployment lost it
+    if exists(shared) && !exists(var) {
+        symlink("../../var", var)?;
+    }
+    if !is_dir(&moved) {
+        remove_file(&moved)?;
+    }
+    Ok(())
+}
+
+/// Which parts of the state of a deployment to reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResetOptions {
+    /// Discard the local changes to `/etc`
+    pub etc: bool,
+    /// Discard the contents of `/var`.  If `/var` is shared, this affects all deployments.
+    pub var: bool,
+}
+
+impl ResetOptions {
+    /// Parses `all`, `etc`, `var` or `etc,var`.
+    fn parse(value: &str) -> Result<Self> {
+        let mut options = ResetOptions::default();
+        for what in value.split(',') {
+            match what {
+                "all" => (options.etc, options.var) = (true, true),
+                "etc" => options.etc = true,
+                "var" => options.var = true,
+                _ => bail!("Invalid reset value: {value}"),
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl fmt::Display for ResetOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = [("etc", self.etc), ("var", self.var)]
+            .iter()
+            .filter_map(|(name, reset)| reset.then_some(*name))
+            .collect::<Vec<_>>();
+        write!(f, "{}", what.join(","))
+    }
+}
+
+/// Parses the `composefs.reset` kernel command line argument.
+///
+/// `composefs.reset` and `composefs.reset=all` reset both `/etc` and `/var`, while
+/// `composefs.reset=etc` and `composefs.reset=var` (or `etc,var`) reset only one of them.
+pub fn get_cmdline_reset(cmdline: &str) -> Result<Option<ResetOptions>> {
+    let Some(arg) =
+        split_cmdline(cmdline).find(|arg| arg.split('=').next() == Some("composefs.reset"))
+    else {
+        return Ok(None);
+    };
+
+    match arg.split_once('=') {
+        None => Ok(Some(ResetOptions::parse("all")?)),
+        Some((_, value)) => ResetOptions::parse(value)
+            .map(Some)
+            .context("Invalid value for composefs.reset"),
+    }
+}
+
+/// The file in the state directory of a deployment which requests a reset at the next boot.
+const RESET_REQUEST: &str = "reset";
+
+/// The file in the state directory of a deployment which records that it was reset because of
+/// `composefs.reset` on the command line, so that it isn't reset again on the next boot with the
+/// same command line.
+const RESET_DONE: &str = "reset.done";
+
+/// Requests a reset of the state of a deployment at its next boot, given its state directory.
+///
+/// This can be used on the running deployment.
+pub fn request_reset(state: &Path, options: &ResetOptions) -> Result<()> {
+    ensure!(state.is_dir(), "No state directory at {state:?}");
+    let tmp = state.join(".reset.tmp");
+    fs::write(&tmp, format!("{options}\n")).with_context(|| format!("Writing {tmp:?}"))?;
+    fs::rename(&tmp, state.join(RESET_REQUEST))?;
+    Ok(())
+}
+
+/// Removes `path` recursively, if it exists.
+fn remove_all(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Removing {path:?}")),
+    }
+}
+
+/// Removes `path`, if it exists.
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Removing {path:?}")),
+    }
+}
+
+/// Resets the state of a deployment at boot, before anything in it gets mounted, if that was
+/// requested.  Returns what was reset.
+///
+/// A request made with [`request_reset`] is removed once it's been carried out.  A reset from
+/// `cmdline` (see [`get_cmdline_reset`]) is only done on the first boot with `composefs.reset`:
+/// it's done again once there's been a boot without it.  Pass `None` if the command line can't be
+/// trusted to request a reset.
+///
+/// This also removes leftovers of resets which got interrupted.
+pub fn reset_at_boot(state: &Path, cmdline: Option<&str>) -> Result<Option<ResetOptions>> {
+    for dir in [
+        state.join("etc/upper"),
+        state.join("etc/work"),
+        state.join("var"),
+    ] {
+        if let Ok(dir) = fs::canonicalize(dir) {
+            remove_all(&discard_path(&dir)?)?;
+        }
+    }
+
+    let request = state.join(RESET_REQUEST);
+    let requested = match fs::read_to_string(&request) {
+        Ok(value) => Some(
+            ResetOptions::parse(value.trim()).with_context(|| format!("Reading {request:?}"))?,
+        ),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => Err(err).with_context(|| format!("Reading {request:?}"))?,
+    };
+
+    let done = state.join(RESET_DONE);
+    let from_cmdline = match cmdline.map(get_cmdline_reset).transpose()?.flatten() {
+        Some(_) if done.exists() => None,
+        Some(options) => Some(options),
+        None => {
+            remove_file(&done)?;
+            None
+        }
+    };
+
+    let options = match (requested, from_cmdline) {
+        (None, None) => return Ok(None),
+        (a, b) => {
+            let (a, b) = (a.unwrap_or_default(), b.unwrap_or_default());
+            ResetOptions {
+                etc: a.etc || b.etc,
+                var: a.var || b.var,
+            }
+        }
+    };
+    reset_state(state, &options)?;
+
+    if from_cmdline.is_some() {
+        fs::write(&done, format!("{options}\n")).with_context(|| format!("Writing {done:?}"))?;
+    }
+    remove_file(&request)?;
+    Ok(Some(options))
+}
+
+/// The name under which the old contents of `dir` are deleted by [`discard_dir`].
+fn discard_path(dir: &Path) -> Result<PathBuf> {
+    let mut name = dir
+        .file_name()
+        .context("Invalid directory name")?
+        .to_owned();
+    name.push(".discard");
+    Ok(dir.with_file_name(name))
+}
+
+/// Replaces `dir` with an empty directory with the same permissions and ownership.
+///
+/// The empty directory is created next to `dir` and atomically exchanged with it, so that `dir`
+/// is always there (and never partially deleted), whenever we get interrupted.  The old contents
+/// are then deleted.  Leftovers from an interrupted reset are removed the next time.
+fn discard_dir(dir: &Path) -> Result<()> {
+    let discard = discard_path(dir)?;
+    remove_all(&discard)?;
+
+    let meta = match fs::symlink_metadata(dir) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => Err(err)?,
+    };
+    ensure!(meta.is_dir(), "{dir:?} is not a directory");
+
+    DirBuilder::new().mode(meta.mode()).create(&discard)?;
+    lchown(&discard, Some(meta.uid()), Some(meta.gid()))?;
+    fs::set_permissions(&discard, meta.permissions())?;
+    renameat_with(CWD, &discard, CWD, dir, RenameFlags::EXCHANGE)
+        .with_context(|| format!("Exchanging {dir:?} with an empty directory"))?;
+    remove_all(&discard)
+}
+
+/// Resets the state of a deployment, given its state directory (see [`state_dir`]).
+///
+/// The deployment must not be running.
+pub fn reset_state(state: &Path, options: &ResetOptions) -> Result<()> {
+    if options.etc {
+        discard_dir(&state.join("etc/upper"))?;
+        discard_dir(&state.join("etc/work"))?;
+    }
+    if options.var {
+        // A shared /var is a symlink to state/var
+        let var = fs::canonicalize(state.join("var"))
+            .with_context(|| format!("Resolving {:?}", state.join("var")))?;
+        discard_dir(&var)?;
+    }
+    Ok(())
+}
+
+fn merge_etc<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    old: &ObjectID,
+    new: &ObjectID,
+    upper: &Path,
+    new_upper: &Path,
+) -> Result<EtcMerge> {
+    let old_fs = image_to_filesystem::<ObjectID>(&repo.read_image(&old.to_hex())?)?;
+    let new_fs = image_to_filesystem::<ObjectID>(&repo.read_image(&new.to_hex())?)?;
+    let local = read_filesystem::<ObjectID>(CWD, upper, None)?;
+
+    let mut merge = EtcMerge::default();
+    merge_dir(
+        &local.root,
+        old_fs.root.get_directory_opt("etc".as_ref())?,
+        new_fs.root.get_directory_opt("etc".as_ref())?,
+        Path::new("/etc"),
+        &mut merge,
+    );
+
+    copy_all(upper, new_upper)?;
+    for path in &merge.dropped {
+        fs::remove_file(new_upper.join(path.strip_prefix("/etc")?))?;
+    }
+
+    Ok(merge)
+}
+
+fn as_dir<ObjectID: FsVerityHashValue>(
+    inode: Option<&Inode<ObjectID>>,
+) -> Option<&Directory<ObjectID>> {
+    match inode {
+        Some(Inode::Directory(dir)) => Some(dir),
+        _ => None,
+    }
+}
+
+fn is_whiteout<ObjectID: FsVerityHashValue>(leaf: &Leaf<ObjectID>) -> bool {
+    matches!(leaf.content, LeafContent::CharacterDevice(0))
+}
+
+fn is_opaque(stat: &Stat) -> bool {
+    let xattrs = stat.xattrs.borrow();
+    ["trusted.overlay.opaque", "user.overlay.opaque"]
+        .iter()
+        .any(|name| xattrs.get(OsStr::new(name)).is_some_and(|v| &**v == b"y"))
+}
+
+/// Compares the type, content, permissions and ownership of two files.  Timestamps and xattrs are
+/// ignored: they change on copy-up and relabelling without the file being modified.
+fn same_leaf<ObjectID: FsVerityHashValue>(a: &Leaf<ObjectID>, b: &Leaf<ObjectID>) -> bool {
+    same_stat(&a.stat, &b.stat)
+        && match (&a.content, &b.content) {
+            (
+                LeafContent::Regular(RegularFile::Inline(a)),
+                LeafContent::Regular(RegularFile::Inline(b)),
+            ) => a == b,
+            (
+                LeafContent::Regular(RegularFile::External(a, ..)),
+                LeafContent::Regular(RegularFile::External(b, ..)),
+            ) => a == b,
+            (LeafContent::Symlink(a), LeafContent::Symlink(b)) => a == b,
+            (LeafContent::BlockDevice(a), LeafContent::BlockDevice(b))
+            | (LeafContent::CharacterDevice(a), LeafContent::CharacterDevice(b)) => a == b,
+            (LeafContent::Fifo, LeafContent::Fifo) | (LeafContent::Socket, LeafContent::Socket) => {
+                true
+            }
+            _ => false,
+        }
+}
+
+fn same_stat(a: &Stat, b: &Stat) -> bool {
+    (a.st_mode, a.st_uid, a.st_gid) == (b.st_mode, b.st_uid, b.st_gid)
+}
+
+/// Compares two (possibly missing) inodes, recursively.
+fn same_inode<ObjectID: FsVerityHashValue>(
+    a: Option<&Inode<ObjectID>>,
+    b: Option<&Inode<ObjectID>>,
+) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(Inode::Leaf(a)), Some(Inode::Leaf(b))) => same_leaf(a, b),
+        (Some(Inode::Directory(a)), Some(Inode::Directory(b))) => {
+            same_stat(&a.stat, &b.stat)
+                && a.entries().count() == b.entries().count()
+                && a.entries()
+                    .all(|(name, inode)| same_inode(Some(inode), b.lookup(name)))
+        }
+        _ => false,
+    }
+}
+
+/// Decides what to do with each entry of the local upper directory `local`, given the
+/// corresponding directories of the old and new images.
+fn merge_dir<ObjectID: FsVerityHashValue>(
+    local: &Directory<ObjectID>,
+    old: Option<&Directory<ObjectID>>,
+    new: Option<&Directory<ObjectID>>,
+    path: &Path,
+    merge: &mut EtcMerge,
+) {
+    for (name, inode) in local.sorted_entries() {
+        let path = path.join(name);
+        let old = old.and_then(|dir| dir.lookup(name));
+        let new = new.and_then(|dir| dir.lookup(name));
+        let changed = !same_inode(old, new);
+        let conflict = |kind| EtcConflict {
+            path: path.clone(),
+            kind,
+        };
+
+        match inode {
+            // Directories are merged with the directories below them, unless they're opaque
+            Inode::Directory(dir) if !is_opaque(&dir.stat) => {
+                let is_file = |inode: Option<&Inode<_>>| matches!(inode, Some(Inode::Leaf(..)));
+                if !is_file(old) && !is_file(new) {
+                    merge_dir(dir, as_dir(old), as_dir(new), &path, merge);
+                } else if changed {
+                    merge.conflicts.push(conflict(ConflictKind::Modified));
+                }
+            }
+            Inode::Leaf(leaf) if is_whiteout(leaf) => {
+                if new.is_none() {
+                    merge.dropped.push(path);
+                } else if changed {
+                    merge.conflicts.push(conflict(ConflictKind::Deleted));
+                }
+            }
+            Inode::Leaf(leaf) if matches!(new, Some(Inode::Leaf(new)) if same_leaf(leaf, new)) => {
+                merge.dropped.push(path);
+            }
+            _ if changed => merge.conflicts.push(conflict(match old {
+                Some(..) => ConflictKind::Modified,
+                None => ConflictKind::Added,
+            })),
+            _ => {}
+        }
+    }
+}
+
+fn copy_xattrs(src: &Path, dst: &Path) -> Result<()> {
+    let mut names = vec![0; 65536];
+    let size = llistxattr(src, &mut names[..])?;
+    let mut value = vec![0; 65536];
+    for name in names[..size].split(|c| *c == 0).filter(|n| !n.is_empty()) {
+        let name = OsStr::from_bytes(name);
+        let size = lgetxattr(src, name, &mut value[..])?;
+        lsetxattr(dst, name, &value[..size], XattrFlags::empty())
+            .with_context(|| format!("Setting xattr {name:?} on {dst:?}"))?;
+    }
+    Ok(())
+}
+
+/// Copies a file, symlink, device node or directory (recursively), preserving ownership,
+/// permissions, timestamps and xattrs.  Overlay whiteouts are device nodes, and opaque directories
+/// are marked with xattrs, so this gives a faithful copy of an overlay upper directory.
+fn copy_all(src: &Path, dst: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    let file_type = meta.file_type();
+
+    if file_type.is_dir() {
+        fs::create_dir(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    } else if file_type.is_file() {
+        fs::copy(src, dst)?;
+    } else if file_type.is_symlink() {
+        symlink(fs::read_link(src)?, dst)?;
+    } else {
+        let mode = meta.mode();
+        let file_type = FileType::from_raw_mode(mode);
+        mknodat(CWD, dst, file_type, Mode::from_raw_mode(mode), meta.rdev())?;
+    }
+
+    lchown(dst, Some(meta.uid()), Some(meta.gid()))?;
+    if !file_type.is_symlink() {
+        // This comes after chown(), which clears the setuid and setgid bits
+        fs::set_permissions(dst, meta.permissions())?;
+    }
+    copy_xattrs(src, dst)?;
+
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: meta.atime(),
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        last_modification: Timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    };
+    utimensat(CWD, dst, &times, AtFlags::SYMLINK_NOFOLLOW)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use composefs::{dumpfile::dumpfile_to_filesystem, fsverity::Sha256HashValue};
+    use similar_asserts::assert_eq;
+
+    use super::*;
+    use crate::test::{self, commit};
+
+    #[test]
+    fn test_create_state() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+
+        let meta = fs::metadata(sysroot).unwrap();
+        let (uid, gid) = (meta.uid(), meta.gid());
+        let file = |name: &str, content: &str| {
+            format!(
+                "/etc/{name} {} 100644 1 {uid} {gid} 0 0.0 - {content} -\n",
+                content.len()
+            )
+        };
+
+        let old = commit(
+            &repo,
+            &[
+                file("conflict", "old"),
+                file("deleted", "old"),
+                file("same", "old"),
+                file("unchanged", "old"),
+            ]
+            .concat(),
+        );
+        let new = commit(
+            &repo,
+            &[
+                file("added", "new"),
+                file("conflict", "new"),
+                file("deleted", "new"),
+                file("same", "new"),
+                file("unchanged", "old"),
+            ]
+            .concat(),
+        );
+
+        // The first deployment gets empty state
+        let merge = create_state(&repo, sysroot, &old, None, &StateOptions::default()).unwrap();
+        assert_eq!(merge, EtcMerge::default());
+        let old_state = state_dir(sysroot, &old);
+        assert!(fs::read_dir(old_state.join("etc/upper"))
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(old_state.join("etc/work").is_dir());
+        assert_eq!(
+            fs::read_link(old_state.join("var")).unwrap(),
+            Path::new("../../var")
+        );
+        assert!(create_state(&repo, sysroot, &old, None, &StateOptions::default()).is_err());
+
+        // Make some local changes
+        let upper = old_state.join("etc/upper");
+        for (name, content) in [
+            ("added", "local"),
+            ("conflict", "local"),
+            ("same", "new"),
+            ("unchanged", "local"),
+        ] {
+            fs::write(upper.join(name), content).unwrap();
+        }
+        fs::create_dir(upper.join("dir")).unwrap();
+        fs::write(upper.join("dir/local"), "local").unwrap();
+        symlink("target", upper.join("symlink")).unwrap();
+        fs::write(old_state.join("var/data"), "data").unwrap();
+
+        let merge = create_state(&repo, sysroot, &new, Some(&old), &StateOptions::default());
+        let merge = merge.unwrap();
+        assert_eq!(merge.dropped, [PathBuf::from("/etc/same")]);
+        assert_eq!(
+            merge.conflicts,
+            [
+                EtcConflict {
+                    path: "/etc/added".into(),
+                    kind: ConflictKind::Added
+                },
+                EtcConflict {
+                    path: "/etc/conflict".into(),
+                    kind: ConflictKind::Modified
+                },
+            ]
+        );
+        assert_eq!(
+            merge.conflicts[1].to_string(),
+            "/etc/conflict: modified locally and changed in the new image, keeping the local version"
+        );
+
+        let new_upper = state_dir(sysroot, &new).join("etc/upper");
+        let mut names: Vec<_> = fs::read_dir(&new_upper)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["added", "conflict", "dir", "symlink", "unchanged"]);
+        assert_eq!(fs::read(new_upper.join("dir/local")).unwrap(), b"local");
+        assert_eq!(fs::read(new_upper.join("unchanged")).unwrap(), b"local");
+        assert_eq!(
+            fs::read_link(new_upper.join("symlink")).unwrap(),
+            Path::new("target")
+        );
+        assert_eq!(
+            fs::metadata(new_upper.join("conflict"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            fs::metadata(upper.join("conflict"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+
+        // The changes to the old deployment are untouched, and /var is shared
+        assert_eq!(fs::read(upper.join("same")).unwrap(), b"new");
+        let new_var = state_dir(sysroot, &new).join("var");
+        assert_eq!(fs::read(new_var.join("data")).unwrap(), b"data");
+
+        // Without sharing, /var starts out empty
+        let third = commit(&repo, &file("third", "third"));
+        let options = StateOptions { share_var: false };
+        create_state(&repo, sysroot, &third, Some(&new), &options).unwrap();
+        let var = state_dir(sysroot, &third).join("var");
+        assert!(fs::read_dir(var).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_migrate_var() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let old = commit(&repo, "");
+        let new = commit(&repo, "/etc/new 0 100644 1 0 0 0 0.0 - - -\n");
+
+        // A deployment from before /var was shared
+        let old_state = state_dir(sysroot, &old);
+        fs::create_dir_all(old_state.join("var")).unwrap();
+        fs::write(old_state.join("var/data"), "data").unwrap();
+
+        create_state(&repo, sysroot, &new, Some(&old), &StateOptions::default()).unwrap();
+        assert_eq!(fs::read(sysroot.join("state/var/data")).unwrap(), b"data");
+        assert_eq!(fs::read(old_state.join("var/data")).unwrap(), b"data");
+        assert!(fs::symlink_metadata(old_state.join("var"))
+            .unwrap()
+            .is_symlink());
+        let new_var = state_dir(sysroot, &new).join("var");
+        assert_eq!(fs::read(new_var.join("data")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_migrate_var_interrupted() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let old = commit(&repo, "");
+        let old_state = state_dir(sysroot, &old);
+        let old_var = old_state.join("var");
+        let shared = sysroot.join("state/var");
+        let check = |new: &Sha256HashValue| {
+            create_state(&repo, sysroot, new, Some(&old), &StateOptions::default()).unwrap();
+            assert_eq!(fs::read(shared.join("data")).unwrap(), b"data");
+            assert!(fs::symlink_metadata(&old_var).unwrap().is_symlink());
+            assert_eq!(fs::read(old_var.join("data")).unwrap(), b"data");
+            assert!(fs::symlink_metadata(old_state.join("var.shared")).is_err());
+            let new_var = state_dir(sysroot, new).join("var");
+            assert_eq!(fs::read(new_var.join("data")).unwrap(), b"data");
+        };
+
+        // Interrupted after exchanging the old /var with the symlink
+        fs::create_dir_all(old_state.join("var.shared")).unwrap();
+        fs::write(old_state.join("var.shared/data"), "data").unwrap();
+        symlink("../../var", &old_var).unwrap();
+        check(&commit(&repo, "/etc/a 0 100644 1 0 0 0 0.0 - - -\n"));
+
+        // Interrupted after moving the old /var, before creating the symlink
+        fs::remove_file(&old_var).unwrap();
+        check(&commit(&repo, "/etc/b 0 100644 1 0 0 0 0.0 - - -\n"));
+
+        // Interrupted after creating the symlink, before the exchange
+        fs::remove_file(&old_var).unwrap();
+        fs::rename(&shared, &old_var).unwrap();
+        symlink("../../var", old_state.join("var.shared")).unwrap();
+        check(&commit(&repo, "/etc/c 0 100644 1 0 0 0 0.0 - - -\n"));
+    }
+
+    #[test]
+    fn test_reset_state() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let image = commit(&repo, "");
+        create_state(&repo, sysroot, &image, None, &StateOptions::default()).unwrap();
+
+        let state = state_dir(sysroot, &image);
+        fs::write(state.join("etc/upper/hostname"), "local").unwrap();
+        fs::create_dir(state.join("etc/work/work")).unwrap();
+        fs::create_dir(state.join("var/lib")).unwrap();
+        fs::write(state.join("var/lib/data"), "data").unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().mode();
+        let work_mode = mode(&state.join("etc/work"));
+
+        // Leftovers of an interrupted reset
+        fs::create_dir(state.join("etc/upper.discard")).unwrap();
+        fs::write(state.join("etc/upper.discard/old"), "old").unwrap();
+
+        let is_empty = |path: &Path| fs::read_dir(path).unwrap().next().is_none();
+        let etc = ResetOptions {
+            etc: true,
+            var: false,
+        };
+        reset_state(&state, &etc).unwrap();
+        assert!(is_empty(&state.join("etc/upper")));
+        assert!(is_empty(&state.join("etc/work")));
+        assert_eq!(mode(&state.join("etc/work")), work_mode);
+        assert!(!state.join("etc/upper.discard").exists());
+        assert!(!is_empty(&state.join("var")));
+
+        // The shared /var is reset, and stays shared
+        let var = ResetOptions {
+            etc: false,
+            var: true,
+        };
+        reset_state(&state, &var).unwrap();
+        assert!(is_empty(&sysroot.join("state/var")));
+        assert!(fs::symlink_metadata(state.join("var"))
+            .unwrap()
+            .is_symlink());
+        assert!(!sysroot.join("state/var.discard").exists());
+    }
+
+    #[test]
+    fn test_reset_at_boot() {
+        let (tmp, repo) = test::sysroot();
+        let sysroot = tmp.path();
+        let image = commit(&repo, "");
+        create_state(&repo, sysroot, &image, None, &StateOptions::default()).unwrap();
+
+        let state = state_dir(sysroot, &image);
+        let hostname = state.join("etc/upper/hostname");
+        let data = sysroot.join("state/var/data");
+        let change = || {
+            fs::write(&hostname, "local").unwrap();
+            fs::write(&data, "data").unwrap();
+        };
+        change();
+        let etc = ResetOptions {
+            etc: true,
+            var: false,
+        };
+
+        // Nothing happens without a request, except for cleaning up after interrupted resets
+        fs::create_dir(sysroot.join("state/var.discard")).unwrap();
+        assert_eq!(reset_at_boot(&state, Some("quiet")).unwrap(), None);
+        assert!(hostname.exists() && data.exists());
+        assert!(!sysroot.join("state/var.discard").exists());
+
+        // A request is carried out once
+        request_reset(&state, &etc).unwrap();
+        assert_eq!(reset_at_boot(&state, None).unwrap(), Some(etc));
+        assert!(!hostname.exists() && data.exists());
+        change();
+        assert_eq!(reset_at_boot(&state, None).unwrap(), None);
+        assert!(hostname.exists());
+
+        // So is composefs.reset, until there's a boot without it
+        let all = ResetOptions {
+            etc: true,
+            var: true,
+        };
+        let cmdline = Some("composefs.reset");
+        assert_eq!(reset_at_boot(&state, cmdline).unwrap(), Some(all));
+        assert!(!hostname.exists() && !data.exists());
+        change();
+        assert_eq!(reset_at_boot(&state, cmdline).unwrap(), None);
+        assert!(hostname.exists() && data.exists());
+        assert_eq!(reset_at_boot(&state, Some("quiet")).unwrap(), None);
+        assert_eq!(reset_at_boot(&state, cmdline).unwrap(), Some(all));
+        assert!(!hostname.exists() && !data.exists());
+
+        // Both together reset everything which was asked for
+        reset_at_boot(&state, None).unwrap();
+        request_reset(&state, &etc).unwrap();
+        assert_eq!(
+            reset_at_boot(&state, Some("composefs.reset=var")).unwrap(),
+            Some(all)
+        );
+        assert!(!state.join(RESET_REQUEST).exists());
+        assert!(reset_at_boot(&state, Some("composefs.reset=home")).is_err());
+    }
+
+    #[test]
+    fn test_get_cmdline_reset() {
+        let all = ResetOptions {
+            etc: true,
+            var: true,
+        };
+        let etc = ResetOptions {
+            etc: true,
+            var: false,
+        };
+        assert_eq!(get_cmdline_reset("composefs=1234 quiet").unwrap(), None);
+        assert_eq!(get_cmdline_reset("composefs.resetx=1").unwrap(), None);
+        assert_eq!(
+            get_cmdline_reset("quiet composefs.reset").unwrap(),
+            Some(all)
+        );
+        assert_eq!(get_cmdline_reset("composefs.reset=all").unwrap(), Some(all));
+        assert_eq!(
+            get_cmdline_reset("composefs.reset=var,etc").unwrap(),
+            Some(all)
+        );
+        assert_eq!(get_cmdline_reset("composefs.reset=etc").unwrap(), Some(etc));
+        assert!(get_cmdline_reset("composefs.reset=home").is_err());
+    }
+
+    #[test]
+    fn test_merge_dir() {
+        let tree = |dumpfile: &str| {
+            let dumpfile = format!("/ 4096 40755 2 0 0 0 0.0 - - -\n{dumpfile}");
+            dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap()
+        };
+        let old = tree(
+            "/changed 3 100644 1 0 0 0 0.0 - old -\n\
+             /dir 4096 40755 2 0 0 0 0.0 - - -\n\
+             /dir/file 3 100644 1 0 0 0 0.0 - old -\n\
+             /removed 3 100644 1 0 0 0 0.0 - old -\n",
+        );
+        let new = tree(
+            "/changed 3 100644 1 0 0 0 0.0 - new -\n\
+             /dir 4096 40755 2 0 0 0 0.0 - - -\n\
+             /dir/file 3 100644 1 0 0 0 0.0 - new -\n",
+        );
+        let local = tree(
+            "/changed 0 20000 1 0 0 0 0.0 - - -\n\
+             /dir 4096 40755 2 0 0 0 0.0 - - - trusted.overlay.opaque=y\n\
+             /removed 0 20000 1 0 0 0 0.0 - - -\n",
+        );
+
+        let mut merge = EtcMerge::default();
+        merge_dir(
+            &local.root,
+            Some(&old.root),
+            Some(&new.root),
+            Path::new("/etc"),
+            &mut merge,
+        );
+        assert_eq!(merge.dropped, [PathBuf::from("/etc/removed")]);
+        assert_eq!(
+            merge.conflicts,
+            [
+                EtcConflict {
+                    path: "/etc/changed".into(),
+                    kind: ConflictKind::Deleted
+                },
+                EtcConflict {
+                    path: "/etc/dir".into(),
+                    kind: ConflictKind::Modified
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use std::fs;
+
+use composefs::{
+    dumpfile::dumpfile_to_filesystem, fsverity::Sha256HashValue, repository::Repository,
+};
+use rustix::fs::CWD;
+use tempfile::TempDir;
+
+/// Creates a temporary sysroot with an insecure repository in `composefs/`.
+pub(crate) fn sysroot() -> (TempDir, Repository<Sha256HashValue>) {
+    let tmp = TempDir::new().unwrap();
+    fs::create_dir(tmp.path().join("composefs")).unwrap();
+    let mut repo = Repository::open_path(CWD, tmp.path().join("composefs")).unwrap();
+    repo.set_insecure(true);
+    (tmp, repo)
+}
+
+/// Commits an image with an `/etc` directory, given the dumpfile lines of its content.
+pub(crate) fn commit(repo: &Repository<Sha256HashValue>, etc: &str) -> Sha256HashValue {
+    let dumpfile = format!(
+        "/ 4096 40755 3 0 0 0 0.0 - - -\n\
+         /etc 4096 40755 2 0 0 0 0.0 - - -\n{etc}"
+    );
+    let fs = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+    fs.commit_image(repo, None).unwrap()
+}
@@ -1,21 +1,29 @@
-//! Unified Kernel Image (UKI) parsing and metadata extraction.
+//! Unified Kernel Image (UKI) parsing, metadata extraction and assembly.
 //!
 //! This module provides functionality to parse PE (Portable Executable) format UKI files
 //! and extract embedded sections like .osrel and .cmdline. It implements the Boot Loader
 //! Specification Type 2 requirements for UKI boot entries, including extraction of boot
-//! labels from os-release information embedded in the UKI binary.
+//! labels from os-release information embedded in the UKI binary.  It can also assemble new UKIs
+//! by adding sections to an EFI stub, in the way that `ukify` does, and verify their Authenticode
+//! signatures.
+
+use core::mem::size_of;
 
 use thiserror::Error;
 use zerocopy::{
     little_endian::{U16, U32},
-    FromBytes, Immutable, KnownLayout,
+    FromBytes, Immutable, IntoBytes, KnownLayout,
 };
 
 use crate::os_release::OsReleaseInfo;
 
+mod authenticode;
+
+pub use authenticode::{verify_authenticode, AuthenticodeError, TrustedCerts};
+
 // https://learn.microsoft.com/en-us/windows/win32/debug/pe-format
-#[derive(Debug, FromBytes, Immutable, KnownLayout)]
-#[cfg_attr(test, derive(zerocopy::IntoBytes, Default))]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[cfg_attr(test, derive(Default))]
 #[repr(C)]
 struct DosStub {
     _unused1: [u8; 0x20],
@@ -23,8 +31,8 @@ struct DosStub {
     pe_offset: U32,
 }
 
-#[derive(Debug, FromBytes, Immutable, KnownLayout)]
-#[cfg_attr(test, derive(zerocopy::IntoBytes, Default))]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[cfg_attr(test, derive(Default))]
 #[repr(C)]
 struct CoffFileHeader {
     machine: U16,
@@ -36,8 +44,8 @@ struct CoffFileHeader {
     characteristics: U16,
 }
 
-#[derive(Debug, FromBytes, Immutable, KnownLayout)]
-#[cfg_attr(test, derive(zerocopy::IntoBytes, Default))]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[cfg_attr(test, derive(Default))]
 #[repr(C)]
 struct PeHeader {
     pe_magic: [u8; 4], // P E \0 \0
@@ -45,8 +53,44 @@ struct PeHeader {
 }
 const PE_MAGIC: [u8; 4] = *b"PE\0\0";
 
-#[derive(Debug, FromBytes, Immutable, KnownLayout)]
-#[cfg_attr(test, derive(zerocopy::IntoBytes, Default))]
+// The part of the optional header which is common to PE32 and PE32+
+#[derive(Debug, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[cfg_attr(test, derive(Default))]
+#[repr(C)]
+struct OptionalHeader {
+    magic: U16,
+    major_linker_version: u8,
+    minor_linker_version: u8,
+    size_of_code: U32,
+    size_of_initialized_data: U32,
+    size_of_uninitialized_data: U32,
+    address_of_entry_point: U32,
+    base_of_code: U32,
+    base_of_data_and_image_base: [u8; 8], // different for PE32 and PE32+
+    section_alignment: U32,
+    file_alignment: U32,
+    versions: [u8; 12],
+    win32_version_value: U32,
+    size_of_image: U32,
+    size_of_headers: U32,
+    check_sum: U32,
+    subsystem: U16,
+    dll_characteristics: U16,
+}
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+// The offset of NumberOfRvaAndSizes in the optional header, followed by the data directories
+const PE32_NUMBER_OF_RVA_AND_SIZES: usize = 92;
+const PE32_PLUS_NUMBER_OF_RVA_AND_SIZES: usize = 108;
+// The index of the data directory for the certificate table (ie: Authenticode signatures)
+const CERTIFICATE_TABLE: usize = 4;
+
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x40;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+#[derive(Debug, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[cfg_attr(test, derive(Default))]
 #[repr(C)]
 struct SectionHeader {
     name: [u8; 8],
@@ -76,6 +120,12 @@ pub enum UkiError {
     /// The .osrel section lacks name information
     #[error("No name information found in .osrel section")]
     NoName,
+    /// The EFI stub already contains a section that was to be added
+    #[error("EFI stub already contains a '{0}' section")]
+    DuplicateSection(&'static str),
+    /// There is no room for more section headers in the EFI stub
+    #[error("Not enough space in the EFI stub's headers for {0} more sections")]
+    NoHeaderSpace(usize),
 }
 
 /// Extracts a text section from a UKI PE file by name and validates it as UTF-8.
@@ -100,6 +150,14 @@ pub fn get_text_section<'a>(
     std::str::from_utf8(bytes).or(Err(UkiError::UnicodeError(section_name)))
 }
 
+/// Turns the section_name ".osrel" into a section_key b".osrel\0\0".
+/// This will panic if section_name.len() > 8, which is what we want.
+fn section_key(section_name: &str) -> [u8; 8] {
+    let mut section_key = [0u8; 8];
+    section_key[..section_name.len()].copy_from_slice(section_name.as_bytes());
+    section_key
+}
+
 /// Extracts a raw section from a UKI PE file by name.
 ///
 /// Parses the PE file format to locate and extract the raw bytes of a named
@@ -127,10 +185,7 @@ pub fn get_section<'a>(
     image: &'a [u8],
     section_name: &'static str,
 ) -> Option<Result<&'a [u8], UkiError>> {
-    // Turn the section_name ".osrel" into a section_key b".osrel\0\0".
-    // This will panic if section_name.len() > 8, which is what we want.
-    let mut section_key = [0u8; 8];
-    section_key[..section_name.len()].copy_from_slice(section_name.as_bytes());
+    let section_key = section_key(section_name);
 
     // Skip the DOS stub
     let (dos_stub, ..) = DosStub::ref_from_prefix(image).ok()?;
@@ -194,15 +249,263 @@ pub fn get_cmdline(image: &[u8]) -> Result<&str, UkiError> {
     get_text_section(image, ".cmdline")
 }
 
+/// Gets the contents of the .cmdline section of a UKI addon, if it has one.
+///
+/// Unlike UKIs, addons don't need to have a .cmdline section: they might only carry devicetrees,
+/// for example.
+pub fn get_addon_cmdline(image: &[u8]) -> Result<Option<&str>, UkiError> {
+    match get_text_section(image, ".cmdline") {
+        Ok(cmdline) => Ok(Some(cmdline)),
+        Err(UkiError::MissingSection(..)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// The locations of the headers in a PE image.
+#[derive(Debug)]
+struct PeLayout {
+    pe_offset: usize,
+    optional_offset: usize,
+    n_sections: usize,
+    section_table: usize,
+    checksum_offset: usize,
+    /// The offset of the data directory entry for the certificate table, if there is one
+    certificate_table: Option<usize>,
+}
+
+fn pe_layout(image: &[u8]) -> Option<PeLayout> {
+    let (dos_stub, ..) = DosStub::ref_from_prefix(image).ok()?;
+    let pe_offset = dos_stub.pe_offset.get() as usize;
+    let (pe_header, ..) = PeHeader::ref_from_prefix(image.get(pe_offset..)?).ok()?;
+    if pe_header.pe_magic != PE_MAGIC {
+        return None;
+    }
+
+    let optional_offset = pe_offset + size_of::<PeHeader>();
+    let optional_size = pe_header.coff_file_header.size_of_optional_header.get() as usize;
+    let optional = image.get(optional_offset..optional_offset + optional_size)?;
+    let (header, ..) = OptionalHeader::ref_from_prefix(optional).ok()?;
+    let number_of_rva_and_sizes = match header.magic.get() {
+        PE32_MAGIC => PE32_NUMBER_OF_RVA_AND_SIZES,
+        PE32_PLUS_MAGIC => PE32_PLUS_NUMBER_OF_RVA_AND_SIZES,
+        _ => return None,
+    };
+
+    let cert_dir = number_of_rva_and_sizes + 4 + CERTIFICATE_TABLE * 8;
+    let certificate_table = optional
+        .get(number_of_rva_and_sizes..number_of_rva_and_sizes + 4)
+        .map(|n| u32::from_le_bytes(n.try_into().unwrap()) as usize)
+        .filter(|&n_dirs| n_dirs > CERTIFICATE_TABLE && cert_dir + 8 <= optional_size)
+        .map(|_| optional_offset + cert_dir);
+
+    Some(PeLayout {
+        pe_offset,
+        optional_offset,
+        n_sections: pe_header.coff_file_header.number_of_sections.get() as usize,
+        section_table: optional_offset + optional_size,
+        checksum_offset: optional_offset + core::mem::offset_of!(OptionalHeader, check_sum),
+        certificate_table,
+    })
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Computes the PE image checksum, as stored in the optional header.
+fn pe_checksum(image: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum = 0u32;
+    for (offset, word) in image.chunks(2).enumerate().map(|(i, w)| (i * 2, w)) {
+        if offset == checksum_offset || offset == checksum_offset + 2 {
+            continue;
+        }
+        sum += u16::from_le_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum.wrapping_add(image.len() as u32)
+}
+
+/// Creates a new PE image by appending data sections to an existing one (like an EFI stub).
+///
+/// The new sections are placed after the existing ones, both in the file and in memory, and
+/// aligned according to the file and section alignment in the optional header.  The size of the
+/// image and the checksum are updated to match.  Any existing Authenticode signature is removed
+/// (it would be invalid anyway) so that the result can be signed with tools like `sbsign`.
+///
+/// Section names must be at most 8 bytes long, or this function will panic.
+///
+/// # Arguments
+///
+/// * `stub` - The complete PE image to add the sections to
+/// * `sections` - Pairs of section names (like ".linux") and their content, in order
+///
+/// # Returns
+///
+/// * `Ok(Vec<u8>)` - The new PE image
+/// * `Err(UkiError)` - If the stub is not a valid PE image, doesn't have enough space for the
+///   new section headers, or already contains one of the sections
+pub fn add_sections(stub: &[u8], sections: &[(&'static str, &[u8])]) -> Result<Vec<u8>, UkiError> {
+    let PeLayout {
+        pe_offset,
+        optional_offset,
+        n_sections,
+        section_table,
+        checksum_offset,
+        certificate_table,
+    } = pe_layout(stub).ok_or(UkiError::PortableExecutableError)?;
+    // SAFETY: pe_layout() checked this
+    let (header, ..) = OptionalHeader::ref_from_prefix(&stub[optional_offset..]).unwrap();
+    let file_alignment = header.file_alignment.get() as usize;
+    let section_alignment = header.section_alignment.get() as usize;
+    if file_alignment == 0 || section_alignment == 0 {
+        return Err(UkiError::PortableExecutableError);
+    }
+
+    let (existing, ..) = stub
+        .get(section_table..)
+        .and_then(|rest| <[SectionHeader]>::ref_from_prefix_with_elems(rest, n_sections).ok())
+        .ok_or(UkiError::PortableExecutableError)?;
+    for (name, ..) in sections {
+        if existing.iter().any(|s| s.name == section_key(name)) {
+            return Err(UkiError::DuplicateSection(name));
+        }
+    }
+
+    // The new section headers have to fit before the data of the first section
+    let size_of_headers = header.size_of_headers.get() as usize;
+    let first_data = existing
+        .iter()
+        .filter(|s| s.size_of_raw_data.get() != 0)
+        .map(|s| s.pointer_to_raw_data.get() as usize)
+        .fold(size_of_headers, usize::min);
+    let headers_end = section_table + (n_sections + sections.len()) * size_of::<SectionHeader>();
+    if headers_end > first_data {
+        return Err(UkiError::NoHeaderSpace(sections.len()));
+    }
+
+    // Drop anything after the last section, like a certificate table
+    let data_end = existing
+        .iter()
+        .map(|s| s.pointer_to_raw_data.get() as usize + s.size_of_raw_data.get() as usize)
+        .fold(size_of_headers, usize::max);
+    let mut output = stub
+        .get(..data_end)
+        .ok_or(UkiError::PortableExecutableError)?
+        .to_vec();
+
+    let mut next_address = existing
+        .iter()
+        .map(|s| {
+            let size = s.virtual_size.get().max(s.size_of_raw_data.get());
+            s.virtual_address.get() as usize + size as usize
+        })
+        .fold(size_of_headers, usize::max);
+    let mut new_headers = vec![];
+    let mut initialized_data = 0;
+    for (name, data) in sections {
+        next_address = align_up(next_address, section_alignment);
+        output.resize(align_up(output.len(), file_alignment), 0);
+        let size_of_raw_data = align_up(data.len(), file_alignment);
+        let too_big = |_| UkiError::PortableExecutableError;
+
+        new_headers.push(SectionHeader {
+            name: section_key(name),
+            virtual_size: U32::new(data.len().try_into().map_err(too_big)?),
+            virtual_address: U32::new(next_address.try_into().map_err(too_big)?),
+            size_of_raw_data: U32::new(size_of_raw_data.try_into().map_err(too_big)?),
+            pointer_to_raw_data: U32::new(output.len().try_into().map_err(too_big)?),
+            pointer_to_relocations: U32::new(0),
+            pointer_to_line_numbers: U32::new(0),
+            number_of_relocations: U16::new(0),
+            number_of_line_numbers: U16::new(0),
+            characteristics: U32::new(IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ),
+        });
+
+        output.extend_from_slice(data);
+        output.resize(output.len() + size_of_raw_data - data.len(), 0);
+        initialized_data += size_of_raw_data;
+        next_address += data.len();
+    }
+    let size_of_image = align_up(next_address, section_alignment);
+
+    // Write the new section headers and update the existing headers
+    let table_end = section_table + n_sections * size_of::<SectionHeader>();
+    output[table_end..headers_end].copy_from_slice(new_headers.as_bytes());
+
+    let (pe_header, ..) = PeHeader::mut_from_prefix(&mut output[pe_offset..]).unwrap();
+    pe_header.coff_file_header.number_of_sections = U16::new((n_sections + sections.len()) as u16);
+
+    let initialized_data = header.size_of_initialized_data.get() as usize + initialized_data;
+    let (header, ..) = OptionalHeader::mut_from_prefix(&mut output[optional_offset..]).unwrap();
+    let too_big = |_| UkiError::PortableExecutableError;
+    header.size_of_image = U32::new(size_of_image.try_into().map_err(too_big)?);
+    header.size_of_initialized_data = U32::new(initialized_data.try_into().map_err(too_big)?);
+
+    if let Some(cert_dir) = certificate_table {
+        output[cert_dir..cert_dir + 8].fill(0);
+    }
+
+    let checksum = pe_checksum(&output, checksum_offset);
+    output[checksum_offset..checksum_offset + 4].copy_from_slice(&checksum.to_le_bytes());
+
+    Ok(output)
+}
+
+/// The components of a Unified Kernel Image.
+#[derive(Debug)]
+pub struct UkiComponents<'a> {
+    /// The Linux kernel (which must be an EFI-stub kernel)
+    pub linux: &'a [u8],
+    /// The initramfs, if any
+    pub initrd: Option<&'a [u8]>,
+    /// The devicetree blob, if any
+    pub devicetree: Option<&'a [u8]>,
+    /// The os-release file describing the operating system
+    pub os_release: &'a [u8],
+    /// The kernel command line, including the composefs= argument
+    pub cmdline: &'a str,
+    /// The kernel version, as reported by `uname -r`, if known
+    pub uname: Option<&'a str>,
+}
+
+/// Assembles a Unified Kernel Image from a systemd EFI stub and the given components.
+///
+/// The sections are laid out in the same order as `ukify` does it.  The result isn't signed, but
+/// can be signed with external tools.
+///
+/// # Arguments
+///
+/// * `stub` - The EFI stub, like `/usr/lib/systemd/boot/efi/linuxx64.efi.stub`
+/// * `components` - The content for the sections of the UKI
+pub fn build_uki(stub: &[u8], components: &UkiComponents) -> Result<Vec<u8>, UkiError> {
+    let mut sections = vec![
+        (".osrel", components.os_release),
+        (".cmdline", components.cmdline.as_bytes()),
+    ];
+    if let Some(devicetree) = components.devicetree {
+        sections.push((".dtb", devicetree));
+    }
+    if let Some(uname) = components.uname {
+        sections.push((".uname", uname.as_bytes()));
+    }
+    if let Some(initrd) = components.initrd {
+        sections.push((".initrd", initrd));
+    }
+    sections.push((".linux", components.linux));
+
+    add_sections(stub, &sections)
+}
+
 #[cfg(test)]
-mod test {
-    use core::mem::size_of;
+pub(crate) mod test {
 
     use similar_asserts::assert_eq;
     use zerocopy::IntoBytes;
 
     use super::*;
 
+    pub(crate) use super::authenticode::test as authenticode;
+
     fn data_offset(n_sections: usize) -> usize {
         size_of::<DosStub>() + size_of::<PeHeader>() + n_sections * size_of::<SectionHeader>()
     }
@@ -231,7 +534,7 @@ mod test {
         for section in sections {
             output.extend_from_slice(section.as_bytes());
         }
-        assert_eq!(output.len(), data_offset(sections.len()));
+        assert_eq!(output.len(), data_offset(sections.len()) + optional.len());
         for data in rest {
             output.extend_from_slice(data);
         }
@@ -319,4 +622,119 @@ ID=pretty-os
             &[],
         ));
     }
+
+    /// Creates a PE32+ image that looks like an EFI stub with a single .text section, followed
+    /// by a fake signature.
+    pub(crate) fn stub(size_of_headers: u32) -> Vec<u8> {
+        let mut optional = OptionalHeader {
+            magic: U16::new(PE32_PLUS_MAGIC),
+            size_of_initialized_data: U32::new(0x200),
+            section_alignment: U32::new(0x1000),
+            file_alignment: U32::new(0x200),
+            size_of_image: U32::new(0x2000),
+            size_of_headers: U32::new(size_of_headers),
+            ..Default::default()
+        }
+        .as_bytes()
+        .to_vec();
+        optional.resize(PE32_PLUS_NUMBER_OF_RVA_AND_SIZES, 0);
+        optional.extend_from_slice(&16u32.to_le_bytes());
+        for n in 0..16u8 {
+            optional.extend_from_slice(&[n; 8]);
+        }
+
+        let mut image = peify(
+            &optional,
+            &[SectionHeader {
+                name: *b".text\0\0\0",
+                virtual_size: U32::new(0x180),
+                virtual_address: U32::new(0x1000),
+                size_of_raw_data: U32::new(0x200),
+                pointer_to_raw_data: U32::new(size_of_headers),
+                ..Default::default()
+            }],
+            &[],
+        );
+        image.resize(size_of_headers as usize, 0);
+        image.extend_from_slice(&[0xcc; 0x200]);
+        image.extend_from_slice(b"signature");
+        image
+    }
+
+    #[test]
+    fn test_build_uki() {
+        let initrd = vec![0x55; 5000];
+        let uki = build_uki(
+            &stub(0x400),
+            &UkiComponents {
+                linux: b"kernel",
+                initrd: Some(&initrd),
+                devicetree: None,
+                os_release: b"ID=test\nVERSION_ID=1\n",
+                cmdline: "composefs=abc rw",
+                uname: Some("6.12.0"),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(get_boot_label(&uki).unwrap(), "test 1");
+        assert_eq!(get_cmdline(&uki).unwrap(), "composefs=abc rw");
+        assert_eq!(get_text_section(&uki, ".uname").unwrap(), "6.12.0");
+        assert_eq!(get_section(&uki, ".initrd").unwrap().unwrap(), initrd);
+        assert_eq!(get_section(&uki, ".linux").unwrap().unwrap(), b"kernel");
+        assert_eq!(get_section(&uki, ".text").unwrap().unwrap(), [0xcc; 0x180]);
+
+        let optional_offset = size_of::<DosStub>() + size_of::<PeHeader>();
+        let (header, ..) = OptionalHeader::ref_from_prefix(&uki[optional_offset..]).unwrap();
+        let section_table = data_offset(0) + 240;
+        let (sections, ..) =
+            <[SectionHeader]>::ref_from_prefix_with_elems(&uki[section_table..], 6).unwrap();
+
+        // Sections are contiguous and aligned, both in memory and in the file
+        let mut address = 0x1000;
+        let mut offset = 0x400;
+        for section in sections {
+            assert_eq!(section.virtual_address.get(), address);
+            assert_eq!(section.pointer_to_raw_data.get(), offset);
+            assert_eq!(section.size_of_raw_data.get() % 0x200, 0);
+            address += section.virtual_size.get().div_ceil(0x1000) * 0x1000;
+            offset += section.size_of_raw_data.get();
+        }
+        assert_eq!(header.size_of_image.get(), address);
+        assert_eq!(uki.len(), offset as usize);
+        assert_eq!(header.size_of_initialized_data.get(), 0x200 + 0x1c00);
+
+        // The certificate table is gone, and the other data directories are untouched
+        let dirs = optional_offset + PE32_PLUS_NUMBER_OF_RVA_AND_SIZES + 4;
+        assert_eq!(uki[dirs + 3 * 8..dirs + 4 * 8], [3; 8]);
+        assert_eq!(uki[dirs + 4 * 8..dirs + 5 * 8], [0; 8]);
+
+        let checksum_offset = optional_offset + core::mem::offset_of!(OptionalHeader, check_sum);
+        assert_eq!(header.check_sum.get(), pe_checksum(&uki, checksum_offset));
+    }
+
+    #[test]
+    fn test_add_sections_errors() {
+        let sections: [(&str, &[u8]); 4] = [("a", b""), ("b", b""), ("c", b""), ("d", b"")];
+        assert_eq!(
+            add_sections(&stub(0x200), &sections),
+            Err(UkiError::NoHeaderSpace(4))
+        );
+        add_sections(&stub(0x200), &sections[..3]).unwrap();
+        assert_eq!(
+            add_sections(&stub(0x400), &[(".text", b"")]),
+            Err(UkiError::DuplicateSection(".text"))
+        );
+        assert_eq!(
+            add_sections(b"not a PE", &[(".linux", b"")]),
+            Err(UkiError::PortableExecutableError)
+        );
+    }
+
+    #[test]
+    fn test_pe_checksum() {
+        // The checksum field itself is skipped, and carries wrap around
+        assert_eq!(pe_checksum(&[0xff, 0xff, 0x02, 0x00], 4), 0x0002 + 4);
+        assert_eq!(pe_checksum(&[1, 0, 9, 9, 9, 9, 1], 2), 2 + 7);
+    }
 }
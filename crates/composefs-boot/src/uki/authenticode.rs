@@ -0,0 +1,697 @@
+//! Authenticode signature verification for UKIs and UKI addons.
+//!
+//! With Secure Boot enabled, the firmware (or shim) refuses to start PE images which aren't signed
+//! by a key in its `db`.  Checking the signature before installing an image lets us refuse to
+//! write an entry which would fail to boot, instead of finding out at the next reboot.
+//!
+//! The signature is a PKCS#7 `SignedData` structure stored in the certificate table of the PE
+//! file.  It contains an `SpcIndirectDataContent` with a digest of the image (excluding the
+//! checksum, the certificate table and its data directory entry) which is signed by one of the
+//! embedded certificates.  We check the digest, the signature, and that the signing certificate
+//! chains up to one of the trusted certificates.
+
+use core::fmt;
+
+use openssl::{
+    error::ErrorStack,
+    hash::{Hasher, MessageDigest},
+    sign::Verifier,
+    stack::Stack,
+    x509::{
+        store::{X509Store, X509StoreBuilder},
+        verify::X509VerifyFlags,
+        X509StoreContext, X509,
+    },
+};
+use thiserror::Error;
+
+use super::pe_layout;
+
+// Object identifiers, as the DER-encoded content of the OBJECT IDENTIFIER
+const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+const OID_CONTENT_TYPE: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x03];
+const OID_MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+const OID_SPC_INDIRECT_DATA: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+// DER tags
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_CONTEXT_0: u8 = 0xa0;
+const TAG_CONTEXT_1: u8 = 0xa1;
+
+// WIN_CERTIFICATE
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+/// Errors that can occur while verifying the Authenticode signature of a PE image.
+#[derive(Error, Debug)]
+pub enum AuthenticodeError {
+    /// The image has no signatures
+    #[error("The image is not signed")]
+    NotSigned,
+    /// The image or its signature couldn't be parsed
+    #[error("Malformed signature: {0}")]
+    Malformed(&'static str),
+    /// The signature uses a digest algorithm we don't support
+    #[error("Unsupported digest algorithm")]
+    UnsupportedDigest,
+    /// The signed digest doesn't match the image
+    #[error("The image digest doesn't match the signature")]
+    DigestMismatch,
+    /// The signature couldn't be verified with the signing certificate
+    #[error("Invalid signature")]
+    BadSignature,
+    /// The signing certificate isn't trusted
+    #[error("The signing certificate is not trusted: {0}")]
+    Untrusted(String),
+    /// An error from OpenSSL
+    #[error("OpenSSL error: {0}")]
+    Openssl(#[from] ErrorStack),
+}
+
+/// A set of certificates which are trusted to sign boot images, like the Secure Boot `db`.
+///
+/// A signature is accepted if its signing certificate is one of these or chains up to one of
+/// them.  Since firmware doesn't check expiry times, neither do we.
+pub struct TrustedCerts {
+    store: X509Store,
+}
+
+impl fmt::Debug for TrustedCerts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrustedCerts")
+            .field("count", &self.store.all_certificates().len())
+            .finish()
+    }
+}
+
+impl TrustedCerts {
+    fn new(certs: Vec<X509>) -> Result<Self, AuthenticodeError> {
+        if certs.is_empty() {
+            return Err(AuthenticodeError::Malformed("no trusted certificates"));
+        }
+        let mut builder = X509StoreBuilder::new()?;
+        for cert in certs {
+            builder.add_cert(cert)?;
+        }
+        // The trusted certificates are often not self-signed root CAs
+        builder.set_flags(X509VerifyFlags::PARTIAL_CHAIN | X509VerifyFlags::NO_CHECK_TIME)?;
+        Ok(Self {
+            store: builder.build(),
+        })
+    }
+
+    /// Loads the trusted certificates from a PEM file containing one or more certificates.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, AuthenticodeError> {
+        Self::new(X509::stack_from_pem(pem)?)
+    }
+
+    /// Loads a single trusted certificate in DER format (as found in EFI signature lists).
+    pub fn from_der(der: &[u8]) -> Result<Self, AuthenticodeError> {
+        Self::new(vec![X509::from_der(der)?])
+    }
+}
+
+/// A DER type-length-value.
+#[derive(Debug, Clone, Copy)]
+struct Tlv<'a> {
+    tag: u8,
+    content: &'a [u8],
+    /// The complete encoding, including the tag and length
+    raw: &'a [u8],
+}
+
+/// A cursor over a sequence of DER values.
+#[derive(Debug)]
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    fn next(&mut self) -> Result<Tlv<'a>, AuthenticodeError> {
+        let truncated = || AuthenticodeError::Malformed("truncated DER value");
+        let data = self.0;
+        let (&tag, rest) = data.split_first().ok_or_else(truncated)?;
+        let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+        let (len, rest) = match first {
+            0..=0x7f => (first as usize, rest),
+            0x81..=0x84 => {
+                let n = (first & 0x7f) as usize;
+                let bytes = rest.get(..n).ok_or_else(truncated)?;
+                let len = bytes.iter().fold(0, |len, &b| (len << 8) | b as usize);
+                (len, &rest[n..])
+            }
+            _ => return Err(AuthenticodeError::Malformed("unsupported DER length")),
+        };
+        let content = rest.get(..len).ok_or_else(truncated)?;
+        let header = data.len() - rest.len();
+        self.0 = &rest[len..];
+        Ok(Tlv {
+            tag,
+            content,
+            raw: &data[..header + len],
+        })
+    }
+
+    fn expect(&mut self, tag: u8, what: &'static str) -> Result<Tlv<'a>, AuthenticodeError> {
+        match self.next() {
+            Ok(tlv) if tlv.tag == tag => Ok(tlv),
+            _ => Err(AuthenticodeError::Malformed(what)),
+        }
+    }
+
+    /// Reads a value with the given tag if it is next.
+    fn optional(&mut self, tag: u8) -> Result<Option<Tlv<'a>>, AuthenticodeError> {
+        if self.peek_tag() == Some(tag) {
+            self.next().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn contents(tlv: Tlv<'_>) -> Reader<'_> {
+    Reader(tlv.content)
+}
+
+/// Returns the digest algorithm from an `AlgorithmIdentifier`.
+fn digest_algorithm(alg: Tlv<'_>) -> Result<MessageDigest, AuthenticodeError> {
+    let oid = contents(alg).expect(TAG_OID, "bad AlgorithmIdentifier")?;
+    match oid.content {
+        OID_SHA1 => Ok(MessageDigest::sha1()),
+        OID_SHA256 => Ok(MessageDigest::sha256()),
+        OID_SHA384 => Ok(MessageDigest::sha384()),
+        OID_SHA512 => Ok(MessageDigest::sha512()),
+        _ => Err(AuthenticodeError::UnsupportedDigest),
+    }
+}
+
+/// Computes the Authenticode digest of a PE image.
+///
+/// This covers the whole file except for the checksum, the data directory entry for the
+/// certificate table and the certificate table itself.  The specification excludes any gaps
+/// between the sections as well, but there are none in the images produced by the usual
+/// toolchains (or by [`super::build_uki`]), and the signing tools hash them the same way.
+fn image_digest(
+    image: &[u8],
+    checksum_offset: usize,
+    cert_dir: usize,
+    cert_table: (usize, usize),
+    md: MessageDigest,
+) -> Result<Vec<u8>, AuthenticodeError> {
+    let mut hasher = Hasher::new(md)?;
+    hasher.update(&image[..checksum_offset])?;
+    hasher.update(&image[checksum_offset + 4..cert_dir])?;
+    hasher.update(&image[cert_dir + 8..cert_table.0])?;
+    hasher.update(&image[cert_table.1..])?;
+    Ok(hasher.finish()?.to_vec())
+}
+
+/// Finds the issuer and serial number of a certificate, as raw DER values.
+fn issuer_and_serial(cert: Tlv<'_>) -> Result<(&[u8], &[u8]), AuthenticodeError> {
+    let mut tbs = contents(contents(cert).expect(TAG_SEQUENCE, "bad certificate")?);
+    tbs.optional(TAG_CONTEXT_0)?; // version
+    let serial = tbs.expect(TAG_INTEGER, "bad certificate serial")?;
+    tbs.expect(TAG_SEQUENCE, "bad certificate signature algorithm")?;
+    let issuer = tbs.expect(TAG_SEQUENCE, "bad certificate issuer")?;
+    Ok((issuer.raw, serial.raw))
+}
+
+/// Verifies a single PKCS#7 signature from the certificate table.
+fn verify_signature(
+    signature: &[u8],
+    digest_image: impl Fn(MessageDigest) -> Result<Vec<u8>, AuthenticodeError>,
+    trusted: &TrustedCerts,
+) -> Result<(), AuthenticodeError> {
+    // ContentInfo
+    let content_info = Reader(signature).expect(TAG_SEQUENCE, "bad ContentInfo")?;
+    let mut content_info = contents(content_info);
+    if content_info.expect(TAG_OID, "bad ContentInfo")?.content != OID_SIGNED_DATA {
+        return Err(AuthenticodeError::Malformed("not a SignedData"));
+    }
+    let signed_data = content_info.expect(TAG_CONTEXT_0, "bad ContentInfo")?;
+
+    // SignedData
+    let signed_data = contents(signed_data).expect(TAG_SEQUENCE, "bad SignedData")?;
+    let mut signed_data = contents(signed_data);
+    signed_data.expect(TAG_INTEGER, "bad SignedData version")?;
+    signed_data.expect(TAG_SET, "bad SignedData digest algorithms")?;
+    let encap = signed_data.expect(TAG_SEQUENCE, "bad SignedData content")?;
+    let certificates = signed_data.optional(TAG_CONTEXT_0)?;
+    signed_data.optional(TAG_CONTEXT_1)?; // crls
+    let signer_infos = signed_data.expect(TAG_SET, "bad SignedData signer infos")?;
+
+    // SpcIndirectDataContent
+    let mut encap = contents(encap);
+    if encap.expect(TAG_OID, "bad content type")?.content != OID_SPC_INDIRECT_DATA {
+        return Err(AuthenticodeError::Malformed(
+            "not an SpcIndirectDataContent",
+        ));
+    }
+    let indirect = contents(encap.expect(TAG_CONTEXT_0, "missing content")?)
+        .expect(TAG_SEQUENCE, "bad SpcIndirectDataContent")?;
+    let mut reader = contents(indirect);
+    reader.expect(TAG_SEQUENCE, "bad SpcIndirectDataContent")?;
+    let mut digest_info = contents(reader.expect(TAG_SEQUENCE, "bad DigestInfo")?);
+    let md = digest_algorithm(digest_info.expect(TAG_SEQUENCE, "bad DigestInfo")?)?;
+    let digest = digest_info.expect(TAG_OCTET_STRING, "bad DigestInfo")?;
+    if digest.content != digest_image(md)? {
+        return Err(AuthenticodeError::DigestMismatch);
+    }
+
+    // Authenticode requires exactly one SignerInfo
+    let mut signer_infos = contents(signer_infos);
+    let mut signer_info = contents(signer_infos.expect(TAG_SEQUENCE, "bad SignerInfo")?);
+    if !signer_infos.is_empty() {
+        return Err(AuthenticodeError::Malformed("more than one SignerInfo"));
+    }
+    signer_info.expect(TAG_INTEGER, "bad SignerInfo version")?;
+    let mut issuer_and_serial_number =
+        contents(signer_info.expect(TAG_SEQUENCE, "bad SignerInfo issuer")?);
+    let issuer = issuer_and_serial_number.expect(TAG_SEQUENCE, "bad SignerInfo issuer")?;
+    let serial = issuer_and_serial_number.expect(TAG_INTEGER, "bad SignerInfo serial")?;
+    let md = digest_algorithm(signer_info.expect(TAG_SEQUENCE, "bad SignerInfo digest")?)?;
+    let Some(attributes) = signer_info.optional(TAG_CONTEXT_0)? else {
+        return Err(AuthenticodeError::Malformed(
+            "missing authenticated attributes",
+        ));
+    };
+    signer_info.expect(TAG_SEQUENCE, "bad SignerInfo signature algorithm")?;
+    let encrypted_digest = signer_info.expect(TAG_OCTET_STRING, "bad SignerInfo signature")?;
+
+    // The authenticated attributes contain the digest of the SpcIndirectDataContent (without its
+    // tag and length)
+    let mut content_type = None;
+    let mut message_digest = None;
+    let mut reader = contents(attributes);
+    while !reader.is_empty() {
+        let mut attribute = contents(reader.expect(TAG_SEQUENCE, "bad attribute")?);
+        let oid = attribute.expect(TAG_OID, "bad attribute")?;
+        let mut values = contents(attribute.expect(TAG_SET, "bad attribute")?);
+        match oid.content {
+            OID_CONTENT_TYPE => content_type = Some(values.expect(TAG_OID, "bad content type")?),
+            OID_MESSAGE_DIGEST => {
+                message_digest = Some(values.expect(TAG_OCTET_STRING, "bad message digest")?)
+            }
+            _ => {}
+        }
+    }
+    if content_type.map(|oid| oid.content) != Some(OID_SPC_INDIRECT_DATA) {
+        return Err(AuthenticodeError::Malformed("bad content type attribute"));
+    }
+    let Some(message_digest) = message_digest else {
+        return Err(AuthenticodeError::Malformed("missing message digest"));
+    };
+    let mut hasher = Hasher::new(md)?;
+    hasher.update(indirect.content)?;
+    if message_digest.content != &*hasher.finish()? {
+        return Err(AuthenticodeError::DigestMismatch);
+    }
+
+    // Find the signing certificate; the others are intermediates for the chain
+    let mut signer = None;
+    let mut chain = Stack::new()?;
+    let mut reader = contents(certificates.ok_or(AuthenticodeError::Malformed("no certificates"))?);
+    while !reader.is_empty() {
+        let cert = reader.expect(TAG_SEQUENCE, "bad certificate")?;
+        let x509 = X509::from_der(cert.raw)?;
+        if issuer_and_serial(cert)? == (issuer.raw, serial.raw) {
+            signer = Some(x509.clone());
+        }
+        chain.push(x509)?;
+    }
+    let Some(signer) = signer else {
+        return Err(AuthenticodeError::Malformed("missing signing certificate"));
+    };
+
+    // The signature is over the DER encoding of the attributes as a SET OF, rather than with the
+    // [0] IMPLICIT tag they're stored with
+    let mut signed = attributes.raw.to_vec();
+    signed[0] = TAG_SET;
+    let public_key = signer.public_key()?;
+    let mut verifier = Verifier::new(md, &public_key)?;
+    verifier.update(&signed)?;
+    if !verifier.verify(encrypted_digest.content).unwrap_or(false) {
+        return Err(AuthenticodeError::BadSignature);
+    }
+
+    let mut context = X509StoreContext::new()?;
+    let (valid, error) = context.init(&trusted.store, &signer, &chain, |context| {
+        Ok((context.verify_cert()?, context.error()))
+    })?;
+    if !valid {
+        return Err(AuthenticodeError::Untrusted(
+            error.error_string().to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Verifies the Authenticode signature of a PE image (a UKI or UKI addon).
+///
+/// The image is accepted if any of the signatures in its certificate table is valid and was made
+/// by one of the `trusted` certificates (or a certificate chaining up to one of them).  If none
+/// are, the error for the first signature is returned.
+pub fn verify_authenticode(image: &[u8], trusted: &TrustedCerts) -> Result<(), AuthenticodeError> {
+    let layout = pe_layout(image).ok_or(AuthenticodeError::Malformed("not a PE image"))?;
+    let Some(cert_dir) = layout.certificate_table else {
+        return Err(AuthenticodeError::NotSigned);
+    };
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+    let table_start = read_u32(cert_dir) as usize;
+    let table_size = read_u32(cert_dir + 4) as usize;
+    if table_size == 0 {
+        return Err(AuthenticodeError::NotSigned);
+    }
+    let table_end = table_start
+        .checked_add(table_size)
+        .filter(|&end| table_start >= cert_dir + 8 && end <= image.len())
+        .ok_or(AuthenticodeError::Malformed("bad certificate table"))?;
+    let digest_image = |md| {
+        image_digest(
+            image,
+            layout.checksum_offset,
+            cert_dir,
+            (table_start, table_end),
+            md,
+        )
+    };
+
+    let mut first_error = None;
+    let mut offset = table_start;
+    while offset + 8 <= table_end {
+        let length = read_u32(offset) as usize;
+        let revision = u16::from_le_bytes(image[offset + 4..offset + 6].try_into().unwrap());
+        let cert_type = u16::from_le_bytes(image[offset + 6..offset + 8].try_into().unwrap());
+        let Some(end) = offset
+            .checked_add(length)
+            .filter(|&end| end <= table_end && length >= 8)
+        else {
+            return Err(AuthenticodeError::Malformed("bad certificate table entry"));
+        };
+        if revision == WIN_CERT_REVISION_2_0 && cert_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            match verify_signature(&image[offset + 8..end], digest_image, trusted) {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        offset = super::align_up(end, 8);
+    }
+
+    Err(first_error.unwrap_or(AuthenticodeError::NotSigned))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::{BigNum, MsbOption},
+        hash::hash,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        sign::Signer,
+        x509::{extension::BasicConstraints, X509Builder, X509NameBuilder},
+    };
+
+    use super::*;
+    use crate::uki::{build_uki, test::stub, UkiComponents};
+
+    const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+    const OID_SPC_PE_IMAGE_DATA: &[u8] =
+        &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x0f];
+    const NULL: &[u8] = &[0x05, 0x00];
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut output = vec![tag];
+        if content.len() < 0x80 {
+            output.push(content.len() as u8);
+        } else {
+            let len = content.len().to_be_bytes();
+            let len = &len[content.len().leading_zeros() as usize / 8..];
+            output.push(0x80 | len.len() as u8);
+            output.extend_from_slice(len);
+        }
+        output.extend_from_slice(content);
+        output
+    }
+
+    fn seq(parts: &[&[u8]]) -> Vec<u8> {
+        der(TAG_SEQUENCE, &parts.concat())
+    }
+
+    fn sha256_algorithm() -> Vec<u8> {
+        seq(&[&der(TAG_OID, OID_SHA256), NULL])
+    }
+
+    /// Creates a certificate for `key`, signed by `issuer` (or self-signed).
+    pub(crate) fn certificate(
+        name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        ca: bool,
+    ) -> X509 {
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        match issuer {
+            Some((cert, _)) => builder.set_issuer_name(cert.subject_name()).unwrap(),
+            None => builder.set_issuer_name(&subject).unwrap(),
+        }
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if ca {
+            let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+            builder.append_extension(constraints).unwrap();
+        }
+        let signing_key = issuer.map_or(key, |(_, key)| key);
+        builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    pub(crate) fn key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    /// Pads the image to the alignment of the certificate table.
+    fn prepare(image: &[u8]) -> Vec<u8> {
+        let mut image = image.to_vec();
+        image.resize(image.len().next_multiple_of(8), 0);
+        image
+    }
+
+    /// Creates an Authenticode signature for an image, as done by `sbsign`.
+    fn signature(image: &[u8], key: &PKey<Private>, certs: &[&X509]) -> Vec<u8> {
+        let image = prepare(image);
+        let layout = pe_layout(&image).unwrap();
+        let cert_dir = layout.certificate_table.unwrap();
+        let end = image.len();
+        let digest = image_digest(
+            &image,
+            layout.checksum_offset,
+            cert_dir,
+            (end, end),
+            MessageDigest::sha256(),
+        )
+        .unwrap();
+
+        let indirect = [
+            seq(&[&der(TAG_OID, OID_SPC_PE_IMAGE_DATA), &seq(&[])]),
+            seq(&[&sha256_algorithm(), &der(TAG_OCTET_STRING, &digest)]),
+        ]
+        .concat();
+        let indirect_digest = hash(MessageDigest::sha256(), &indirect).unwrap();
+        let attributes = [
+            seq(&[
+                &der(TAG_OID, OID_CONTENT_TYPE),
+                &der(TAG_SET, &der(TAG_OID, OID_SPC_INDIRECT_DATA)),
+            ]),
+            seq(&[
+                &der(TAG_OID, OID_MESSAGE_DIGEST),
+                &der(TAG_SET, &der(TAG_OCTET_STRING, &indirect_digest)),
+            ]),
+        ]
+        .concat();
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        let encrypted_digest = signer
+            .sign_oneshot_to_vec(&der(TAG_SET, &attributes))
+            .unwrap();
+
+        let signer_cert = certs[0].to_der().unwrap();
+        let (issuer, serial) = issuer_and_serial(Reader(&signer_cert).next().unwrap()).unwrap();
+        let signer_info = seq(&[
+            &der(TAG_INTEGER, &[1]),
+            &seq(&[issuer, serial]),
+            &sha256_algorithm(),
+            &der(TAG_CONTEXT_0, &attributes),
+            &seq(&[&der(TAG_OID, OID_RSA_ENCRYPTION), NULL]),
+            &der(TAG_OCTET_STRING, &encrypted_digest),
+        ]);
+
+        let certs: Vec<u8> = certs.iter().flat_map(|c| c.to_der().unwrap()).collect();
+        let signed_data = seq(&[
+            &der(TAG_INTEGER, &[1]),
+            &der(TAG_SET, &sha256_algorithm()),
+            &seq(&[
+                &der(TAG_OID, OID_SPC_INDIRECT_DATA),
+                &der(TAG_CONTEXT_0, &der(TAG_SEQUENCE, &indirect)),
+            ]),
+            &der(TAG_CONTEXT_0, &certs),
+            &der(TAG_SET, &signer_info),
+        ]);
+        seq(&[
+            &der(TAG_OID, OID_SIGNED_DATA),
+            &der(TAG_CONTEXT_0, &signed_data),
+        ])
+    }
+
+    /// Appends a certificate table with the given signatures to an image.
+    fn attach(image: &[u8], signatures: &[Vec<u8>]) -> Vec<u8> {
+        let mut image = prepare(image);
+        let start = image.len();
+        for signature in signatures {
+            image.extend_from_slice(&(signature.len() as u32 + 8).to_le_bytes());
+            image.extend_from_slice(&WIN_CERT_REVISION_2_0.to_le_bytes());
+            image.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+            image.extend_from_slice(signature);
+            image.resize(image.len().next_multiple_of(8), 0);
+        }
+        let cert_dir = pe_layout(&image).unwrap().certificate_table.unwrap();
+        let size = (image.len() - start) as u32;
+        image[cert_dir..cert_dir + 4].copy_from_slice(&(start as u32).to_le_bytes());
+        image[cert_dir + 4..cert_dir + 8].copy_from_slice(&size.to_le_bytes());
+        image
+    }
+
+    /// Signs an image with a key and its certificate (followed by any intermediates).
+    pub(crate) fn sign(image: &[u8], key: &PKey<Private>, certs: &[&X509]) -> Vec<u8> {
+        attach(image, &[signature(image, key, certs)])
+    }
+
+    fn uki() -> Vec<u8> {
+        build_uki(
+            &stub(0x400),
+            &UkiComponents {
+                linux: b"kernel",
+                initrd: Some(b"initrd"),
+                devicetree: None,
+                os_release: b"ID=test\n",
+                cmdline: "composefs=abc",
+                uname: None,
+            },
+        )
+        .unwrap()
+    }
+
+    fn trust(certs: &[&X509]) -> TrustedCerts {
+        let pem: Vec<u8> = certs.iter().flat_map(|c| c.to_pem().unwrap()).collect();
+        TrustedCerts::from_pem(&pem).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let ca_key = key();
+        let ca = certificate("Test CA", &ca_key, None, true);
+        let intermediate_key = key();
+        let intermediate = certificate(
+            "Intermediate",
+            &intermediate_key,
+            Some((&ca, &ca_key)),
+            true,
+        );
+        let signing_key = key();
+        let signing = certificate(
+            "Signing key",
+            &signing_key,
+            Some((&intermediate, &intermediate_key)),
+            false,
+        );
+        let other_key = key();
+        let other = certificate("Other CA", &other_key, None, true);
+
+        let uki = uki();
+        let signed = sign(&uki, &signing_key, &[&signing, &intermediate]);
+
+        // Trusted directly via the CA, or via an intermediate or leaf certificate in the db
+        verify_authenticode(&signed, &trust(&[&ca])).unwrap();
+        verify_authenticode(&signed, &trust(&[&other, &intermediate])).unwrap();
+        verify_authenticode(
+            &signed,
+            &TrustedCerts::from_der(&signing.to_der().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_authenticode(&signed, &trust(&[&other])),
+            Err(AuthenticodeError::Untrusted(..))
+        ));
+        assert!(matches!(
+            verify_authenticode(&uki, &trust(&[&ca])),
+            Err(AuthenticodeError::NotSigned)
+        ));
+
+        // Changing the image breaks the signature, but the checksum isn't covered by it
+        let mut tampered = signed.clone();
+        tampered[0x400] ^= 1;
+        assert!(matches!(
+            verify_authenticode(&tampered, &trust(&[&ca])),
+            Err(AuthenticodeError::DigestMismatch)
+        ));
+        let layout = pe_layout(&signed).unwrap();
+        let mut checksummed = signed.clone();
+        checksummed[layout.checksum_offset] ^= 1;
+        verify_authenticode(&checksummed, &trust(&[&ca])).unwrap();
+
+        // A signature made by a different key than the embedded certificate
+        let forged = sign(&uki, &other_key, &[&signing, &intermediate]);
+        assert!(matches!(
+            verify_authenticode(&forged, &trust(&[&ca])),
+            Err(AuthenticodeError::BadSignature)
+        ));
+
+        // Any one valid signature is enough
+        let both = attach(
+            &uki,
+            &[
+                signature(&uki, &other_key, &[&other]),
+                signature(&uki, &signing_key, &[&signing, &intermediate]),
+            ],
+        );
+        verify_authenticode(&both, &trust(&[&ca])).unwrap();
+        verify_authenticode(&both, &trust(&[&other])).unwrap();
+        let unknown = certificate("Unknown", &other_key, None, true);
+        assert!(matches!(
+            verify_authenticode(&both, &trust(&[&unknown])),
+            Err(AuthenticodeError::Untrusted(..))
+        ));
+    }
+}
@@ -4,22 +4,351 @@
 //! Boot Loader Specification Type 1 entries (separate kernel/initrd files) and Type 2
 //! Unified Kernel Images. It manages file placement, directory creation, and command line
 //! argument injection for composefs boot scenarios.
+//!
+//! All files are first written to a staging directory on the boot partition and synced before
+//! being renamed into place, with the loader entry itself going last.  Files which get replaced
+//! are kept in the staging directory until the end.  If anything fails along the way, the
+//! partially-written entry is removed again and the replaced files are restored, so the
+//! bootloader never sees an entry that refers to missing or truncated files.  If the process dies
+//! instead, the next write restores the files that were moved aside before cleaning up.
 
 use std::{
-    fs::{create_dir_all, write},
-    path::Path,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{
+        create_dir, create_dir_all, read, read_dir, remove_dir, remove_dir_all, remove_file,
+        rename, File,
+    },
+    io::{ErrorKind, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
 };
 
-use anyhow::{ensure, Result};
+use anyhow::{ensure, Context, Result};
+use rustix::{
+    fs::{renameat_with, RenameFlags, CWD},
+    io::Errno,
+};
 
-use composefs::{fsverity::FsVerityHashValue, repository::Repository};
+use composefs::{fsverity::FsVerityHashValue, repository::Repository, tree::RegularFile};
 
 use crate::{
-    bootloader::{BootEntry, Type1Entry, Type2Entry},
-    cmdline::get_cmdline_composefs,
-    uki,
+    bootloader::{BootEntry, PEType, Type1Entry, Type2Entry, EFI_EXT},
+    cmdline::{check_addon_cmdline, get_cmdline_composefs},
+    entries::BootCounter,
+    uki::{self, TrustedCerts},
 };
 
+/// The name of the directory (in the boot partition) where files are staged before being moved
+/// into place.
+const STAGING_DIR: &str = ".composefs-staging";
+
+/// The suffix of the files in the staging directory which record the original path of a file
+/// that was set aside.
+const ORIGIN_EXT: &str = ".origin";
+
+/// The file in the staging directory which records that all files were moved into place, so the
+/// files that were set aside are no longer needed.
+const COMMITTED: &str = "committed";
+
+/// Injects failures into the write process for testing.
+///
+/// Each call is a point where the write might fail, named after the operation and the path it
+/// applies to, like `rename /boot/loader/entries/a.conf`.  The function set in `FAIL_AT` decides
+/// whether to fail, given the name.
+#[cfg(test)]
+fn failpoint(what: fmt::Arguments) -> Result<()> {
+    let what = what.to_string();
+    let fail = FAIL_AT.with_borrow_mut(|fail_at| fail_at.as_mut().is_some_and(|f| f(&what)));
+    ensure!(!fail, "injected failure: {what}");
+    Ok(())
+}
+
+#[cfg(test)]
+type FailAt = Box<dyn FnMut(&str) -> bool>;
+
+#[cfg(test)]
+thread_local! {
+    static FAIL_AT: std::cell::RefCell<Option<FailAt>> = const { std::cell::RefCell::new(None) };
+}
+
+#[cfg(not(test))]
+fn failpoint(_what: fmt::Arguments) -> Result<()> {
+    Ok(())
+}
+
+fn file_size<ObjectID: FsVerityHashValue>(file: &RegularFile<ObjectID>) -> u64 {
+    match file {
+        RegularFile::Inline(data) => data.len() as u64,
+        RegularFile::External(_, size) => *size,
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    failpoint(format_args!("sync {}", dir.display()))?;
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {dir:?}"))
+}
+
+/// Checks that there's enough free space in `dir` to write files of the given sizes.
+fn check_space(dir: &Path, sizes: &[u64]) -> Result<()> {
+    let stat = rustix::fs::statvfs(dir).with_context(|| format!("Failed to statvfs {dir:?}"))?;
+    let block_size = stat.f_frsize.max(1);
+    let required = sizes
+        .iter()
+        .map(|size| size.div_ceil(block_size).saturating_mul(block_size))
+        .fold(0u64, u64::saturating_add);
+    let available = stat.f_bavail.saturating_mul(block_size);
+
+    ensure!(
+        required <= available,
+        "Not enough space in {dir:?}: {required} bytes required, but only {available} available"
+    );
+    Ok(())
+}
+
+/// A set of files which are written to a staging directory and then moved into place.
+///
+/// Files are published in the order in which they were staged, so the loader entry should be
+/// staged last.  Files which are replaced (or set aside) are kept in the staging directory.  If
+/// the write isn't committed, or committing it fails, everything that was put in place is removed
+/// again and the replaced files are restored when this is dropped.
+#[derive(Debug)]
+struct StagedWrite {
+    staging: PathBuf,
+    targets: Vec<PathBuf>,
+    placed: Vec<PathBuf>,
+    /// Pairs of original path and backup in the staging directory
+    backups: Vec<(PathBuf, PathBuf)>,
+    created_dirs: Vec<PathBuf>,
+    committed: bool,
+}
+
+impl StagedWrite {
+    /// Prepares to write files of the given sizes to `root`, which must be on the same filesystem
+    /// as the final location of all of the files.
+    fn new(root: &Path, sizes: &[u64]) -> Result<Self> {
+        create_dir_all(root)?;
+        check_space(root, sizes)?;
+
+        // Clean up after a previous write that was interrupted
+        let staging = root.join(STAGING_DIR);
+        Self::recover(&staging)?;
+        match remove_dir_all(&staging) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Failed to remove {staging:?}"))?
+            }
+            _ => {}
+        }
+
+        failpoint(format_args!("create {}", staging.display()))?;
+        create_dir(&staging).with_context(|| format!("Failed to create {staging:?}"))?;
+
+        Ok(Self {
+            staging,
+            targets: vec![],
+            placed: vec![],
+            backups: vec![],
+            created_dirs: vec![],
+            committed: false,
+        })
+    }
+
+    /// Restores the files that an interrupted write set aside, if it didn't get to commit and
+    /// nothing took their place.
+    fn recover(staging: &Path) -> Result<()> {
+        let dir = match read_dir(staging) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {staging:?}"))?,
+        };
+        if staging.join(COMMITTED).exists() {
+            return Ok(());
+        }
+
+        for dirent in dir {
+            let record = dirent?.path();
+            let Some(name) = record
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(ORIGIN_EXT))
+            else {
+                continue;
+            };
+            let backup = staging.join(name);
+            let origin = PathBuf::from(OsString::from_vec(read(&record)?));
+            if !backup.exists() || origin.symlink_metadata().is_ok() {
+                continue;
+            }
+
+            rename(&backup, &origin).with_context(|| format!("Failed to restore {origin:?}"))?;
+            log::warn!("Restored {origin:?}, which an interrupted write had moved aside");
+            if let Some(parent) = origin.parent() {
+                sync_dir(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn staged_path(&self, n: usize) -> PathBuf {
+        self.staging.join(n.to_string())
+    }
+
+    /// Writes the content for `target` to the staging directory and syncs it.
+    fn stage(&mut self, target: PathBuf, content: &[u8]) -> Result<()> {
+        let path = self.staged_path(self.targets.len());
+
+        failpoint(format_args!("write {}", target.display()))?;
+        let mut file = File::create(&path)?;
+        file.write_all(content)
+            .with_context(|| format!("Failed to write {path:?}"))?;
+        failpoint(format_args!("sync {}", target.display()))?;
+        file.sync_all()?;
+
+        self.targets.push(target);
+        Ok(())
+    }
+
+    fn create_dirs(&mut self, dir: &Path) -> Result<()> {
+        if dir.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dirs(parent)?;
+        }
+        failpoint(format_args!("create {}", dir.display()))?;
+        create_dir(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        self.created_dirs.push(dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Moves an existing file into the staging directory.  It's restored if the write fails, and
+    /// removed along with the staging directory otherwise.
+    ///
+    /// Its original path is recorded first, so that the next write can restore it if this one
+    /// never finishes (see [`Self::recover`]).
+    fn set_aside(&mut self, path: &Path) -> Result<()> {
+        let name = format!("old-{}", self.backups.len());
+        let backup = self.staging.join(&name);
+
+        let record = self.staging.join(format!("{name}{ORIGIN_EXT}"));
+        failpoint(format_args!("record {}", path.display()))?;
+        let mut file = File::create(&record)?;
+        file.write_all(path.as_os_str().as_bytes())
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to write {record:?}"))?;
+        sync_dir(&self.staging)?;
+
+        failpoint(format_args!("set aside {}", path.display()))?;
+        rename(path, &backup).with_context(|| format!("Failed to move {path:?} aside"))?;
+        self.backups.push((path.to_path_buf(), backup));
+        Ok(())
+    }
+
+    /// Moves the staged file `n` to `target`, replacing any existing file atomically if the
+    /// filesystem supports it.
+    fn place(&mut self, n: usize, target: &Path) -> Result<()> {
+        let staged = self.staged_path(n);
+        failpoint(format_args!("rename {}", target.display()))?;
+        match renameat_with(CWD, &staged, CWD, target, RenameFlags::EXCHANGE) {
+            // The replaced file now has the staged file's name
+            Ok(()) => self.backups.push((target.to_path_buf(), staged)),
+            // There's nothing to replace, or the filesystem can't exchange files (like vfat
+            // before Linux 6.0)
+            Err(Errno::NOENT | Errno::INVAL) => {
+                if target.symlink_metadata().is_ok() {
+                    self.set_aside(target)?;
+                }
+                rename(&staged, target)
+                    .with_context(|| format!("Failed to move {target:?} into place"))?;
+            }
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to move {target:?} into place"))?
+            }
+        }
+        self.placed.push(target.to_path_buf());
+        Ok(())
+    }
+
+    /// Moves all staged files into place.
+    fn commit(mut self) -> Result<()> {
+        sync_dir(&self.staging)?;
+
+        for (n, target) in std::mem::take(&mut self.targets).into_iter().enumerate() {
+            // SAFETY: targets are always full paths of files
+            let parent = target.parent().unwrap();
+            self.create_dirs(parent)?;
+            self.place(n, &target)?;
+            sync_dir(parent)?;
+        }
+
+        // From here on, the replaced files mustn't come back, even if we don't get to clean up
+        File::create(self.staging.join(COMMITTED))
+            .with_context(|| format!("Failed to mark {:?} as committed", self.staging))?;
+        sync_dir(&self.staging)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedWrite {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = remove_file(self.staging.join(COMMITTED));
+            for target in self.placed.iter().rev() {
+                let _ = remove_file(target);
+            }
+            for (path, backup) in self.backups.iter().rev() {
+                if let Err(err) = rename(backup, path) {
+                    log::error!("Failed to restore {path:?}: {err}");
+                }
+            }
+            for dir in self.created_dirs.iter().rev() {
+                let _ = remove_dir(dir);
+            }
+        }
+        let _ = remove_dir_all(&self.staging);
+    }
+}
+
+/// Finds existing entries which have the same name as `entry` after removing boot counters.
+fn existing_entries(entry: &Path, ext: &str) -> Result<Vec<PathBuf>> {
+    let stem = |path: &Path| {
+        let stem = path.file_name()?.to_str()?.strip_suffix(ext)?;
+        Some(BootCounter::parse(stem).0.to_string())
+    };
+    let Some(name) = stem(entry) else {
+        return Ok(vec![]);
+    };
+    // SAFETY: entries always have a parent directory
+    let dir = entry.parent().unwrap();
+
+    let readdir = match read_dir(dir) {
+        Ok(readdir) => readdir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {dir:?}"))?,
+    };
+
+    let mut result = vec![];
+    for dirent in readdir {
+        let path = dirent?.path();
+        if stem(&path).as_ref() == Some(&name) {
+            result.push(path);
+        }
+    }
+    Ok(result)
+}
+
+/// Adds a boot counter suffix to the filename of an entry, which will make systemd-boot count
+/// down the number of tries left each time the entry is booted.
+fn add_boot_counter(filename: &OsStr, ext: &str, tries: u32) -> Result<String> {
+    let name = filename
+        .to_str()
+        .and_then(|f| f.strip_suffix(ext))
+        .with_context(|| format!("Entry filename {filename:?} doesn't end with {ext}"))?;
+    Ok(format!("{name}+{tries}{ext}"))
+}
+
 /// Writes a Type 1 boot entry to the boot directory.
 ///
 /// # Arguments
@@ -31,6 +360,9 @@ use crate::{
 /// * `insecure` - Whether to allow optional fs-verity verification
 /// * `cmdline_extra` - Additional kernel command line arguments
 /// * `repo` - The composefs repository
+///
+/// If an entry with the same filename (ignoring boot counters) already exists, it is removed
+/// before the new files are moved into place.  It's restored if the write fails.
 pub fn write_t1_simple<ObjectID: FsVerityHashValue>(
     mut t1: Type1Entry<ObjectID>,
     bootdir: &Path,
@@ -50,27 +382,35 @@ pub fn write_t1_simple<ObjectID: FsVerityHashValue>(
     t1.entry
         .adjust_cmdline(Some(&root_id.to_hex()), insecure, cmdline_extra);
 
-    // Write the content before we write the loader entry
+    let entry_content = t1.entry.lines.join("\n") + "\n";
+    let mut sizes: Vec<u64> = t1.files.values().map(file_size).collect();
+    sizes.push(entry_content.len() as u64);
+    let mut staged = StagedWrite::new(&bootdir, &sizes)?;
+
+    // Stage the content before the loader entry, so that it gets moved into place first
     for (filename, file) in &t1.files {
         let pathname = Path::new(filename.as_ref());
         let file_path = bootdir.join(pathname.strip_prefix(Path::new("/"))?);
-        // SAFETY: what safety? :)
-        create_dir_all(file_path.parent().unwrap())?;
-        write(file_path, composefs::fs::read_file(file, repo)?)?;
+        staged.stage(file_path, &composefs::fs::read_file(file, repo)?)?;
     }
 
     // And now the loader entry itself
-    let loader_entries = bootdir.join("loader/entries");
-    create_dir_all(&loader_entries)?;
-    let entry = loader_entries.join(t1.filename.as_ref());
-    let entry_content = t1.entry.lines.join("\n") + "\n";
-    write(entry, entry_content)?;
-    Ok(())
+    let entry = bootdir.join("loader/entries").join(t1.filename.as_ref());
+    staged.stage(entry.clone(), entry_content.as_bytes())?;
+
+    // Never leave an existing entry pointing at files which are in the process of being replaced
+    for old in existing_entries(&entry, ".conf")? {
+        staged.set_aside(&old)?;
+    }
+
+    staged.commit()
 }
 
 /// Writes a Type 2 boot entry (UKI) to the boot directory.
 ///
-/// Validates that the UKI's embedded composefs= parameter matches the expected root_id.
+/// Validates that the UKI's embedded composefs= parameter matches the expected root_id.  UKI
+/// addons are written in the same way, but they must not have a composefs= parameter at all, since
+/// it would override the one in the UKI.
 ///
 /// # Arguments
 ///
@@ -78,23 +418,47 @@ pub fn write_t1_simple<ObjectID: FsVerityHashValue>(
 /// * `bootdir` - Path to the boot directory
 /// * `root_id` - The expected composefs root object ID
 /// * `repo` - The composefs repository
+/// * `trusted` - If `Some`, refuse to write the UKI unless it has a valid Authenticode signature
+///   from one of these certificates
 pub fn write_t2_simple<ObjectID: FsVerityHashValue>(
     t2: Type2Entry<ObjectID>,
     bootdir: &Path,
     root_id: &ObjectID,
     repo: &Repository<ObjectID>,
+    trusted: Option<&TrustedCerts>,
 ) -> Result<()> {
-    let efi_linux = bootdir.join("EFI/Linux");
-    create_dir_all(&efi_linux)?;
-    let filename = efi_linux.join(t2.file_path);
+    let filename = bootdir.join("EFI/Linux").join(t2.file_path);
     let content = composefs::fs::read_file(&t2.file, repo)?;
-    let (composefs, _) = get_cmdline_composefs::<ObjectID>(uki::get_cmdline(&content)?)?;
+    if let Some(trusted) = trusted {
+        uki::verify_authenticode(&content, trusted)
+            .with_context(|| format!("Refusing to install {filename:?}"))?;
+    }
+    match t2.pe_type {
+        PEType::Uki => {
+            let (composefs, _) = get_cmdline_composefs::<ObjectID>(uki::get_cmdline(&content)?)?;
+            ensure!(
+                &composefs == root_id,
+                "The UKI has the wrong composefs= parameter (is '{composefs:?}', should be {root_id:?})"
+            );
+        }
+        PEType::UkiAddon => {
+            if let Some(cmdline) = uki::get_addon_cmdline(&content)? {
+                check_addon_cmdline(cmdline)
+                    .with_context(|| format!("Refusing to install {filename:?}"))?;
+            }
+        }
+    }
 
-    ensure!(
-        &composefs == root_id,
-        "The UKI has the wrong composefs= parameter (is '{composefs:?}', should be {root_id:?})"
-    );
-    write(filename, content)?;
+    let old_entries = existing_entries(&filename, EFI_EXT)?;
+    let mut staged = StagedWrite::new(bootdir, &[content.len() as u64])?;
+    staged.stage(filename.clone(), &content)?;
+    staged.commit()?;
+
+    // The new UKI atomically replaced any old one with exactly the same name, but there might
+    // also be versions of it with different boot counters
+    for old in old_entries.iter().filter(|old| **old != filename) {
+        remove_file(old).with_context(|| format!("Failed to remove {old:?}"))?;
+    }
     Ok(())
 }
 
@@ -127,6 +491,11 @@ pub fn write_t2_simple<ObjectID: FsVerityHashValue>(
 ///
 /// * entry_id       - In case of a BLS entry, the name of file to be generated in `loader/entries`
 /// * cmdline_extra  - Extra kernel command line arguments
+/// * boot_tries     - If `Some(n)`, add a `+n` boot counter to the entry filename so that
+///   systemd-boot falls back to another entry after `n` failed attempts to boot it.  See
+///   [`crate::entries::BootDir::mark_good`].
+/// * trusted        - If `Some`, UKIs and UKI addons must be signed by one of these certificates
+///   (usually the Secure Boot `db`), or they are not written.  See [`uki::verify_authenticode`].
 ///
 #[allow(clippy::too_many_arguments)]
 pub fn write_boot_simple<ObjectID: FsVerityHashValue>(
@@ -138,12 +507,17 @@ pub fn write_boot_simple<ObjectID: FsVerityHashValue>(
     boot_subdir: Option<&str>,
     entry_id: Option<&str>,
     cmdline_extra: &[&str],
+    boot_tries: Option<u32>,
+    trusted: Option<&TrustedCerts>,
 ) -> Result<()> {
     match entry {
         BootEntry::Type1(mut t1) => {
             if let Some(name) = entry_id {
                 t1.relocate(boot_subdir, name);
             }
+            if let Some(tries) = boot_tries {
+                t1.filename = Box::from(add_boot_counter(&t1.filename, ".conf", tries)?.as_ref());
+            }
             write_t1_simple(
                 t1,
                 boot_partition,
@@ -159,13 +533,22 @@ pub fn write_boot_simple<ObjectID: FsVerityHashValue>(
                 t2.rename(name);
             }
             ensure!(cmdline_extra.is_empty(), "Can't add --cmdline args to UKIs");
-            write_t2_simple(t2, boot_partition, root_id, repo)?;
+            if let (Some(tries), PEType::Uki) = (boot_tries, &t2.pe_type) {
+                // SAFETY: the UKI path always has a filename
+                let filename = t2.file_path.file_name().unwrap();
+                let filename = add_boot_counter(filename, EFI_EXT, tries)?;
+                t2.file_path.set_file_name(filename);
+            }
+            write_t2_simple(t2, boot_partition, root_id, repo, trusted)?;
         }
         BootEntry::UsrLibModulesVmLinuz(entry) => {
-            let mut t1 = entry.into_type1(entry_id);
+            let mut t1 = entry.into_type1(entry_id, repo)?;
             if let Some(name) = entry_id {
                 t1.relocate(boot_subdir, name);
             }
+            if let Some(tries) = boot_tries {
+                t1.filename = Box::from(add_boot_counter(&t1.filename, ".conf", tries)?.as_ref());
+            }
             write_t1_simple(
                 t1,
                 boot_partition,
@@ -180,3 +563,292 @@ pub fn write_boot_simple<ObjectID: FsVerityHashValue>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, collections::HashMap, fs};
+
+    use composefs::fsverity::Sha256HashValue;
+    use rustix::fs::CWD;
+    use similar_asserts::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::bootloader::BootLoaderEntryFile;
+
+    /// Returns the content of every file and directory below `dir`.
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Option<Vec<u8>>> {
+        let mut result = BTreeMap::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                result.extend(snapshot(&path));
+                result.insert(path, None);
+            } else {
+                result.insert(path.clone(), Some(fs::read(path).unwrap()));
+            }
+        }
+        result
+    }
+
+    fn type1_entry(id: &str, kernel: &[u8]) -> Type1Entry<Sha256HashValue> {
+        Type1Entry {
+            filename: Box::from(format!("{id}.conf").as_ref()),
+            entry: BootLoaderEntryFile::new(&format!(
+                "title Test\nlinux /{id}/vmlinuz\ninitrd /{id}/initramfs.img\n"
+            )),
+            files: HashMap::from([
+                (
+                    Box::from(format!("/{id}/vmlinuz")),
+                    RegularFile::Inline(kernel.into()),
+                ),
+                (
+                    Box::from(format!("/{id}/initramfs.img")),
+                    RegularFile::Inline((*b"initrd").into()),
+                ),
+            ]),
+        }
+    }
+
+    fn write(
+        boot: &Path,
+        repo: &Repository<Sha256HashValue>,
+        t1: Type1Entry<Sha256HashValue>,
+        fail_at: impl FnMut(&str) -> bool + 'static,
+    ) -> Result<()> {
+        FAIL_AT.set(Some(Box::new(fail_at)));
+        let result = write_t1_simple(t1, boot, None, &Sha256HashValue::EMPTY, false, &[], repo);
+        FAIL_AT.set(None);
+        result
+    }
+
+    fn never(_: &str) -> bool {
+        false
+    }
+
+    /// Fails at the `n`th failpoint (counting from 0) and every one after it
+    fn after(mut n: usize) -> impl FnMut(&str) -> bool {
+        move |_| match n {
+            0 => true,
+            _ => {
+                n -= 1;
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_t1_failures() {
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::open_path(CWD, tmp.path()).unwrap();
+        let boot = tmp.path().join("boot");
+
+        write(&boot, &repo, type1_entry("old", b"old kernel"), never).unwrap();
+
+        // Fail at every possible point in turn: none of them may leave anything behind, and
+        // replacing an entry must restore the old one
+        for (id, kernel) in [("old", &b"replaced kernel"[..]), ("new", b"new kernel")] {
+            let before = snapshot(&boot);
+            let mut n = 0;
+            while write(&boot, &repo, type1_entry(id, kernel), after(n)).is_err() {
+                assert_eq!(snapshot(&boot), before, "{id}: failure after {n} steps");
+                n += 1;
+            }
+            assert!(n > 10);
+        }
+
+        assert_eq!(
+            fs::read(boot.join("old/vmlinuz")).unwrap(),
+            b"replaced kernel"
+        );
+
+        let entry = fs::read_to_string(boot.join("loader/entries/new.conf")).unwrap();
+        assert!(entry.contains(&format!("composefs={}", Sha256HashValue::EMPTY.to_hex())));
+        assert_eq!(fs::read(boot.join("new/vmlinuz")).unwrap(), b"new kernel");
+        assert!(!boot.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn test_write_t1_replace() {
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::open_path(CWD, tmp.path()).unwrap();
+        let boot = tmp.path().join("boot");
+
+        // A leftover staging directory from an interrupted write gets cleaned up
+        fs::create_dir_all(boot.join(STAGING_DIR)).unwrap();
+        fs::write(boot.join(STAGING_DIR).join("0"), "junk").unwrap();
+
+        write(&boot, &repo, type1_entry("a", b"kernel 1"), never).unwrap();
+        write(&boot, &repo, type1_entry("a", b"kernel 2"), never).unwrap();
+        assert_eq!(fs::read(boot.join("a/vmlinuz")).unwrap(), b"kernel 2");
+        assert!(!boot.join(STAGING_DIR).exists());
+
+        // Failing to put the new entry in place restores the old entry and its files, rather than
+        // leaving a mix of old and new files
+        let entry = boot.join("loader/entries/a.conf");
+        let rename_entry = format!("rename {}", entry.display());
+        let err = write(&boot, &repo, type1_entry("a", b"kernel 3"), move |what| {
+            what == rename_entry
+        })
+        .unwrap_err();
+        assert!(
+            err.to_string().starts_with("injected failure: rename"),
+            "{err}"
+        );
+        assert!(entry.exists());
+        assert_eq!(fs::read(boot.join("a/vmlinuz")).unwrap(), b"kernel 2");
+        assert!(!boot.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn test_write_t1_crash() {
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::open_path(CWD, tmp.path()).unwrap();
+        let boot = tmp.path().join("boot");
+        let entries = boot.join("loader/entries");
+
+        let mut t1 = type1_entry("a", b"kernel 1");
+        t1.filename = Box::from(add_boot_counter(&t1.filename, ".conf", 3).unwrap().as_ref());
+        write(&boot, &repo, t1, never).unwrap();
+        let old = fs::read(entries.join("a+3.conf")).unwrap();
+
+        // Die after moving the entry aside: nothing gets rolled back
+        let crash = || {
+            let mut staged = StagedWrite::new(&boot, &[]).unwrap();
+            staged.set_aside(&entries.join("a+3.conf")).unwrap();
+            std::mem::forget(staged);
+            assert!(!entries.join("a+3.conf").exists());
+        };
+
+        // The next write restores the old entry before cleaning up
+        crash();
+        write(&boot, &repo, type1_entry("b", b"kernel b"), never).unwrap();
+        assert_eq!(fs::read(entries.join("a+3.conf")).unwrap(), old);
+        assert!(!boot.join(STAGING_DIR).exists());
+
+        // ...including one which replaces it
+        crash();
+        write(&boot, &repo, type1_entry("a", b"kernel 2"), never).unwrap();
+        assert!(!entries.join("a+3.conf").exists());
+        assert!(entries.join("a.conf").exists());
+        assert_eq!(fs::read(boot.join("a/vmlinuz")).unwrap(), b"kernel 2");
+
+        // Files which were replaced by a committed write don't come back
+        let staging = boot.join(STAGING_DIR);
+        fs::create_dir(&staging).unwrap();
+        fs::write(staging.join("old-0"), "old").unwrap();
+        fs::write(
+            staging.join("old-0.origin"),
+            entries.join("gone.conf").as_os_str().as_bytes(),
+        )
+        .unwrap();
+        fs::write(staging.join(COMMITTED), "").unwrap();
+        write(&boot, &repo, type1_entry("b", b"kernel b2"), never).unwrap();
+        assert!(!entries.join("gone.conf").exists());
+    }
+
+    #[test]
+    fn test_boot_counter() {
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::open_path(CWD, tmp.path()).unwrap();
+        let boot = tmp.path().join("boot");
+        let entries = boot.join("loader/entries");
+
+        let mut t1 = type1_entry("a", b"kernel");
+        t1.filename = Box::from(add_boot_counter(&t1.filename, ".conf", 3).unwrap().as_ref());
+        write(&boot, &repo, t1, never).unwrap();
+        assert!(entries.join("a+3.conf").exists());
+
+        // Replaces the old entry, regardless of its boot counter
+        fs::rename(entries.join("a+3.conf"), entries.join("a+1-2.conf")).unwrap();
+        write(&boot, &repo, type1_entry("a", b"kernel"), never).unwrap();
+        assert_eq!(
+            snapshot(&entries).into_keys().collect::<Vec<_>>(),
+            [entries.join("a.conf")]
+        );
+    }
+
+    #[test]
+    fn test_write_t2_signed() {
+        use crate::{bootloader::PEType, uki::test::authenticode};
+
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::open_path(CWD, tmp.path()).unwrap();
+        let boot = tmp.path().join("boot");
+
+        let key = authenticode::key();
+        let cert = authenticode::certificate("db", &key, None, true);
+        let trusted = TrustedCerts::from_pem(&cert.to_pem().unwrap()).unwrap();
+        let uki = uki::build_uki(
+            &uki::test::stub(0x400),
+            &uki::UkiComponents {
+                linux: b"kernel",
+                initrd: None,
+                devicetree: None,
+                os_release: b"ID=test\n",
+                cmdline: &format!("composefs={}", Sha256HashValue::EMPTY.to_hex()),
+                uname: None,
+            },
+        )
+        .unwrap();
+        let t2 = |content: &[u8]| Type2Entry {
+            kver: None,
+            file_path: PathBuf::from("test.efi"),
+            file: RegularFile::Inline(content.into()),
+            pe_type: PEType::Uki,
+        };
+        let write = |content| {
+            let root_id = &Sha256HashValue::EMPTY;
+            write_t2_simple(t2(content), &boot, root_id, &repo, Some(&trusted))
+        };
+
+        let err = write(&uki).unwrap_err();
+        assert!(format!("{err:#}").contains("The image is not signed"));
+        assert!(!boot.join("EFI/Linux/test.efi").exists());
+
+        let signed = authenticode::sign(&uki, &key, &[&cert]);
+        write(&signed).unwrap();
+        assert_eq!(fs::read(boot.join("EFI/Linux/test.efi")).unwrap(), signed);
+    }
+
+    #[test]
+    fn test_write_addon() {
+        use crate::bootloader::PEType;
+
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::open_path(CWD, tmp.path()).unwrap();
+        let boot = tmp.path().join("boot");
+        let addon = |cmdline: &str| {
+            let sections: &[(&str, &[u8])] = &[(".cmdline", cmdline.as_bytes())];
+            Type2Entry {
+                kver: None,
+                file_path: PathBuf::from("test.efi.extra.d/console.addon.efi"),
+                file: RegularFile::Inline(
+                    uki::add_sections(&uki::test::stub(0x400), sections)
+                        .unwrap()
+                        .into(),
+                ),
+                pe_type: PEType::UkiAddon,
+            }
+        };
+        let root_id = &Sha256HashValue::EMPTY;
+
+        // Addons don't need a composefs= argument, and aren't allowed to have one
+        write_t2_simple(addon("console=ttyS0"), &boot, root_id, &repo, None).unwrap();
+        assert!(boot
+            .join("EFI/Linux/test.efi.extra.d/console.addon.efi")
+            .exists());
+
+        let cmdline = format!("composefs={}", root_id.to_hex());
+        let err = write_t2_simple(addon(&cmdline), &boot, root_id, &repo, None).unwrap_err();
+        assert!(format!("{err:#}").contains("must not set composefs="));
+    }
+
+    #[test]
+    fn test_check_space() {
+        let tmp = TempDir::new().unwrap();
+        check_space(tmp.path(), &[1, 4096]).unwrap();
+        let err = check_space(tmp.path(), &[u64::MAX / 2]).unwrap_err();
+        assert!(err.to_string().starts_with("Not enough space"));
+    }
+}
@@ -0,0 +1,171 @@
+//! Snapshot tests for GRUB configuration generation
+
+use std::collections::HashMap;
+
+use insta::assert_snapshot;
+use similar_asserts::assert_eq;
+
+use composefs::{
+    fsverity::{FsVerityHashValue, Sha256HashValue},
+    repository::Repository,
+    tree::RegularFile,
+};
+use composefs_boot::{
+    bootloader::{
+        BootEntry, BootLoaderEntryFile, PEType, Type1Entry, Type2Entry, UsrLibModulesVmlinuz,
+    },
+    entries::BootDir,
+    grub::{grub_cfg, installed_grub_cfg, menuentry},
+    write_boot::write_boot_simple,
+};
+
+fn file(content: &[u8]) -> RegularFile<Sha256HashValue> {
+    RegularFile::Inline(content.into())
+}
+
+fn repo() -> (tempfile::TempDir, Repository<Sha256HashValue>) {
+    let tmp = tempfile::TempDir::new().unwrap();
+    let repo = Repository::open_path(rustix::fs::CWD, tmp.path()).unwrap();
+    (tmp, repo)
+}
+
+fn root_id() -> Sha256HashValue {
+    Sha256HashValue::from_hex(hex_id()).unwrap()
+}
+
+fn hex_id() -> &'static str {
+    "5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a"
+}
+
+fn type1() -> BootEntry<Sha256HashValue> {
+    BootEntry::Type1(Type1Entry {
+        filename: Box::from("fedora-6.12.conf".as_ref()),
+        entry: BootLoaderEntryFile::new(
+            "title Fedora Linux 42 (Adams)\n\
+             version 6.12.0\n\
+             linux /6.12.0/vmlinuz\n\
+             initrd /6.12.0/microcode.img\n\
+             initrd /6.12.0/initramfs.img\n\
+             options rw console=ttyS0 systemd.setenv=\"A=b c\"\n\
+             devicetree /6.12.0/board.dtb\n\
+             devicetree /6.12.0/board-rev2.dtb\n",
+        ),
+        files: HashMap::from([
+            (Box::from("/6.12.0/vmlinuz"), file(b"kernel")),
+            (Box::from("/6.12.0/microcode.img"), file(b"ucode")),
+            (Box::from("/6.12.0/initramfs.img"), file(b"initrd")),
+            (Box::from("/6.12.0/board.dtb"), file(b"dtb")),
+            (Box::from("/6.12.0/board-rev2.dtb"), file(b"dtb2")),
+        ]),
+    })
+}
+
+fn vmlinuz() -> BootEntry<Sha256HashValue> {
+    BootEntry::UsrLibModulesVmLinuz(UsrLibModulesVmlinuz {
+        kver: Box::from("6.13.1"),
+        vmlinuz: file(b"kernel"),
+        initramfs: Some(file(b"initrd")),
+        os_release: None,
+        dtbs: Default::default(),
+        devicetree: None,
+        devicetree_overlays: vec![],
+        microcode: vec![],
+    })
+}
+
+fn type2() -> BootEntry<Sha256HashValue> {
+    BootEntry::Type2(Type2Entry {
+        kver: None,
+        file_path: "fedora.efi".into(),
+        file: file(b"uki"),
+        pe_type: PEType::Uki,
+    })
+}
+
+#[test]
+fn test_grub_cfg() {
+    let (_tmp, repo) = repo();
+    let entries = [
+        menuentry(&repo, type1(), &root_id(), false, None, None, &[]).unwrap(),
+        menuentry(
+            &repo,
+            vmlinuz(),
+            &root_id(),
+            true,
+            Some("composefs"),
+            Some(hex_id()),
+            &["quiet", "enforcing=0"],
+        )
+        .unwrap(),
+        menuentry(
+            &repo,
+            type2(),
+            &root_id(),
+            false,
+            None,
+            Some("composefs-42"),
+            &[],
+        )
+        .unwrap(),
+    ];
+
+    assert_snapshot!(grub_cfg(&entries, Some("composefs-fedora-6.12")));
+}
+
+#[test]
+fn test_grub_cfg_relocated() {
+    let (_tmp, repo) = repo();
+    let entry = menuentry(
+        &repo,
+        type1(),
+        &root_id(),
+        false,
+        None,
+        Some("deploy-1"),
+        &[],
+    )
+    .unwrap();
+    assert_snapshot!(grub_cfg(&[entry], None));
+}
+
+#[test]
+fn test_installed_grub_cfg() {
+    let (tmp, repo) = repo();
+    let boot = tmp.path().join("boot");
+    for (entry, entry_id, cmdline) in [
+        (type1(), "deploy-1", &[][..]),
+        (vmlinuz(), "deploy-2", &["quiet"][..]),
+    ] {
+        let id = root_id();
+        write_boot_simple(
+            &repo,
+            entry,
+            &id,
+            false,
+            &boot,
+            None,
+            Some(entry_id),
+            cmdline,
+            None,
+            None,
+        )
+        .unwrap();
+    }
+    // Entries which don't boot a composefs image are left to the system's configuration
+    std::fs::write(
+        boot.join("loader/entries/other.conf"),
+        "title Other\nlinux /other/vmlinuz\n",
+    )
+    .unwrap();
+
+    let boot = BootDir::new(&boot, None);
+    boot.set_default::<Sha256HashValue>("deploy-1").unwrap();
+    assert_snapshot!(installed_grub_cfg::<Sha256HashValue>(&boot).unwrap());
+}
+
+#[test]
+fn test_grub_uki_cmdline() {
+    let (_tmp, repo) = repo();
+    let err = menuentry(&repo, type2(), &root_id(), false, None, None, &["quiet"]).unwrap_err();
+    assert_eq!(err.to_string(), "Can't add --cmdline args to UKIs");
+}
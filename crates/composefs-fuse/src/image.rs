@@ -0,0 +1,553 @@
+//! Serving EROFS images over FUSE.
+//!
+//! [`serve_image_fuse`] serves a composefs image directly from its EROFS representation, without
+//! converting it to a [`composefs::tree::FileSystem`] first.  Inodes are looked up by their nid
+//! when the kernel asks for them, so the memory use doesn't depend on the size of the image.
+//! Inline file content is read from the image, and external file content is read from the
+//! repository object named by the `trusted.overlay.redirect` xattr, just like the kernel does
+//! when the image is mounted with overlayfs.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use fuser::{
+    consts, FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyXattr, Request, Session, SessionACL,
+};
+use rustix::io::Errno;
+
+use composefs::{
+    erofs::{
+        format::{DataLayout, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG},
+        reader::{original_xattr_name, DirectoryBlock, Image, InodeHeader, InodeType},
+    },
+    fsverity::FsVerityHashValue,
+    repository::Repository,
+};
+
+use crate::{reply_xattr, verity::Objects, FuseOptions, OpenHandle, Readers, TTL};
+
+const ROOT_INO: u64 = 1;
+
+/// Extended attributes, as pairs of name and value.
+type Xattrs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The entries of a directory, as triples of inode number, type and name.
+type Listing = Vec<(u64, FileType, OsString)>;
+
+fn kind(inode: &InodeType) -> FileType {
+    match inode.mode().0.get() & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFREG => FileType::RegularFile,
+        S_IFLNK => FileType::Symlink,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFCHR => FileType::CharDevice,
+        S_IFIFO => FileType::NamedPipe,
+        _ => FileType::Socket,
+    }
+}
+
+#[derive(Debug)]
+struct ImageFuse<'a, ObjectID: FsVerityHashValue> {
+    img: Image<'a>,
+    objects: Objects<'a, ObjectID>,
+    handles: HashMap<u64, Arc<OpenHandle<ObjectID>>>,
+    /// The listings of open directories, so that readdir doesn't re-read them for every batch
+    dirs: HashMap<u64, Listing>,
+    next_fh: u64,
+    readers: Readers<ObjectID>,
+}
+
+impl<ObjectID: FsVerityHashValue> ImageFuse<'_, ObjectID> {
+    /// Converts a nid to a FUSE inode number.  The root directory always has inode number 1.
+    fn ino(&self, nid: u64) -> u64 {
+        if nid == self.img.sb.root_nid.get() as u64 {
+            ROOT_INO
+        } else {
+            nid + 2
+        }
+    }
+
+    fn nid(&self, ino: u64) -> u64 {
+        if ino == ROOT_INO {
+            self.img.sb.root_nid.get() as u64
+        } else {
+            ino - 2
+        }
+    }
+
+    fn fileattr(&self, nid: u64) -> FileAttr {
+        let inode = self.img.inode(nid);
+        let kind = kind(&inode);
+        let mtime = match inode {
+            // Compact inodes share the build time from the superblock
+            InodeType::Compact(..) => self.img.sb.build_time.get() as i64,
+            InodeType::Extended(..) => inode.mtime(),
+        };
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime as u64);
+
+        FileAttr {
+            ino: self.ino(nid),
+            size: inode.size(),
+            blocks: inode.size().div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm: inode.mode().0.get() & !S_IFMT,
+            nlink: inode.nlink(),
+            uid: inode.uid(),
+            gid: inode.gid(),
+            rdev: match kind {
+                FileType::BlockDevice | FileType::CharDevice => inode.rdev(),
+                _ => 0,
+            },
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    fn directory(&self, ino: u64) -> Result<InodeType<'_>, Errno> {
+        let inode = self.img.inode(self.nid(ino));
+        if !inode.mode().is_dir() {
+            return Err(Errno::NOTDIR);
+        }
+        Ok(inode)
+    }
+
+    fn lookup(&self, parent: u64, name: &OsStr) -> Result<FileAttr, Errno> {
+        let name = name.as_bytes();
+        if name == b"." || name == b".." {
+            return Err(Errno::NOENT);
+        }
+
+        // The entries are sorted by name across all of the blocks: find the last block which
+        // starts at or before the name, and then the entry within it.
+        let inode = self.directory(parent)?;
+        let blocks = self.img.directory_blocks(&inode).map_err(|err| {
+            log::error!(
+                "lookup({parent}, {:?}) failed: {err}",
+                OsStr::from_bytes(name)
+            );
+            Errno::IO
+        })?;
+        let n = blocks.partition_point(|block| {
+            block
+                .entries()
+                .next()
+                .is_some_and(|first| first.name <= name)
+        });
+        let block = n.checked_sub(1).map(|n| blocks[n]).ok_or(Errno::NOENT)?;
+        let entries: Vec<_> = block.entries().collect();
+        let n = entries
+            .binary_search_by(|entry| entry.name.cmp(name))
+            .map_err(|_| Errno::NOENT)?;
+        Ok(self.fileattr(entries[n].nid()))
+    }
+
+    fn readdir(&self, ino: u64) -> Result<Listing, Errno> {
+        let inode = self.directory(ino)?;
+        let blocks = self.img.directory_blocks(&inode).map_err(|err| {
+            log::error!("readdir({ino}) failed: {err}");
+            Errno::IO
+        })?;
+        Ok(blocks
+            .into_iter()
+            .flat_map(DirectoryBlock::entries)
+            .map(|entry| {
+                let nid = entry.nid();
+                let name = OsStr::from_bytes(entry.name).to_os_string();
+                (self.ino(nid), kind(&self.img.inode(nid)), name)
+            })
+            .collect())
+    }
+
+    fn readlink(&self, ino: u64) -> Result<Box<[u8]>, Errno> {
+        let inode = self.img.inode(self.nid(ino));
+        if kind(&inode) != FileType::Symlink {
+            return Err(Errno::INVAL);
+        }
+        self.img.inode_data(&inode).map_err(|err| {
+            log::error!("readlink({ino}) failed: {err}");
+            Errno::IO
+        })
+    }
+
+    /// Returns the xattrs of an inode, as they were in the original filesystem.
+    fn xattrs(&self, ino: u64) -> Result<Xattrs, Errno> {
+        let inode = self.img.inode(self.nid(ino));
+        let xattrs = self.img.inode_xattrs(&inode).map_err(|err| {
+            log::error!("xattrs({ino}) failed: {err}");
+            Errno::IO
+        })?;
+        Ok(xattrs
+            .into_iter()
+            .filter_map(|(name, value)| Some((original_xattr_name(&name)?.into(), value.into())))
+            .collect())
+    }
+
+    fn open(&mut self, ino: u64) -> Result<u64, Errno> {
+        let inode = self.img.inode(self.nid(ino));
+        let handle = match kind(&inode) {
+            FileType::RegularFile if inode.data_layout() == DataLayout::ChunkBased => {
+                let xattrs = self.img.inode_xattrs(&inode).map_err(|err| {
+                    log::error!("open({ino}) failed: {err}");
+                    Errno::IO
+                })?;
+                let redirect = xattrs
+                    .iter()
+                    .find(|(name, _)| name == b"trusted.overlay.redirect")
+                    .map(|(_, value)| value);
+                let Some(Ok(id)) = redirect.map(ObjectID::from_object_pathname) else {
+                    log::error!("open({ino}) external file without a valid overlay.redirect");
+                    return Err(Errno::IO);
+                };
+                self.objects.open(&id)?
+            }
+            FileType::RegularFile => {
+                OpenHandle::Data(self.img.inode_data(&inode).map_err(|err| {
+                    log::error!("open({ino}) failed: {err}");
+                    Errno::IO
+                })?)
+            }
+            FileType::Directory => return Err(Errno::ISDIR),
+            _ => return Err(Errno::INVAL),
+        };
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, Arc::new(handle));
+        Ok(fh)
+    }
+}
+
+impl<ObjectID: FsVerityHashValue> Filesystem for ImageFuse<'_, ObjectID> {
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        reply.statfs(0, 0, 0, 0, 0, 4096, 255, 4096);
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        log::trace!("lookup {parent} {name:?}");
+        match Self::lookup(self, parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        reply.attr(&TTL, &self.fileattr(self.nid(ino)));
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match Self::readlink(self, ino) {
+            Ok(target) => reply.data(&target),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match Self::readdir(self, ino) {
+            Ok(listing) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.dirs.insert(fh, listing);
+                // Images are immutable, so the kernel may cache the listing too
+                reply.opened(fh, consts::FOPEN_CACHE_DIR)
+            }
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(listing) = self.dirs.get(&fh) else {
+            log::error!("Handle doesn't exist: readdir({fh}, {offset})");
+            return reply.error(Errno::BADF.raw_os_error());
+        };
+
+        for (n, (ino, kind, name)) in listing.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, n as i64 + 1, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        match self.dirs.remove(&fh) {
+            Some(_) => reply.ok(),
+            None => {
+                log::error!("Handle doesn't exist: closedir({fh})");
+                reply.error(Errno::BADF.raw_os_error())
+            }
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let xattrs = match self.xattrs(ino) {
+            Ok(xattrs) => xattrs,
+            Err(errno) => return reply.error(errno.raw_os_error()),
+        };
+        match xattrs.iter().find(|(n, _)| n == name.as_bytes()) {
+            Some((_, value)) => reply_xattr(reply, value, size),
+            None => reply.error(Errno::NODATA.raw_os_error()),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let xattrs = match self.xattrs(ino) {
+            Ok(xattrs) => xattrs,
+            Err(errno) => return reply.error(errno.raw_os_error()),
+        };
+        let mut list = vec![];
+        for (name, _) in xattrs {
+            list.extend_from_slice(&name);
+            list.push(b'\0');
+        }
+        reply_xattr(reply, &list, size);
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        log::trace!("open({ino})");
+        match Self::open(self, ino) {
+            // Images are immutable, so cached pages stay valid across opens
+            Ok(fh) => reply.opened(fh, consts::FOPEN_KEEP_CACHE),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(handle) = self.handles.get(&fh) else {
+            log::error!("Handle doesn't exist: pread({fh}, {size}, {offset})");
+            return reply.error(Errno::BADF.raw_os_error());
+        };
+
+        self.readers.read(handle, offset as u64, size, reply);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.handles.remove(&fh) {
+            Some(_) => reply.ok(),
+            None => {
+                log::error!("Handle doesn't exist: close({fh})");
+                reply.error(Errno::BADF.raw_os_error())
+            }
+        }
+    }
+}
+
+/// Serves a FUSE filesystem exposing the content of an EROFS `image`, backed by `repo`.
+///
+/// The image is typically one of the images in the repository (see
+/// [`Repository::read_image()`]).  You should have called mount_fuse() on the dev_fuse fd to
+/// establish a mount point.
+pub fn serve_image_fuse<ObjectID: FsVerityHashValue>(
+    dev_fuse: std::os::fd::OwnedFd,
+    image: &[u8],
+    repo: &Repository<ObjectID>,
+    options: FuseOptions,
+) -> std::io::Result<()> {
+    let fs = ImageFuse {
+        img: Image::open(image),
+        objects: Objects::new(repo, options.verify_objects),
+        handles: Default::default(),
+        dirs: Default::default(),
+        next_fh: 1,
+        readers: Readers::new(options.threads),
+    };
+    Session::from_fd(fs, dev_fuse, SessionACL::All).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use composefs::{
+        dumpfile::dumpfile_to_filesystem, erofs::writer::mkfs_erofs_default,
+        fsverity::Sha256HashValue,
+    };
+    use rustix::fs::CWD;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_image() {
+        let tmp = TempDir::new().unwrap();
+        let mut repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        repo.set_insecure(true);
+        let object = repo.ensure_object(b"external content").unwrap();
+
+        let dumpfile = format!(
+            "/ 4096 40755 3 0 0 0 0.0 - - -\n\
+             /dir 4096 40700 2 1000 1000 0 1000.0 - - - user.test=value trusted.overlay.x=y\n\
+             /dir/inline 6 100644 2 0 0 0 0.0 - inline -\n\
+             /external 16 100644 1 0 0 0 0.0 {}/{} - {}\n\
+             /hardlink 0 @100644 2 0 0 0 0.0 /dir/inline - -\n\
+             /null 0 20666 1 0 0 259 0.0 - - -\n\
+             /symlink 3 120777 1 0 0 0 0.0 dir - -\n",
+            &object.to_hex()[..2],
+            &object.to_hex()[2..],
+            object.to_hex(),
+        );
+        let tree = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+        let image = mkfs_erofs_default(&tree);
+        let mut fs = ImageFuse {
+            img: Image::open(&image),
+            objects: Objects::new(&repo, false),
+            handles: HashMap::new(),
+            dirs: HashMap::new(),
+            next_fh: 1,
+            readers: Readers::new(0),
+        };
+
+        let names = |fs: &ImageFuse<Sha256HashValue>, ino| -> Vec<String> {
+            let list = fs.readdir(ino).unwrap().into_iter();
+            list.map(|(_, _, name)| name.to_string_lossy().into_owned())
+                .collect()
+        };
+        let read_all = |fs: &mut ImageFuse<Sha256HashValue>, ino| {
+            let fh = ImageFuse::open(fs, ino).unwrap();
+            let data = fs.handles[&fh].read(0, 4096).unwrap();
+            fs.handles.remove(&fh);
+            data
+        };
+
+        assert_eq!(
+            names(&fs, ROOT_INO),
+            [".", "..", "dir", "external", "hardlink", "null", "symlink"]
+        );
+        let root = fs.readdir(ROOT_INO).unwrap();
+        assert_eq!((root[0].0, root[1].0), (ROOT_INO, ROOT_INO));
+
+        let dir = fs.lookup(ROOT_INO, "dir".as_ref()).unwrap();
+        assert_eq!(
+            (dir.kind, dir.perm, dir.uid),
+            (FileType::Directory, 0o700, 1000)
+        );
+        assert_eq!(fs.fileattr(fs.nid(dir.ino)).ino, dir.ino);
+        assert_eq!(names(&fs, dir.ino), [".", "..", "inline"]);
+        assert_eq!(fs.readdir(dir.ino).unwrap()[1].0, ROOT_INO);
+        assert_eq!(
+            fs.xattrs(dir.ino).unwrap(),
+            [
+                (b"trusted.overlay.x".to_vec(), b"y".to_vec()),
+                (b"user.test".to_vec(), b"value".to_vec())
+            ]
+        );
+        assert_eq!(
+            fs.lookup(dir.ino, "missing".as_ref()).unwrap_err(),
+            Errno::NOENT
+        );
+
+        let inline = fs.lookup(dir.ino, "inline".as_ref()).unwrap();
+        let hardlink = fs.lookup(ROOT_INO, "hardlink".as_ref()).unwrap();
+        assert_eq!((inline.ino, inline.nlink), (hardlink.ino, 2));
+        assert_eq!(read_all(&mut fs, inline.ino), b"inline");
+        assert_eq!(
+            fs.lookup(inline.ino, "x".as_ref()).unwrap_err(),
+            Errno::NOTDIR
+        );
+
+        let external = fs.lookup(ROOT_INO, "external".as_ref()).unwrap();
+        assert_eq!(external.size, 16);
+        assert_eq!(read_all(&mut fs, external.ino), b"external content");
+        assert!(fs.xattrs(external.ino).unwrap().is_empty());
+
+        let symlink = fs.lookup(ROOT_INO, "symlink".as_ref()).unwrap();
+        assert_eq!(&*fs.readlink(symlink.ino).unwrap(), b"dir");
+        assert_eq!(fs.readlink(dir.ino).unwrap_err(), Errno::INVAL);
+
+        let null = fs.lookup(ROOT_INO, "null".as_ref()).unwrap();
+        assert_eq!((null.kind, null.rdev), (FileType::CharDevice, 259));
+        assert_eq!(
+            ImageFuse::open(&mut fs, null.ino).unwrap_err(),
+            Errno::INVAL
+        );
+    }
+
+    #[test]
+    fn test_lookup_large_directory() {
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+
+        // Enough entries to need several directory blocks, with names on either side of "."
+        let mut dumpfile = String::from("/ 4096 40755 2 0 0 0 0.0 - - -\n");
+        for n in 0..1000 {
+            let prefix = if n < 500 { "+" } else { "z" };
+            dumpfile += &format!("/{prefix}{n:04} 0 100644 1 0 0 0 0.0 - - -\n");
+        }
+        let tree = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+        let image = mkfs_erofs_default(&tree);
+        let fs = ImageFuse {
+            img: Image::open(&image),
+            objects: Objects::new(&repo, false),
+            handles: HashMap::new(),
+            dirs: HashMap::new(),
+            next_fh: 1,
+            readers: Readers::new(0),
+        };
+
+        let root = fs.directory(ROOT_INO).unwrap();
+        assert!(fs.img.directory_blocks(&root).unwrap().len() > 1);
+
+        let listing = fs.readdir(ROOT_INO).unwrap();
+        assert_eq!(listing.len(), 1002);
+        assert_eq!(listing[500].2, ".");
+        for (ino, _, name) in listing
+            .iter()
+            .filter(|(_, _, name)| name != "." && name != "..")
+        {
+            assert_eq!(fs.lookup(ROOT_INO, name).unwrap().ino, *ino);
+        }
+        for missing in ["", "+", "+0000x", "a", "z9999", "\u{ff}"] {
+            assert_eq!(
+                fs.lookup(ROOT_INO, missing.as_ref()).unwrap_err(),
+                Errno::NOENT
+            );
+        }
+    }
+}
@@ -3,22 +3,31 @@
 //! This crate provides a userspace filesystem implementation that exposes composefs
 //! directory trees through FUSE. It supports read-only access to files, directories,
 //! symlinks, and extended attributes, with data served from a composefs repository.
+//! Optionally, the tree can be made writable by serving it with an upper layer for the
+//! changes (see [`serve_tree_fuse_overlay`]).  EROFS images can also be served directly,
+//! without building a tree first (see [`serve_image_fuse`]).
+//!
+//! Objects without fs-verity enabled in the kernel can optionally be verified in userspace as
+//! they're read (see [`FuseOptions::verify_objects`]).  Reads can be served on a pool of threads
+//! (see [`FuseOptions::threads`]).  FUSE passthrough, which lets the kernel read the repository
+//! objects itself, needs version 7.40 of the FUSE protocol, which fuser doesn't support yet.
 
 use std::{
     collections::HashMap,
-    ffi::OsStr,
+    ffi::{c_int, OsStr},
     os::{
         fd::{AsFd, AsRawFd, OwnedFd},
         unix::ffi::OsStrExt,
     },
     rc::Rc,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
 use anyhow::Context;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
-    Request, Session, SessionACL,
+    consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEntry, ReplyOpen, ReplyXattr, Request, Session, SessionACL,
 };
 use rustix::{
     buffer::spare_capacity,
@@ -28,6 +37,7 @@ use rustix::{
         fsconfig_create, fsconfig_set_flag, fsconfig_set_string, fsmount, FsMountFlags,
         MountAttrFlags,
     },
+    process::{getgid, getuid},
 };
 
 use composefs::{
@@ -37,6 +47,17 @@ use composefs::{
     tree::{Directory, Inode, Leaf, LeafContent, RegularFile, Stat},
 };
 
+mod image;
+mod overlay;
+mod readers;
+mod verity;
+
+pub use image::serve_image_fuse;
+pub use overlay::{serve_tree_fuse_overlay, UpperLayer};
+
+use readers::Readers;
+use verity::{Objects, VerifiedObject};
+
 const TTL: Duration = Duration::from_secs(1_000_000);
 
 #[derive(Debug, Clone)]
@@ -45,12 +66,44 @@ enum InodeRef<'a, ObjectID: FsVerityHashValue> {
     Leaf(&'a Rc<Leaf<ObjectID>>),
 }
 
+/// Options for serving FUSE filesystems.
+#[derive(Debug, Clone, Default)]
+pub struct FuseOptions {
+    /// Verify the content of objects against their fs-verity digests in userspace, for objects
+    /// which don't have fs-verity enabled in the kernel (as can be the case in insecure
+    /// repositories).  Reads of data which doesn't match fail with EIO.
+    pub verify_objects: bool,
+    /// The number of threads serving reads of file content, in addition to the thread receiving
+    /// requests.  With 0 (the default), reads are served by that thread, one at a time.  The
+    /// writable overlay always serves reads on the session thread.
+    pub threads: usize,
+}
+
 #[derive(Debug)]
-enum OpenHandle {
+enum OpenHandle<ObjectID: FsVerityHashValue> {
     Fd(OwnedFd),
+    Verified(OwnedFd, Arc<VerifiedObject<ObjectID>>),
     Data(Box<[u8]>),
 }
 
+impl<ObjectID: FsVerityHashValue> OpenHandle<ObjectID> {
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        match self {
+            OpenHandle::Fd(fd) => {
+                let mut data = Vec::with_capacity(size as usize);
+                pread(fd, spare_capacity(&mut data), offset)?;
+                Ok(data)
+            }
+            OpenHandle::Verified(fd, object) => object.read(fd, offset, size),
+            OpenHandle::Data(data) => {
+                let start = data.len().min(offset as usize);
+                let end = data.len().min(start + size as usize);
+                Ok(data[start..end].to_vec())
+            }
+        }
+    }
+}
+
 impl<'a, ObjectID: FsVerityHashValue> InodeRef<'a, ObjectID> {
     fn new(inode: &'a Inode<ObjectID>, parent: u64) -> Self {
         match inode {
@@ -148,74 +201,177 @@ impl<'a, ObjectID: FsVerityHashValue> InodeRef<'a, ObjectID> {
     }
 }
 
+/// Replies to a getxattr or listxattr request, following the convention that a `size` of 0 is a
+/// request for the size of the value.
+fn reply_xattr(reply: ReplyXattr, value: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(Errno::RANGE.raw_os_error());
+    } else {
+        reply.data(value);
+    }
+}
+
+/// An inode of the tree, along with its attributes.  The tree is immutable, so the attributes are
+/// computed once, when the inode is first seen.
+#[derive(Debug)]
+struct Node<'a, ObjectID: FsVerityHashValue> {
+    iref: InodeRef<'a, ObjectID>,
+    attr: FileAttr,
+}
+
 #[derive(Debug)]
 struct TreeFuse<'a, ObjectID: FsVerityHashValue> {
-    repo: &'a Repository<ObjectID>,
-    inodes: HashMap<u64, InodeRef<'a, ObjectID>>,
-    attrs: HashMap<u64, FileAttr>,
-    handles: HashMap<u64, OpenHandle>,
+    objects: Objects<'a, ObjectID>,
+    nodes: HashMap<u64, Node<'a, ObjectID>>,
+    handles: HashMap<u64, Arc<OpenHandle<ObjectID>>>,
     next_fh: u64,
+    readers: Readers<ObjectID>,
 }
 
 impl<'a, ObjectID: FsVerityHashValue> TreeFuse<'a, ObjectID> {
-    fn inode_ref(&mut self, inode: &'a Inode<ObjectID>, parent: u64) -> InodeRef<'a, ObjectID> {
+    fn new(
+        root: &'a Directory<ObjectID>,
+        repo: &'a Repository<ObjectID>,
+        options: FuseOptions,
+    ) -> Self {
+        let iref = InodeRef::Directory(root, 1);
+        let attr = FileAttr {
+            ino: 1,
+            ..iref.fileattr()
+        };
+        Self {
+            objects: Objects::new(repo, options.verify_objects),
+            nodes: HashMap::from([(1, Node { iref, attr })]),
+            handles: HashMap::new(),
+            next_fh: 1,
+            readers: Readers::new(options.threads),
+        }
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node<'a, ObjectID>, Errno> {
+        self.nodes.get(&ino).ok_or(Errno::BADF)
+    }
+
+    /// Returns the attributes of an inode in the directory `parent`, registering it if required.
+    fn insert(&mut self, inode: &'a Inode<ObjectID>, parent: u64) -> &FileAttr {
         let iref = InodeRef::new(inode, parent);
-        self.inodes.insert(iref.ino(), iref.clone());
-        iref
+        &self
+            .nodes
+            .entry(iref.ino())
+            .or_insert_with(|| Node {
+                attr: iref.fileattr(),
+                iref,
+            })
+            .attr
+    }
+
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, Errno> {
+        let InodeRef::Directory(dir, ..) = self.node(parent)?.iref else {
+            return Err(Errno::NOTDIR);
+        };
+        let inode = dir.lookup(name).ok_or(Errno::NOENT)?;
+        Ok(*self.insert(inode, parent))
+    }
+
+    /// Lists the entries of a directory, starting at `offset`.  `add` is called with the offset
+    /// of the next entry, the attributes and the name of each entry, and returns true to stop.
+    fn readdir(
+        &mut self,
+        ino: u64,
+        mut offset: i64,
+        mut add: impl FnMut(i64, &FileAttr, &OsStr) -> bool,
+    ) -> Result<(), Errno> {
+        let InodeRef::Directory(dir, parent) = self.node(ino)?.iref else {
+            return Err(Errno::NOTDIR);
+        };
+
+        if offset == 0 {
+            offset += 1;
+            if add(offset, &self.node(ino)?.attr, OsStr::new(".")) {
+                return Ok(());
+            }
+        }
+
+        if offset == 1 {
+            offset += 1;
+            if add(offset, &self.node(parent)?.attr, OsStr::new("..")) {
+                return Ok(());
+            }
+        }
+
+        for (name, inode) in dir.sorted_entries().skip(offset as usize - 2) {
+            offset += 1;
+            if add(offset, self.insert(inode, ino), name) {
+                break;
+            }
+        }
+
+        Ok(())
     }
 
-    fn iref_fileattr(&mut self, iref: &InodeRef<ObjectID>) -> &FileAttr {
-        self.attrs.insert(iref.ino(), iref.fileattr());
-        self.attrs.get(&iref.ino()).unwrap()
+    fn open(&mut self, ino: u64) -> Result<u64, Errno> {
+        let InodeRef::Leaf(leaf) = self.node(ino)?.iref else {
+            return Err(Errno::ISDIR);
+        };
+
+        let handle = match &leaf.content {
+            LeafContent::Regular(RegularFile::External(id, ..)) => self.objects.open(id)?,
+            LeafContent::Regular(RegularFile::Inline(data)) => OpenHandle::Data(data.clone()),
+            _ => return Err(Errno::INVAL),
+        };
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        log::debug!("self.handles.insert({fh}, {handle:?})");
+        self.handles.insert(fh, Arc::new(handle));
+        Ok(fh)
     }
 
-    fn inode_fileattr(&mut self, inode: &'a Inode<ObjectID>, parent: u64) -> &FileAttr {
-        let iref = self.inode_ref(inode, parent);
-        self.attrs.insert(iref.ino(), iref.fileattr());
-        self.attrs.get(&iref.ino()).unwrap()
+    #[cfg(test)]
+    fn read(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        self.handles.get(&fh).ok_or(Errno::BADF)?.read(offset, size)
     }
 }
 
 impl<ObjectID: FsVerityHashValue> Filesystem for TreeFuse<'_, ObjectID> {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // Let the kernel fetch the attributes of directory entries along with the entries, rather
+        // than looking them up one by one.  This is only an optimisation, so ignore failures.
+        let _ =
+            config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO);
+        Ok(())
+    }
+
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
         reply.statfs(0, 0, 0, 0, 0, 4096, 255, 4096);
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         log::trace!("lookup {parent} {name:?}");
-        let Some(InodeRef::Directory(dir, ..)) = self.inodes.get(&parent) else {
-            log::error!("lookup({parent}, {name:?}) parent does not exist");
-            return reply.error(Errno::BADF.raw_os_error());
-        };
-
-        // dir is &&Directory which means it holds a reference to the image and also a reference to
-        // self.  Dereference to drop the spurious self-reference to allow further mutability.
-        let dir = *dir;
-
-        match dir.lookup(name) {
-            Some(inode) => reply.entry(&TTL, self.inode_fileattr(inode, parent), 0),
-            None => reply.error(Errno::NOENT.raw_os_error()),
+        match Self::lookup(self, parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err.raw_os_error()),
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        if let Some(attrs) = self.attrs.get(&ino) {
-            return reply.attr(&TTL, attrs);
+        match self.node(ino) {
+            Ok(node) => reply.attr(&TTL, &node.attr),
+            Err(err) => {
+                log::error!("getattr({ino}) inode does not exist");
+                reply.error(err.raw_os_error())
+            }
         }
-
-        let Some(iref) = self.inodes.get(&ino) else {
-            log::error!("getattr({ino}) inode does not exist");
-            return reply.error(Errno::BADF.raw_os_error());
-        };
-
-        // iref is &InodeRef which means it holds a reference to self.  Drop that.
-        let iref = iref.clone();
-
-        reply.attr(&TTL, self.iref_fileattr(&iref));
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        let Some(InodeRef::Leaf(leaf, ..)) = self.inodes.get(&ino) else {
+        let Ok(Node {
+            iref: InodeRef::Leaf(leaf, ..),
+            ..
+        }) = self.node(ino)
+        else {
             return reply.error(Errno::INVAL.raw_os_error());
         };
 
@@ -235,38 +391,39 @@ impl<ObjectID: FsVerityHashValue> Filesystem for TreeFuse<'_, ObjectID> {
         _req: &Request,
         ino: u64,
         _fh: u64,
-        mut offset: i64,
+        offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let Some(InodeRef::Directory(dir, parent)) = self.inodes.get(&ino) else {
-            log::error!("readdir({ino}) inode is not a directory");
-            return reply.error(Errno::BADF.raw_os_error());
-        };
-
-        if offset == 0 {
-            offset += 1;
-            if reply.add(ino, offset, FileType::Directory, ".") {
-                return reply.ok();
+        let result = Self::readdir(self, ino, offset, |offset, attr, name| {
+            reply.add(attr.ino, offset, attr.kind, name)
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                log::error!("readdir({ino}) failed: {err}");
+                reply.error(err.raw_os_error())
             }
         }
+    }
 
-        if offset == 1 {
-            offset += 1;
-            if reply.add(*parent, offset, FileType::Directory, "..") {
-                return reply.ok();
-            }
-        }
-
-        for (name, inode) in dir.sorted_entries().skip(offset as usize - 2) {
-            let iref = self.inode_ref(inode, ino);
-
-            offset += 1;
-            if reply.add(iref.ino(), offset, iref.kind(), name) {
-                break;
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let result = Self::readdir(self, ino, offset, |offset, attr, name| {
+            reply.add(attr.ino, offset, name, &TTL, attr, 0)
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                log::error!("readdirplus({ino}) failed: {err}");
+                reply.error(err.raw_os_error())
             }
         }
-
-        reply.ok();
     }
 
     fn releasedir(
@@ -286,80 +443,45 @@ impl<ObjectID: FsVerityHashValue> Filesystem for TreeFuse<'_, ObjectID> {
         ino: u64,
         name: &OsStr,
         size: u32,
-        reply: fuser::ReplyXattr,
+        reply: ReplyXattr,
     ) {
-        let Some(iref) = self.inodes.get(&ino) else {
+        let Ok(node) = self.node(ino) else {
             log::error!("getxattr({ino}, {name:?}, {size}) inode does not exist");
             return reply.error(Errno::BADF.raw_os_error());
         };
 
-        let xattrs = iref.stat().xattrs.borrow();
-        let Some(value) = xattrs.get(name) else {
-            return reply.error(Errno::NODATA.raw_os_error());
-        };
-
-        if size == 0 {
-            return reply.size(value.len() as u32);
-        } else if value.len() > size as usize {
-            return reply.error(Errno::RANGE.raw_os_error());
+        match node.iref.stat().xattrs.borrow().get(name) {
+            Some(value) => reply_xattr(reply, value, size),
+            None => reply.error(Errno::NODATA.raw_os_error()),
         }
-
-        reply.data(value);
     }
 
-    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        let Some(iref) = self.inodes.get(&ino) else {
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let Ok(node) = self.node(ino) else {
             log::error!("listxattr({ino}, {size}) inode does not exist");
             return reply.error(Errno::BADF.raw_os_error());
         };
 
         let mut list = vec![];
-        for name in iref.stat().xattrs.borrow().keys() {
+        for name in node.iref.stat().xattrs.borrow().keys() {
             list.extend_from_slice(name.as_bytes());
             list.push(b'\0');
         }
 
-        if size == 0 {
-            return reply.size(list.len() as u32);
-        } else if list.len() > size as usize {
-            return reply.error(Errno::RANGE.raw_os_error());
-        }
-
-        reply.data(&list);
+        reply_xattr(reply, &list, size);
     }
 
     fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
         log::trace!("open({ino})");
-        let Some(iref) = self.inodes.get(&ino) else {
-            log::error!("open({ino}) inode does not exist");
-            return reply.error(Errno::BADF.raw_os_error());
-        };
-
-        let InodeRef::Leaf(leaf) = iref else {
-            log::error!("open({ino}) inode is a directory");
-            return reply.error(Errno::BADF.raw_os_error());
-        };
-
-        let handle = match &leaf.content {
-            LeafContent::Regular(RegularFile::External(id, ..)) => {
-                let Ok(fd) = self.repo.open_object(id) else {
-                    log::error!("open({ino}) open object failed");
-                    return reply.error(Errno::INVAL.raw_os_error());
-                };
-                OpenHandle::Fd(fd)
-            }
-            LeafContent::Regular(RegularFile::Inline(data)) => OpenHandle::Data(data.clone()),
-            _ => {
-                log::error!("open({ino}) non-regular file");
-                return reply.error(Errno::BADF.raw_os_error());
+        match Self::open(self, ino) {
+            // The content of the tree never changes, so the kernel can keep cached pages around
+            // across opens.
+            Ok(fh) => reply.opened(fh, consts::FOPEN_KEEP_CACHE),
+            Err(err) => {
+                log::error!("open({ino}) failed: {err}");
+                reply.error(err.raw_os_error())
             }
-        };
-
-        let fh = self.next_fh;
-        self.next_fh += 1;
-        log::debug!("self.handles.insert({fh}, {handle:?})");
-        self.handles.insert(fh, handle);
-        reply.opened(fh, 0);
+        }
     }
 
     fn read(
@@ -374,27 +496,10 @@ impl<ObjectID: FsVerityHashValue> Filesystem for TreeFuse<'_, ObjectID> {
         reply: fuser::ReplyData,
     ) {
         match self.handles.get(&fh) {
-            Some(OpenHandle::Fd(fd)) => {
-                let mut data = Vec::with_capacity(size as usize);
-                match pread(fd, spare_capacity(&mut data), offset as u64) {
-                    Ok(_) => reply.data(&data),
-                    Err(errno) => reply.error(errno.raw_os_error()),
-                }
-            }
-            Some(OpenHandle::Data(data)) => {
-                if offset as usize > data.len() {
-                    reply.data(b"");
-                } else {
-                    let mut data = &data[offset as usize..];
-                    if data.len() > size as usize {
-                        data = &data[..size as usize];
-                    }
-                    reply.data(data);
-                }
-            }
+            Some(handle) => self.readers.read(handle, offset as u64, size, reply),
             None => {
                 log::error!("Handle doesn't exist: pread({fh}, {size}, {offset})");
-                reply.error(Errno::BADF.raw_os_error());
+                reply.error(Errno::BADF.raw_os_error())
             }
         }
     }
@@ -435,14 +540,59 @@ pub fn open_fuse() -> anyhow::Result<OwnedFd> {
 /// order for this to be useful, you'll also need to call serve_tree_fuse() to actually satisfy the
 /// requests for data.
 pub fn mount_fuse(dev_fuse: impl AsFd) -> anyhow::Result<OwnedFd> {
+    mount_fuse_with_options(dev_fuse, &MountOptions::default())
+}
+
+/// Mounts a writable FUSE filesystem with the given /dev/fuse fd.
+///
+/// This is the same as mount_fuse(), except that the mount isn't read-only.  Use it together with
+/// serve_tree_fuse_overlay().
+pub fn mount_fuse_writable(dev_fuse: impl AsFd) -> anyhow::Result<OwnedFd> {
+    let options = MountOptions {
+        readonly: false,
+        ..Default::default()
+    };
+    mount_fuse_with_options(dev_fuse, &options)
+}
+
+/// Options for mounting FUSE filesystems.
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    /// Make the mount read-only.  This is the default.
+    pub readonly: bool,
+    /// Allow users other than the one doing the mount to access the filesystem.  This is the
+    /// default.  Otherwise, only processes running as the current user can access it.
+    pub allow_other: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            readonly: true,
+            allow_other: true,
+        }
+    }
+}
+
+/// Mounts a FUSE filesystem with the given /dev/fuse fd and options.
+///
+/// This is the same as mount_fuse(), with control over the mount options.
+pub fn mount_fuse_with_options(
+    dev_fuse: impl AsFd,
+    options: &MountOptions,
+) -> anyhow::Result<OwnedFd> {
     let fusefs = FsHandle::open("fuse")?;
-    fsconfig_set_flag(fusefs.as_fd(), "ro")?;
+    if options.readonly {
+        fsconfig_set_flag(fusefs.as_fd(), "ro")?;
+    }
     fsconfig_set_flag(fusefs.as_fd(), "default_permissions")?;
-    fsconfig_set_flag(fusefs.as_fd(), "allow_other")?;
+    if options.allow_other {
+        fsconfig_set_flag(fusefs.as_fd(), "allow_other")?;
+    }
     fsconfig_set_string(fusefs.as_fd(), "source", "composefs-fuse")?;
     fsconfig_set_string(fusefs.as_fd(), "rootmode", "040555")?;
-    fsconfig_set_string(fusefs.as_fd(), "user_id", "0")?;
-    fsconfig_set_string(fusefs.as_fd(), "group_id", "0")?;
+    fsconfig_set_string(fusefs.as_fd(), "user_id", getuid().as_raw().to_string())?;
+    fsconfig_set_string(fusefs.as_fd(), "group_id", getgid().as_raw().to_string())?;
     fsconfig_set_string(
         fusefs.as_fd(),
         "fd",
@@ -463,13 +613,243 @@ pub fn serve_tree_fuse<'a, ObjectID: FsVerityHashValue>(
     dev_fuse: OwnedFd,
     root: &'a Directory<ObjectID>,
     repo: &'a Repository<ObjectID>,
+    options: FuseOptions,
 ) -> std::io::Result<()> {
-    let fs = TreeFuse::<ObjectID> {
-        repo,
-        inodes: HashMap::from([(1, InodeRef::Directory(root, 1))]),
-        attrs: Default::default(),
-        handles: Default::default(),
-        next_fh: 1,
-    };
+    let fs = TreeFuse::new(root, repo, options);
     Session::from_fd(fs, dev_fuse, SessionACL::All).run()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use composefs::{dumpfile::dumpfile_to_filesystem, fsverity::Sha256HashValue};
+    use rustix::fs::CWD;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn names(fs: &mut TreeFuse<Sha256HashValue>, ino: u64) -> Vec<(u64, String)> {
+        let mut list = vec![];
+        TreeFuse::readdir(fs, ino, 0, |_, attr, name| {
+            list.push((attr.ino, name.to_string_lossy().into_owned()));
+            false
+        })
+        .unwrap();
+        list
+    }
+
+    fn read_all(fs: &mut TreeFuse<Sha256HashValue>, ino: u64) -> Vec<u8> {
+        let fh = TreeFuse::open(fs, ino).unwrap();
+        let data = TreeFuse::read(fs, fh, 0, 4096).unwrap();
+        fs.handles.remove(&fh);
+        data
+    }
+
+    #[test]
+    fn test_tree() {
+        let tmp = TempDir::new().unwrap();
+        let mut repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        repo.set_insecure(true);
+        let object = repo.ensure_object(b"external content").unwrap();
+
+        let dumpfile = format!(
+            "/ 4096 40755 3 0 0 0 0.0 - - -\n\
+             /dir 4096 40700 2 1000 1000 0 1000.0 - - -\n\
+             /dir/inline 6 100644 2 0 0 0 0.0 - inline -\n\
+             /external 16 100644 1 0 0 0 0.0 {}/{} - {}\n\
+             /hardlink 0 @100644 2 0 0 0 0.0 /dir/inline - -\n\
+             /symlink 3 120777 1 0 0 0 0.0 dir - -\n",
+            &object.to_hex()[..2],
+            &object.to_hex()[2..],
+            object.to_hex(),
+        );
+        let tree = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+        let mut fs = TreeFuse::new(&tree.root, &repo, FuseOptions::default());
+
+        let root = fs.node(1).unwrap().attr;
+        assert_eq!(
+            (root.ino, root.kind, root.nlink),
+            (1, FileType::Directory, 3)
+        );
+
+        let dir = TreeFuse::lookup(&mut fs, 1, "dir".as_ref()).unwrap();
+        assert_eq!((dir.perm, dir.uid, dir.nlink), (0o700, 1000, 2));
+        assert_eq!(fs.node(dir.ino).unwrap().attr, dir);
+        assert_eq!(
+            TreeFuse::lookup(&mut fs, 1, "missing".as_ref()).unwrap_err(),
+            Errno::NOENT
+        );
+
+        // Listing a directory returns the same attributes as looking up its entries
+        let list = names(&mut fs, 1);
+        let expected = [".", "..", "dir", "external", "hardlink", "symlink"];
+        assert_eq!(
+            list.iter().map(|(_, name)| name).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!((list[0].0, list[1].0, list[2].0), (1, 1, dir.ino));
+        for (ino, name) in &list[2..] {
+            let attr = TreeFuse::lookup(&mut fs, 1, name.as_ref()).unwrap();
+            assert_eq!(attr.ino, *ino);
+        }
+        assert_eq!(names(&mut fs, dir.ino)[1], (1, "..".to_string()));
+
+        // Listing can resume at an offset and stop early
+        let mut offsets = vec![];
+        TreeFuse::readdir(&mut fs, 1, 3, |offset, _, _| {
+            offsets.push(offset);
+            offsets.len() == 2
+        })
+        .unwrap();
+        assert_eq!(offsets, [4, 5]);
+
+        let inline = TreeFuse::lookup(&mut fs, dir.ino, "inline".as_ref()).unwrap();
+        let hardlink = TreeFuse::lookup(&mut fs, 1, "hardlink".as_ref()).unwrap();
+        assert_eq!((inline.ino, inline.nlink), (hardlink.ino, 2));
+        assert_eq!(read_all(&mut fs, inline.ino), b"inline");
+
+        let external = TreeFuse::lookup(&mut fs, 1, "external".as_ref()).unwrap();
+        assert_eq!(external.size, 16);
+        assert_eq!(read_all(&mut fs, external.ino), b"external content");
+
+        assert_eq!(TreeFuse::open(&mut fs, dir.ino).unwrap_err(), Errno::ISDIR);
+        let symlink = TreeFuse::lookup(&mut fs, 1, "symlink".as_ref()).unwrap();
+        assert_eq!(
+            TreeFuse::open(&mut fs, symlink.ino).unwrap_err(),
+            Errno::INVAL
+        );
+        assert_eq!(TreeFuse::read(&fs, 1234, 0, 1).unwrap_err(), Errno::BADF);
+    }
+
+    /// Compares listing a large directory with readdir followed by a lookup of each entry (what
+    /// the kernel has to do without readdirplus) against listing it with readdirplus.
+    ///
+    /// Run with `cargo test -p composefs-fuse --release -- --ignored --nocapture bench_tree`.
+    #[test]
+    #[ignore]
+    fn bench_tree() {
+        const ENTRIES: usize = 100_000;
+
+        let tmp = TempDir::new().unwrap();
+        let repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        let mut dumpfile = "/ 4096 40755 2 0 0 0 0.0 - - -\n".to_string();
+        for n in 0..ENTRIES {
+            dumpfile.push_str(&format!("/file{n:06} 1 100644 1 0 0 0 0.0 - x -\n"));
+        }
+        let tree = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+
+        let mut fs = TreeFuse::new(&tree.root, &repo, FuseOptions::default());
+        let start = Instant::now();
+        let mut entries = vec![];
+        TreeFuse::readdir(&mut fs, 1, 0, |_, _, name| {
+            entries.push(name.to_os_string());
+            false
+        })
+        .unwrap();
+        for name in &entries[2..] {
+            TreeFuse::lookup(&mut fs, 1, name).unwrap();
+        }
+        let lookups = start.elapsed();
+
+        let mut fs = TreeFuse::new(&tree.root, &repo, FuseOptions::default());
+        let start = Instant::now();
+        let mut count = 0;
+        TreeFuse::readdir(&mut fs, 1, 0, |_, _, _| {
+            count += 1;
+            false
+        })
+        .unwrap();
+        let readdirplus = start.elapsed();
+        assert_eq!(count, entries.len());
+
+        println!("{ENTRIES} entries: readdir + lookup {lookups:?}, readdirplus {readdirplus:?}");
+    }
+
+    /// Measures the throughput of reading files in parallel through a real mount, with reads
+    /// served on the session thread or on a pool of threads, and with and without userspace
+    /// verification, against reading the objects from the repository directly.
+    ///
+    /// This needs permission to mount FUSE filesystems (e.g. root, or `unshare -Umr`).  Run with
+    /// `cargo test -p composefs-fuse --release -- --ignored --nocapture bench_mount`.
+    #[test]
+    #[ignore]
+    fn bench_mount() {
+        const FILES: usize = 8;
+        const FILE_SIZE: usize = 64 << 20;
+
+        let tmp = TempDir::new().unwrap();
+        let mut repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        repo.set_insecure(true);
+        let mut dumpfile = "/ 4096 40755 2 0 0 0 0.0 - - -\n".to_string();
+        let mut objects = vec![];
+        for n in 0..FILES {
+            let content: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251 + n) as u8).collect();
+            let object = repo.ensure_object(&content).unwrap();
+            dumpfile.push_str(&format!(
+                "/file{n} {FILE_SIZE} 100644 1 0 0 0 0.0 {}/{} - {}\n",
+                &object.to_hex()[..2],
+                &object.to_hex()[2..],
+                object.to_hex(),
+            ));
+            objects.push(object);
+        }
+        let tree = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+
+        // Reads all the files at once, one thread per file, and returns the throughput in MiB/s
+        let read_files = |open: &(dyn Fn(usize) -> OwnedFd + Sync)| {
+            let start = Instant::now();
+            std::thread::scope(|scope| {
+                for n in 0..FILES {
+                    scope.spawn(move || {
+                        let fd = open(n);
+                        let mut buf = vec![0; 1 << 20];
+                        let mut total = 0;
+                        loop {
+                            match rustix::io::read(&fd, &mut buf).unwrap() {
+                                0 => break,
+                                size => total += size,
+                            }
+                        }
+                        assert_eq!(total, FILE_SIZE);
+                    });
+                }
+            });
+            ((FILES * FILE_SIZE) >> 20) as f64 / start.elapsed().as_secs_f64()
+        };
+
+        let direct = read_files(&|n| repo.open_object(&objects[n]).unwrap());
+        println!(
+            "{FILES} files of {} MiB: direct {direct:.0} MiB/s",
+            FILE_SIZE >> 20
+        );
+
+        let cpus = std::thread::available_parallelism().unwrap().get();
+        for verify_objects in [false, true] {
+            for threads in [0, cpus] {
+                let dev_fuse = open_fuse().unwrap();
+                let mnt = mount_fuse(&dev_fuse).unwrap();
+                let options = FuseOptions {
+                    verify_objects,
+                    threads,
+                };
+                let rate = std::thread::scope(|scope| {
+                    let reader = scope.spawn(move || {
+                        let rate = read_files(&|n| {
+                            let name = format!("file{n}");
+                            rustix::fs::openat(&mnt, name, OFlags::RDONLY, Mode::empty()).unwrap()
+                        });
+                        // Unmounting ends the session
+                        drop(mnt);
+                        rate
+                    });
+                    serve_tree_fuse(dev_fuse, &tree.root, &repo, options).unwrap();
+                    reader.join().unwrap()
+                });
+                println!(
+                    "mounted (verify_objects: {verify_objects}, threads: {threads}): {rate:.0} MiB/s"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,1292 @@
+//! A writable FUSE view of a composefs tree.
+//!
+//! [`serve_tree_fuse_overlay`] serves the same content as [`crate::serve_tree_fuse`], but allows
+//! changing it, in the style of overlayfs: the composefs tree (the "lower layer") is never
+//! modified, and the content of files which are created or written to is kept in an "upper layer"
+//! (see [`UpperLayer`]).  Regular files from the tree are copied up when they are first opened for
+//! writing or truncated.  Files which haven't been copied up are read through
+//! [`Repository::open_object`], so the fs-verity digests of objects are verified as usual (or in
+//! userspace, with [`crate::FuseOptions::verify_objects`]).
+//!
+//! The directory structure, file attributes and xattrs are kept in memory.  Nothing is persisted:
+//! all changes are lost when the filesystem is unmounted.  This makes it possible to run a
+//! composefs-based root filesystem without root privileges and without kernel overlayfs, for
+//! example in unprivileged development containers.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::Write,
+    os::{fd::OwnedFd, unix::ffi::OsStrExt},
+    path::Path,
+    rc::Rc,
+    time::SystemTime,
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, Session, SessionACL, TimeOrNow,
+};
+use rustix::{
+    fs::{
+        fsync, ftruncate, memfd_create, openat, MemfdFlags, Mode, OFlags, RenameFlags, XattrFlags,
+    },
+    io::{pwrite, Errno},
+};
+
+use composefs::{
+    fsverity::FsVerityHashValue,
+    repository::Repository,
+    tree::{Directory, Inode, Leaf, LeafContent, RegularFile},
+};
+
+use crate::{reply_xattr, verity::Objects, FuseOptions, InodeRef, OpenHandle, TTL};
+
+const ROOT_INO: u64 = 1;
+
+/// How much data to copy at once when copying up a file.
+const COPY_CHUNK_SIZE: u32 = 128 * 1024;
+
+/// Where the content of files which are created or modified in a writable mount is stored.
+#[derive(Debug)]
+pub enum UpperLayer {
+    /// Anonymous memory-backed files (see memfd_create(2)).
+    Memory,
+    /// Unnamed temporary files in the given scratch directory, which must be on a filesystem that
+    /// supports `O_TMPFILE`.  Nothing is left behind in the directory after unmounting.
+    Directory(OwnedFd),
+}
+
+impl UpperLayer {
+    fn create_file(&self) -> Result<OwnedFd, Errno> {
+        match self {
+            UpperLayer::Memory => memfd_create("composefs-fuse", MemfdFlags::CLOEXEC),
+            UpperLayer::Directory(dirfd) => openat(
+                dirfd,
+                ".",
+                OFlags::RDWR | OFlags::TMPFILE | OFlags::CLOEXEC,
+                Mode::RUSR | Mode::WUSR,
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Content<'a, ObjectID: FsVerityHashValue> {
+    /// A directory, possibly backed by one from the lower layer.  The entries are only filled in
+    /// from the lower directory once they are first needed.
+    Directory {
+        lower: Option<&'a Directory<ObjectID>>,
+        entries: Option<BTreeMap<OsString, u64>>,
+    },
+    /// A regular file from the lower layer which hasn't been copied up.
+    Lower(&'a Leaf<ObjectID>),
+    /// A regular file in the upper layer.
+    Upper(OwnedFd),
+    Symlink(Box<OsStr>),
+    /// A device node, fifo or socket: everything we need to know about it is in the attributes.
+    Special,
+}
+
+#[derive(Debug)]
+struct Node<'a, ObjectID: FsVerityHashValue> {
+    content: Content<'a, ObjectID>,
+    attr: FileAttr,
+    xattrs: BTreeMap<Box<OsStr>, Box<[u8]>>,
+    /// The parent directory.  Only meaningful for directories.
+    parent: u64,
+    /// The number of lookups the kernel holds on this node.  Nodes are dropped when they have been
+    /// unlinked and the kernel has forgotten about them.
+    lookups: u64,
+}
+
+/// The owner of newly-created nodes.
+#[derive(Debug, Clone, Copy)]
+struct Owner {
+    uid: u32,
+    gid: u32,
+}
+
+impl From<&Request<'_>> for Owner {
+    fn from(req: &Request<'_>) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+}
+
+/// The changes requested by a setattr call.
+#[derive(Debug, Default)]
+struct SetAttr {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+}
+
+fn new_attr(kind: FileType, mode: u32, rdev: u32, owner: Owner) -> FileAttr {
+    let now = SystemTime::now();
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind,
+        perm: (mode & 0o7777) as u16,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: owner.uid,
+        gid: owner.gid,
+        rdev,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+fn io_errno(err: std::io::Error) -> Errno {
+    Errno::from_io_error(&err).unwrap_or(Errno::IO)
+}
+
+#[derive(Debug)]
+struct OverlayFuse<'a, ObjectID: FsVerityHashValue> {
+    objects: Objects<'a, ObjectID>,
+    upper: UpperLayer,
+    nodes: HashMap<u64, Node<'a, ObjectID>>,
+    /// The inode numbers of the leaves of the lower layer, so that hardlinks stay hardlinked.
+    lower_leaves: HashMap<*const Leaf<ObjectID>, u64>,
+    next_ino: u64,
+    handles: HashMap<u64, OpenHandle<ObjectID>>,
+    next_fh: u64,
+}
+
+impl<'a, ObjectID: FsVerityHashValue> OverlayFuse<'a, ObjectID> {
+    fn new(
+        root: &'a Directory<ObjectID>,
+        repo: &'a Repository<ObjectID>,
+        upper: UpperLayer,
+        options: FuseOptions,
+    ) -> Self {
+        let mut fs = Self {
+            objects: Objects::new(repo, options.verify_objects),
+            upper,
+            nodes: HashMap::new(),
+            lower_leaves: HashMap::new(),
+            next_ino: ROOT_INO,
+            handles: HashMap::new(),
+            next_fh: 1,
+        };
+        fs.lower_directory(root, ROOT_INO);
+        fs
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node<'a, ObjectID>, Errno> {
+        self.nodes.get(&ino).ok_or(Errno::BADF)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> Result<&mut Node<'a, ObjectID>, Errno> {
+        self.nodes.get_mut(&ino).ok_or(Errno::BADF)
+    }
+
+    fn alloc(
+        &mut self,
+        content: Content<'a, ObjectID>,
+        mut attr: FileAttr,
+        xattrs: BTreeMap<Box<OsStr>, Box<[u8]>>,
+        parent: u64,
+    ) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        attr.ino = ino;
+        attr.perm &= 0o7777;
+        let node = Node {
+            content,
+            attr,
+            xattrs,
+            parent,
+            lookups: 0,
+        };
+        self.nodes.insert(ino, node);
+        ino
+    }
+
+    fn lower_directory(&mut self, dir: &'a Directory<ObjectID>, parent: u64) -> u64 {
+        let content = Content::Directory {
+            lower: Some(dir),
+            entries: None,
+        };
+        let attr = InodeRef::Directory(dir, parent).fileattr();
+        let xattrs = dir.stat.xattrs.borrow().clone();
+        self.alloc(content, attr, xattrs, parent)
+    }
+
+    fn lower_leaf(&mut self, leaf: &'a Rc<Leaf<ObjectID>>) -> u64 {
+        if let Some(ino) = self.lower_leaves.get(&Rc::as_ptr(leaf)) {
+            return *ino;
+        }
+
+        let content = match &leaf.content {
+            LeafContent::Regular(..) => Content::Lower(leaf),
+            LeafContent::Symlink(target) => Content::Symlink(target.clone()),
+            _ => Content::Special,
+        };
+        let attr = InodeRef::Leaf(leaf).fileattr();
+        let xattrs = leaf.stat.xattrs.borrow().clone();
+        let ino = self.alloc(content, attr, xattrs, 0);
+        self.lower_leaves.insert(Rc::as_ptr(leaf), ino);
+        ino
+    }
+
+    /// Returns the entries of a directory, filling them in from the lower layer if necessary.
+    fn entries(&mut self, ino: u64) -> Result<&mut BTreeMap<OsString, u64>, Errno> {
+        if let Content::Directory {
+            lower,
+            entries: None,
+        } = self.node(ino)?.content
+        {
+            let mut entries = BTreeMap::new();
+            for (name, inode) in lower.iter().flat_map(|dir| dir.sorted_entries()) {
+                let child = match inode {
+                    Inode::Directory(dir) => self.lower_directory(dir, ino),
+                    Inode::Leaf(leaf) => self.lower_leaf(leaf),
+                };
+                entries.insert(name.to_os_string(), child);
+            }
+            self.node_mut(ino)?.content = Content::Directory {
+                lower,
+                entries: Some(entries),
+            };
+        }
+
+        match &mut self.node_mut(ino)?.content {
+            Content::Directory {
+                entries: Some(entries),
+                ..
+            } => Ok(entries),
+            _ => Err(Errno::NOTDIR),
+        }
+    }
+
+    fn child(&mut self, parent: u64, name: &OsStr) -> Result<u64, Errno> {
+        self.entries(parent)?.get(name).copied().ok_or(Errno::NOENT)
+    }
+
+    fn is_dir(&self, ino: u64) -> Result<bool, Errno> {
+        Ok(self.node(ino)?.attr.kind == FileType::Directory)
+    }
+
+    /// Checks if `ino` is `dir` or one of its parent directories.
+    fn is_ancestor(&self, ino: u64, mut dir: u64) -> Result<bool, Errno> {
+        loop {
+            if dir == ino {
+                return Ok(true);
+            } else if dir == ROOT_INO {
+                return Ok(false);
+            }
+            dir = self.node(dir)?.parent;
+        }
+    }
+
+    fn touch(&mut self, ino: u64) -> Result<(), Errno> {
+        let node = self.node_mut(ino)?;
+        node.attr.mtime = SystemTime::now();
+        node.attr.ctime = node.attr.mtime;
+        Ok(())
+    }
+
+    /// Returns the attributes of a node for an entry reply, which counts as a lookup.
+    fn entry(&mut self, ino: u64) -> Result<FileAttr, Errno> {
+        let node = self.node_mut(ino)?;
+        node.lookups += 1;
+        Ok(node.attr)
+    }
+
+    /// Drops a node if it's unreachable: unlinked, and forgotten by the kernel.  Open files keep
+    /// their own file descriptors, so they aren't affected by this.
+    fn maybe_drop(&mut self, ino: u64) -> Result<(), Errno> {
+        let node = self.node(ino)?;
+        if ino != ROOT_INO && node.attr.nlink == 0 && node.lookups == 0 {
+            self.nodes.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Adds an existing node to a directory.  This doesn't change the link count of the node.
+    fn add_entry(&mut self, parent: u64, name: &OsStr, ino: u64) -> Result<(), Errno> {
+        let entries = self.entries(parent)?;
+        if entries.contains_key(name) {
+            return Err(Errno::EXIST);
+        }
+        entries.insert(name.to_os_string(), ino);
+        self.touch(parent)?;
+
+        if self.is_dir(ino)? {
+            self.node_mut(ino)?.parent = parent;
+            self.node_mut(parent)?.attr.nlink += 1;
+        }
+        Ok(())
+    }
+
+    /// Removes an entry from a directory.  This doesn't change the link count of the node.
+    fn remove_entry(&mut self, parent: u64, name: &OsStr) -> Result<u64, Errno> {
+        let ino = self.entries(parent)?.remove(name).ok_or(Errno::NOENT)?;
+        self.touch(parent)?;
+
+        if self.is_dir(ino)? {
+            self.node_mut(parent)?.attr.nlink -= 1;
+        }
+        Ok(ino)
+    }
+
+    /// Accounts for a removed link to a node.
+    fn drop_link(&mut self, ino: u64) -> Result<(), Errno> {
+        let is_dir = self.is_dir(ino)?;
+        let node = self.node_mut(ino)?;
+        node.attr.nlink = if is_dir { 0 } else { node.attr.nlink - 1 };
+        node.attr.ctime = SystemTime::now();
+        self.maybe_drop(ino)
+    }
+
+    fn add_node(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        content: Content<'a, ObjectID>,
+        attr: FileAttr,
+    ) -> Result<FileAttr, Errno> {
+        if self.entries(parent)?.contains_key(name) {
+            return Err(Errno::EXIST);
+        }
+        let ino = self.alloc(content, attr, BTreeMap::new(), parent);
+        self.add_entry(parent, name, ino)?;
+        self.entry(ino)
+    }
+
+    /// Copies a regular file from the lower layer to the upper layer, unless it's already there.
+    ///
+    /// If `copy_data` is false, the file is left empty, which is useful if it's about to be
+    /// truncated anyway.
+    fn copy_up(&mut self, ino: u64, copy_data: bool) -> Result<(), Errno> {
+        let leaf = match self.node(ino)?.content {
+            Content::Upper(..) => return Ok(()),
+            Content::Lower(leaf) => leaf,
+            Content::Directory { .. } => return Err(Errno::ISDIR),
+            _ => return Err(Errno::INVAL),
+        };
+
+        let mut file = File::from(self.upper.create_file()?);
+        if copy_data {
+            match &leaf.content {
+                LeafContent::Regular(RegularFile::Inline(data)) => {
+                    file.write_all(data).map_err(io_errno)?;
+                }
+                LeafContent::Regular(RegularFile::External(id, ..)) => {
+                    // Read through the handle, so that the content gets verified if required
+                    let object = self.objects.open(id)?;
+                    let mut offset = 0;
+                    loop {
+                        let data = object.read(offset, COPY_CHUNK_SIZE)?;
+                        if data.is_empty() {
+                            break;
+                        }
+                        file.write_all(&data).map_err(io_errno)?;
+                        offset += data.len() as u64;
+                    }
+                }
+                _ => return Err(Errno::INVAL),
+            }
+        }
+
+        let node = self.node_mut(ino)?;
+        node.content = Content::Upper(file.into());
+        if !copy_data {
+            node.attr.size = 0;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, ino: u64, size: u64) -> Result<(), Errno> {
+        self.copy_up(ino, size != 0)?;
+        let node = self.node_mut(ino)?;
+        let Content::Upper(fd) = &node.content else {
+            return Err(Errno::INVAL);
+        };
+        ftruncate(fd, size)?;
+        node.attr.size = size;
+        node.attr.blocks = size.div_ceil(512);
+        self.touch(ino)
+    }
+
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, Errno> {
+        let ino = self.child(parent, name)?;
+        self.entry(ino)
+    }
+
+    fn forget(&mut self, ino: u64, nlookup: u64) -> Result<(), Errno> {
+        let node = self.node_mut(ino)?;
+        node.lookups = node.lookups.saturating_sub(nlookup);
+        self.maybe_drop(ino)
+    }
+
+    fn getattr(&self, ino: u64) -> Result<FileAttr, Errno> {
+        Ok(self.node(ino)?.attr)
+    }
+
+    fn setattr(&mut self, ino: u64, changes: SetAttr) -> Result<FileAttr, Errno> {
+        if let Some(size) = changes.size {
+            self.truncate(ino, size)?;
+        }
+
+        let now = SystemTime::now();
+        let time = |time| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => now,
+        };
+
+        let node = self.node_mut(ino)?;
+        if let Some(mode) = changes.mode {
+            node.attr.perm = (mode & 0o7777) as u16;
+        }
+        if let Some(uid) = changes.uid {
+            node.attr.uid = uid;
+        }
+        if let Some(gid) = changes.gid {
+            node.attr.gid = gid;
+        }
+        if let Some(atime) = changes.atime {
+            node.attr.atime = time(atime);
+        }
+        if let Some(mtime) = changes.mtime {
+            node.attr.mtime = time(mtime);
+        }
+        node.attr.ctime = now;
+        Ok(node.attr)
+    }
+
+    fn readlink(&self, ino: u64) -> Result<&[u8], Errno> {
+        match &self.node(ino)?.content {
+            Content::Symlink(target) => Ok(target.as_bytes()),
+            _ => Err(Errno::INVAL),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        owner: Owner,
+    ) -> Result<FileAttr, Errno> {
+        let (kind, content) = match rustix::fs::FileType::from_raw_mode(mode) {
+            rustix::fs::FileType::RegularFile => (
+                FileType::RegularFile,
+                Content::Upper(self.upper.create_file()?),
+            ),
+            rustix::fs::FileType::BlockDevice => (FileType::BlockDevice, Content::Special),
+            rustix::fs::FileType::CharacterDevice => (FileType::CharDevice, Content::Special),
+            rustix::fs::FileType::Fifo => (FileType::NamedPipe, Content::Special),
+            rustix::fs::FileType::Socket => (FileType::Socket, Content::Special),
+            _ => return Err(Errno::INVAL),
+        };
+        self.add_node(parent, name, content, new_attr(kind, mode, rdev, owner))
+    }
+
+    fn mkdir(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<FileAttr, Errno> {
+        let content = Content::Directory {
+            lower: None,
+            entries: Some(BTreeMap::new()),
+        };
+        let attr = new_attr(FileType::Directory, mode, 0, owner);
+        self.add_node(parent, name, content, attr)
+    }
+
+    fn symlink(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        target: &Path,
+        owner: Owner,
+    ) -> Result<FileAttr, Errno> {
+        let mut attr = new_attr(FileType::Symlink, 0o777, 0, owner);
+        attr.size = target.as_os_str().len() as u64;
+        let content = Content::Symlink(target.as_os_str().into());
+        self.add_node(parent, name, content, attr)
+    }
+
+    fn link(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr, Errno> {
+        if self.is_dir(ino)? {
+            return Err(Errno::PERM);
+        }
+        self.add_entry(newparent, newname, ino)?;
+        let node = self.node_mut(ino)?;
+        node.attr.nlink += 1;
+        node.attr.ctime = SystemTime::now();
+        self.entry(ino)
+    }
+
+    fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let ino = self.child(parent, name)?;
+        if self.is_dir(ino)? {
+            return Err(Errno::ISDIR);
+        }
+        self.remove_entry(parent, name)?;
+        self.drop_link(ino)
+    }
+
+    fn rmdir(&mut self, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let ino = self.child(parent, name)?;
+        if !self.entries(ino)?.is_empty() {
+            return Err(Errno::NOTEMPTY);
+        }
+        self.remove_entry(parent, name)?;
+        self.drop_link(ino)
+    }
+
+    fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: RenameFlags,
+    ) -> Result<(), Errno> {
+        let ino = self.child(parent, name)?;
+        let target = self.entries(newparent)?.get(newname).copied();
+
+        // Directories can't be moved below themselves
+        if self.is_dir(ino)? && self.is_ancestor(ino, newparent)? {
+            return Err(Errno::INVAL);
+        }
+
+        if flags.contains(RenameFlags::EXCHANGE) {
+            let target = target.ok_or(Errno::NOENT)?;
+            if self.is_dir(target)? && self.is_ancestor(target, parent)? {
+                return Err(Errno::INVAL);
+            }
+            self.remove_entry(parent, name)?;
+            self.remove_entry(newparent, newname)?;
+            self.add_entry(parent, name, target)?;
+            return self.add_entry(newparent, newname, ino);
+        }
+
+        if let Some(target) = target {
+            if flags.contains(RenameFlags::NOREPLACE) {
+                return Err(Errno::EXIST);
+            } else if target == ino {
+                return Ok(());
+            }
+
+            match (self.is_dir(ino)?, self.is_dir(target)?) {
+                (true, false) => return Err(Errno::NOTDIR),
+                (false, true) => return Err(Errno::ISDIR),
+                (true, true) if !self.entries(target)?.is_empty() => return Err(Errno::NOTEMPTY),
+                _ => {}
+            }
+            self.remove_entry(newparent, newname)?;
+            self.drop_link(target)?;
+        }
+
+        self.remove_entry(parent, name)?;
+        self.add_entry(newparent, newname, ino)?;
+        self.node_mut(ino)?.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    fn open(&mut self, ino: u64, flags: i32) -> Result<u64, Errno> {
+        let flags = OFlags::from_bits_retain(flags as _);
+        let truncate = flags.contains(OFlags::TRUNC);
+        if truncate {
+            self.truncate(ino, 0)?;
+        } else if flags & OFlags::RWMODE != OFlags::RDONLY {
+            self.copy_up(ino, true)?;
+        }
+
+        let handle = match &self.node(ino)?.content {
+            Content::Upper(fd) => OpenHandle::Fd(fd.try_clone().map_err(io_errno)?),
+            Content::Lower(leaf) => match &leaf.content {
+                LeafContent::Regular(RegularFile::External(id, ..)) => self.objects.open(id)?,
+                LeafContent::Regular(RegularFile::Inline(data)) => OpenHandle::Data(data.clone()),
+                _ => return Err(Errno::INVAL),
+            },
+            Content::Directory { .. } => return Err(Errno::ISDIR),
+            _ => return Err(Errno::INVAL),
+        };
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, handle);
+        Ok(fh)
+    }
+
+    fn create(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: i32,
+        owner: Owner,
+    ) -> Result<(FileAttr, u64), Errno> {
+        let mode = rustix::fs::FileType::RegularFile.as_raw_mode() | (mode & 0o7777);
+        let attr = self.mknod(parent, name, mode, 0, owner)?;
+        Ok((
+            attr,
+            self.open(attr.ino, flags & !OFlags::TRUNC.bits() as i32)?,
+        ))
+    }
+
+    fn read(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        self.handles.get(&fh).ok_or(Errno::BADF)?.read(offset, size)
+    }
+
+    fn write(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, Errno> {
+        let Some(OpenHandle::Fd(fd)) = self.handles.get(&fh) else {
+            return Err(Errno::BADF);
+        };
+        let written = pwrite(fd, data, offset)?;
+
+        // The node might have been unlinked and forgotten while the file is still open
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.attr.size = node.attr.size.max(offset + written as u64);
+            node.attr.blocks = node.attr.size.div_ceil(512);
+            node.attr.mtime = SystemTime::now();
+            node.attr.ctime = node.attr.mtime;
+        }
+        Ok(written as u32)
+    }
+
+    fn fsync(&self, fh: u64) -> Result<(), Errno> {
+        match self.handles.get(&fh).ok_or(Errno::BADF)? {
+            OpenHandle::Fd(fd) => fsync(fd),
+            OpenHandle::Verified(..) | OpenHandle::Data(..) => Ok(()),
+        }
+    }
+
+    fn release(&mut self, fh: u64) -> Result<(), Errno> {
+        self.handles.remove(&fh).map(drop).ok_or(Errno::BADF)
+    }
+
+    fn readdir(&mut self, ino: u64) -> Result<Vec<(u64, FileType, OsString)>, Errno> {
+        let parent = self.node(ino)?.parent;
+        let mut list = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (parent, FileType::Directory, OsString::from("..")),
+        ];
+        let entries = self.entries(ino)?.clone();
+        for (name, child) in entries {
+            list.push((child, self.node(child)?.attr.kind, name));
+        }
+        Ok(list)
+    }
+
+    fn getxattr(&self, ino: u64, name: &OsStr) -> Result<&[u8], Errno> {
+        match self.node(ino)?.xattrs.get(name) {
+            Some(value) => Ok(value),
+            None => Err(Errno::NODATA),
+        }
+    }
+
+    fn listxattr(&self, ino: u64) -> Result<Vec<u8>, Errno> {
+        let mut list = vec![];
+        for name in self.node(ino)?.xattrs.keys() {
+            list.extend_from_slice(name.as_bytes());
+            list.push(b'\0');
+        }
+        Ok(list)
+    }
+
+    fn setxattr(
+        &mut self,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> Result<(), Errno> {
+        let node = self.node_mut(ino)?;
+        match node.xattrs.contains_key(name) {
+            true if flags.contains(XattrFlags::CREATE) => return Err(Errno::EXIST),
+            false if flags.contains(XattrFlags::REPLACE) => return Err(Errno::NODATA),
+            _ => {}
+        }
+        node.xattrs.insert(name.into(), value.into());
+        node.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    fn removexattr(&mut self, ino: u64, name: &OsStr) -> Result<(), Errno> {
+        let node = self.node_mut(ino)?;
+        node.xattrs.remove(name).ok_or(Errno::NODATA)?;
+        node.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl<ObjectID: FsVerityHashValue> Filesystem for OverlayFuse<'_, ObjectID> {
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        reply.statfs(0, 0, 0, 0, 0, 4096, 255, 4096);
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        log::trace!("lookup {parent} {name:?}");
+        match self.lookup(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        if let Err(errno) = self.forget(ino, nlookup) {
+            log::error!("forget({ino}, {nlookup}) failed: {errno}");
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match Self::getattr(self, ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let changes = SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+        };
+        match self.setattr(ino, changes) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match Self::readlink(self, ino) {
+            Ok(target) => reply.data(target),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.mknod(parent, name, mode & !umask, rdev, req.into()) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.mkdir(parent, name, mode & !umask, req.into()) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.symlink(parent, link_name, target, req.into()) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let flags = RenameFlags::from_bits_retain(flags);
+        match self.rename(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.link(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        log::trace!("open({ino}, {flags:#x})");
+        match self.open(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.create(parent, name, mode & !umask, flags, req.into()) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match Self::read(self, fh, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.write(ino, fh, offset as u64, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match Self::fsync(self, fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.release(fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => {
+                log::error!("Handle doesn't exist: close({fh})");
+                reply.error(errno.raw_os_error())
+            }
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let list = match self.readdir(ino) {
+            Ok(list) => list,
+            Err(errno) => return reply.error(errno.raw_os_error()),
+        };
+
+        for (n, (ino, kind, name)) in list.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, n as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        match Self::getxattr(self, ino, name) {
+            Ok(value) => reply_xattr(reply, value, size),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        match Self::listxattr(self, ino) {
+            Ok(list) => reply_xattr(reply, &list, size),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let flags = XattrFlags::from_bits_retain(flags as _);
+        match self.setxattr(ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.removexattr(ino, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno.raw_os_error()),
+        }
+    }
+}
+
+/// Serves a writable FUSE filesystem exposing the content of `root`, backed by `repo`.
+///
+/// Changes are stored in `upper`, and `root` itself is never modified.  You should have called
+/// mount_fuse_writable() on the dev_fuse fd to establish a mount point.
+pub fn serve_tree_fuse_overlay<'a, ObjectID: FsVerityHashValue>(
+    dev_fuse: OwnedFd,
+    root: &'a Directory<ObjectID>,
+    repo: &'a Repository<ObjectID>,
+    upper: UpperLayer,
+    options: FuseOptions,
+) -> std::io::Result<()> {
+    let fs = OverlayFuse::new(root, repo, upper, options);
+    Session::from_fd(fs, dev_fuse, SessionACL::All).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use composefs::{dumpfile::dumpfile_to_filesystem, fsverity::Sha256HashValue};
+    use rustix::fs::CWD;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const RDWR: i32 = OFlags::RDWR.bits() as i32;
+    const OWNER: Owner = Owner {
+        uid: 1000,
+        gid: 1000,
+    };
+
+    fn names(fs: &mut OverlayFuse<Sha256HashValue>, ino: u64) -> Vec<String> {
+        let list = fs.readdir(ino).unwrap();
+        list.into_iter()
+            .skip(2)
+            .map(|(_, _, name)| name.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn read_all(fs: &mut OverlayFuse<Sha256HashValue>, ino: u64) -> Vec<u8> {
+        let fh = fs.open(ino, 0).unwrap();
+        let data = OverlayFuse::read(fs, fh, 0, 4096).unwrap();
+        fs.release(fh).unwrap();
+        data
+    }
+
+    fn test_overlay(upper: impl Fn(&TempDir) -> UpperLayer) {
+        let tmp = TempDir::new().unwrap();
+        let mut repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        repo.set_insecure(true);
+        let object = repo.ensure_object(b"external content").unwrap();
+
+        let dumpfile = format!(
+            "/ 4096 40755 3 0 0 0 0.0 - - -\n\
+             /dir 4096 40755 2 0 0 0 0.0 - - - user.test=lower\n\
+             /dir/inline 6 100644 2 0 0 0 0.0 - inline -\n\
+             /external 16 100644 1 0 0 0 0.0 {}/{} - {}\n\
+             /hardlink 0 @100644 2 0 0 0 0.0 /dir/inline - -\n\
+             /symlink 3 120777 1 0 0 0 0.0 dir - -\n",
+            &object.to_hex()[..2],
+            &object.to_hex()[2..],
+            object.to_hex(),
+        );
+        let tree = dumpfile_to_filesystem::<Sha256HashValue>(&dumpfile).unwrap();
+        let mut fs = OverlayFuse::new(&tree.root, &repo, upper(&tmp), FuseOptions::default());
+
+        // The lower layer is visible as it is
+        assert_eq!(
+            names(&mut fs, ROOT_INO),
+            ["dir", "external", "hardlink", "symlink"]
+        );
+        let dir = fs.lookup(ROOT_INO, "dir".as_ref()).unwrap();
+        let inline = fs.lookup(dir.ino, "inline".as_ref()).unwrap();
+        let hardlink = fs.lookup(ROOT_INO, "hardlink".as_ref()).unwrap();
+        assert_eq!((inline.ino, inline.nlink), (hardlink.ino, 2));
+        assert_eq!(read_all(&mut fs, inline.ino), b"inline");
+        let symlink = fs.lookup(ROOT_INO, "symlink".as_ref()).unwrap();
+        assert_eq!(fs.readlink(symlink.ino).unwrap(), b"dir");
+        assert_eq!(
+            fs.getxattr(dir.ino, "user.test".as_ref()).unwrap(),
+            b"lower"
+        );
+
+        // Writing copies the file up, without touching the object in the repository
+        let external = fs.lookup(ROOT_INO, "external".as_ref()).unwrap();
+        let fh = fs.open(external.ino, RDWR).unwrap();
+        assert_eq!(fs.write(external.ino, fh, 9, b"changes!").unwrap(), 8);
+        fs.release(fh).unwrap();
+        assert_eq!(read_all(&mut fs, external.ino), b"external changes!");
+        assert_eq!(fs.getattr(external.ino).unwrap().size, 17);
+        assert_eq!(repo.read_object(&object).unwrap(), b"external content");
+
+        // Truncating keeps the start of the file, and hardlinks see the change
+        let changes = SetAttr {
+            size: Some(3),
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        let attr = fs.setattr(hardlink.ino, changes).unwrap();
+        assert_eq!((attr.size, attr.perm), (3, 0o600));
+        assert_eq!(read_all(&mut fs, inline.ino), b"inl");
+
+        // Opening with O_TRUNC empties the file
+        let fh = fs
+            .open(inline.ino, RDWR | OFlags::TRUNC.bits() as i32)
+            .unwrap();
+        assert_eq!(fs.read(fh, 0, 4096).unwrap(), b"");
+        fs.release(fh).unwrap();
+
+        // Creating new nodes
+        let (file, fh) = fs
+            .create(dir.ino, "new".as_ref(), 0o644, RDWR, OWNER)
+            .unwrap();
+        fs.write(file.ino, fh, 0, b"new file").unwrap();
+        fs.release(fh).unwrap();
+        assert_eq!((file.uid, file.perm), (1000, 0o644));
+        let subdir = fs.mkdir(dir.ino, "subdir".as_ref(), 0o755, OWNER).unwrap();
+        assert_eq!(fs.getattr(dir.ino).unwrap().nlink, 3);
+        fs.symlink(subdir.ino, "link".as_ref(), "../new".as_ref(), OWNER)
+            .unwrap();
+        let fifo = fs.mknod(subdir.ino, "fifo".as_ref(), 0o010644, 0, OWNER);
+        assert_eq!(fifo.unwrap().kind, FileType::NamedPipe);
+        assert_eq!(
+            fs.mkdir(dir.ino, "new".as_ref(), 0o755, OWNER).unwrap_err(),
+            Errno::EXIST
+        );
+        assert_eq!(names(&mut fs, dir.ino), ["inline", "new", "subdir"]);
+        assert_eq!(names(&mut fs, subdir.ino), ["fifo", "link"]);
+
+        // Hardlinks
+        let link = fs.link(file.ino, ROOT_INO, "newlink".as_ref()).unwrap();
+        assert_eq!((link.ino, link.nlink), (file.ino, 2));
+        assert_eq!(
+            fs.link(subdir.ino, ROOT_INO, "dirlink".as_ref())
+                .unwrap_err(),
+            Errno::PERM
+        );
+
+        // Renames
+        let none = RenameFlags::empty();
+        let (root, name, newname) = (ROOT_INO, OsStr::new("newlink"), OsStr::new("renamed"));
+        fs.rename(root, name, subdir.ino, newname, none).unwrap();
+        assert_eq!(fs.lookup(subdir.ino, newname).unwrap().ino, file.ino);
+        let err = fs.rename(
+            subdir.ino,
+            newname,
+            subdir.ino,
+            "fifo".as_ref(),
+            RenameFlags::NOREPLACE,
+        );
+        assert_eq!(err.unwrap_err(), Errno::EXIST);
+        fs.rename(
+            subdir.ino,
+            newname,
+            subdir.ino,
+            "link".as_ref(),
+            RenameFlags::EXCHANGE,
+        )
+        .unwrap();
+        assert_eq!(
+            fs.lookup(subdir.ino, "link".as_ref()).unwrap().ino,
+            file.ino
+        );
+        let err = fs.rename(dir.ino, "subdir".as_ref(), subdir.ino, "x".as_ref(), none);
+        assert_eq!(err.unwrap_err(), Errno::INVAL);
+        fs.rename(ROOT_INO, "dir".as_ref(), ROOT_INO, "moved".as_ref(), none)
+            .unwrap();
+        assert_eq!(
+            names(&mut fs, ROOT_INO),
+            ["external", "hardlink", "moved", "symlink"]
+        );
+
+        // Removing things
+        assert_eq!(
+            fs.rmdir(dir.ino, "subdir".as_ref()).unwrap_err(),
+            Errno::NOTEMPTY
+        );
+        assert_eq!(
+            fs.unlink(dir.ino, "subdir".as_ref()).unwrap_err(),
+            Errno::ISDIR
+        );
+        for name in ["fifo", "link", "renamed"] {
+            fs.unlink(subdir.ino, name.as_ref()).unwrap();
+        }
+        fs.rmdir(dir.ino, "subdir".as_ref()).unwrap();
+        assert_eq!(fs.getattr(dir.ino).unwrap().nlink, 2);
+        assert_eq!(fs.getattr(file.ino).unwrap().nlink, 1);
+        fs.unlink(ROOT_INO, "hardlink".as_ref()).unwrap();
+        assert_eq!(fs.getattr(inline.ino).unwrap().nlink, 1);
+
+        // Unlinked nodes stay around until they're forgotten
+        fs.unlink(dir.ino, "new".as_ref()).unwrap();
+        assert_eq!(fs.getattr(file.ino).unwrap().nlink, 0);
+        let lookups = fs.node(file.ino).unwrap().lookups;
+        fs.forget(file.ino, lookups - 1).unwrap();
+        assert_eq!(fs.getattr(file.ino).unwrap().nlink, 0);
+        fs.forget(file.ino, 1).unwrap();
+        assert_eq!(fs.getattr(file.ino).unwrap_err(), Errno::BADF);
+
+        // Xattrs
+        let name = OsStr::new("user.test");
+        let flags = XattrFlags::CREATE;
+        assert_eq!(fs.setxattr(dir.ino, name, b"x", flags), Err(Errno::EXIST));
+        fs.setxattr(dir.ino, name, b"upper", XattrFlags::REPLACE)
+            .unwrap();
+        assert_eq!(fs.getxattr(dir.ino, name).unwrap(), b"upper");
+        fs.setxattr(inline.ino, name, b"x", flags).unwrap();
+        assert_eq!(fs.listxattr(inline.ino).unwrap(), b"user.test\0");
+        fs.removexattr(inline.ino, name).unwrap();
+        assert_eq!(fs.removexattr(inline.ino, name), Err(Errno::NODATA));
+
+        // The lower layer itself is unchanged
+        let lower = tree.root.get_directory("dir".as_ref()).unwrap();
+        assert_eq!(lower.stat.xattrs.borrow()[name].as_ref(), b"lower");
+        assert_eq!(lower.sorted_entries().count(), 1);
+    }
+
+    #[test]
+    fn test_memory() {
+        test_overlay(|_| UpperLayer::Memory);
+    }
+
+    #[test]
+    fn test_directory() {
+        test_overlay(|tmp| {
+            let dir = tmp.path().join("upper");
+            std::fs::create_dir(&dir).unwrap();
+            UpperLayer::Directory(File::open(dir).unwrap().into())
+        });
+    }
+}
@@ -0,0 +1,116 @@
+//! A pool of threads serving reads.
+//!
+//! fuser runs a session on a single thread, which receives requests one by one.  Lookups and
+//! attributes are served from memory, but reads of file content go to the repository (and, with
+//! userspace verification, need to hash the data), so they're handed off to a pool of worker
+//! threads which reply to the kernel directly.  The kernel can have many reads in flight at once.
+//!
+//! We can't use FUSE_DEV_IOC_CLONE to run several sessions on clones of the /dev/fuse fd instead:
+//! the INIT request only ever shows up on one of them, and fuser refuses to serve any request on
+//! a session which hasn't seen INIT.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use fuser::ReplyData;
+
+use composefs::fsverity::FsVerityHashValue;
+
+use crate::OpenHandle;
+
+struct ReadJob<ObjectID: FsVerityHashValue> {
+    handle: Arc<OpenHandle<ObjectID>>,
+    offset: u64,
+    size: u32,
+    reply: ReplyData,
+}
+
+impl<ObjectID: FsVerityHashValue> ReadJob<ObjectID> {
+    fn run(self) {
+        match self.handle.read(self.offset, self.size) {
+            Ok(data) => self.reply.data(&data),
+            Err(errno) => {
+                log::error!("pread({}, {}) failed: {errno}", self.size, self.offset);
+                self.reply.error(errno.raw_os_error())
+            }
+        }
+    }
+}
+
+/// Serves reads, either on a pool of threads or, with no threads, directly.
+#[derive(Debug)]
+pub(crate) struct Readers<ObjectID: FsVerityHashValue> {
+    jobs: Option<mpsc::Sender<ReadJob<ObjectID>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<ObjectID: FsVerityHashValue> Readers<ObjectID> {
+    /// Starts `threads` worker threads.  With 0, reads are served by the calling thread.
+    pub(crate) fn new(threads: usize) -> Self {
+        if threads == 0 {
+            return Self {
+                jobs: None,
+                threads: vec![],
+            };
+        }
+
+        let (sender, receiver) = mpsc::channel::<ReadJob<ObjectID>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads)
+            .map(|n| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("fuse-read-{n}"))
+                    .spawn(move || loop {
+                        // Only hold the lock while waiting for a job, not while running it
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job.run(),
+                            Err(mpsc::RecvError) => break,
+                        }
+                    })
+                    .expect("failed to spawn FUSE read thread")
+            })
+            .collect();
+
+        Self {
+            jobs: Some(sender),
+            threads,
+        }
+    }
+
+    /// Reads `size` bytes at `offset` from `handle` and sends them as the reply.
+    pub(crate) fn read(
+        &self,
+        handle: &Arc<OpenHandle<ObjectID>>,
+        offset: u64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let job = ReadJob {
+            handle: Arc::clone(handle),
+            offset,
+            size,
+            reply,
+        };
+        match &self.jobs {
+            // If the workers died, serve the read here instead
+            Some(jobs) => jobs
+                .send(job)
+                .unwrap_or_else(|mpsc::SendError(job)| job.run()),
+            None => job.run(),
+        }
+    }
+}
+
+impl<ObjectID: FsVerityHashValue> Drop for Readers<ObjectID> {
+    fn drop(&mut self) {
+        // Let the workers finish the jobs which are queued and exit
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
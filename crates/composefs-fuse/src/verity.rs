@@ -0,0 +1,198 @@
+//! Userspace verification of object content.
+//!
+//! Objects in a repository are named after their fs-verity digest, and the kernel normally makes
+//! sure that their content matches it.  Insecure repositories on filesystems without fs-verity
+//! support (like tmpfs or overlayfs, which are common in CI containers) can't rely on that, so
+//! this module does the same thing in userspace: when an object is first opened, its Merkle tree
+//! is computed and checked against the object ID, and blocks are then checked against the hashes
+//! in the tree every time they are read.  Unlike the kernel, which only verifies a page when it's
+//! read into the page cache and serves later reads from there, we read from the backing file
+//! every time, so a block which was fine before could have been changed since.  The kernel's
+//! page cache still saves us from most repeated reads, since the content of objects never
+//! changes.
+
+use std::{collections::HashMap, os::fd::OwnedFd, sync::Arc};
+
+use rustix::io::{pread, Errno};
+
+use composefs::{
+    fsverity::{measure_verity, FsVerityHashValue, FsVerityHasher},
+    repository::Repository,
+};
+
+use crate::OpenHandle;
+
+const BLOCK_SIZE: usize = 4096;
+
+/// How many blocks to read at once while computing the Merkle tree.
+const CHUNK_BLOCKS: usize = 32;
+
+/// Reads from `fd` at `offset` until `buf` is full or the end of the file is reached.
+fn pread_full(fd: &OwnedFd, buf: &mut [u8], offset: u64) -> Result<usize, Errno> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match pread(fd, &mut buf[filled..], offset + filled as u64)? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// The size of an object and the lowest level of its Merkle tree.
+#[derive(Debug)]
+pub(crate) struct VerifiedObject<ObjectID: FsVerityHashValue> {
+    size: u64,
+    /// The hashes of the data blocks: the lowest level of the Merkle tree.
+    block_hashes: Vec<ObjectID>,
+}
+
+impl<ObjectID: FsVerityHashValue> VerifiedObject<ObjectID> {
+    /// Computes the Merkle tree of an object and checks it against the object ID.
+    fn new(fd: &OwnedFd, id: &ObjectID) -> Result<Self, Errno> {
+        let mut hasher = FsVerityHasher::<ObjectID>::new();
+        let mut block_hashes = vec![];
+        let mut buf = vec![0; CHUNK_BLOCKS * BLOCK_SIZE];
+        let mut size = 0;
+
+        loop {
+            let n = pread_full(fd, &mut buf, size)?;
+            for block in buf[..n].chunks(BLOCK_SIZE) {
+                block_hashes.push(hasher.add_block(block));
+            }
+            size += n as u64;
+            if n < buf.len() {
+                break;
+            }
+        }
+
+        if hasher.digest() != *id {
+            log::error!("Object {id:?} doesn't match its fs-verity digest");
+            return Err(Errno::IO);
+        }
+
+        Ok(Self { size, block_hashes })
+    }
+
+    /// Reads a range of the object, verifying all the blocks it touches.
+    pub(crate) fn read(&self, fd: &OwnedFd, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let end = self.size.min(offset.saturating_add(size as u64));
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        // Read the complete blocks covering the range
+        let first_block = offset as usize / BLOCK_SIZE;
+        let start = (first_block * BLOCK_SIZE) as u64;
+        let mut data =
+            vec![0; (self.size.min(end.next_multiple_of(BLOCK_SIZE as u64)) - start) as usize];
+        if pread_full(fd, &mut data, start)? != data.len() {
+            log::error!("Object was truncated after it was opened");
+            return Err(Errno::IO);
+        }
+
+        for (n, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            let index = first_block + n;
+            if FsVerityHasher::<ObjectID>::hash_block(block) != self.block_hashes[index] {
+                log::error!("Block {index} of object doesn't match its fs-verity hash");
+                return Err(Errno::IO);
+            }
+        }
+
+        data.drain(..(offset - start) as usize);
+        data.truncate((end - offset) as usize);
+        Ok(data)
+    }
+}
+
+/// Opens objects from a repository, verifying them in userspace if required.
+#[derive(Debug)]
+pub(crate) struct Objects<'a, ObjectID: FsVerityHashValue> {
+    repo: &'a Repository<ObjectID>,
+    verify: bool,
+    /// The Merkle trees of the objects opened so far.  These are kept for as long as the
+    /// filesystem is served, so that objects don't need to be hashed again when they're reopened.
+    trees: HashMap<ObjectID, Arc<VerifiedObject<ObjectID>>>,
+}
+
+impl<'a, ObjectID: FsVerityHashValue> Objects<'a, ObjectID> {
+    pub(crate) fn new(repo: &'a Repository<ObjectID>, verify: bool) -> Self {
+        Self {
+            repo,
+            verify,
+            trees: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn open(&mut self, id: &ObjectID) -> Result<OpenHandle<ObjectID>, Errno> {
+        let fd = self.repo.open_object(id).map_err(|err| {
+            log::error!("Opening object {id:?} failed: {err:#}");
+            Errno::IO
+        })?;
+
+        // If the kernel has fs-verity enabled on the object, it's been checked against the ID
+        // already and the kernel takes care of verifying the content.
+        if !self.verify || measure_verity::<ObjectID>(&fd).is_ok() {
+            return Ok(OpenHandle::Fd(fd));
+        }
+
+        let tree = match self.trees.get(id) {
+            Some(tree) => Arc::clone(tree),
+            None => {
+                let tree = Arc::new(VerifiedObject::new(&fd, id)?);
+                self.trees.insert(id.clone(), Arc::clone(&tree));
+                tree
+            }
+        };
+        Ok(OpenHandle::Verified(fd, tree))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, os::unix::fs::FileExt};
+
+    use composefs::fsverity::Sha256HashValue;
+    use rustix::fs::CWD;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let tmp = TempDir::new().unwrap();
+        let mut repo = Repository::<Sha256HashValue>::open_path(CWD, tmp.path()).unwrap();
+        repo.set_insecure(true);
+
+        let content: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let id = repo.ensure_object(&content).unwrap();
+        let path = tmp.path().join("objects").join(id.to_object_pathname());
+        let corrupt = |offset| {
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.write_all_at(b"X", offset).unwrap();
+        };
+
+        // Without verification, we get whatever is in the file
+        let mut objects = Objects::new(&repo, false);
+        assert!(matches!(objects.open(&id).unwrap(), OpenHandle::Fd(..)));
+
+        let mut objects = Objects::new(&repo, true);
+        let handle = objects.open(&id).unwrap();
+        assert!(matches!(handle, OpenHandle::Verified(..)));
+        assert_eq!(handle.read(4000, 50).unwrap(), &content[4000..4050]);
+        assert_eq!(handle.read(12000, 4096).unwrap(), &content[12000..]);
+        assert_eq!(handle.read(20000, 10).unwrap(), b"");
+
+        // Blocks are checked on every read, including ones which were fine when read before
+        corrupt(4010);
+        assert_eq!(handle.read(4000, 50), Err(Errno::IO));
+        assert_eq!(handle.read(0, 10), Err(Errno::IO));
+        assert_eq!(handle.read(8192, 10).unwrap(), &content[8192..8202]);
+        corrupt(BLOCK_SIZE as u64 + 1);
+        assert_eq!(handle.read(BLOCK_SIZE as u64, 1), Err(Errno::IO));
+
+        // Reopening uses the cached tree, but a fresh one can't be computed from corrupt data
+        objects.open(&id).unwrap();
+        assert_eq!(Objects::new(&repo, true).open(&id).unwrap_err(), Errno::IO);
+    }
+}
@@ -0,0 +1,191 @@
+//! systemd generator mode.
+//!
+//! When the binary is installed as a generator (for example by symlinking it as
+//! `/usr/lib/systemd/system-generators/composefs-setup-root-generator`), it emits a service
+//! which runs it after the initramfs has mounted the root filesystem on `/sysroot`, and hooks it
+//! into `initrd-root-fs.target`.  This replaces the hand-written unit from the examples.  Nothing
+//! is generated outside of the initramfs, or without `composefs=` on the kernel command line.
+
+use std::{
+    ffi::OsStr,
+    fs::{create_dir_all, write},
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use crate::{
+    journal::{Logger, Priority},
+    parse_image_address,
+};
+
+pub(crate) const UNIT_NAME: &str = "composefs-setup-root.service";
+
+/// The arguments systemd passes to generators, plus some for testing
+#[derive(Parser, Debug)]
+pub(crate) struct GeneratorArgs {
+    normal_dir: PathBuf,
+    early_dir: Option<PathBuf>,
+    late_dir: Option<PathBuf>,
+
+    #[arg(long, help = "Kernel commandline args (for testing)")]
+    cmdline: Option<String>,
+}
+
+/// Checks if we were started as a generator, from the name we were started as.
+pub(crate) fn is_generator(argv0: &OsStr) -> bool {
+    Path::new(argv0)
+        .file_name()
+        .is_some_and(|name| name.as_encoded_bytes().ends_with(b"-generator"))
+}
+
+fn in_initrd() -> bool {
+    match std::env::var_os("SYSTEMD_IN_INITRD") {
+        Some(value) => value == "1",
+        None => Path::new("/etc/initrd-release").exists(),
+    }
+}
+
+fn service(image: &str, exec: &Path) -> String {
+    format!(
+        "\
+# Automatically generated by composefs-setup-root
+
+[Unit]
+Description=composefs root filesystem {image}
+DefaultDependencies=no
+ConditionPathExists=/etc/initrd-release
+After=sysroot.mount
+Requires=sysroot.mount
+Before=initrd-root-fs.target
+Before=initrd-switch-root.target
+
+OnFailure=emergency.target
+OnFailureJobMode=isolate
+
+[Service]
+Type=oneshot
+ExecStart={}
+StandardInput=null
+StandardOutput=journal
+StandardError=tty
+RemainAfterExit=yes
+",
+        exec.display()
+    )
+}
+
+/// Writes the units for setting up `image` to the generator output directory `dir`.
+pub(crate) fn generate(dir: &Path, image: &str, exec: &Path) -> Result<()> {
+    write(dir.join(UNIT_NAME), service(image, exec))?;
+
+    let requires = dir.join("initrd-root-fs.target.requires");
+    create_dir_all(&requires)?;
+    symlink(Path::new("..").join(UNIT_NAME), requires.join(UNIT_NAME))?;
+
+    Ok(())
+}
+
+pub(crate) fn run(args: GeneratorArgs, logger: &Logger) -> Result<()> {
+    if in_initrd() {
+        run_in_initrd(args, logger)
+    } else {
+        Ok(())
+    }
+}
+
+fn run_in_initrd(args: GeneratorArgs, logger: &Logger) -> Result<()> {
+    let cmdline = match &args.cmdline {
+        Some(cmdline) => cmdline,
+        None => &std::fs::read_to_string("/proc/cmdline")?,
+    };
+    // Not a composefs boot
+    if !cmdline
+        .split_ascii_whitespace()
+        .any(|arg| arg.starts_with("composefs="))
+    {
+        return Ok(());
+    }
+    let (image, _insecure) = parse_image_address(cmdline)?;
+
+    let exec = std::env::current_exe().context("Failed to find our own executable")?;
+    generate(&args.normal_dir, &image, &exec)
+        .with_context(|| format!("Failed to write units to {:?}", args.normal_dir))?;
+
+    logger.log(
+        Priority::Info,
+        &format!("Generated {UNIT_NAME} for composefs image {image}"),
+        &[("COMPOSEFS_IMAGE", &image)],
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{read_link, read_to_string};
+
+    use super::*;
+
+    #[test]
+    fn test_is_generator() {
+        assert!(is_generator(OsStr::new(
+            "/usr/lib/systemd/system-generators/composefs-setup-root-generator"
+        )));
+        assert!(is_generator(OsStr::new("composefs-generator")));
+        assert!(!is_generator(OsStr::new("/usr/bin/composefs-setup-root")));
+        assert!(!is_generator(OsStr::new("/generator/composefs-setup-root")));
+    }
+
+    #[test]
+    fn test_generate() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let digest = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52";
+        generate(
+            tmp.path(),
+            digest,
+            Path::new("/usr/bin/composefs-setup-root"),
+        )
+        .unwrap();
+
+        let unit = read_to_string(tmp.path().join(UNIT_NAME)).unwrap();
+        assert!(unit.contains(&format!("Description=composefs root filesystem {digest}\n")));
+        assert!(unit.contains("\nRequires=sysroot.mount\n"));
+        assert!(unit.contains("\nExecStart=/usr/bin/composefs-setup-root\n"));
+
+        let link = tmp
+            .path()
+            .join("initrd-root-fs.target.requires")
+            .join(UNIT_NAME);
+        assert_eq!(read_link(&link).unwrap(), Path::new("..").join(UNIT_NAME));
+        assert_eq!(read_to_string(&link).unwrap(), unit);
+    }
+
+    #[test]
+    fn test_run() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let logger = Logger::stderr();
+        let args = |cmdline: &str| GeneratorArgs {
+            normal_dir: tmp.path().to_path_buf(),
+            early_dir: None,
+            late_dir: None,
+            cmdline: Some(cmdline.to_string()),
+        };
+
+        // Nothing to do without composefs=
+        run_in_initrd(args("root=/dev/vda2 quiet"), &logger).unwrap();
+        assert!(!tmp.path().join(UNIT_NAME).exists());
+
+        assert!(run_in_initrd(args("composefs=nonsense"), &logger).is_err());
+
+        let digest = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52";
+        run_in_initrd(
+            args(&format!("root=/dev/vda2 composefs=?{digest}")),
+            &logger,
+        )
+        .unwrap();
+        let unit = read_to_string(tmp.path().join(UNIT_NAME)).unwrap();
+        assert!(unit.contains(digest));
+    }
+}
@@ -0,0 +1,151 @@
+//! Status logging for the initramfs.
+//!
+//! Messages go to journald's native socket, with structured fields which make them easy to find
+//! (like `journalctl SYSLOG_IDENTIFIER=composefs-setup-root COMPOSEFS_IMAGE=...`).  Generators
+//! run before journald is started, so if the socket isn't there, messages go to the kernel log
+//! instead, where journald picks them up later.  When run interactively, everything goes to
+//! stderr.
+//!
+//! Errors are also written to stderr, which the unit connects to the console only
+//! (`StandardError=tty`): sending it to the journal as well would log every error twice.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    os::unix::net::UnixDatagram,
+};
+
+const IDENTIFIER: &str = "composefs-setup-root";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// syslog(3) facility
+const LOG_DAEMON: u8 = 3 << 3;
+
+/// syslog(3) priorities
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Priority {
+    Err = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+#[derive(Debug)]
+enum Target {
+    Journal(UnixDatagram),
+    Kmsg(File),
+    Stderr,
+}
+
+#[derive(Debug)]
+pub(crate) struct Logger {
+    target: Target,
+}
+
+impl Logger {
+    pub(crate) fn open() -> Self {
+        let journal = || -> std::io::Result<UnixDatagram> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNAL_SOCKET)?;
+            Ok(socket)
+        };
+
+        let target = if std::io::stderr().is_terminal() {
+            Target::Stderr
+        } else if let Ok(socket) = journal() {
+            Target::Journal(socket)
+        } else if let Ok(kmsg) = OpenOptions::new().write(true).open("/dev/kmsg") {
+            Target::Kmsg(kmsg)
+        } else {
+            Target::Stderr
+        };
+
+        Self { target }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn stderr() -> Self {
+        Self {
+            target: Target::Stderr,
+        }
+    }
+
+    /// Logs a message along with extra journal fields, which should have uppercase names.  The
+    /// fields are dropped when logging to the kernel log or stderr.
+    pub(crate) fn log(&self, priority: Priority, message: &str, fields: &[(&str, &str)]) {
+        let result = match &self.target {
+            Target::Journal(socket) => socket
+                .send(&journal_entry(priority, message, fields))
+                .map(drop),
+            // Each write to /dev/kmsg is one record
+            Target::Kmsg(kmsg) => kmsg_records(priority, message)
+                .iter()
+                .try_for_each(|record| (&*kmsg).write_all(record.as_bytes())),
+            Target::Stderr => Err(std::io::ErrorKind::Unsupported.into()),
+        };
+
+        // Errors always go to stderr too, so that they show up on the console
+        if result.is_err() || priority == Priority::Err {
+            eprintln!("{message}");
+        }
+    }
+}
+
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        // Values with newlines need the binary encoding
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Formats an entry for the journal's native protocol.
+fn journal_entry(priority: Priority, message: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut entry = vec![];
+    journal_field(&mut entry, "PRIORITY", &(priority as u8).to_string());
+    journal_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    journal_field(&mut entry, "MESSAGE", message);
+    for (key, value) in fields {
+        journal_field(&mut entry, key, value);
+    }
+    entry
+}
+
+fn kmsg_records(priority: Priority, message: &str) -> Vec<String> {
+    let prefix = LOG_DAEMON | priority as u8;
+    message
+        .lines()
+        .map(|line| format!("<{prefix}>{IDENTIFIER}: {line}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let entry = journal_entry(
+            Priority::Info,
+            "Mounted\nimage",
+            &[("COMPOSEFS_IMAGE", "1234")],
+        );
+        let mut expected =
+            b"PRIORITY=6\nSYSLOG_IDENTIFIER=composefs-setup-root\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&13u64.to_le_bytes());
+        expected.extend_from_slice(b"Mounted\nimage\nCOMPOSEFS_IMAGE=1234\n");
+        assert_eq!(entry, expected);
+
+        assert_eq!(
+            kmsg_records(Priority::Err, "Failed\nCaused by"),
+            [
+                "<27>composefs-setup-root: Failed\n",
+                "<27>composefs-setup-root: Caused by\n"
+            ]
+        );
+    }
+}
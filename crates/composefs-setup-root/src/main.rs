@@ -5,11 +5,14 @@
 //! writable directories, state management, and system integration.
 
 use std::{
+    collections::BTreeMap,
     ffi::OsString,
     fmt::Debug,
     io::ErrorKind,
+    ops::Range,
     os::fd::{AsFd, OwnedFd},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
 };
 
 use anyhow::{Context, Result};
@@ -19,22 +22,31 @@ use rustix::{
     fs::{major, minor, mkdirat, openat, stat, symlink, Mode, OFlags, CWD},
     io::Errno,
     mount::{
-        fsconfig_create, fsconfig_set_string, fsmount, open_tree, unmount, FsMountFlags,
-        MountAttrFlags, OpenTreeFlags, UnmountFlags,
+        fsconfig_create, fsconfig_set_string, fsmount, mount_remount, open_tree, unmount,
+        FsMountFlags, MountAttrFlags, MountFlags, OpenTreeFlags, UnmountFlags,
     },
 };
-use serde::Deserialize;
+use serde::{de::Visitor, Deserialize};
+use toml::Spanned;
+use toml_edit::{ImDocument, Item, Key, TableLike};
 
 use composefs::{
     fsverity::{FsVerityHashValue, Sha256HashValue, Sha512HashValue},
     mount::{mount_at, FsHandle},
-    mountcompat::{overlayfs_set_fd, overlayfs_set_lower_and_data_fds, prepare_mount},
+    mountcompat::{
+        overlayfs_set_fd, overlayfs_set_lower_and_data_fds, overlayfs_set_lower_fds, prepare_mount,
+    },
     repository::Repository,
 };
-use composefs_boot::cmdline::get_cmdline_composefs;
+use composefs_boot::{cmdline::get_cmdline_composefs, signature::TrustedKey, state::reset_at_boot};
+
+use journal::{Logger, Priority};
+
+mod generator;
+mod journal;
 
 // Config file
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum MountType {
     None,
@@ -43,10 +55,43 @@ enum MountType {
     Transient,
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MountOption {
+    Ro,
+    Nosuid,
+    Nodev,
+    Noexec,
+    Noatime,
+}
+
+impl MountOption {
+    fn attr(self) -> MountAttrFlags {
+        match self {
+            Self::Ro => MountAttrFlags::MOUNT_ATTR_RDONLY,
+            Self::Nosuid => MountAttrFlags::MOUNT_ATTR_NOSUID,
+            Self::Nodev => MountAttrFlags::MOUNT_ATTR_NODEV,
+            Self::Noexec => MountAttrFlags::MOUNT_ATTR_NOEXEC,
+            Self::Noatime => MountAttrFlags::MOUNT_ATTR_NOATIME,
+        }
+    }
+
+    fn flag(self) -> MountFlags {
+        match self {
+            Self::Ro => MountFlags::RDONLY,
+            Self::Nosuid => MountFlags::NOSUID,
+            Self::Nodev => MountFlags::NODEV,
+            Self::Noexec => MountFlags::NOEXEC,
+            Self::Noatime => MountFlags::NOATIME,
+        }
+    }
+}
+
 #[derive(Debug, Default, Deserialize)]
 struct RootConfig {
     #[serde(default)]
     transient: bool,
+    size: Option<Spanned<String>>,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -54,16 +99,375 @@ struct MountConfig {
     mount: Option<MountType>,
     #[serde(default)]
     transient: bool,
+    /// The size limit of the tmpfs of a transient mount, like "512M" or "50%"
+    size: Option<Spanned<String>>,
+    /// Applied when the mount is created for overlay and transient mounts, and by remounting
+    /// bind mounts
+    options: Option<Spanned<Vec<MountOption>>>,
 }
 
-#[derive(Deserialize, Default)]
+impl MountConfig {
+    fn mount_type(&self) -> Option<MountType> {
+        self.mount
+            .or(self.transient.then_some(MountType::Transient))
+    }
+
+    fn size(&self) -> Option<&str> {
+        self.size.as_ref().map(|size| size.get_ref().as_str())
+    }
+
+    fn attrs(&self) -> MountAttrFlags {
+        self.options
+            .iter()
+            .flat_map(|options| options.get_ref())
+            .fold(MountAttrFlags::empty(), |attrs, option| {
+                attrs | option.attr()
+            })
+    }
+
+    fn flags(&self) -> MountFlags {
+        self.options
+            .iter()
+            .flat_map(|options| options.get_ref())
+            .fold(MountFlags::empty(), |flags, option| flags | option.flag())
+    }
+}
+
+// The span of a table which isn't in the config file
+fn unset<T: Default>() -> Spanned<T> {
+    Spanned::new(0..0, T::default())
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ExtensionMode {
+    /// Stack the image's copy of the path on top of the root filesystem's, like systemd-sysext
+    #[default]
+    Overlay,
+    /// Mount the entire image at the path
+    Mount,
+}
+
+fn default_extension_path() -> PathBuf {
+    PathBuf::from("/usr")
+}
+
+/// An additional composefs image from the repository, referred to by its digest or by a ref name
+/// (like `refs/tools`).  Images referred to by digest are checked against it.
+#[derive(Debug, Deserialize)]
+struct ExtensionConfig {
+    image: String,
+    #[serde(default = "default_extension_path")]
+    path: PathBuf,
+    #[serde(default)]
+    mode: ExtensionMode,
+}
+
+fn default_signature_paths() -> Vec<PathBuf> {
+    vec![PathBuf::from("composefs/signatures")]
+}
+
+/// Verification of the root image digest with a detached signature, for boots where the kernel
+/// command line isn't signed.  It's enabled by setting `key`.
+///
+/// Only the digest is signed, so the rest of the (unsigned) command line must not be able to
+/// weaken it: with a key, `composefs=?...` (which disables fs-verity) is refused, and so are
+/// extensions referred to by ref name rather than by digest, since refs live in the unsigned
+/// repository.  `composefs.reset` isn't covered by the signature either, so it's ignored: resets
+/// need to be requested with `cfsctl state reset --next-boot`.
+#[derive(Debug, Deserialize)]
+struct SignatureConfig {
+    /// The public key (PEM) in the initramfs
+    key: Option<PathBuf>,
+    /// The directories to look for `<digest>.sig` in.  Relative paths are relative to the sysroot.
+    #[serde(default = "default_signature_paths")]
+    paths: Vec<PathBuf>,
+}
+
+impl Default for SignatureConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            paths: default_signature_paths(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
 struct Config {
+    #[serde(default = "unset")]
+    etc: Spanned<MountConfig>,
+    #[serde(default = "unset")]
+    var: Spanned<MountConfig>,
     #[serde(default)]
-    etc: MountConfig,
+    root: RootConfig,
+    /// Mounts on other paths, like `[mounts."/home"]`.  Their state lives in the deployment's
+    /// state directory under the same path, like it does for `etc` and `var`.
     #[serde(default)]
-    var: MountConfig,
+    mounts: BTreeMap<Spanned<String>, MountConfig>,
+    #[serde(default, rename = "extension")]
+    extensions: Vec<ExtensionConfig>,
     #[serde(default)]
-    root: RootConfig,
+    signature: SignatureConfig,
+}
+
+/// A setting in the config file which is invalid
+#[derive(Debug)]
+struct ConfigError {
+    key: String,
+    span: Range<usize>,
+    message: &'static str,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, span: Range<usize>, message: &'static str) -> Self {
+        Self {
+            key: key.into(),
+            span,
+            message,
+        }
+    }
+}
+
+// The tmpfs "size" option: a number of bytes with an optional suffix, or a percentage of RAM
+fn valid_tmpfs_size(size: &str) -> bool {
+    let digits = size.trim_end_matches(|c: char| "kKmMgGtTpPeE%".contains(c));
+    size.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.bytes().all(|c| c.is_ascii_digit())
+}
+
+fn validate_size(
+    key: &str,
+    size: &Option<Spanned<String>>,
+    transient: bool,
+) -> Result<(), ConfigError> {
+    if let Some(size) = size {
+        let key = format!("{key}.size");
+        if !transient {
+            return Err(ConfigError::new(
+                key,
+                size.span(),
+                "size only applies to transient mounts",
+            ));
+        }
+        if !valid_tmpfs_size(size.get_ref()) {
+            return Err(ConfigError::new(
+                key,
+                size.span(),
+                "expected a size like \"512M\" or \"50%\"",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_mount(
+    key: &str,
+    key_span: Range<usize>,
+    config: &MountConfig,
+    default: Option<MountType>,
+) -> Result<(), ConfigError> {
+    let Some(mount_type) = config.mount_type().or(default) else {
+        return Err(ConfigError::new(
+            key,
+            key_span,
+            "mount or transient needs to be set",
+        ));
+    };
+    validate_size(key, &config.size, mount_type == MountType::Transient)?;
+
+    if let Some(options) = &config.options {
+        let message = match mount_type {
+            MountType::None => "options have no effect without a mount",
+            MountType::Bind | MountType::Overlay | MountType::Transient => return Ok(()),
+        };
+        return Err(ConfigError::new(
+            format!("{key}.options"),
+            options.span(),
+            message,
+        ));
+    }
+
+    Ok(())
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_size("root", &self.root.size, self.root.transient)?;
+        validate_mount(
+            "etc",
+            self.etc.span(),
+            self.etc.get_ref(),
+            Some(MountType::Overlay),
+        )?;
+        validate_mount(
+            "var",
+            self.var.span(),
+            self.var.get_ref(),
+            Some(MountType::Bind),
+        )?;
+
+        for (path, config) in &self.mounts {
+            let key = format!("mounts.{:?}", path.get_ref());
+            let relative = Path::new(path.get_ref().trim_start_matches('/'));
+            let components: Vec<_> = relative.components().collect();
+            if components.is_empty()
+                || !components
+                    .iter()
+                    .all(|c| matches!(c, Component::Normal(..)))
+            {
+                return Err(ConfigError::new(key, path.span(), "invalid mount path"));
+            }
+            if relative == Path::new("etc") || relative == Path::new("var") {
+                return Err(ConfigError::new(
+                    key,
+                    path.span(),
+                    "use the [etc] and [var] tables",
+                ));
+            }
+            validate_mount(&key, path.span(), config, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the state mounts: `etc`, `var` and then the ones in `[mounts]`, as triples of
+    /// path relative to the root, config and default mount type
+    fn state_mounts(&self) -> impl Iterator<Item = (&str, &MountConfig, MountType)> {
+        [
+            ("etc", self.etc.get_ref(), MountType::Overlay),
+            ("var", self.var.get_ref(), MountType::Bind),
+        ]
+        .into_iter()
+        .chain(self.mounts.iter().map(|(path, config)| {
+            let path = path.get_ref().trim_start_matches('/');
+            (path, config, MountType::None)
+        }))
+    }
+}
+
+/// Returns the names of the fields of a struct which derives `Deserialize`
+///
+/// The derived implementation passes them to `deserialize_struct()`, which is all we implement.
+fn struct_fields<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for Fields<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(serde::de::Error::custom("only looking for the fields"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(Fields(&mut fields));
+    fields
+}
+
+/// Returns the keys in the config file which don't correspond to a setting, with their spans
+///
+/// They're ignored (rather than refused) so that a config file written for a newer version still
+/// boots, but they're probably typos.
+fn unknown_keys(text: &str) -> Vec<(String, Range<usize>)> {
+    // Syntax errors are reported when deserializing
+    let Ok(doc) = ImDocument::parse(text) else {
+        return vec![];
+    };
+
+    let mut unknown = vec![];
+    let mut check = |table: &dyn TableLike, prefix: &str, fields: &[&str]| {
+        for (key, _) in table.iter() {
+            if !fields.contains(&key) {
+                let span = table.key(key).and_then(Key::span).unwrap_or_default();
+                unknown.push((format!("{prefix}{key}"), span));
+            }
+        }
+    };
+
+    check(doc.as_table(), "", struct_fields::<Config>());
+    let table = |name| doc.get(name).and_then(Item::as_table_like);
+    for name in ["etc", "var"] {
+        if let Some(table) = table(name) {
+            check(table, &format!("{name}."), struct_fields::<MountConfig>());
+        }
+    }
+    if let Some(table) = table("root") {
+        check(table, "root.", struct_fields::<RootConfig>());
+    }
+    if let Some(table) = table("signature") {
+        check(table, "signature.", struct_fields::<SignatureConfig>());
+    }
+    if let Some(mounts) = table("mounts") {
+        for (path, item) in mounts.iter() {
+            if let Some(table) = item.as_table_like() {
+                check(
+                    table,
+                    &format!("mounts.{path:?}."),
+                    struct_fields::<MountConfig>(),
+                );
+            }
+        }
+    }
+    if let Some(extensions) = doc.get("extension").and_then(Item::as_array_of_tables) {
+        for (n, table) in extensions.iter().enumerate() {
+            check(
+                table,
+                &format!("extension[{n}]."),
+                struct_fields::<ExtensionConfig>(),
+            );
+        }
+    }
+
+    unknown
+}
+
+fn load_config(path: &Path, logger: &Logger) -> Result<Config> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        // All of the settings have defaults
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(toml::from_str("")?),
+        Err(err) => Err(err)?,
+    };
+    let line = |span: Range<usize>| text[..span.start].matches('\n').count() + 1;
+
+    let config: Config =
+        toml::from_str(&text).with_context(|| format!("Invalid config file {path:?}"))?;
+    if let Err(err) = config.validate() {
+        let line = line(err.span);
+        anyhow::bail!("{}:{line}: {}: {}", path.display(), err.key, err.message);
+    }
+
+    for (key, span) in unknown_keys(&text) {
+        logger.log(
+            Priority::Warning,
+            &format!(
+                "{}:{}: {key}: unknown key, ignored",
+                path.display(),
+                line(span)
+            ),
+            &[],
+        );
+    }
+
+    Ok(config)
 }
 
 // Command-line arguments
@@ -132,8 +536,11 @@ fn bind_mount(fd: impl AsFd, path: &str) -> rustix::io::Result<OwnedFd> {
     })
 }
 
-fn mount_tmpfs() -> Result<OwnedFd> {
+fn mount_tmpfs(size: Option<&str>) -> Result<OwnedFd> {
     let tmpfs = FsHandle::open("tmpfs")?;
+    if let Some(size) = size {
+        fsconfig_set_string(tmpfs.as_fd(), "size", size)?;
+    }
     fsconfig_create(tmpfs.as_fd())?;
     Ok(fsmount(
         tmpfs.as_fd(),
@@ -142,7 +549,12 @@ fn mount_tmpfs() -> Result<OwnedFd> {
     )?)
 }
 
-fn overlay_state(base: impl AsFd, state: impl AsFd, source: &str) -> Result<()> {
+fn overlay_state(
+    base: impl AsFd,
+    state: impl AsFd,
+    source: &str,
+    attrs: MountAttrFlags,
+) -> Result<()> {
     let upper = ensure_dir(state.as_fd(), "upper")?;
     let work = ensure_dir(state.as_fd(), "work")?;
 
@@ -152,17 +564,27 @@ fn overlay_state(base: impl AsFd, state: impl AsFd, source: &str) -> Result<()>
     overlayfs_set_fd(overlayfs.as_fd(), "upperdir", upper.as_fd())?;
     overlayfs_set_lower_and_data_fds(&overlayfs, base.as_fd(), None::<OwnedFd>)?;
     fsconfig_create(overlayfs.as_fd())?;
-    let fs = fsmount(
-        overlayfs.as_fd(),
-        FsMountFlags::FSMOUNT_CLOEXEC,
-        MountAttrFlags::empty(),
-    )?;
+    let fs = fsmount(overlayfs.as_fd(), FsMountFlags::FSMOUNT_CLOEXEC, attrs)?;
 
     Ok(mount_at(fs, base, ".")?)
 }
 
-fn overlay_transient(base: impl AsFd) -> Result<()> {
-    overlay_state(base, prepare_mount(mount_tmpfs()?)?, "transient")
+fn overlay_transient(base: impl AsFd, size: Option<&str>, attrs: MountAttrFlags) -> Result<()> {
+    overlay_state(base, prepare_mount(mount_tmpfs(size)?)?, "transient", attrs)
+}
+
+// Like ensure_dir(), but creating all missing parents, and readable for everyone: the state
+// directories of other mounts are mounted (or overlaid) on directories like /home
+fn ensure_state_dir(state: impl AsFd, path: &str) -> Result<OwnedFd> {
+    let mut dir = open_dir(&state, ".")?;
+    for name in path.split('/') {
+        match mkdirat(&dir, name, 0o755.into()) {
+            Ok(()) | Err(Errno::EXIST) => {}
+            Err(err) => Err(err)?,
+        }
+        dir = open_dir(&dir, name)?;
+    }
+    Ok(dir)
 }
 
 fn open_root_fs(path: &Path) -> Result<OwnedFd> {
@@ -178,8 +600,14 @@ fn open_root_fs(path: &Path) -> Result<OwnedFd> {
     Ok(rootfs)
 }
 
-fn mount_composefs_image(sysroot: &OwnedFd, name: &str, insecure: bool) -> Result<OwnedFd> {
-    match name.len() {
+// The repository's hash algorithm is determined by the length of the root image's digest
+fn mount_composefs_image(
+    sysroot: &OwnedFd,
+    image_addr: &str,
+    name: &str,
+    insecure: bool,
+) -> Result<OwnedFd> {
+    match image_addr.len() {
         128 => {
             let mut repo = Repository::<Sha512HashValue>::open_path(sysroot, "composefs")?;
             repo.set_insecure(insecure);
@@ -190,37 +618,95 @@ fn mount_composefs_image(sysroot: &OwnedFd, name: &str, insecure: bool) -> Resul
             repo.set_insecure(insecure);
             repo.mount(name).context("Failed to mount composefs image")
         }
-        _ => anyhow::bail!("Invalid composefs digest length: {}", name.len()),
+        _ => anyhow::bail!("Invalid composefs digest length: {}", image_addr.len()),
     }
 }
 
+fn mount_extensions(
+    sysroot: &OwnedFd,
+    new_root: impl AsFd,
+    image_addr: &str,
+    extensions: &[ExtensionConfig],
+    insecure: bool,
+) -> Result<()> {
+    // The images need to stay mounted until the overlays are created
+    let mut images = vec![];
+    // The layers to stack on top of each path, in the order they're listed in the config
+    let mut overlays: Vec<(&Path, Vec<OwnedFd>)> = vec![];
+
+    for extension in extensions {
+        let path = extension
+            .path
+            .strip_prefix("/")
+            .with_context(|| format!("Extension path {:?} isn't absolute", extension.path))?;
+        let image = mount_composefs_image(sysroot, image_addr, &extension.image, insecure)
+            .with_context(|| format!("Failed to mount extension {}", extension.image))?;
+
+        match extension.mode {
+            ExtensionMode::Mount => mount_at(image, &new_root, path)?,
+            ExtensionMode::Overlay => {
+                let image = prepare_mount(image)?;
+                let layer = open_dir(&image, path)?;
+                images.push(image);
+                match overlays.iter_mut().find(|(p, _)| *p == path) {
+                    Some((_, layers)) => layers.push(layer),
+                    None => overlays.push((path, vec![layer])),
+                }
+            }
+        }
+    }
+
+    for (path, mut layers) in overlays {
+        // Extensions listed later go on top, and the root filesystem goes at the bottom
+        layers.reverse();
+        layers.push(open_dir(&new_root, path)?);
+
+        let overlayfs = FsHandle::open("overlay")?;
+        fsconfig_set_string(overlayfs.as_fd(), "source", "extensions")?;
+        overlayfs_set_lower_fds(&overlayfs, &layers)?;
+        fsconfig_create(overlayfs.as_fd())?;
+        let fs = fsmount(
+            overlayfs.as_fd(),
+            FsMountFlags::FSMOUNT_CLOEXEC,
+            MountAttrFlags::empty(),
+        )?;
+        mount_at(fs, &new_root, path)?;
+    }
+
+    Ok(())
+}
+
 fn mount_subdir(
     new_root: impl AsFd,
     state: impl AsFd,
     subdir: &str,
-    config: MountConfig,
+    config: &MountConfig,
     default: MountType,
 ) -> Result<()> {
-    let mount_type = match config.mount {
-        Some(mt) => mt,
-        None => match config.transient {
-            true => MountType::Transient,
-            false => default,
-        },
-    };
-
-    match mount_type {
+    match config.mount_type().unwrap_or(default) {
         MountType::None => Ok(()),
+        // The options are applied later: see remount_bind()
         MountType::Bind => Ok(mount_at(bind_mount(&state, subdir)?, &new_root, subdir)?),
         MountType::Overlay => overlay_state(
             open_dir(&new_root, subdir)?,
             open_dir(&state, subdir)?,
             "overlay",
+            config.attrs(),
         ),
-        MountType::Transient => overlay_transient(open_dir(&new_root, subdir)?),
+        MountType::Transient => {
+            overlay_transient(open_dir(&new_root, subdir)?, config.size(), config.attrs())
+        }
     }
 }
 
+// Bind mounts can't be given mount options when they're created, so they're remounted with them.
+// This only works once they're attached to the mount namespace, so it's done last.
+fn remount_bind(sysroot: &Path, subdir: &str, config: &MountConfig) -> Result<()> {
+    let path = sysroot.join(subdir);
+    mount_remount(&path, MountFlags::BIND | config.flags(), "")
+        .with_context(|| format!("Failed to apply the mount options of {path:?}"))
+}
+
 fn gpt_workaround() -> Result<()> {
     // https://github.com/systemd/systemd/issues/35017
     let rootdev = stat("/dev/gpt-auto-root")?;
@@ -248,12 +734,51 @@ fn parse_image_address(cmdline: &str) -> Result<(String, bool)> {
     }
 }
 
-fn setup_root(args: Args) -> Result<()> {
-    let config = match std::fs::read_to_string(args.config) {
-        Ok(text) => toml::from_str(&text)?,
-        Err(err) if err.kind() == ErrorKind::NotFound => Config::default(),
-        Err(err) => Err(err)?,
+fn verify_signature(sysroot: &Path, config: &SignatureConfig, image_addr: &str) -> Result<()> {
+    let Some(key_path) = &config.key else {
+        return Ok(());
     };
+    let key = std::fs::read(key_path)
+        .with_context(|| format!("Failed to read signing key {key_path:?}"))?;
+    let key = TrustedKey::from_pem(&key)?;
+
+    let name = format!("{image_addr}.sig");
+    for dir in &config.paths {
+        let path = sysroot.join(dir).join(&name);
+        match std::fs::read(&path) {
+            Ok(signature) => {
+                key.verify(image_addr, &signature)
+                    .with_context(|| format!("Failed to verify {path:?}"))?;
+                return Ok(());
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}"))?,
+        }
+    }
+
+    anyhow::bail!("No signature found for image {image_addr}, refusing to mount it")
+}
+
+/// Checks that nothing outside of the signature can change what gets mounted, when the root image
+/// is verified with a signature.
+fn check_signed_boot(config: &Config, image_addr: &str, insecure: bool) -> Result<()> {
+    anyhow::ensure!(
+        !insecure,
+        "composefs=? (mounting without fs-verity) isn't allowed with a signed root image"
+    );
+    for extension in &config.extensions {
+        anyhow::ensure!(
+            extension.image.len() == image_addr.len()
+                && extension.image.bytes().all(|c| c.is_ascii_hexdigit()),
+            "Extension {} needs to be referred to by digest with a signed root image",
+            extension.image
+        );
+    }
+    Ok(())
+}
+
+fn setup_root(args: Args, logger: &Logger) -> Result<()> {
+    let config = load_config(&args.config, logger)?;
 
     let sysroot = open_dir(CWD, &args.sysroot)
         .with_context(|| format!("Failed to open sysroot {:?}", args.sysroot))?;
@@ -264,10 +789,35 @@ fn setup_root(args: Args) -> Result<()> {
     };
 
     let (image_addr, insecure) = parse_image_address(cmdline)?;
+    verify_signature(&args.sysroot, &config.signature, &image_addr)?;
+    if config.signature.key.is_some() {
+        check_signed_boot(&config, &image_addr, insecure)?;
+    }
+
+    // This needs to happen before anything from the state directory gets mounted
+    let state = args.sysroot.join("state/deploy").join(&image_addr);
+    let reset_cmdline = match config.signature.key {
+        Some(_) => None,
+        None => Some(cmdline.as_str()),
+    };
+    let reset = match state.is_dir() {
+        true => {
+            reset_at_boot(&state, reset_cmdline).context("Failed to reset the deployment state")?
+        }
+        false => None,
+    };
+    if let Some(reset) = reset {
+        let what = reset.to_string();
+        logger.log(
+            Priority::Info,
+            &format!("Reset the state of {image_addr} ({what})"),
+            &[("COMPOSEFS_IMAGE", &image_addr), ("COMPOSEFS_RESET", &what)],
+        );
+    }
 
     let new_root = match args.root_fs {
         Some(path) => open_root_fs(&path).context("Failed to clone specified root fs")?,
-        None => mount_composefs_image(&sysroot, &image_addr, insecure)?,
+        None => mount_composefs_image(&sysroot, &image_addr, &image_addr, insecure)?,
     };
 
     // we need to clone this before the next step to make sure we get the old one
@@ -281,18 +831,41 @@ fn setup_root(args: Args) -> Result<()> {
     }
 
     if config.root.transient {
-        overlay_transient(&new_root)?;
+        let size = config
+            .root
+            .size
+            .as_ref()
+            .map(|size| size.get_ref().as_str());
+        overlay_transient(&new_root, size, MountAttrFlags::empty())?;
     }
 
+    mount_extensions(
+        &sysroot,
+        &new_root,
+        &image_addr,
+        &config.extensions,
+        insecure,
+    )?;
+
     match mount_at(&sysroot_clone, &new_root, "sysroot") {
         Ok(()) | Err(Errno::NOENT) => {}
         Err(err) => Err(err)?,
     }
 
-    // etc + var
+    // etc, var and the other state mounts
     let state = open_dir(open_dir(&sysroot, "state/deploy")?, &image_addr)?;
-    mount_subdir(&new_root, &state, "etc", config.etc, MountType::Overlay)?;
-    mount_subdir(&new_root, &state, "var", config.var, MountType::Bind)?;
+    for (path, mount, default) in config.state_mounts() {
+        if default == MountType::None
+            && matches!(
+                mount.mount_type(),
+                Some(MountType::Bind | MountType::Overlay)
+            )
+        {
+            ensure_state_dir(&state, path)?;
+        }
+        mount_subdir(&new_root, &state, path, mount, default)
+            .with_context(|| format!("Failed to set up /{path}"))?;
+    }
 
     if cfg!(not(feature = "pre-6.15")) {
         // Replace the /sysroot with the new composed root filesystem
@@ -300,13 +873,59 @@ fn setup_root(args: Args) -> Result<()> {
         mount_at(&new_root, CWD, &args.sysroot)?;
     }
 
+    for (path, mount, default) in config.state_mounts() {
+        if mount.mount_type().unwrap_or(default) == MountType::Bind && mount.options.is_some() {
+            remount_bind(&args.sysroot, path, mount)?;
+        }
+    }
+
+    let mut message = format!("Mounted composefs image {image_addr}");
+    if insecure {
+        message.push_str(" (without fs-verity)");
+    }
+    let extensions: Vec<_> = config.extensions.iter().map(|e| e.image.as_str()).collect();
+    logger.log(
+        Priority::Info,
+        &message,
+        &[
+            ("COMPOSEFS_IMAGE", &image_addr),
+            ("COMPOSEFS_INSECURE", if insecure { "1" } else { "0" }),
+            (
+                "COMPOSEFS_SIGNED",
+                if config.signature.key.is_some() {
+                    "1"
+                } else {
+                    "0"
+                },
+            ),
+            ("COMPOSEFS_EXTENSIONS", &extensions.join(" ")),
+        ],
+    );
+
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let _ = gpt_workaround(); // best effort
-    setup_root(args)
+fn main() -> ExitCode {
+    let logger = Logger::open();
+
+    let result = match std::env::args_os().next() {
+        Some(argv0) if generator::is_generator(&argv0) => {
+            generator::run(generator::GeneratorArgs::parse(), &logger)
+        }
+        _ => {
+            let args = Args::parse();
+            let _ = gpt_workaround(); // best effort
+            setup_root(args, &logger)
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            logger.log(Priority::Err, &format!("Error: {err:?}"), &[]);
+            ExitCode::FAILURE
+        }
+    }
 }
 
 #[cfg(test)]
@@ -337,4 +956,253 @@ mod test {
         let (parsed_addr, _) = parse_image_address(cmdline).unwrap();
         assert_eq!(digest, parsed_addr);
     }
+
+    #[test]
+    fn test_mount_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [etc]
+            transient = true
+            size = "64M"
+            options = ["nosuid", "nodev"]
+
+            [root]
+            transient = true
+            size = "10%"
+
+            [mounts."/home"]
+            mount = "bind"
+
+            [mounts."/srv/www"]
+            mount = "overlay"
+            options = ["noexec"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let etc = config.etc.get_ref();
+        assert_eq!(etc.mount_type(), Some(MountType::Transient));
+        assert_eq!(etc.size(), Some("64M"));
+        assert_eq!(
+            etc.attrs(),
+            MountAttrFlags::MOUNT_ATTR_NOSUID | MountAttrFlags::MOUNT_ATTR_NODEV
+        );
+        assert_eq!(config.var.get_ref().mount_type(), None);
+        assert_eq!(config.var.get_ref().attrs(), MountAttrFlags::empty());
+        assert_eq!(config.var.span(), 0..0);
+
+        let mounts: Vec<_> = config.mounts.iter().collect();
+        assert_eq!(mounts[0].0.get_ref(), "/home");
+        assert_eq!(mounts[0].1.mount_type(), Some(MountType::Bind));
+        assert_eq!(mounts[0].1.flags(), MountFlags::empty());
+        assert_eq!(mounts[1].0.get_ref(), "/srv/www");
+        assert_eq!(mounts[1].1.attrs(), MountAttrFlags::MOUNT_ATTR_NOEXEC);
+        let paths: Vec<_> = config.state_mounts().map(|(path, ..)| path).collect();
+        assert_eq!(paths, ["etc", "var", "home", "srv/www"]);
+
+        assert!(valid_tmpfs_size("1024"));
+        assert!(valid_tmpfs_size("2g"));
+        for size in ["", "M", "1MB", "1.5G", "-1"] {
+            assert!(!valid_tmpfs_size(size), "{size}");
+        }
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("setup-root-conf.toml");
+        let error = |text: &str| {
+            std::fs::write(&path, text).unwrap();
+            let err = load_config(&path, &Logger::stderr()).err().unwrap();
+            format!("{err:#}").replace(&path.display().to_string(), "conf")
+        };
+
+        assert!(load_config(&tmp.path().join("missing"), &Logger::stderr()).is_ok());
+
+        let cases = [
+            (
+                "[etc]\nsize = \"1G\"\n",
+                "conf:2: etc.size: size only applies to transient mounts",
+            ),
+            (
+                "[var]\ntransient = true\nsize = \"lots\"\n",
+                "conf:3: var.size: expected a size like \"512M\" or \"50%\"",
+            ),
+            (
+                "[etc]\nmount = \"none\"\noptions = [\"nodev\"]\n",
+                "conf:3: etc.options: options have no effect without a mount",
+            ),
+            (
+                "[root]\nsize = \"1G\"\n",
+                "conf:2: root.size: size only applies to transient mounts",
+            ),
+            (
+                "\n[mounts.\"/home\"]\noptions = [\"nodev\"]\n",
+                "conf:2: mounts.\"/home\": mount or transient needs to be set",
+            ),
+            (
+                "[mounts.\"/srv/../etc\"]\nmount = \"bind\"\n",
+                "conf:1: mounts.\"/srv/../etc\": invalid mount path",
+            ),
+            (
+                "[mounts.\"/etc\"]\nmount = \"bind\"\n",
+                "conf:1: mounts.\"/etc\": use the [etc] and [var] tables",
+            ),
+            (
+                "[mounts.\"/home\"]\nmount = \"none\"\noptions = [\"ro\"]\n",
+                "conf:3: mounts.\"/home\".options: options have no effect without a mount",
+            ),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(error(text), expected);
+        }
+
+        // Errors from the parser point at the key as well
+        let err = error("[etc]\noptions = [\"nosuid\", \"nodevv\"]\n");
+        assert!(err.contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let text = "[etc]\n\
+                    mount = \"overlay\"\n\
+                    transiant = true\n\
+                    \n\
+                    [var]\n\
+                    mount = \"bind\"\n\
+                    options = [\"nodev\"]\n\
+                    \n\
+                    [mounts.\"/home\"]\n\
+                    mount = \"bind\"\n\
+                    sise = \"1G\"\n\
+                    \n\
+                    [[extension]]\n\
+                    image = \"refs/tools\"\n\
+                    \n\
+                    [[extension]]\n\
+                    image = \"refs/more\"\n\
+                    moed = \"mount\"\n\
+                    \n\
+                    [signature]\n\
+                    paths = []\n\
+                    \n\
+                    [future]\n\
+                    setting = 1\n";
+        let config: Config = toml::from_str(text).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.var.get_ref().flags(), MountFlags::NODEV);
+        assert!(text[config.etc.span()].starts_with("[etc]"));
+
+        let unknown: Vec<_> = unknown_keys(text)
+            .into_iter()
+            .map(|(key, span)| (key, &text[span]))
+            .collect();
+        assert_eq!(
+            unknown,
+            [
+                ("future".to_string(), "future"),
+                ("etc.transiant".to_string(), "transiant"),
+                ("mounts.\"/home\".sise".to_string(), "sise"),
+                ("extension[1].moed".to_string(), "moed"),
+            ]
+        );
+
+        assert!(unknown_keys("[etc]\nmount = \"bind\"\n[root]\ntransient = true").is_empty());
+        assert_eq!(
+            struct_fields::<Config>(),
+            ["etc", "var", "root", "mounts", "extension", "signature"]
+        );
+    }
+
+    #[test]
+    fn test_extension_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[extension]]
+            image = "refs/tools"
+
+            [[extension]]
+            image = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52"
+            path = "/opt/vendor"
+            mode = "mount"
+            "#,
+        )
+        .unwrap();
+        let [tools, vendor] = &config.extensions[..] else {
+            panic!("Expected two extensions");
+        };
+        assert_eq!(tools.image, "refs/tools");
+        assert_eq!(tools.path, Path::new("/usr"));
+        assert_eq!(tools.mode, ExtensionMode::Overlay);
+        assert_eq!(vendor.path, Path::new("/opt/vendor"));
+        assert_eq!(vendor.mode, ExtensionMode::Mount);
+
+        assert!(toml::from_str::<Config>("[[extension]]\npath = \"/usr\"").is_err());
+        assert!(toml::from_str::<Config>("").unwrap().extensions.is_empty());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        use composefs_boot::signature::sign_image;
+        use openssl::pkey::PKey;
+
+        let digest = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52";
+        let tmp = tempfile::TempDir::new().unwrap();
+        let sysroot = tmp.path().join("sysroot");
+        let esp = tmp.path().join("esp");
+        std::fs::create_dir_all(sysroot.join("composefs/signatures")).unwrap();
+        std::fs::create_dir(&esp).unwrap();
+
+        let key = PKey::generate_ed25519().unwrap();
+        let key_path = tmp.path().join("key.pem");
+        std::fs::write(&key_path, key.public_key_to_pem().unwrap()).unwrap();
+
+        // Without a key, nothing is checked
+        let mut config = SignatureConfig::default();
+        verify_signature(&sysroot, &config, digest).unwrap();
+
+        config.key = Some(key_path);
+        let err = verify_signature(&sysroot, &config, digest).unwrap_err();
+        assert!(err.to_string().contains("No signature found"));
+
+        let signature = sign_image(digest, &key).unwrap();
+        let sig_name = format!("{digest}.sig");
+        std::fs::write(
+            sysroot.join("composefs/signatures").join(&sig_name),
+            &signature,
+        )
+        .unwrap();
+        verify_signature(&sysroot, &config, digest).unwrap();
+
+        // The first signature found is the one that counts
+        std::fs::write(esp.join(&sig_name), b"bad").unwrap();
+        config.paths.insert(0, esp.clone());
+        assert!(verify_signature(&sysroot, &config, digest).is_err());
+        std::fs::write(esp.join(&sig_name), &signature).unwrap();
+        verify_signature(&sysroot, &config, digest).unwrap();
+
+        // The signature is for a different image
+        let other = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        std::fs::write(esp.join(format!("{other}.sig")), &signature).unwrap();
+        assert!(verify_signature(&sysroot, &config, other).is_err());
+    }
+
+    #[test]
+    fn test_check_signed_boot() {
+        let digest = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52";
+        let config: Config = toml::from_str(&format!(
+            "[[extension]]\nimage = \"{digest}\"\npath = \"/opt\"\n"
+        ))
+        .unwrap();
+        check_signed_boot(&config, digest, false).unwrap();
+
+        // The unsigned ? on the command line can't turn off fs-verity
+        let err = check_signed_boot(&config, digest, true).unwrap_err();
+        assert!(err.to_string().contains("composefs=?"), "{err}");
+
+        // Refs aren't covered by anything
+        let config: Config = toml::from_str("[[extension]]\nimage = \"refs/tools\"\n").unwrap();
+        assert!(check_signed_boot(&config, digest, false).is_err());
+    }
 }
@@ -0,0 +1,423 @@
+//! Comparison of composefs images.
+//!
+//! This module walks two EROFS images produced by composefs and reports which paths were added,
+//! removed, or modified between them.  Modifications are classified into content changes (the
+//! file data, symlink target, or device number differs, including external files whose fs-verity
+//! digest in `trusted.overlay.metacopy` changed) and metadata-only changes (mode, ownership,
+//! extended attributes, mtime).
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use zerocopy::FromBytes;
+
+use crate::{
+    erofs::{
+        composefs::OverlayMetacopy,
+        format::{
+            DataLayout, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+        },
+        reader::{
+            is_internal_whiteout, original_xattr_name, ErofsReaderError, Image, InodeHeader,
+            InodeType, ReadResult,
+        },
+    },
+    fsverity::FsVerityHashValue,
+};
+
+/// A kind of metadata change between two versions of a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MetadataChange {
+    /// The file type or permission bits changed.
+    Mode,
+    /// The owning user or group changed.
+    Owner,
+    /// The set of (user-visible) extended attributes changed.
+    Xattrs,
+    /// The modification time changed.
+    Mtime,
+}
+
+impl MetadataChange {
+    /// A short lowercase name for the change, suitable for display.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetadataChange::Mode => "mode",
+            MetadataChange::Owner => "owner",
+            MetadataChange::Xattrs => "xattrs",
+            MetadataChange::Mtime => "mtime",
+        }
+    }
+}
+
+impl fmt::Display for MetadataChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The way in which a single path differs between two images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The path exists only in the new image.
+    Added,
+    /// The path exists only in the old image.
+    Removed,
+    /// The path exists in both images but differs.
+    Modified {
+        /// True if the content of the file differs.  For external files this means that the
+        /// fs-verity digest of the backing object changed.
+        content: bool,
+        /// The metadata fields which differ, in sorted order.
+        metadata: Vec<MetadataChange>,
+    },
+}
+
+/// A single difference between two images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The absolute path inside of the image.
+    pub path: PathBuf,
+    /// What changed about it.
+    pub kind: ChangeKind,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ChangeKind::Added => write!(f, "+ {}", self.path.display()),
+            ChangeKind::Removed => write!(f, "- {}", self.path.display()),
+            ChangeKind::Modified { content, metadata } => {
+                write!(f, "M {}", self.path.display())?;
+                let mut what = vec![];
+                if *content {
+                    what.push("content");
+                }
+                what.extend(metadata.iter().map(MetadataChange::as_str));
+                write!(f, " ({})", what.join(", "))
+            }
+        }
+    }
+}
+
+/// The content of an entry, as far as comparison is concerned.
+#[derive(Debug, PartialEq, Eq)]
+enum Content<ObjectID: FsVerityHashValue> {
+    None,
+    Data(Box<[u8]>),
+    External(ObjectID),
+    Device(u32),
+}
+
+/// Extended attributes, by full name.
+type Xattrs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// The comparable state of a single path in an image.
+#[derive(Debug)]
+struct Entry<ObjectID: FsVerityHashValue> {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: (i64, u32),
+    xattrs: Xattrs,
+    content: Content<ObjectID>,
+}
+
+impl<ObjectID: FsVerityHashValue> Entry<ObjectID> {
+    fn compare(&self, other: &Self) -> Option<ChangeKind> {
+        let content = self.content != other.content;
+
+        let mut metadata = vec![];
+        if self.mode != other.mode {
+            metadata.push(MetadataChange::Mode);
+        }
+        if (self.uid, self.gid) != (other.uid, other.gid) {
+            metadata.push(MetadataChange::Owner);
+        }
+        if self.xattrs != other.xattrs {
+            metadata.push(MetadataChange::Xattrs);
+        }
+        if self.mtime != other.mtime {
+            metadata.push(MetadataChange::Mtime);
+        }
+
+        (content || !metadata.is_empty()).then_some(ChangeKind::Modified { content, metadata })
+    }
+}
+
+/// Walks an image, recording the comparable state of every path.
+struct Walker<'img, ObjectID: FsVerityHashValue> {
+    image: &'img Image<'img>,
+    visited_dirs: HashSet<u64>,
+    entries: BTreeMap<PathBuf, Entry<ObjectID>>,
+}
+
+impl<ObjectID: FsVerityHashValue> Walker<'_, ObjectID> {
+    fn mtime(&self, inode: &InodeType) -> (i64, u32) {
+        match inode {
+            // Compact inodes store their mtime in the superblock
+            InodeType::Compact(..) => (
+                self.image.sb.build_time.get() as i64,
+                self.image.sb.build_time_nsec.get(),
+            ),
+            InodeType::Extended(..) => (inode.mtime(), inode.mtime_nsec()),
+        }
+    }
+
+    /// Returns the original xattrs and the metacopy digest, if any.
+    fn xattrs(&self, inode: &InodeType) -> ReadResult<(Xattrs, Option<ObjectID>)> {
+        let mut xattrs = BTreeMap::new();
+        let mut metacopy = None;
+
+        for (name, value) in self.image.inode_xattrs(inode)? {
+            if name == b"trusted.overlay.metacopy" {
+                if let Ok(value) = OverlayMetacopy::<ObjectID>::read_from_bytes(value) {
+                    if value.valid() {
+                        metacopy = Some(value.digest);
+                    }
+                }
+            } else if let Some(name) = original_xattr_name(&name) {
+                xattrs.insert(name.into_owned(), value.to_vec());
+            }
+        }
+
+        Ok((xattrs, metacopy))
+    }
+
+    fn visit(&mut self, path: &Path, nid: u64) -> ReadResult<()> {
+        let inode = self.image.try_inode(nid)?;
+        let (mut xattrs, metacopy) = self.xattrs(&inode)?;
+        let mut mode = inode.mode().0.get();
+
+        // An escaped whiteout is stored as a regular file: report it as a character device
+        if mode & S_IFMT == S_IFREG && xattrs.remove(&b"trusted.overlay.whiteout"[..]).is_some() {
+            mode = (mode & !S_IFMT) | S_IFCHR;
+        }
+
+        let content = match mode & S_IFMT {
+            S_IFREG if inode.data_layout() == DataLayout::ChunkBased => {
+                Content::External(metacopy.ok_or(ErofsReaderError::MissingMetacopy)?)
+            }
+            S_IFREG | S_IFLNK => Content::Data(self.image.inode_data(&inode)?),
+            S_IFBLK | S_IFCHR => Content::Device(inode.rdev()),
+            S_IFDIR | S_IFIFO | S_IFSOCK => Content::None,
+            _ => return Err(ErofsReaderError::InvalidFileType),
+        };
+
+        self.entries.insert(
+            path.to_path_buf(),
+            Entry {
+                mode,
+                uid: inode.uid(),
+                gid: inode.gid(),
+                mtime: self.mtime(&inode),
+                xattrs,
+                content,
+            },
+        );
+
+        if inode.mode().is_dir() {
+            if !self.visited_dirs.insert(nid) {
+                return Err(ErofsReaderError::DirectoryHardlinks);
+            }
+            for entry in self.image.directory_entries(&inode)? {
+                if entry.name == b"." || entry.name == b".." {
+                    continue;
+                }
+                let child = self.image.try_inode(entry.nid())?;
+                if path == Path::new("/") && is_internal_whiteout(entry.name, &child) {
+                    continue;
+                }
+                self.visit(&path.join(OsStr::from_bytes(entry.name)), entry.nid())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_entries<ObjectID: FsVerityHashValue>(
+    image: &[u8],
+) -> ReadResult<BTreeMap<PathBuf, Entry<ObjectID>>> {
+    let image = Image::try_open(image)?;
+    let mut walker = Walker {
+        image: &image,
+        visited_dirs: HashSet::new(),
+        entries: BTreeMap::new(),
+    };
+    walker.visit(Path::new("/"), image.sb.root_nid.get() as u64)?;
+    Ok(walker.entries)
+}
+
+/// Compares two composefs EROFS images.
+///
+/// Returns the list of changes required to get from `old` to `new`, sorted by path.  Added or
+/// removed directories are reported along with every path inside of them.  Hardlink counts are
+/// not considered to be part of the metadata, since they change as a side-effect of adding and
+/// removing other paths.  Returns an error if either image is malformed.
+pub fn diff_images<ObjectID: FsVerityHashValue>(old: &[u8], new: &[u8]) -> ReadResult<Vec<Change>> {
+    let old = read_entries::<ObjectID>(old)?;
+    let mut new = read_entries::<ObjectID>(new)?;
+    let mut changes = vec![];
+
+    for (path, old_entry) in old {
+        let kind = match new.remove(&path) {
+            Some(new_entry) => match old_entry.compare(&new_entry) {
+                Some(kind) => kind,
+                None => continue,
+            },
+            None => ChangeKind::Removed,
+        };
+        changes.push(Change { path, kind });
+    }
+
+    changes.extend(new.into_keys().map(|path| Change {
+        path,
+        kind: ChangeKind::Added,
+    }));
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dumpfile::dumpfile_to_filesystem,
+        erofs::{format, writer::mkfs_erofs_default},
+        fsverity::Sha256HashValue,
+    };
+
+    const DIGEST_A: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+    const DIGEST_B: &str = "0000000000000000000000000000000000000000000000000000000000000002";
+
+    fn image(dumpfile: &str) -> Box<[u8]> {
+        let fs = dumpfile_to_filesystem::<Sha256HashValue>(dumpfile).unwrap();
+        mkfs_erofs_default(&fs)
+    }
+
+    fn diff(old: &str, new: &str) -> Vec<String> {
+        diff_images::<Sha256HashValue>(&image(old), &image(new))
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_identical() {
+        let dump = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                    /file 5 100644 1 0 0 0 1000.0 - hello - user.a=b\n";
+        assert!(diff(dump, dump).is_empty());
+    }
+
+    #[test]
+    fn test_added_removed() {
+        let old = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /gone 5 100644 1 0 0 0 1000.0 - hello -\n";
+        let new = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /dir 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /dir/new 5 100644 1 0 0 0 1000.0 - hello -\n";
+        assert_eq!(diff(old, new), ["+ /dir", "+ /dir/new", "- /gone"]);
+    }
+
+    #[test]
+    fn test_content_changes() {
+        let old = format!(
+            "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+             /external 4096 100644 1 0 0 0 1000.0 00/{} - {DIGEST_A}\n\
+             /inline 5 100644 1 0 0 0 1000.0 - hello -\n\
+             /link 7 120777 1 0 0 0 1000.0 /target - -\n",
+            &DIGEST_A[2..]
+        );
+        let new = format!(
+            "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+             /external 4096 100644 1 0 0 0 1000.0 00/{} - {DIGEST_B}\n\
+             /inline 5 100644 1 0 0 0 1000.0 - world -\n\
+             /link 7 120777 1 0 0 0 1000.0 /tarjet - -\n",
+            &DIGEST_B[2..]
+        );
+        assert_eq!(
+            diff(&old, &new),
+            [
+                "M /external (content)",
+                "M /inline (content)",
+                "M /link (content)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata_changes() {
+        let old = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /mode 5 100644 1 0 0 0 1000.0 - hello -\n\
+                   /owner 5 100644 1 0 0 0 1000.0 - hello -\n\
+                   /xattr 5 100644 1 0 0 0 1000.0 - hello - user.a=b\n\
+                   /mtime 5 100644 1 0 0 0 1000.0 - hello -\n\
+                   /both 5 100644 1 0 0 0 1000.0 - hello -\n";
+        let new = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /mode 5 100755 1 0 0 0 1000.0 - hello -\n\
+                   /owner 5 100644 1 1000 0 0 1000.0 - hello -\n\
+                   /xattr 5 100644 1 0 0 0 1000.0 - hello - user.a=c\n\
+                   /mtime 5 100644 1 0 0 0 2000.0 - hello -\n\
+                   /both 6 100600 1 0 0 0 1000.0 - hello! -\n";
+        assert_eq!(
+            diff(old, new),
+            [
+                "M /both (content, mode)",
+                "M /mode (mode)",
+                "M /mtime (mtime)",
+                "M /owner (owner)",
+                "M /xattr (xattrs)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaped_overlay_xattrs() {
+        let old = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /dir 4096 40755 2 0 0 0 1000.0 - - -\n";
+        let new = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /dir 4096 40755 2 0 0 0 1000.0 - - - trusted.overlay.opaque=y\n";
+        assert_eq!(diff(old, new), ["M /dir (xattrs)"]);
+    }
+
+    #[test]
+    fn test_whiteouts() {
+        // Whiteouts from the original filesystem are reported, except for the ones in the root
+        // directory which look like those added by mkcomposefs
+        let old = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /dir 4096 40755 2 0 0 0 1000.0 - - -\n";
+        let new = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /00 0 20000 1 0 0 0 1000.0 - - -\n\
+                   /dir 4096 40755 2 0 0 0 1000.0 - - -\n\
+                   /dir/00 0 20000 1 0 0 0 1000.0 - - -\n\
+                   /whiteout 0 20000 1 0 0 0 1000.0 - - -\n";
+        assert_eq!(diff(old, new), ["+ /dir/00", "+ /whiteout"]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let dump = "/ 4096 40755 2 0 0 0 1000.0 - - -\n\
+                    /file 5 100644 1 0 0 0 1000.0 - hello -\n";
+        let good = image(dump);
+        for len in [0, 100, 1100] {
+            assert!(diff_images::<Sha256HashValue>(&good, &good[..len]).is_err());
+        }
+
+        // Point the root directory at an inode past the end of the image
+        let mut bad = good.to_vec();
+        let sb = 1024 + std::mem::offset_of!(format::Superblock, root_nid);
+        bad[sb..sb + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            diff_images::<Sha256HashValue>(&good, &bad),
+            Err(ErofsReaderError::InvalidInode(..))
+        ));
+    }
+}
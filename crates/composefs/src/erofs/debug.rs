@@ -14,10 +14,13 @@ use std::{
 };
 
 use anyhow::Result;
-use zerocopy::FromBytes;
+use zerocopy::{little_endian::U16, FromBytes};
 
 use super::{
-    format::{self, CompactInodeHeader, ComposefsHeader, ExtendedInodeHeader, Superblock},
+    format::{
+        self, CompactInodeHeader, ComposefsHeader, DeflateConfig, ExtendedInodeHeader,
+        LclusterIndex, MapHeader, Superblock,
+    },
     reader::{DataBlock, DirectoryBlock, Image, Inode, InodeHeader, InodeOps, InodeType, XAttr},
 };
 
@@ -62,6 +65,7 @@ fn utf8_or_hex(data: &[u8]) -> String {
 enum SegmentType<'img> {
     Header(&'img ComposefsHeader),
     Superblock(&'img Superblock),
+    CompressionConfigs(&'img [u8]),
     CompactInode(&'img Inode<CompactInodeHeader>),
     ExtendedInode(&'img Inode<ExtendedInodeHeader>),
     XAttr(&'img XAttr),
@@ -75,6 +79,7 @@ impl SegmentType<'_> {
         match self {
             SegmentType::Header(h) => addr!(*h),
             SegmentType::Superblock(sb) => addr!(*sb),
+            SegmentType::CompressionConfigs(c) => c.as_ptr() as usize,
             SegmentType::CompactInode(i) => addr!(*i),
             SegmentType::ExtendedInode(i) => addr!(*i),
             SegmentType::XAttr(x) => addr!(*x),
@@ -87,6 +92,7 @@ impl SegmentType<'_> {
         match self {
             SegmentType::Header(h) => size_of_val(*h),
             SegmentType::Superblock(sb) => size_of_val(*sb),
+            SegmentType::CompressionConfigs(c) => c.len(),
             SegmentType::CompactInode(i) => size_of_val(*i),
             SegmentType::ExtendedInode(i) => size_of_val(*i),
             SegmentType::XAttr(x) => size_of_val(*x),
@@ -99,6 +105,7 @@ impl SegmentType<'_> {
         match self {
             SegmentType::Header(..) => "header",
             SegmentType::Superblock(..) => "superblock",
+            SegmentType::CompressionConfigs(..) => "compression configs",
             SegmentType::CompactInode(..) => "compact inode",
             SegmentType::ExtendedInode(..) => "extended inode",
             SegmentType::XAttr(..) => "shared xattr",
@@ -201,11 +208,54 @@ impl<'img> ImageVisitor<'img> {
         };
         this.note(SegmentType::Header(image.header), None);
         this.note(SegmentType::Superblock(image.sb), None);
+        if let Some(configs) = compression_configs(image) {
+            this.note(SegmentType::CompressionConfigs(configs), None);
+        }
         this.visit_inode(image.sb.root_nid.get() as u64, &PathBuf::from("/"));
         this.visited
     }
 }
 
+/// Finds the compression config records following the superblock, if present
+fn compression_configs<'img>(image: &Image<'img>) -> Option<&'img [u8]> {
+    if (image.sb.feature_incompat & format::FEATURE_INCOMPAT_COMPR_CFGS).get() == 0 {
+        return None;
+    }
+
+    let start = 1024 + size_of::<Superblock>() + 16 * image.sb.extslots as usize;
+    let mut end = start;
+    for _ in 0..image.sb.available_compr_algs.get().count_ones() {
+        end = end.next_multiple_of(4);
+        let size = U16::read_from_prefix(image.image.get(end..)?).ok()?.0;
+        end += size_of::<U16>() + size.get() as usize;
+    }
+    image.image.get(start..end)
+}
+
+fn write_compression_configs(f: &mut impl fmt::Write, configs: &[u8], algs: u16) -> fmt::Result {
+    writeln!(f, "Compression configs")?;
+    let mut offset = 0usize;
+    for alg in (0..16).filter(|alg| algs & (1 << alg) != 0) {
+        offset = offset.next_multiple_of(4);
+        let Ok((size, rest)) = U16::read_from_prefix(&configs[offset..]) else {
+            break;
+        };
+        let size = size.get() as usize;
+        let record = &rest[..size.min(rest.len())];
+        match DeflateConfig::ref_from_bytes(record) {
+            Ok(config) if alg == format::COMPRESSION_DEFLATE => {
+                writeln!(f, "{offset:+8x}     deflate ({size} bytes): {config:?}")?;
+            }
+            _ => writeln!(
+                f,
+                "{offset:+8x}     algorithm {alg} ({size} bytes): {record:?}"
+            )?,
+        }
+        offset += size_of::<U16>() + size;
+    }
+    Ok(())
+}
+
 impl fmt::Debug for XAttr {
     // Injective (ie: accounts for every byte in the input)
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -318,6 +368,33 @@ impl<T: fmt::Debug + InodeHeader> fmt::Debug for Inode<T> {
             return Ok(());
         };
 
+        // Compression metadata, followed by tail-packed compressed data
+        if self.header.data_layout().is_compressed() {
+            let padding = self.header.xattr_size().next_multiple_of(8) - self.header.xattr_size();
+            let Ok((map_header, rest)) = MapHeader::ref_from_prefix(&inline[padding..]) else {
+                return hexdump(f, inline, &raw const self.header as usize);
+            };
+            write_fields!(f, self, map_header,
+                reserved; idata_size; advise; algorithm_type; cluster_bits);
+
+            let idata_size = map_header.idata_size.get() as usize;
+            let rest = rest.get(8..).unwrap_or_default();
+            let n_indexes = rest.len().saturating_sub(idata_size) / size_of::<LclusterIndex>();
+            if let Ok((indexes, data)) =
+                <[LclusterIndex]>::ref_from_prefix_with_elems(rest, n_indexes)
+            {
+                for index in indexes {
+                    write_with_offset!(f, self, "lcluster", index)?;
+                }
+                hexdump(f, data, &raw const self.header as usize)?;
+            }
+
+            if let Ok(Some(data)) = self.inline_data(format::BLOCK_BITS) {
+                writeln!(f, "          # decompressed: {}", utf8_or_hex(&data))?;
+            }
+            return Ok(());
+        }
+
         // Directory dump
         if self.header.mode().is_dir() {
             let dir = DirectoryBlock::ref_from_bytes(inline).unwrap();
@@ -476,6 +553,11 @@ pub fn debug_img(output: &mut impl std::io::Write, data: &[u8]) -> Result<()> {
             SegmentType::Superblock(sb) => {
                 writeln!(output, "{offset:08x} {sb:?}")?;
             }
+            SegmentType::CompressionConfigs(configs) => {
+                let mut dump = String::new();
+                write_compression_configs(&mut dump, configs, image.sb.available_compr_algs.get())?;
+                writeln!(output, "{offset:08x} {dump}")?;
+            }
             SegmentType::CompactInode(inode) => {
                 writeln!(output, "# nid #{}", (offset - inode_start) / 32)?;
                 writeln!(output, "{offset:08x} {inode:#?}")?;
@@ -275,7 +275,8 @@ impl<'img> DumpContext<'img> {
     }
 
     /// Reads file content from blocks and optional inline tail
-    /// This handles FlatPlain (blocks only) and FlatInline (blocks + tail) layouts
+    /// This handles FlatPlain (blocks only) and FlatInline (blocks + tail) layouts, as well as
+    /// compressed data which is entirely tail-packed
     fn read_file_content(&self, inode: &InodeType<'_>) -> Vec<u8> {
         let size = inode.size() as usize;
         if size == 0 {
@@ -318,6 +319,13 @@ impl<'img> DumpContext<'img> {
                 // External file - no inline content
                 vec![]
             }
+            DataLayout::CompressedFull | DataLayout::CompressedCompact => {
+                // Compressed data, tail-packed into the inode
+                match inode.inline_data(self.image.blkszbits) {
+                    Ok(Some(content)) => content.into_owned(),
+                    _ => vec![],
+                }
+            }
         }
     }
 
@@ -77,7 +77,9 @@ impl From<FormatField> for InodeLayout {
 
 const INODE_DATALAYOUT_MASK: u16 = 0b00001110;
 const INODE_DATALAYOUT_FLAT_PLAIN: u16 = 0;
+const INODE_DATALAYOUT_COMPRESSED_FULL: u16 = 2;
 const INODE_DATALAYOUT_FLAT_INLINE: u16 = 4;
+const INODE_DATALAYOUT_COMPRESSED_COMPACT: u16 = 6;
 const INODE_DATALAYOUT_CHUNK_BASED: u16 = 8;
 
 /// Data layout method for file content storage
@@ -86,8 +88,12 @@ const INODE_DATALAYOUT_CHUNK_BASED: u16 = 8;
 pub enum DataLayout {
     /// File data stored in separate blocks
     FlatPlain = 0,
+    /// Compressed file data with a full (8-byte) logical cluster index
+    CompressedFull = 2,
     /// File data stored inline within the inode
     FlatInline = 4,
+    /// Compressed file data with a compacted logical cluster index
+    CompressedCompact = 6,
     /// File data stored using chunk-based addressing
     ChunkBased = 8,
 }
@@ -98,7 +104,9 @@ impl TryFrom<FormatField> for DataLayout {
     fn try_from(value: FormatField) -> Result<Self, FormatError> {
         match value.0.get() & INODE_DATALAYOUT_MASK {
             INODE_DATALAYOUT_FLAT_PLAIN => Ok(DataLayout::FlatPlain),
+            INODE_DATALAYOUT_COMPRESSED_FULL => Ok(DataLayout::CompressedFull),
             INODE_DATALAYOUT_FLAT_INLINE => Ok(DataLayout::FlatInline),
+            INODE_DATALAYOUT_COMPRESSED_COMPACT => Ok(DataLayout::CompressedCompact),
             INODE_DATALAYOUT_CHUNK_BASED => Ok(DataLayout::ChunkBased),
             // This is non-injective, but only occurs in error cases.
             _ => Err(FormatError::InvalidDataLayout),
@@ -106,6 +114,16 @@ impl TryFrom<FormatField> for DataLayout {
     }
 }
 
+impl DataLayout {
+    /// Checks if this layout stores compressed data
+    pub fn is_compressed(self) -> bool {
+        matches!(
+            self,
+            DataLayout::CompressedFull | DataLayout::CompressedCompact
+        )
+    }
+}
+
 impl std::ops::BitOr<DataLayout> for InodeLayout {
     type Output = FormatField;
 
@@ -259,6 +277,22 @@ impl fmt::Debug for ModeField {
     }
 }
 
+impl From<ModeField> for FileTypeField {
+    // Convert the ifmt part of a st_mode field into a dirent file type
+    fn from(value: ModeField) -> Self {
+        FileTypeField(match value.0.get() & S_IFMT {
+            S_IFREG => FILE_TYPE_REGULAR_FILE,
+            S_IFCHR => FILE_TYPE_CHARACTER_DEVICE,
+            S_IFDIR => FILE_TYPE_DIRECTORY,
+            S_IFBLK => FILE_TYPE_BLOCK_DEVICE,
+            S_IFIFO => FILE_TYPE_FIFO,
+            S_IFLNK => FILE_TYPE_SYMLINK,
+            S_IFSOCK => FILE_TYPE_SOCKET,
+            _ => FILE_TYPE_UNKNOWN,
+        })
+    }
+}
+
 impl std::ops::BitOr<u32> for FileType {
     type Output = ModeField;
 
@@ -294,6 +328,20 @@ pub enum FormatVersion {
     /// This is the current default format.
     #[default]
     V1_1,
+    /// Format 1.1 with DEFLATE-compressed inline file data, composefs_version=2
+    ///
+    /// Small regular files whose content is stored inline in the image are
+    /// compressed if doing so saves space.  These are tail-packed into the
+    /// inode, so no extra blocks are used.  This is opt-in: mounting such an
+    /// image requires Linux 6.6 or later built with
+    /// `CONFIG_EROFS_FS_ZIP_DEFLATE`.
+    ///
+    /// Only inline content of up to one block is compressed.  Trees read from
+    /// directories or tar layers only store files of up to
+    /// [`crate::INLINE_CONTENT_MAX`] bytes inline, so use
+    /// [`crate::tree::FileSystem::inline_compressible_files`] first to move
+    /// the external files which fit in a block and compress into the image.
+    V1_1Compressed,
 }
 
 impl FormatVersion {
@@ -301,9 +349,14 @@ impl FormatVersion {
     pub fn composefs_version(self) -> U32 {
         match self {
             FormatVersion::V1_0 => COMPOSEFS_VERSION_V1_0,
-            FormatVersion::V1_1 => COMPOSEFS_VERSION_V1_1,
+            FormatVersion::V1_1 | FormatVersion::V1_1Compressed => COMPOSEFS_VERSION_V1_1,
         }
     }
+
+    /// Checks if this format version compresses inline file data
+    pub fn compressed(self) -> bool {
+        self == FormatVersion::V1_1Compressed
+    }
 }
 
 /// Flag indicating the presence of ACL data
@@ -333,6 +386,12 @@ pub const MAGIC_V1: U32 = U32::new(0xE0F5E1E2);
 pub const FEATURE_COMPAT_MTIME: U32 = U32::new(2);
 /// Feature flag for xattr filtering support
 pub const FEATURE_COMPAT_XATTR_FILTER: U32 = U32::new(4);
+/// Incompatible feature flag: compressed data is zero-padded at the start of its pcluster
+pub const FEATURE_INCOMPAT_ZERO_PADDING: U32 = U32::new(1);
+/// Incompatible feature flag: per-algorithm compression configs follow the superblock
+pub const FEATURE_INCOMPAT_COMPR_CFGS: U32 = U32::new(2);
+/// Incompatible feature flag: the tail pcluster of compressed files may be stored inline
+pub const FEATURE_INCOMPAT_ZTAILPACKING: U32 = U32::new(0x10);
 
 /// EROFS filesystem superblock structure
 #[derive(Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
@@ -476,6 +535,55 @@ pub struct InodeXAttrHeader {
     pub reserved: [u8; 7],
 }
 
+/* Compression */
+
+/// Compression algorithm number for DEFLATE (bit index in `available_compr_algs`)
+pub const COMPRESSION_DEFLATE: u8 = 2;
+/// Map header advise flag: the tail pcluster is stored inline after the index
+pub const ADVISE_INLINE_PCLUSTER: u16 = 0x0008;
+/// Mask for the logical cluster type in [`LclusterIndex::advise`]
+pub const LCLUSTER_TYPE_MASK: u16 = 0x3;
+/// Logical cluster type: start of a pcluster compressed with the head 1 algorithm
+pub const LCLUSTER_TYPE_HEAD1: u16 = 1;
+
+/// DEFLATE configuration record, stored after the superblock
+#[derive(Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+pub struct DeflateConfig {
+    /// log2 of the DEFLATE window size
+    pub windowbits: u8,
+    /// Reserved field
+    pub reserved: [u8; 5],
+}
+
+/// Header describing the compressed data of an inode, following its xattrs
+#[derive(Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+pub struct MapHeader {
+    /// Reserved field
+    pub reserved: U16,
+    /// Size of the inline (tail-packed) compressed data
+    pub idata_size: U16,
+    /// Advise flags
+    pub advise: U16,
+    /// Algorithms for head 1 (low nibble) and head 2 (high nibble) lclusters
+    pub algorithm_type: u8,
+    /// log2 of the logical cluster size, minus the block size bits
+    pub cluster_bits: u8,
+}
+
+/// An entry in the full logical cluster index of a compressed inode
+#[derive(Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
+#[repr(C)]
+pub struct LclusterIndex {
+    /// Logical cluster type and flags
+    pub advise: U16,
+    /// Offset of the start of decompressed data within this lcluster
+    pub cluster_ofs: U16,
+    /// Block address of the pcluster (for head lclusters)
+    pub blkaddr: U32,
+}
+
 /* Extended attributes */
 /// Seed value for xattr name filter hash calculation
 pub const XATTR_FILTER_SEED: u32 = 0x25BBE08F;
@@ -1,12 +1,18 @@
 //! EROFS image reading and parsing functionality.
 //!
 //! This module provides safe parsing and navigation of EROFS filesystem
-//! images, including inode traversal, directory reading, and object
-//! reference collection for garbage collection.
+//! images, including inode traversal, directory reading, object
+//! reference collection for garbage collection, and conversion of images
+//! back into filesystem trees.
 
 use core::mem::size_of;
-use std::collections::{BTreeSet, HashSet};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::rc::Rc;
 
 use thiserror::Error;
 use zerocopy::{little_endian::U32, FromBytes, Immutable, KnownLayout};
@@ -15,10 +21,13 @@ use super::{
     composefs::OverlayMetacopy,
     format::{
         CompactInodeHeader, ComposefsHeader, DataLayout, DirectoryEntryHeader, ExtendedInodeHeader,
-        InodeXAttrHeader, ModeField, Superblock, XAttrHeader,
+        InodeXAttrHeader, LclusterIndex, MapHeader, ModeField, Superblock, XAttrHeader,
+        ADVISE_INLINE_PCLUSTER, COMPRESSION_DEFLATE, LCLUSTER_TYPE_HEAD1, LCLUSTER_TYPE_MASK,
+        S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK, XATTR_PREFIXES,
     },
 };
 use crate::fsverity::FsVerityHashValue;
+use crate::tree;
 
 /// Rounds up a value to the nearest multiple of `to`
 pub fn round_up(n: usize, to: usize) -> usize {
@@ -53,6 +62,9 @@ pub trait InodeHeader {
     }
 
     /// Calculates the number of additional bytes after the header
+    ///
+    /// For compressed inodes this doesn't include the compression metadata, whose size can only
+    /// be determined by reading it: see [`compressed_meta_size()`].
     fn additional_bytes(&self, blkszbits: u8) -> usize {
         let block_size = 1 << blkszbits;
         self.xattr_size()
@@ -60,6 +72,7 @@ pub trait InodeHeader {
                 DataLayout::FlatPlain => 0,
                 DataLayout::FlatInline => self.size() as usize % block_size,
                 DataLayout::ChunkBased => 4,
+                DataLayout::CompressedFull | DataLayout::CompressedCompact => 0,
             }
     }
 
@@ -158,6 +171,99 @@ impl InodeHeader for CompactInodeHeader {
     }
 }
 
+/// Number of padding bytes between the end of the xattrs and the compression map header
+///
+/// The map header is 8-byte aligned.  Inodes are 32-byte aligned and the headers are a multiple
+/// of 8 bytes in size, so this depends only on the size of the xattrs.
+fn map_header_padding(xattr_size: usize) -> usize {
+    round_up(xattr_size, 8) - xattr_size
+}
+
+/// Size of the compression metadata of a full-index compressed inode, including any inline
+/// (tail-packed) compressed data
+///
+/// `meta` is the inode data following the xattrs.  Returns 0 for uncompressed inodes.
+pub fn compressed_meta_size(header: &impl InodeHeader, meta: &[u8], blkszbits: u8) -> usize {
+    match header.data_layout() {
+        DataLayout::CompressedFull => {}
+        DataLayout::CompressedCompact => {
+            // We can't parse the compact index, but we can at least find the map header
+            return map_header_padding(header.xattr_size()) + size_of::<MapHeader>();
+        }
+        _ => return 0,
+    }
+
+    let padding = map_header_padding(header.xattr_size());
+    let Some(Ok((map_header, _))) = meta.get(padding..).map(MapHeader::ref_from_prefix) else {
+        return 0;
+    };
+    let lcluster_bits = blkszbits as u32 + (map_header.cluster_bits & 7) as u32;
+    let lclusters = header.size().div_ceil(1 << lcluster_bits) as usize;
+    let inline_size = if map_header.advise.get() & ADVISE_INLINE_PCLUSTER != 0 {
+        map_header.idata_size.get() as usize
+    } else {
+        0
+    };
+
+    // Saturate rather than overflow on corrupt sizes: the caller fails to fit the inode instead
+    (padding + size_of::<MapHeader>() + 8)
+        .saturating_add(lclusters.saturating_mul(size_of::<LclusterIndex>()))
+        .saturating_add(inline_size)
+}
+
+/// Decompresses the file content of a compressed inode
+///
+/// Only DEFLATE-compressed full-index inodes whose data fits in a single, tail-packed pcluster
+/// are supported.  This is what the writer produces for `FormatVersion::V1_1Compressed`.
+fn decompress_inline(
+    header: &(impl InodeHeader + ?Sized),
+    meta: &[u8],
+    blkszbits: u8,
+) -> ReadResult<Vec<u8>> {
+    if header.data_layout() != DataLayout::CompressedFull {
+        return Err(ErofsReaderError::UnsupportedCompression);
+    }
+
+    let meta = meta
+        .get(map_header_padding(header.xattr_size())..)
+        .ok_or(ErofsReaderError::InvalidCompressedData)?;
+    let (map_header, rest) =
+        MapHeader::ref_from_prefix(meta).map_err(|_| ErofsReaderError::InvalidCompressedData)?;
+    let lcluster_bits = blkszbits as u32 + (map_header.cluster_bits & 7) as u32;
+    if map_header.advise.get() & ADVISE_INLINE_PCLUSTER == 0
+        || map_header.algorithm_type & 0xf != COMPRESSION_DEFLATE
+        || header.size() > 1 << lcluster_bits
+    {
+        return Err(ErofsReaderError::UnsupportedCompression);
+    }
+
+    let rest = rest
+        .get(8..)
+        .ok_or(ErofsReaderError::InvalidCompressedData)?;
+    let (index, data) = LclusterIndex::ref_from_prefix(rest)
+        .map_err(|_| ErofsReaderError::InvalidCompressedData)?;
+    if index.advise.get() & LCLUSTER_TYPE_MASK != LCLUSTER_TYPE_HEAD1
+        || index.cluster_ofs.get() != 0
+    {
+        return Err(ErofsReaderError::UnsupportedCompression);
+    }
+
+    // Compressed data may be preceded by zero padding
+    let data = data
+        .get(..map_header.idata_size.get() as usize)
+        .ok_or(ErofsReaderError::InvalidCompressedData)?;
+    let start = data
+        .iter()
+        .position(|&b| b != 0)
+        .ok_or(ErofsReaderError::InvalidCompressedData)?;
+
+    let size = header.size() as usize;
+    match miniz_oxide::inflate::decompress_to_vec_with_limit(&data[start..], size) {
+        Ok(content) if content.len() == size => Ok(content),
+        _ => Err(ErofsReaderError::InvalidCompressedData),
+    }
+}
+
 /// Extended attribute entry with header and variable-length data
 #[repr(C)]
 #[derive(FromBytes, Immutable, KnownLayout)]
@@ -198,8 +304,15 @@ impl XAttrHeader {
 impl XAttr {
     /// Parses an xattr from a byte slice, returning the xattr and remaining bytes
     pub fn from_prefix(data: &[u8]) -> (&XAttr, &[u8]) {
-        let header = XAttrHeader::ref_from_bytes(&data[..4]).unwrap();
-        Self::ref_from_prefix_with_elems(data, header.calculate_n_elems()).unwrap()
+        Self::try_from_prefix(data).expect("invalid xattr")
+    }
+
+    /// Parses an xattr from a byte slice, returning an error if it doesn't fit
+    pub fn try_from_prefix(data: &[u8]) -> ReadResult<(&XAttr, &[u8])> {
+        let (header, _) =
+            XAttrHeader::ref_from_prefix(data).map_err(|_| ErofsReaderError::InvalidXAttr)?;
+        Self::ref_from_prefix_with_elems(data, header.calculate_n_elems())
+            .map_err(|_| ErofsReaderError::InvalidXAttr)
     }
 
     /// Returns the attribute name suffix
@@ -223,9 +336,29 @@ pub trait InodeOps {
     /// Returns the extended attributes section if present
     fn xattrs(&self) -> Option<&InodeXAttrs>;
     /// Returns the inline data portion
+    ///
+    /// For compressed inodes, this is the raw compression metadata and compressed data.
     fn inline(&self) -> Option<&[u8]>;
     /// Returns the range of block IDs used by this inode
     fn blocks(&self, blkszbits: u8) -> Range<u64>;
+
+    /// Returns the file content stored inline in the inode, decompressing it if required
+    ///
+    /// For uncompressed inodes this is the same as [`Self::inline()`].
+    fn inline_data(&self, blkszbits: u8) -> ReadResult<Option<Cow<'_, [u8]>>>
+    where
+        Self: InodeHeader,
+    {
+        let Some(inline) = self.inline() else {
+            return Ok(None);
+        };
+        if !self.data_layout().is_compressed() {
+            return Ok(Some(Cow::Borrowed(inline)));
+        }
+        Ok(Some(Cow::Owned(decompress_inline(
+            self, inline, blkszbits,
+        )?)))
+    }
 }
 
 impl<Header: InodeHeader> InodeHeader for &Inode<Header> {
@@ -296,13 +429,17 @@ impl<Header: InodeHeader> InodeOps for &Inode<Header> {
         match self.header.data_layout() {
             DataLayout::FlatPlain => Range {
                 start,
-                end: start + size.div_ceil(block_size),
+                end: start.saturating_add(size.div_ceil(block_size)),
             },
             DataLayout::FlatInline => Range {
                 start,
-                end: start + size / block_size,
+                end: start.saturating_add(size / block_size),
             },
             DataLayout::ChunkBased => Range { start, end: start },
+            // We only support compressed data which is stored inline
+            DataLayout::CompressedFull | DataLayout::CompressedCompact => {
+                Range { start, end: start }
+            }
         }
     }
 }
@@ -434,52 +571,93 @@ pub struct Image<'i> {
 
 impl<'img> Image<'img> {
     /// Opens an EROFS image from raw bytes
+    ///
+    /// Panics if the image is too short or its superblock is invalid: see [`Self::try_open()`].
     pub fn open(image: &'img [u8]) -> Self {
+        Self::try_open(image).expect("invalid EROFS image")
+    }
+
+    /// Opens an EROFS image from raw bytes, returning an error if it is malformed
+    pub fn try_open(image: &'img [u8]) -> ReadResult<Self> {
         let header = ComposefsHeader::ref_from_prefix(image)
-            .expect("header err")
+            .map_err(|_| ErofsReaderError::InvalidImage)?
             .0;
-        let sb = Superblock::ref_from_prefix(&image[1024..])
-            .expect("superblock err")
+        let sb = image
+            .get(1024..)
+            .and_then(|sb| Superblock::ref_from_prefix(sb).ok())
+            .ok_or(ErofsReaderError::InvalidImage)?
             .0;
         let blkszbits = sb.blkszbits;
+        // EROFS supports block sizes from 512 bytes up to the largest page size
+        if !(9..=16).contains(&blkszbits) {
+            return Err(ErofsReaderError::InvalidImage);
+        }
         let block_size = 1usize << blkszbits;
-        assert!(block_size != 0);
-        let inodes = &image[sb.meta_blkaddr.get() as usize * block_size..];
-        let xattrs = &image[sb.xattr_blkaddr.get() as usize * block_size..];
-        Image {
+        let region = |blkaddr: u32| {
+            image
+                .get(blkaddr as usize * block_size..)
+                .ok_or(ErofsReaderError::InvalidImage)
+        };
+        Ok(Image {
             image,
             header,
             blkszbits,
             block_size,
             sb,
-            inodes,
-            xattrs,
-        }
+            inodes: region(sb.meta_blkaddr.get())?,
+            xattrs: region(sb.xattr_blkaddr.get())?,
+        })
     }
 
     /// Returns an inode by its ID
+    ///
+    /// Panics if the inode doesn't fit in the image: see [`Self::try_inode()`].
     pub fn inode(&self, id: u64) -> InodeType<'_> {
-        let inode_data = &self.inodes[id as usize * 32..];
-        if inode_data[0] & 1 != 0 {
-            let header = ExtendedInodeHeader::ref_from_bytes(&inode_data[..64]).unwrap();
-            InodeType::Extended(
+        self.try_inode(id).expect("invalid inode")
+    }
+
+    /// Returns an inode by its ID, returning an error if it is malformed or doesn't fit
+    pub fn try_inode(&self, id: u64) -> ReadResult<InodeType<'_>> {
+        let invalid = || ErofsReaderError::InvalidInode(id);
+        let inode_data = usize::try_from(id)
+            .ok()
+            .and_then(|id| id.checked_mul(32))
+            .and_then(|offset| self.inodes.get(offset..))
+            .ok_or_else(invalid)?;
+        if inode_data.first().ok_or_else(invalid)? & 1 != 0 {
+            let header = ExtendedInodeHeader::ref_from_prefix(inode_data)
+                .map_err(|_| invalid())?
+                .0;
+            DataLayout::try_from(header.format).map_err(|_| invalid())?;
+            let meta = inode_data
+                .get(64 + header.xattr_size()..)
+                .ok_or_else(invalid)?;
+            Ok(InodeType::Extended(
                 Inode::<ExtendedInodeHeader>::ref_from_prefix_with_elems(
                     inode_data,
-                    header.additional_bytes(self.blkszbits),
+                    header.additional_bytes(self.blkszbits)
+                        + compressed_meta_size(header, meta, self.blkszbits),
                 )
-                .unwrap()
+                .map_err(|_| invalid())?
                 .0,
-            )
+            ))
         } else {
-            let header = CompactInodeHeader::ref_from_bytes(&inode_data[..32]).unwrap();
-            InodeType::Compact(
+            let header = CompactInodeHeader::ref_from_prefix(inode_data)
+                .map_err(|_| invalid())?
+                .0;
+            DataLayout::try_from(header.format).map_err(|_| invalid())?;
+            let meta = inode_data
+                .get(32 + header.xattr_size()..)
+                .ok_or_else(invalid)?;
+            Ok(InodeType::Compact(
                 Inode::<CompactInodeHeader>::ref_from_prefix_with_elems(
                     inode_data,
-                    header.additional_bytes(self.blkszbits),
+                    header.additional_bytes(self.blkszbits)
+                        + compressed_meta_size(header, meta, self.blkszbits),
                 )
-                .unwrap()
+                .map_err(|_| invalid())?
                 .0,
-            )
+            ))
         }
     }
 
@@ -492,11 +670,29 @@ impl<'img> Image<'img> {
             .0
     }
 
+    /// Returns a shared extended attribute by its ID, returning an error if it doesn't fit
+    pub fn try_shared_xattr(&self, id: u32) -> ReadResult<&XAttr> {
+        let xattr_data = self
+            .xattrs
+            .get(id as usize * 4..)
+            .ok_or(ErofsReaderError::InvalidXAttr)?;
+        Ok(XAttr::try_from_prefix(xattr_data)?.0)
+    }
+
     /// Returns a data block by its ID
     pub fn block(&self, id: u64) -> &[u8] {
         &self.image[id as usize * self.block_size..][..self.block_size]
     }
 
+    /// Returns a data block by its ID, returning an error if it lies outside of the image
+    pub fn try_block(&self, id: u64) -> ReadResult<&[u8]> {
+        usize::try_from(id)
+            .ok()
+            .and_then(|id| id.checked_mul(self.block_size))
+            .and_then(|start| self.image.get(start..)?.get(..self.block_size))
+            .ok_or(ErofsReaderError::InvalidBlock(id))
+    }
+
     /// Returns a data block by its ID as a DataBlock reference
     pub fn data_block(&self, id: u64) -> &DataBlock {
         DataBlock::ref_from_bytes(self.block(id)).unwrap()
@@ -511,6 +707,127 @@ impl<'img> Image<'img> {
     pub fn root(&self) -> InodeType<'_> {
         self.inode(self.sb.root_nid.get() as u64)
     }
+
+    /// Returns the xattrs of an inode, including shared ones, as pairs of full name and value
+    ///
+    /// These are the xattrs as stored in the image, including the `trusted.overlay.*` xattrs
+    /// added by the writer.  See [`original_xattr_name()`].
+    pub fn inode_xattrs<'a>(
+        &'a self,
+        inode: &'a InodeType,
+    ) -> ReadResult<Vec<(Vec<u8>, &'a [u8])>> {
+        let Some(inode_xattrs) = inode.xattrs() else {
+            return Ok(vec![]);
+        };
+
+        let (shared, mut local) = inode_xattrs
+            .data
+            .split_at_checked(inode_xattrs.header.shared_count as usize * 4)
+            .ok_or(ErofsReaderError::InvalidXAttr)?;
+
+        let mut xattrs = vec![];
+        for id in <[U32]>::ref_from_bytes(shared).map_err(|_| ErofsReaderError::InvalidXAttr)? {
+            xattrs.push(self.try_shared_xattr(id.get())?);
+        }
+        while !local.is_empty() {
+            let (xattr, rest) = XAttr::try_from_prefix(local)?;
+            xattrs.push(xattr);
+            local = rest;
+        }
+
+        Ok(xattrs
+            .into_iter()
+            .map(|xattr| {
+                let prefix = XATTR_PREFIXES
+                    .get(xattr.header.name_index as usize)
+                    .copied()
+                    .unwrap_or_default();
+                ([prefix, xattr.suffix()].concat(), xattr.value())
+            })
+            .collect())
+    }
+
+    /// Returns the content of an inode which stores its data in the image
+    ///
+    /// This is the file content of inline regular files and the target of symlinks.  It doesn't
+    /// make sense for chunk-based (external) files.
+    pub fn inode_data(&self, inode: &InodeType) -> ReadResult<Box<[u8]>> {
+        if inode.data_layout().is_compressed() {
+            let data = inode.inline_data(self.blkszbits)?.unwrap_or_default();
+            return Ok(Box::from(data.as_ref()));
+        }
+
+        let size = inode.size() as usize;
+        let mut data = Vec::with_capacity(size.min(self.image.len()));
+        for blkid in inode.blocks(self.blkszbits) {
+            data.extend_from_slice(self.try_block(blkid)?);
+        }
+        if inode.data_layout() == DataLayout::FlatInline {
+            data.extend_from_slice(inode.inline().unwrap_or_default());
+        }
+        data.truncate(size);
+        Ok(data.into_boxed_slice())
+    }
+
+    /// Returns the blocks of a directory inode, including the inline tail block, if any
+    ///
+    /// The entries within each block are sorted by name, and all of the names in a block sort
+    /// before those of the next one.  Returns an error if a block is malformed.
+    pub fn directory_blocks<'a>(
+        &'a self,
+        inode: &'a InodeType,
+    ) -> ReadResult<Vec<&'a DirectoryBlock>> {
+        let mut blocks = vec![];
+        for blkid in inode.blocks(self.blkszbits) {
+            blocks.push(DirectoryBlock::ref_from_bytes(self.try_block(blkid)?).unwrap());
+        }
+        if let Some(inline) = inode.inline() {
+            blocks.push(DirectoryBlock::ref_from_bytes(inline).unwrap());
+        }
+        for block in &blocks {
+            block.check()?;
+        }
+        Ok(blocks)
+    }
+
+    /// Returns the entries of a directory inode, including `.` and `..`, in on-disk order
+    pub fn directory_entries<'a>(
+        &'a self,
+        inode: &'a InodeType,
+    ) -> ReadResult<Vec<DirectoryEntry<'a>>> {
+        Ok(self
+            .directory_blocks(inode)?
+            .into_iter()
+            .flat_map(DirectoryBlock::entries)
+            .collect())
+    }
+}
+
+/// Returns true if a root directory entry is one of the whiteouts added by `mkcomposefs`
+///
+/// The C writer adds character devices with rdev 0 named `00` to `ff` to the root directory, to
+/// hide the object directories of a data-only lower layer.  They aren't part of the original
+/// filesystem.  Other character devices with rdev 0 are overlayfs whiteouts which really are.
+pub fn is_internal_whiteout(name: &[u8], inode: &InodeType) -> bool {
+    name.len() == 2
+        && name.iter().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+        && inode.mode().0.get() & S_IFMT == S_IFCHR
+        && inode.rdev() == 0
+}
+
+/// Returns the original name of an xattr stored in an image
+///
+/// This undoes the escaping of `trusted.overlay.*` xattrs done by the writer.  Returns `None`
+/// for the `trusted.overlay.*` xattrs which were added by the writer itself (like
+/// `trusted.overlay.metacopy`), which aren't part of the original filesystem.
+pub fn original_xattr_name(name: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if let Some(escapee) = name.strip_prefix(b"trusted.overlay.overlay.") {
+        Some(Cow::Owned([b"trusted.overlay.", escapee].concat()))
+    } else if name.starts_with(b"trusted.overlay.") {
+        None
+    } else {
+        Some(Cow::Borrowed(name))
+    }
 }
 
 // TODO: there must be an easier way...
@@ -581,6 +898,24 @@ impl DirectoryBlock {
              .0
     }
 
+    /// Checks that the entry headers and names all lie within the block, in order
+    fn check(&self) -> ReadResult<()> {
+        let (first, _) = DirectoryEntryHeader::ref_from_prefix(&self.0)
+            .map_err(|_| ErofsReaderError::InvalidDirectoryBlock)?;
+        let mut offset = first.name_offset.get() as usize;
+        if offset == 0 || !offset.is_multiple_of(12) || offset > self.0.len() {
+            return Err(ErofsReaderError::InvalidDirectoryBlock);
+        }
+        for header in self.get_entry_headers() {
+            let name_offset = header.name_offset.get() as usize;
+            if name_offset < offset || name_offset > self.0.len() {
+                return Err(ErofsReaderError::InvalidDirectoryBlock);
+            }
+            offset = name_offset;
+        }
+        Ok(())
+    }
+
     /// Returns the number of entries in this directory block
     pub fn n_entries(&self) -> usize {
         let first = self.get_entry_header(0);
@@ -611,7 +946,8 @@ pub struct DirectoryEntry<'a> {
 }
 
 impl DirectoryEntry<'_> {
-    fn nid(&self) -> u64 {
+    /// Returns the ID of the inode that the entry refers to
+    pub fn nid(&self) -> u64 {
         self.header.inode_offset.get()
     }
 }
@@ -668,9 +1004,36 @@ pub enum ErofsReaderError {
     /// File type in directory entry doesn't match inode
     #[error("File type in dirent doesn't match type in inode")]
     FileTypeMismatch,
+    /// An inode has a file type which can't be represented
+    #[error("Invalid file type in inode")]
+    InvalidFileType,
+    /// A chunk-based regular file has no valid `trusted.overlay.metacopy` xattr
+    #[error("External file without a valid overlay.metacopy xattr")]
+    MissingMetacopy,
+    /// A compressed inode uses a layout or algorithm we can't decompress
+    #[error("Unsupported compressed data layout")]
+    UnsupportedCompression,
+    /// The compressed data of an inode is corrupt
+    #[error("Invalid compressed data")]
+    InvalidCompressedData,
+    /// The image is too short or its superblock is invalid
+    #[error("Invalid image header or superblock")]
+    InvalidImage,
+    /// An inode is malformed or doesn't fit in the image
+    #[error("Invalid inode {0}")]
+    InvalidInode(u64),
+    /// A data block lies outside of the image
+    #[error("Invalid block {0}")]
+    InvalidBlock(u64),
+    /// The entries of a directory block are malformed
+    #[error("Invalid directory block")]
+    InvalidDirectoryBlock,
+    /// An extended attribute is malformed or doesn't fit in the image
+    #[error("Invalid extended attribute")]
+    InvalidXAttr,
 }
 
-type ReadResult<T> = Result<T, ErofsReaderError>;
+pub(crate) type ReadResult<T> = Result<T, ErofsReaderError>;
 
 /// Collects object references from an EROFS image for garbage collection
 pub struct ObjectCollector<'f, ObjectID: FsVerityHashValue> {
@@ -801,11 +1164,151 @@ pub fn collect_objects<ObjectID: FsVerityHashValue>(
     Ok(this.objects)
 }
 
+/// Converts an EROFS image back into a filesystem tree
+struct TreeBuilder<'img, ObjectID: FsVerityHashValue> {
+    img: &'img Image<'img>,
+    visited_dirs: HashSet<u64>,
+    hardlinks: HashMap<u64, Rc<tree::Leaf<ObjectID>>>,
+}
+
+impl<ObjectID: FsVerityHashValue> TreeBuilder<'_, ObjectID> {
+    /// Returns the stat for the inode, plus the digest from its metacopy xattr, if any.
+    ///
+    /// This undoes the transformations done to xattrs by the writer: escaped `trusted.overlay.*`
+    /// xattrs are unescaped and all other `trusted.overlay.*` xattrs are dropped.
+    fn stat(&self, inode: &InodeType) -> ReadResult<(tree::Stat, Option<ObjectID>)> {
+        let mut xattrs = BTreeMap::new();
+        let mut metacopy = None;
+
+        for (name, value) in self.img.inode_xattrs(inode)? {
+            if name == b"trusted.overlay.metacopy" {
+                if let Ok(value) = OverlayMetacopy::<ObjectID>::read_from_bytes(value) {
+                    if value.valid() {
+                        metacopy = Some(value.digest);
+                    }
+                }
+            } else if let Some(name) = original_xattr_name(&name) {
+                xattrs.insert(Box::from(OsStr::from_bytes(&name)), Box::from(value));
+            }
+        }
+
+        let st_mtim_sec = match inode {
+            // Compact inodes share the build time from the superblock
+            InodeType::Compact(..) => self.img.sb.build_time.get() as i64,
+            InodeType::Extended(..) => inode.mtime(),
+        };
+
+        let stat = tree::Stat {
+            st_mode: (inode.mode().0.get() & !S_IFMT) as u32,
+            st_uid: inode.uid(),
+            st_gid: inode.gid(),
+            st_mtim_sec,
+            xattrs: RefCell::new(xattrs),
+        };
+
+        Ok((stat, metacopy))
+    }
+
+    fn leaf(&mut self, nid: u64) -> ReadResult<Rc<tree::Leaf<ObjectID>>> {
+        if let Some(leaf) = self.hardlinks.get(&nid) {
+            return Ok(Rc::clone(leaf));
+        }
+
+        let inode = self.img.try_inode(nid)?;
+        let (stat, metacopy) = self.stat(&inode)?;
+
+        let content = match inode.mode().0.get() & S_IFMT {
+            S_IFREG => tree::LeafContent::Regular(match inode.data_layout() {
+                DataLayout::ChunkBased => tree::RegularFile::External(
+                    metacopy.ok_or(ErofsReaderError::MissingMetacopy)?,
+                    inode.size(),
+                ),
+                _ => tree::RegularFile::Inline(self.img.inode_data(&inode)?),
+            }),
+            S_IFLNK => tree::LeafContent::Symlink(Box::from(OsStr::from_bytes(
+                &self.img.inode_data(&inode)?,
+            ))),
+            S_IFBLK => tree::LeafContent::BlockDevice(inode.rdev() as u64),
+            S_IFCHR => tree::LeafContent::CharacterDevice(inode.rdev() as u64),
+            S_IFIFO => tree::LeafContent::Fifo,
+            S_IFSOCK => tree::LeafContent::Socket,
+            _ => return Err(ErofsReaderError::InvalidFileType),
+        };
+
+        let leaf = Rc::new(tree::Leaf { stat, content });
+        if inode.nlink() > 1 {
+            self.hardlinks.insert(nid, Rc::clone(&leaf));
+        }
+        Ok(leaf)
+    }
+
+    fn directory(&mut self, nid: u64, parent: u64) -> ReadResult<tree::Directory<ObjectID>> {
+        if !self.visited_dirs.insert(nid) {
+            return Err(ErofsReaderError::DirectoryHardlinks);
+        }
+
+        let inode = self.img.try_inode(nid)?;
+        let (stat, _) = self.stat(&inode)?;
+        let mut directory = tree::Directory::new(stat);
+
+        for entry in self.img.directory_entries(&inode)? {
+            let child = entry.nid();
+            match entry.name {
+                b"." if child != nid => return Err(ErofsReaderError::InvalidSelfReference),
+                b".." if child != parent => return Err(ErofsReaderError::InvalidParentReference),
+                b"." | b".." => {}
+                name => {
+                    let child_inode = self.img.try_inode(child)?;
+                    if entry.header.file_type != child_inode.mode().into() {
+                        return Err(ErofsReaderError::FileTypeMismatch);
+                    }
+                    let inode = if child_inode.mode().0.get() & S_IFMT == S_IFDIR {
+                        tree::Inode::Directory(Box::new(self.directory(child, nid)?))
+                    } else {
+                        tree::Inode::Leaf(self.leaf(child)?)
+                    };
+                    directory.insert(OsStr::from_bytes(name), inode);
+                }
+            }
+        }
+
+        Ok(directory)
+    }
+}
+
+/// Converts an EROFS image into a filesystem tree
+///
+/// This is the inverse of [`super::writer::mkfs_erofs()`]: external files are recovered from
+/// their `trusted.overlay.metacopy` xattr, inline files become [`tree::RegularFile::Inline`],
+/// and hardlinked inodes are shared between all of their directory entries.  Xattrs added or
+/// escaped by the writer are restored to their original form.
+///
+/// Writing the resulting filesystem with the same format version produces the same image.
+pub fn image_to_filesystem<ObjectID: FsVerityHashValue>(
+    image: &[u8],
+) -> ReadResult<tree::FileSystem<ObjectID>> {
+    let img = Image::try_open(image)?;
+    let mut builder = TreeBuilder {
+        img: &img,
+        visited_dirs: HashSet::new(),
+        hardlinks: HashMap::new(),
+    };
+
+    let root_nid = img.sb.root_nid.get() as u64;
+    Ok(tree::FileSystem {
+        root: builder.directory(root_nid, root_nid)?,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        dumpfile::dumpfile_to_filesystem, erofs::writer::mkfs_erofs_default,
+        dumpfile::{dumpfile_to_filesystem, write_dumpfile},
+        erofs::{
+            format::FormatVersion,
+            writer::{mkfs_erofs, mkfs_erofs_default},
+        },
         fsverity::Sha256HashValue,
     };
     use std::collections::HashMap;
@@ -1198,4 +1701,145 @@ mod tests {
         let inline_data = file1_inode.inline();
         assert_eq!(inline_data, Some(b"hello".as_slice()));
     }
+
+    const ROUNDTRIP_DUMP: &str = r#"/ 4096 40755 4 0 0 0 1000.0 - - - user.root=yes
+/blkdev 0 60600 1 0 6 2049 1000.0 - - -
+/chrdev 0 20600 1 0 6 1281 1000.0 - - -
+/dir 4096 40700 2 42 42 0 1500.0 - - - trusted.overlay.opaque=y
+/dir/aaa 11 100644 2 0 0 0 2000.0 - hello_world - user.a=b
+/dir/external 1048576 100644 1 0 0 0 1000.0 00/0000000000000000000000000000000000000000000000000000000000000a - 000000000000000000000000000000000000000000000000000000000000000a user.a=b
+/empty 0 100644 1 0 0 0 1000.0 - - -
+/fifo 0 10644 1 0 0 0 1000.0 - - -
+/hardlink 0 @120000 2 0 0 0 0.0 /dir/aaa - -
+/link 7 120777 1 0 0 0 1000.0 /target - -
+/whiteout 0 20000 1 0 0 0 1000.0 - - -
+"#;
+
+    #[test]
+    fn test_image_to_filesystem_roundtrip() {
+        let fs = dumpfile_to_filesystem::<Sha256HashValue>(ROUNDTRIP_DUMP).unwrap();
+
+        for version in [
+            FormatVersion::V1_0,
+            FormatVersion::V1_1,
+            FormatVersion::V1_1Compressed,
+        ] {
+            let image = mkfs_erofs(&fs, version);
+            let read_back = image_to_filesystem::<Sha256HashValue>(&image).unwrap();
+
+            let mut expected = vec![];
+            write_dumpfile(&mut expected, &fs).unwrap();
+            let mut actual = vec![];
+            write_dumpfile(&mut actual, &read_back).unwrap();
+            similar_asserts::assert_eq!(
+                String::from_utf8(expected).unwrap(),
+                String::from_utf8(actual).unwrap()
+            );
+
+            assert_eq!(image, mkfs_erofs(&read_back, version));
+        }
+    }
+
+    #[test]
+    fn test_image_to_filesystem_hardlinks() {
+        let fs = dumpfile_to_filesystem::<Sha256HashValue>(ROUNDTRIP_DUMP).unwrap();
+        let image = mkfs_erofs_default(&fs);
+        let read_back = image_to_filesystem::<Sha256HashValue>(&image).unwrap();
+
+        let dir = read_back.root.get_directory(OsStr::new("dir")).unwrap();
+        let original = dir.ref_leaf(OsStr::new("aaa")).unwrap();
+        let link = read_back.root.ref_leaf(OsStr::new("hardlink")).unwrap();
+        assert!(Rc::ptr_eq(&original, &link));
+
+        let Ok(tree::RegularFile::External(id, size)) = dir.get_file(OsStr::new("external")) else {
+            panic!("expected external file");
+        };
+        assert_eq!(*size, 1048576);
+        assert_eq!(id.to_hex(), "0".repeat(63) + "a");
+    }
+
+    #[test]
+    fn test_image_to_filesystem_modify() {
+        let fs = dumpfile_to_filesystem::<Sha256HashValue>(ROUNDTRIP_DUMP).unwrap();
+        let image = mkfs_erofs_default(&fs);
+
+        let mut modified = image_to_filesystem::<Sha256HashValue>(&image).unwrap();
+        modified.root.insert(
+            OsStr::new("added"),
+            tree::Inode::Leaf(Rc::new(tree::Leaf {
+                stat: tree::Stat {
+                    st_mode: 0o644,
+                    st_uid: 0,
+                    st_gid: 0,
+                    st_mtim_sec: 1000,
+                    xattrs: Default::default(),
+                },
+                content: tree::LeafContent::Regular(tree::RegularFile::Inline(Box::from(*b"new"))),
+            })),
+        );
+
+        let new_image = mkfs_erofs_default(&modified);
+        let mut expected = ROUNDTRIP_DUMP.to_string();
+        expected.push_str("/added 3 100644 1 0 0 0 1000.0 - new -\n");
+        let expected = dumpfile_to_filesystem::<Sha256HashValue>(&expected).unwrap();
+        assert_eq!(new_image, mkfs_erofs_default(&expected));
+    }
+
+    #[test]
+    fn test_compressed_inline() {
+        let expected = "# comment\nkey = value\n".repeat(50);
+        let mut fs = dumpfile_to_filesystem::<Sha256HashValue>(ROUNDTRIP_DUMP).unwrap();
+        fs.root.insert(
+            OsStr::new("config"),
+            tree::Inode::Leaf(Rc::new(tree::Leaf {
+                stat: tree::Stat {
+                    st_mode: 0o644,
+                    st_uid: 0,
+                    st_gid: 0,
+                    st_mtim_sec: 1000,
+                    xattrs: Default::default(),
+                },
+                content: tree::LeafContent::Regular(tree::RegularFile::Inline(Box::from(
+                    expected.as_bytes(),
+                ))),
+            })),
+        );
+
+        let plain = mkfs_erofs(&fs, FormatVersion::V1_1);
+        let image = mkfs_erofs(&fs, FormatVersion::V1_1Compressed);
+        assert!(image != plain);
+
+        let img = Image::open(&image);
+        let mut nids = HashMap::new();
+        let root = img.root();
+        let entries = DirectoryBlock::ref_from_bytes(root.inline().unwrap()).unwrap();
+        for entry in entries.entries() {
+            nids.insert(entry.name.to_vec(), entry.header.inode_offset.get());
+        }
+
+        let config = img.inode(nids[&b"config"[..]]);
+        assert_eq!(config.data_layout(), DataLayout::CompressedFull);
+        assert!(config.inline().unwrap().len() < expected.len());
+        assert_eq!(
+            config.inline_data(img.blkszbits).unwrap().unwrap(),
+            expected.as_bytes()
+        );
+
+        // Too small to be worth compressing
+        let small = img.inode(nids[&b"hardlink"[..]]);
+        assert_eq!(small.data_layout(), DataLayout::FlatInline);
+        assert_eq!(
+            small.inline_data(img.blkszbits).unwrap().unwrap(),
+            &b"hello_world"[..]
+        );
+
+        // Reading back gives the uncompressed content
+        let read_back = image_to_filesystem::<Sha256HashValue>(&image).unwrap();
+        let Ok(tree::RegularFile::Inline(data)) = read_back.root.get_file(OsStr::new("config"))
+        else {
+            panic!("expected inline file");
+        };
+        assert_eq!(&data[..], expected.as_bytes());
+        assert_eq!(mkfs_erofs(&read_back, FormatVersion::V1_1), plain);
+    }
 }
@@ -7,6 +7,7 @@
 use std::{
     cell::RefCell,
     collections::{BTreeMap, HashMap},
+    io::{self, Write},
     mem::size_of,
     os::unix::ffi::OsStrExt,
     rc::Rc,
@@ -14,11 +15,11 @@ use std::{
 
 use log::trace;
 use xxhash_rust::xxh32::xxh32;
-use zerocopy::{Immutable, IntoBytes};
+use zerocopy::{little_endian::U16, Immutable, IntoBytes};
 
 use crate::{
     erofs::{composefs::OverlayMetacopy, format, reader::round_up},
-    fsverity::FsVerityHashValue,
+    fsverity::{FsVerityHashValue, FsVerityHasher},
     tree,
 };
 
@@ -98,6 +99,7 @@ struct Directory<'a> {
 struct Leaf<'a, ObjectID: FsVerityHashValue> {
     content: &'a tree::LeafContent<ObjectID>,
     nlink: usize,
+    compressed: Option<Box<[u8]>>,
 }
 
 #[derive(Debug)]
@@ -316,11 +318,43 @@ fn compute_chunk_format(file_size: u64) -> u32 {
     chunkbits - BLOCK_BITS
 }
 
+/// The size of the metadata preceding tail-packed compressed data: the map header, 8 bytes of
+/// padding, and a single lcluster index.
+const COMPRESSED_META_SIZE: usize =
+    size_of::<format::MapHeader>() + 8 + size_of::<format::LclusterIndex>();
+
+/// Compresses the content of a regular file for [`format::FormatVersion::V1_1Compressed`].
+///
+/// We only handle files that fit in a single logical cluster, storing the compressed data inline
+/// after the lcluster index ("ztailpacking").  Returns `None` unless that actually makes the inode
+/// smaller.
+pub(crate) fn compress_inline(data: &[u8]) -> Option<Box<[u8]>> {
+    if data.is_empty() || data.len() > usize::from(format::BLOCK_SIZE) {
+        return None;
+    }
+
+    let compressed = miniz_oxide::deflate::compress_to_vec(data, 9);
+
+    // The kernel skips leading zero bytes as padding, so the stream must not start with one.
+    // That can't happen for a single final DEFLATE block, but check anyway.
+    (compressed.first() != Some(&0) && COMPRESSED_META_SIZE + compressed.len() < data.len())
+        .then(|| compressed.into_boxed_slice())
+}
+
 impl<ObjectID: FsVerityHashValue> Leaf<'_, ObjectID> {
+    /// Try to compress the inline data of a regular file.
+    fn compress(&mut self) {
+        if let tree::LeafContent::Regular(tree::RegularFile::Inline(data)) = self.content {
+            self.compressed = compress_inline(data);
+        }
+    }
+
     fn inode_meta(&self) -> (format::DataLayout, u32, u64, usize) {
         let (layout, u, size) = match &self.content {
             tree::LeafContent::Regular(tree::RegularFile::Inline(data)) => {
-                if data.is_empty() {
+                if self.compressed.is_some() {
+                    (format::DataLayout::CompressedFull, 0, data.len() as u64)
+                } else if data.is_empty() {
                     (format::DataLayout::FlatPlain, 0, data.len() as u64)
                 } else {
                     (format::DataLayout::FlatInline, 0, data.len() as u64)
@@ -343,7 +377,38 @@ impl<ObjectID: FsVerityHashValue> Leaf<'_, ObjectID> {
         (layout, u, size, self.nlink)
     }
 
+    /// The size of the inline part of the inode, given the offset at which it starts
+    fn inline_size(&self, inline_start: u64) -> u64 {
+        match &self.compressed {
+            Some(data) => {
+                let align = (round_up(inline_start as usize, 8) as u64) - inline_start;
+                align + (COMPRESSED_META_SIZE + data.len()) as u64
+            }
+            None => {
+                let (.., size, _) = self.inode_meta();
+                size % u64::from(format::BLOCK_SIZE)
+            }
+        }
+    }
+
     fn write_inline(&self, output: &mut impl Output) {
+        if let Some(data) = &self.compressed {
+            output.pad(8);
+            output.write_struct(format::MapHeader {
+                idata_size: (data.len() as u16).into(),
+                advise: format::ADVISE_INLINE_PCLUSTER.into(),
+                algorithm_type: format::COMPRESSION_DEFLATE,
+                ..Default::default()
+            });
+            output.write(&[0; 8]);
+            output.write_struct(format::LclusterIndex {
+                advise: format::LCLUSTER_TYPE_HEAD1.into(),
+                ..Default::default()
+            });
+            output.write(data);
+            return;
+        }
+
         output.write(match self.content {
             tree::LeafContent::Regular(tree::RegularFile::Inline(data)) => data,
             tree::LeafContent::Regular(tree::RegularFile::External(..)) => b"\xff\xff\xff\xff", // null chunk
@@ -431,12 +496,18 @@ impl<ObjectID: FsVerityHashValue> Inode<'_, ObjectID> {
 
         // We need to make sure the inline part doesn't overlap a block boundary
         output.pad(32);
-        if matches!(layout, format::DataLayout::FlatInline) {
+        if matches!(
+            layout,
+            format::DataLayout::FlatInline | format::DataLayout::CompressedFull
+        ) {
             let block_size = u64::from(format::BLOCK_SIZE);
             let inode_and_xattr_size: u64 = (inode_header_size + xattr_size).try_into().unwrap();
             let current_pos: u64 = output.len().try_into().unwrap();
             let inline_start = current_pos + inode_and_xattr_size;
-            let inline_size = size % block_size;
+            let inline_size = match &self.content {
+                InodeContent::Leaf(leaf) => leaf.inline_size(inline_start),
+                InodeContent::Directory(..) => size % block_size,
+            };
 
             // Calculate how much space remains in the current block for inline data.
             // This matches C mkcomposefs logic in compute_erofs_inode_padding_for_tail().
@@ -606,6 +677,7 @@ impl<'a, ObjectID: FsVerityHashValue> InodeCollector<'a, ObjectID> {
             InodeContent::Leaf(Leaf {
                 content: &leaf.content,
                 nlink,
+                compressed: None,
             }),
         );
 
@@ -725,7 +797,28 @@ fn write_erofs(
     // V1_1: use 0 (not used)
     let (build_time, build_time_nsec) = match version {
         format::FormatVersion::V1_0 => min_mtime,
-        format::FormatVersion::V1_1 => (0, 0),
+        format::FormatVersion::V1_1 | format::FormatVersion::V1_1Compressed => (0, 0),
+    };
+
+    // Only require kernel support for compression if we actually compressed something
+    let compressed = inodes.iter().any(|inode| {
+        matches!(
+            &inode.content,
+            InodeContent::Leaf(Leaf {
+                compressed: Some(..),
+                ..
+            })
+        )
+    });
+    let (feature_incompat, available_compr_algs) = if compressed {
+        (
+            format::FEATURE_INCOMPAT_ZERO_PADDING
+                | format::FEATURE_INCOMPAT_COMPR_CFGS
+                | format::FEATURE_INCOMPAT_ZTAILPACKING,
+            1 << format::COMPRESSION_DEFLATE,
+        )
+    } else {
+        (0.into(), 0)
     };
 
     // Write composefs header
@@ -752,9 +845,20 @@ fn write_erofs(
         build_time: build_time.into(),
         build_time_nsec: build_time_nsec.into(),
         xattr_blkaddr: xattr_blkaddr.into(),
+        feature_incompat,
+        available_compr_algs: available_compr_algs.into(),
         ..Default::default()
     });
 
+    // The compression configs follow the superblock, each prefixed by its size
+    if compressed {
+        output.write_struct(U16::new(size_of::<format::DeflateConfig>() as u16));
+        output.write_struct(format::DeflateConfig {
+            windowbits: 15,
+            ..Default::default()
+        });
+    }
+
     // Write inode table
     for (idx, inode) in inodes.iter().enumerate() {
         // The inode may add padding to itself, so it notes its own offset
@@ -794,12 +898,14 @@ struct FirstPass {
     layout: Layout,
 }
 
-struct SecondPass {
-    output: Vec<u8>,
+struct SecondPass<W: Write> {
+    output: W,
+    offset: usize,
     layout: Layout,
+    error: Option<io::Error>,
 }
 
-impl Output for SecondPass {
+impl<W: Write> Output for SecondPass<W> {
     fn note_offset(&mut self, _offset_type: Offset) {
         /* no-op */
     }
@@ -810,16 +916,75 @@ impl Output for SecondPass {
     }
 
     fn write(&mut self, data: &[u8]) {
-        self.output.extend_from_slice(data);
+        // The Output trait is infallible, so we hold on to the first error and
+        // report it once the whole image has been visited.
+        if self.error.is_none() {
+            if let Err(err) = self.output.write_all(data) {
+                self.error = Some(err);
+            }
+        }
+        self.offset += data.len();
     }
 
     fn pad(&mut self, alignment: usize) {
-        self.output
-            .resize(round_up(self.output.len(), alignment), 0);
+        const ZEROS: [u8; 4096] = [0; 4096];
+        let mut remaining = round_up(self.offset, alignment) - self.offset;
+        while remaining > 0 {
+            let n = remaining.min(ZEROS.len());
+            self.write(&ZEROS[..n]);
+            remaining -= n;
+        }
     }
 
     fn len(&self) -> usize {
-        self.output.len()
+        self.offset
+    }
+}
+
+/// A writer that computes the fs-verity digest of everything passing through it.
+struct VerityWriter<W: Write, ObjectID: FsVerityHashValue> {
+    inner: W,
+    hasher: FsVerityHasher<ObjectID>,
+    block: Vec<u8>,
+}
+
+impl<W: Write, ObjectID: FsVerityHashValue> VerityWriter<W, ObjectID> {
+    const BLOCK_SIZE: usize = FsVerityHasher::<ObjectID>::BLOCK_SIZE;
+
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: FsVerityHasher::new(),
+            block: Vec::with_capacity(Self::BLOCK_SIZE),
+        }
+    }
+
+    fn digest(mut self) -> ObjectID {
+        if !self.block.is_empty() {
+            self.hasher.add_block(&self.block);
+        }
+        self.hasher.digest()
+    }
+}
+
+impl<W: Write, ObjectID: FsVerityHashValue> Write for VerityWriter<W, ObjectID> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(data)?;
+        let mut written = &data[..n];
+        while !written.is_empty() {
+            let take = written.len().min(Self::BLOCK_SIZE - self.block.len());
+            self.block.extend_from_slice(&written[..take]);
+            written = &written[take..];
+            if self.block.len() == Self::BLOCK_SIZE {
+                self.hasher.add_block(&self.block);
+                self.block.clear();
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
@@ -885,19 +1050,41 @@ fn calculate_min_mtime(inodes: &[Inode<impl FsVerityHashValue>]) -> (u64, u32) {
 
 /// Creates an EROFS filesystem image from a composefs tree
 ///
-/// This function performs a two-pass generation:
-/// 1. First pass determines the layout and sizes of all structures
-/// 2. Second pass writes the actual image data
+/// This is a convenience wrapper around [`mkfs_erofs_to_writer`] which
+/// collects the image into memory.
 ///
 /// The `version` parameter controls the format version:
 /// - `FormatVersion::V1_0`: Uses composefs_version=0 and sets build_time to min mtime
 /// - `FormatVersion::V1_1`: Uses composefs_version=2 (current default)
+/// - `FormatVersion::V1_1Compressed`: As V1_1, with small inline files DEFLATE-compressed
 ///
 /// Returns the complete EROFS image as a byte array.
 pub fn mkfs_erofs<ObjectID: FsVerityHashValue>(
     fs: &tree::FileSystem<ObjectID>,
     version: format::FormatVersion,
 ) -> Box<[u8]> {
+    let mut output = vec![];
+    mkfs_erofs_to_writer(fs, version, &mut output).expect("writing to a Vec can't fail");
+    output.into_boxed_slice()
+}
+
+/// Writes an EROFS filesystem image from a composefs tree to `output`
+///
+/// This function performs a two-pass generation:
+/// 1. First pass determines the layout and sizes of all structures
+/// 2. Second pass streams the actual image data to `output`
+///
+/// The image is written strictly sequentially and is never held in memory as
+/// a whole, so this is suitable for very large filesystems.  The output is
+/// byte-identical to what [`mkfs_erofs`] produces.  No buffering is done:
+/// consider wrapping files in a [`std::io::BufWriter`].
+///
+/// Returns the size of the image, in bytes.
+pub fn mkfs_erofs_to_writer<ObjectID: FsVerityHashValue>(
+    fs: &tree::FileSystem<ObjectID>,
+    version: format::FormatVersion,
+    output: impl Write,
+) -> io::Result<u64> {
     // Create the intermediate representation: flattened inodes and shared xattrs
     let mut inodes = InodeCollector::collect(fs);
 
@@ -908,6 +1095,14 @@ pub fn mkfs_erofs<ObjectID: FsVerityHashValue>(
         inodes[0].xattrs.add(b"trusted.overlay.opaque", b"y");
     }
 
+    if version.compressed() {
+        for inode in inodes.iter_mut() {
+            if let InodeContent::Leaf(leaf) = &mut inode.content {
+                leaf.compress();
+            }
+        }
+    }
+
     let xattrs = share_xattrs(&mut inodes);
 
     // Calculate minimum mtime for V1_0 build_time
@@ -917,15 +1112,39 @@ pub fn mkfs_erofs<ObjectID: FsVerityHashValue>(
     let mut first_pass = FirstPass::default();
     write_erofs(&mut first_pass, &inodes, &xattrs, version, min_mtime);
 
-    // Do a second pass with the writer to get the actual bytes
+    // Do a second pass with the writer to produce the actual bytes
     let mut second_pass = SecondPass {
-        output: vec![],
+        output,
+        offset: 0,
         layout: first_pass.layout,
+        error: None,
     };
     write_erofs(&mut second_pass, &inodes, &xattrs, version, min_mtime);
 
-    // That's it
-    second_pass.output.into_boxed_slice()
+    if let Some(err) = second_pass.error {
+        return Err(err);
+    }
+    assert_eq!(second_pass.offset, first_pass.offset);
+    second_pass.output.flush()?;
+
+    Ok(second_pass.offset as u64)
+}
+
+/// Writes an EROFS filesystem image to `output`, computing its fs-verity digest
+///
+/// This is [`mkfs_erofs_to_writer`], but the fs-verity digest of the image is
+/// computed incrementally as it is written, avoiding the need to read the image
+/// back.  This is useful when streaming an image into a repository object.
+///
+/// Returns the size of the image, in bytes, and its fs-verity digest.
+pub fn mkfs_erofs_verity<ObjectID: FsVerityHashValue>(
+    fs: &tree::FileSystem<ObjectID>,
+    version: format::FormatVersion,
+    output: impl Write,
+) -> io::Result<(u64, ObjectID)> {
+    let mut writer = VerityWriter::<_, ObjectID>::new(output);
+    let size = mkfs_erofs_to_writer(fs, version, &mut writer)?;
+    Ok((size, writer.digest()))
 }
 
 /// Creates an EROFS filesystem image using the default format version (V1_1)
@@ -4,20 +4,31 @@
 //! FileSystem objects, including computing image IDs, committing to
 //! repositories, and generating dumpfiles.
 
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{ensure, Result};
 
 use crate::{
     dumpfile::write_dumpfile,
-    erofs::writer::mkfs_erofs_default,
-    fsverity::{compute_verity, FsVerityHashValue},
+    erofs::{
+        format::{FormatVersion, BLOCK_SIZE},
+        writer::{compress_inline, mkfs_erofs_verity},
+    },
+    fsverity::FsVerityHashValue,
     repository::Repository,
-    tree::FileSystem,
+    tree::{Directory, FileSystem, Inode, Leaf, LeafContent, RegularFile},
 };
 
 impl<ObjectID: FsVerityHashValue> FileSystem<ObjectID> {
     /// Commits this filesystem as an EROFS image to the repository.
     ///
-    /// Generates an EROFS filesystem image and writes it to the repository
+    /// Generates an EROFS filesystem image and streams it into the repository
     /// with the optional name. Returns the fsverity digest of the committed image.
     ///
     /// Note: Callers should ensure root metadata is set before calling this,
@@ -27,7 +38,23 @@ impl<ObjectID: FsVerityHashValue> FileSystem<ObjectID> {
         repository: &Repository<ObjectID>,
         image_name: Option<&str>,
     ) -> Result<ObjectID> {
-        repository.write_image(image_name, &mkfs_erofs_default(self))
+        self.commit_image_with_format(repository, image_name, FormatVersion::default())
+    }
+
+    /// Commits this filesystem as an EROFS image to the repository, with the given format
+    /// version.
+    ///
+    /// This is the same as [`Self::commit_image`], except for the format.
+    pub fn commit_image_with_format(
+        &self,
+        repository: &Repository<ObjectID>,
+        image_name: Option<&str>,
+        version: FormatVersion,
+    ) -> Result<ObjectID> {
+        let mut output = BufWriter::new(File::from(repository.create_object_tmpfile()?));
+        let (size, id) = mkfs_erofs_verity(self, version, &mut output)?;
+        let file = output.into_inner().map_err(|e| e.into_error())?;
+        repository.write_image_tmpfile(image_name, file, size, &id)
     }
 
     /// Computes the fsverity digest for this filesystem as an EROFS image.
@@ -38,7 +65,83 @@ impl<ObjectID: FsVerityHashValue> FileSystem<ObjectID> {
     /// Note: Callers should ensure root metadata is set before calling this,
     /// typically via `copy_root_metadata_from_usr()` or `set_root_stat()`.
     pub fn compute_image_id(&self) -> ObjectID {
-        compute_verity(&mkfs_erofs_default(self))
+        self.compute_image_id_with_format(FormatVersion::default())
+    }
+
+    /// Computes the fsverity digest for this filesystem as an EROFS image with the given format
+    /// version, as committed by [`Self::commit_image_with_format`].
+    pub fn compute_image_id_with_format(&self, version: FormatVersion) -> ObjectID {
+        mkfs_erofs_verity(self, version, std::io::sink())
+            .expect("writing to io::sink() can't fail")
+            .1
+    }
+
+    /// Stores the external files which fit in a block and compress well inline, so that
+    /// [`FormatVersion::V1_1Compressed`] compresses them into the image.
+    ///
+    /// `read` is called with the path (relative to the root) and the object ID of each candidate
+    /// and returns its content, or `None` to leave it external.  Hardlinks are preserved.
+    pub fn inline_compressible_files(
+        &mut self,
+        mut read: impl FnMut(&Path, &ObjectID) -> Result<Option<Vec<u8>>>,
+    ) -> Result<()> {
+        type Inlined<ObjectID> =
+            HashMap<*const Leaf<ObjectID>, (Rc<Leaf<ObjectID>>, Rc<Leaf<ObjectID>>)>;
+
+        fn visit_dir<ObjectID, F>(
+            dir: &mut Directory<ObjectID>,
+            path: &mut PathBuf,
+            read: &mut F,
+            inlined: &mut Inlined<ObjectID>,
+        ) -> Result<()>
+        where
+            ObjectID: FsVerityHashValue,
+            F: FnMut(&Path, &ObjectID) -> Result<Option<Vec<u8>>>,
+        {
+            for (name, inode) in dir.entries.iter_mut() {
+                path.push(&**name);
+                match inode {
+                    Inode::Directory(dir) => visit_dir(dir, path, read, inlined)?,
+                    Inode::Leaf(leaf) => {
+                        if let Some((_, new)) = inlined.get(&Rc::as_ptr(leaf)) {
+                            *leaf = Rc::clone(new);
+                        } else if let LeafContent::Regular(RegularFile::External(id, size)) =
+                            &leaf.content
+                        {
+                            if *size <= u64::from(BLOCK_SIZE) {
+                                if let Some(data) = read(path, id)? {
+                                    ensure!(
+                                        data.len() as u64 == *size,
+                                        "{path:?} has {} bytes instead of {size}",
+                                        data.len()
+                                    );
+                                    if compress_inline(&data).is_some() {
+                                        let new = Rc::new(Leaf {
+                                            stat: leaf.stat.clone(),
+                                            content: LeafContent::Regular(RegularFile::Inline(
+                                                data.into(),
+                                            )),
+                                        });
+                                        // Keep the old leaf, so its address isn't reused
+                                        let old = std::mem::replace(leaf, Rc::clone(&new));
+                                        inlined.insert(Rc::as_ptr(&old), (old, new));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                path.pop();
+            }
+            Ok(())
+        }
+
+        visit_dir(
+            &mut self.root,
+            &mut PathBuf::new(),
+            &mut read,
+            &mut HashMap::new(),
+        )
     }
 
     /// Prints this filesystem in dumpfile format to stdout.
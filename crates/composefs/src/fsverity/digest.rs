@@ -83,11 +83,22 @@ impl<H: FsVerityHashValue, const LG_BLKSZ: u8> FsVerityHasher<H, LG_BLKSZ> {
         }
     }
 
+    /// Hash a single block of data, as stored in the lowest level of the Merkle tree.
+    ///
+    /// The block may be smaller than the block size, in which case it is zero-padded.
+    pub fn hash_block(data: &[u8]) -> H {
+        let mut context = FsVerityLayer::<H, LG_BLKSZ>::new();
+        context.add_data(data);
+        context.complete()
+    }
+
     /// Add a block of data to the hasher.
     ///
     /// For correct results, data should be provided in block-sized chunks (4KB)
     /// except for the final chunk which may be smaller.
-    pub fn add_block(&mut self, data: &[u8]) {
+    ///
+    /// Returns the hash of the block (see [`Self::hash_block`]).
+    pub fn add_block(&mut self, data: &[u8]) -> H {
         if let Some(value) = self.value.take() {
             // We had a complete value, but now we're adding new data.
             // This means that we need to add a new hash layer...
@@ -97,16 +108,15 @@ impl<H: FsVerityHashValue, const LG_BLKSZ: u8> FsVerityHasher<H, LG_BLKSZ> {
         }
 
         // Get the value of this block
-        let mut context = FsVerityLayer::<H, LG_BLKSZ>::new();
-        context.add_data(data);
-        let mut value = context.complete();
+        let block_hash = Self::hash_block(data);
+        let mut value = block_hash.clone();
         self.n_bytes += data.len() as u64;
 
         for layer in self.layers.iter_mut() {
             // We have a layer we need to hash this value into
             layer.add_data(value.as_bytes());
             if layer.remaining != 0 {
-                return;
+                return block_hash;
             }
             // ...but now this layer itself is now complete, so get the value of *it*.
             value = layer.complete();
@@ -114,6 +124,7 @@ impl<H: FsVerityHashValue, const LG_BLKSZ: u8> FsVerityHasher<H, LG_BLKSZ> {
 
         // If we made it this far, we completed the last layer and have a value.  Store it.
         self.value = Some(value);
+        block_hash
     }
 
     fn root_hash(&mut self) -> H {
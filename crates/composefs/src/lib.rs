@@ -4,6 +4,7 @@
 //! of container filesystem layers by using content-addressable storage
 //! and fs-verity for integrity verification.
 
+pub mod diff;
 pub mod dumpfile;
 pub mod dumpfile_parse;
 pub mod erofs;
@@ -44,6 +44,17 @@ pub fn overlayfs_set_lower_and_data_fds(
     Ok(())
 }
 
+/// Sets the "lowerdir+" mount option of an overlayfs mount once for each of the provided file
+/// descriptors, stacking them in order: the first one is the topmost layer.  This has the same
+/// compatibility considerations as `overlayfs_set_lower_and_data_fds()`.
+#[cfg(not(feature = "rhel9"))]
+pub fn overlayfs_set_lower_fds(fs_fd: impl AsFd, lowers: &[impl AsFd]) -> rustix::io::Result<()> {
+    for lower in lowers {
+        overlayfs_set_fd(fs_fd.as_fd(), "lowerdir+", lower.as_fd())?;
+    }
+    Ok(())
+}
+
 /// Prepares an open erofs image file for mounting.  On kernels versions after 6.12 this is a
 /// simple passthrough.  On older kernels (like on RHEL 9) we need to create a loopback device.
 #[cfg(not(feature = "rhel9"))]
@@ -118,6 +129,23 @@ pub fn overlayfs_set_lower_and_data_fds(
     rustix::mount::fsconfig_set_string(fs_fd.as_fd(), "lowerdir", arg)
 }
 
+/// Sets the lower layers of an overlayfs mount to the provided file descriptors, the first one
+/// being the topmost layer.
+///
+/// On RHEL9 kernels, this constructs a `lowerdir` string using `/proc/self/fd/` paths and
+/// sets it via `fsconfig_set_string()` because file descriptors cannot be set directly.
+#[cfg(feature = "rhel9")]
+pub fn overlayfs_set_lower_fds(fs_fd: impl AsFd, lowers: &[impl AsFd]) -> rustix::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let arg = lowers
+        .iter()
+        .map(|lower| format!("/proc/self/fd/{}", lower.as_fd().as_raw_fd()))
+        .collect::<Vec<_>>()
+        .join(":");
+    rustix::mount::fsconfig_set_string(fs_fd.as_fd(), "lowerdir", arg)
+}
+
 /// Prepares a mounted filesystem for further use.
 ///
 /// On pre-6.15 kernels, this mounts the filesystem to a temporary directory and returns
@@ -773,7 +773,37 @@ impl<ObjectID: FsVerityHashValue> Repository<ObjectID> {
     /// This function is not safe for untrusted users.
     pub fn write_image(&self, name: Option<&str>, data: &[u8]) -> Result<ObjectID> {
         let object_id = self.ensure_object(data)?;
+        self.link_image(name, object_id)
+    }
+
+    /// Finalize an image which was streamed into a tmpfile from
+    /// [`Self::create_object_tmpfile()`], optionally giving it a name.
+    ///
+    /// `expected_id` is the fs-verity digest computed while writing the image
+    /// (e.g. by [`crate::erofs::writer::mkfs_erofs_verity()`]).  It is checked
+    /// against the digest of the stored object.
+    ///
+    /// # Integrity
+    ///
+    /// This function is not safe for untrusted users.
+    pub fn write_image_tmpfile(
+        &self,
+        name: Option<&str>,
+        file: File,
+        size: u64,
+        expected_id: &ObjectID,
+    ) -> Result<ObjectID> {
+        let object_id = self.finalize_object_tmpfile(file, size)?;
+        ensure!(
+            object_id == *expected_id,
+            "Image digest mismatch: wrote {}, but stored {}",
+            expected_id.to_hex(),
+            object_id.to_hex()
+        );
+        self.link_image(name, object_id)
+    }
 
+    fn link_image(&self, name: Option<&str>, object_id: ObjectID) -> Result<ObjectID> {
         let object_path = Self::format_object_path(&object_id);
         let image_path = format!("images/{}", object_id.to_hex());
 
@@ -787,6 +817,42 @@ impl<ObjectID: FsVerityHashValue> Repository<ObjectID> {
         Ok(object_id)
     }
 
+    /// Assign a named reference to an image, making it a GC root.
+    ///
+    /// Creates (or replaces) a symlink at `images/refs/{name}` pointing to the image, which must
+    /// already exist in the repository.  As with [`Self::name_stream`], the `name` can include
+    /// path separators.
+    pub fn name_image(&self, image: &ObjectID, name: &str) -> Result<()> {
+        let image_path = format!("images/{}", image.to_hex());
+        statat(&self.repository, &image_path, AtFlags::SYMLINK_NOFOLLOW)
+            .with_context(|| format!("Looking for image {} in repository", image.to_hex()))?;
+        self.symlink(format!("images/refs/{name}"), &image_path)?;
+        Ok(())
+    }
+
+    /// Checks if there's a named reference to an image (see [`Self::name_image`]).
+    pub fn has_named_image(&self, name: &str) -> Result<bool> {
+        let ref_path = format!("images/refs/{name}");
+        Ok(
+            statat(&self.repository, &ref_path, AtFlags::SYMLINK_NOFOLLOW)
+                .filter_errno(Errno::NOENT)
+                .with_context(|| format!("Looking for {ref_path}"))?
+                .is_some(),
+        )
+    }
+
+    /// Removes the named reference to an image, so that the image becomes subject to garbage
+    /// collection (unless something else refers to it).
+    ///
+    /// Returns false if there was no such reference.
+    pub fn remove_image_ref(&self, name: &str) -> Result<bool> {
+        let ref_path = format!("images/refs/{name}");
+        Ok(unlinkat(&self.repository, &ref_path, AtFlags::empty())
+            .filter_errno(Errno::NOENT)
+            .with_context(|| format!("Removing {ref_path}"))?
+            .is_some())
+    }
+
     /// Import the data from the provided read into the repository as an image.
     ///
     /// The fsverity digest is returned.
@@ -1126,11 +1192,20 @@ impl<ObjectID: FsVerityHashValue> Repository<ObjectID> {
         Ok(())
     }
 
-    /// Given an image, return the set of all objects referenced by it.
-    pub fn objects_for_image(&self, name: &str) -> Result<HashSet<ObjectID>> {
+    /// Read the contents of the named image into a Vec.
+    ///
+    /// The name is interpreted in the same way as for `.mount()`: either an fs-verity digest
+    /// (which is verified, if the repository is not in `insecure` mode) or prefixed with 'refs/'.
+    pub fn read_image(&self, name: &str) -> Result<Vec<u8>> {
         let (image, _) = self.open_image(name)?;
         let mut data = vec![];
         std::fs::File::from(image).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Given an image, return the set of all objects referenced by it.
+    pub fn objects_for_image(&self, name: &str) -> Result<HashSet<ObjectID>> {
+        let data = self.read_image(name)?;
         Ok(crate::erofs::reader::collect_objects(&data, &[])?)
     }
 
@@ -1968,6 +2043,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_gc_name_image() -> Result<()> {
+        let tmp = tempdir();
+        let repo = create_test_repo(&tmp.path().join("repo"))?;
+
+        let obj_size: u64 = 32 * 1024;
+        let obj_id = repo.ensure_object(&generate_test_data(obj_size, 0xAE))?;
+        let image = make_test_fs(&obj_id, obj_size).commit_image(&repo, None)?;
+        let image_path = format!("images/{}", image.to_hex());
+
+        // Naming a missing image fails
+        let missing = make_test_fs(&obj_id, 1).compute_image_id();
+        assert!(repo.name_image(&missing, "deploy/missing").is_err());
+
+        // The named image is kept
+        repo.name_image(&image, "deploy/image")?;
+        assert_eq!(
+            read_links_in_repo(&tmp, "images/refs/deploy/image")?,
+            Some(PathBuf::from("../..").join(image.to_hex()))
+        );
+        let result = repo.gc(&[])?;
+        assert_eq!(result.objects_removed, 0);
+        assert!(test_object_exists(&tmp, &obj_id)?);
+
+        // ...until the ref is removed
+        assert!(repo.remove_image_ref("deploy/image")?);
+        assert!(!repo.remove_image_ref("deploy/image")?);
+        let result = repo.gc(&[])?;
+        assert_eq!(result.objects_removed, 2);
+        assert_eq!(result.images_pruned, 1);
+        assert!(!test_path_exists_in_repo(&tmp, &image_path)?);
+        Ok(())
+    }
+
     fn make_test_fs_with_two_files(
         obj1: &Sha512HashValue,
         size1: u64,
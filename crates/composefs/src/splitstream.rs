@@ -595,6 +595,10 @@ pub struct SplitStreamReader<ObjectID: FsVerityHashValue> {
     pub content_type: u64,
     /// The total size of the original/merged stream, in bytes
     pub total_size: u64,
+    /// The kernel fs-verity algorithm identifier from the header (1 = sha256, 2 = sha512)
+    pub algorithm: u8,
+    /// log2 of the fs-verity block size from the header
+    pub lg_blocksize: u8,
     object_refs: Vec<ObjectID>,
     named_refs: HashMap<Box<str>, ObjectID>,
 }
@@ -686,6 +690,8 @@ impl<ObjectID: FsVerityHashValue> SplitStreamReader<ObjectID> {
             inline_bytes: 0,
             content_type,
             total_size,
+            algorithm: header.algorithm,
+            lg_blocksize: header.lg_blocksize,
             object_refs: object_refs.to_vec(),
             named_refs,
         })
@@ -859,6 +865,24 @@ impl<ObjectID: FsVerityHashValue> SplitStreamReader<ObjectID> {
         }
     }
 
+    /// Reads the next chunk of the split stream, exactly as it was stored.
+    ///
+    /// Inline chunks are returned in full, and external chunks are returned as the referenced
+    /// object ID.  Returns None at the end of the stream.  This is mostly useful for inspecting
+    /// the structure of a stream: use `.cat()` or the `Read` impl for accessing its content.
+    pub fn next_chunk(&mut self) -> Result<Option<SplitStreamData<ObjectID>>> {
+        match self.ensure_chunk(true, true, 0)? {
+            ChunkType::Eof => Ok(None),
+            ChunkType::Inline => {
+                let mut content = vec![];
+                read_into_vec(&mut self.decoder, &mut content, self.inline_bytes)?;
+                self.inline_bytes = 0;
+                Ok(Some(SplitStreamData::Inline(content.into())))
+            }
+            ChunkType::External(id) => Ok(Some(SplitStreamData::External(id))),
+        }
+    }
+
     /// Traverses the split stream and calls the callback for each object reference.
     ///
     /// This includes both references from the digest map and external references in the stream.
@@ -1291,4 +1315,43 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_splitstream_next_chunk() -> Result<()> {
+        let tmp = tempdir();
+        let repo = create_test_repo(&tmp.path().join("repo"))?;
+
+        let header = generate_test_data(512, 0x01);
+        let file_content = generate_test_data(64 * 1024, 0x02);
+        let trailer = generate_test_data(1024, 0x03);
+        let expected_digest: Sha256HashValue = compute_verity(&file_content);
+
+        let mut writer = repo.create_stream(0);
+        writer.write_inline(&header);
+        writer.write_external(&file_content)?;
+        writer.write_inline(&trailer);
+        let stream_id = repo.write_stream(writer, "test-chunks", None)?;
+
+        let mut reader = repo.open_stream("test-chunks", Some(&stream_id), None)?;
+        assert_eq!(reader.algorithm, Sha256HashValue::ALGORITHM);
+        assert_eq!(reader.lg_blocksize, LG_BLOCKSIZE);
+
+        let Some(SplitStreamData::Inline(data)) = reader.next_chunk()? else {
+            panic!("expected inline chunk");
+        };
+        assert_eq!(&data[..], &header[..]);
+
+        let Some(SplitStreamData::External(id)) = reader.next_chunk()? else {
+            panic!("expected external chunk");
+        };
+        assert_eq!(id, expected_digest);
+
+        let Some(SplitStreamData::Inline(data)) = reader.next_chunk()? else {
+            panic!("expected inline chunk");
+        };
+        assert_eq!(&data[..], &trailer[..]);
+
+        assert!(reader.next_chunk()?.is_none());
+        Ok(())
+    }
 }
@@ -2,9 +2,9 @@
 
 use std::{
     cell::RefCell,
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     ffi::OsStr,
-    io::Write,
+    io::{BufWriter, Write},
     process::{Command, Stdio},
     rc::Rc,
 };
@@ -14,8 +14,13 @@ use tempfile::NamedTempFile;
 
 use composefs::{
     dumpfile::write_dumpfile,
-    erofs::{debug::debug_img, format::FormatVersion, writer::mkfs_erofs},
-    fsverity::{FsVerityHashValue, Sha256HashValue},
+    erofs::{
+        debug::debug_img,
+        format::FormatVersion,
+        reader::image_to_filesystem,
+        writer::{mkfs_erofs, mkfs_erofs_to_writer, mkfs_erofs_verity},
+    },
+    fsverity::{compute_verity, FsVerityHashValue, Sha256HashValue},
     tree::{Directory, FileSystem, Inode, Leaf, LeafContent, RegularFile, Stat},
 };
 
@@ -114,6 +119,168 @@ fn test_simple() {
     insta::assert_snapshot!(debug_fs(fs));
 }
 
+fn compressible(fs: &mut FileSystem<Sha256HashValue>) {
+    let config = "# comment\nkey = value\n".repeat(20);
+    add_leaf(
+        &mut fs.root,
+        "config",
+        LeafContent::Regular(RegularFile::Inline(config.as_bytes().into())),
+    );
+    add_leaf(
+        &mut fs.root,
+        "incompressible",
+        LeafContent::Regular(RegularFile::Inline((*b"hihi").into())),
+    );
+}
+
+#[test]
+fn test_compressed() {
+    let mut fs = FileSystem::<Sha256HashValue>::new(default_stat());
+    compressible(&mut fs);
+    let image = mkfs_erofs(&fs, FormatVersion::V1_1Compressed);
+    let mut output = vec![];
+    debug_img(&mut output, &image).unwrap();
+    insta::assert_snapshot!(String::from_utf8(output).unwrap());
+}
+
+/// A tree like one read from a directory, with a few config files of a couple of KiB stored
+/// externally, and their contents.
+fn config_files() -> (
+    FileSystem<Sha256HashValue>,
+    HashMap<Sha256HashValue, Vec<u8>>,
+) {
+    let mut fs = FileSystem::new(default_stat());
+    let mut objects = HashMap::new();
+    let mut add_external = |fs: &mut FileSystem<_>, name: &str, data: Vec<u8>| {
+        let id: Sha256HashValue = compute_verity(&data);
+        let size = data.len() as u64;
+        objects.insert(id.clone(), data);
+        add_leaf(
+            &mut fs.root,
+            name,
+            LeafContent::Regular(RegularFile::External(id, size)),
+        );
+    };
+
+    for (n, service) in ["sshd", "chronyd", "journald", "logind"].iter().enumerate() {
+        let config = (0..40)
+            .map(|i| format!("# Option{i} controls how {service} behaves\n#Option{i} = {n}\n"))
+            .collect::<String>();
+        assert!((1024..=4096).contains(&config.len()));
+        add_external(&mut fs, &format!("{service}.conf"), config.into_bytes());
+    }
+
+    // Doesn't compress
+    let mut state = 1u32;
+    let noise = (0..2000)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 24) as u8
+        })
+        .collect();
+    add_external(&mut fs, "noise", noise);
+
+    // Doesn't fit in a block
+    add_external(
+        &mut fs,
+        "large.conf",
+        "key = value\n".repeat(1000).into_bytes(),
+    );
+
+    // A hardlink
+    let leaf = fs.root.ref_leaf(OsStr::new("sshd.conf")).unwrap();
+    fs.root.insert(OsStr::new("sshd.link"), Inode::Leaf(leaf));
+
+    (fs, objects)
+}
+
+#[test]
+fn test_inline_compressible_files() {
+    let (mut fs, objects) = config_files();
+    let original = fs.clone();
+    let external = mkfs_erofs(&fs, FormatVersion::V1_1Compressed);
+    assert_eq!(external.len(), mkfs_erofs(&fs, FormatVersion::V1_1).len());
+
+    let mut candidates = vec![];
+    fs.inline_compressible_files(|path, id| {
+        candidates.push(path.to_owned());
+        Ok(objects.get(id).cloned())
+    })
+    .unwrap();
+    candidates.sort();
+    assert_eq!(
+        candidates,
+        [
+            "chronyd.conf",
+            "journald.conf",
+            "logind.conf",
+            "noise",
+            "sshd.conf"
+        ]
+        .map(std::path::PathBuf::from)
+    );
+
+    let compressed = mkfs_erofs(&fs, FormatVersion::V1_1Compressed);
+    let uncompressed = mkfs_erofs(&fs, FormatVersion::V1_1);
+    assert!(compressed.len() < uncompressed.len());
+
+    // The image grows by less than the objects which no longer need to be stored
+    let inlined: usize = ["sshd", "chronyd", "journald", "logind"]
+        .map(|service| {
+            match original
+                .root
+                .get_file(OsStr::new(&format!("{service}.conf")))
+            {
+                Ok(RegularFile::External(id, _)) => objects[id].len(),
+                _ => unreachable!(),
+            }
+        })
+        .iter()
+        .sum();
+    assert!(compressed.len() < external.len() + inlined);
+
+    // Hardlinks are kept
+    assert!(Rc::ptr_eq(
+        &fs.root.ref_leaf(OsStr::new("sshd.conf")).unwrap(),
+        &fs.root.ref_leaf(OsStr::new("sshd.link")).unwrap()
+    ));
+
+    // The reader and erofs-debug see the original content
+    let read = image_to_filesystem::<Sha256HashValue>(&compressed).unwrap();
+    for (name, inode) in fs.root.entries() {
+        let Inode::Leaf(leaf) = inode else {
+            unreachable!()
+        };
+        let LeafContent::Regular(file) = &leaf.content else {
+            unreachable!()
+        };
+        let content = match file {
+            RegularFile::Inline(data) => data,
+            RegularFile::External(id, _) => {
+                assert!(matches!(&*name.to_string_lossy(), "noise" | "large.conf"));
+                &objects[id][..]
+            }
+        };
+        match (file, read.root.get_file(name).unwrap()) {
+            (RegularFile::Inline(_), RegularFile::Inline(data)) => assert_eq!(&data[..], content),
+            (RegularFile::External(id, size), RegularFile::External(read_id, read_size)) => {
+                assert_eq!((id, size), (read_id, read_size))
+            }
+            _ => panic!("{name:?} changed its layout"),
+        }
+    }
+    assert!(Rc::ptr_eq(
+        &read.root.ref_leaf(OsStr::new("sshd.conf")).unwrap(),
+        &read.root.ref_leaf(OsStr::new("sshd.link")).unwrap()
+    ));
+
+    let mut output = vec![];
+    debug_img(&mut output, &compressed).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.matches("Ok(CompressedFull)").count(), 4);
+    assert_eq!(output.matches("# decompressed: \"# Option0").count(), 4);
+}
+
 fn foreach_case(f: fn(&FileSystem<Sha256HashValue>)) {
     for case in [empty, simple] {
         let mut fs = FileSystem::new(default_stat());
@@ -132,6 +299,51 @@ fn test_fsck() {
     });
 }
 
+#[test]
+fn test_streaming() {
+    foreach_case(|fs| {
+        for version in [FormatVersion::V1_0, FormatVersion::V1_1] {
+            let image = mkfs_erofs(fs, version);
+
+            let mut tmp = NamedTempFile::new().unwrap();
+            let size =
+                mkfs_erofs_to_writer(fs, version, BufWriter::new(tmp.as_file_mut())).unwrap();
+            assert_eq!(size, image.len() as u64);
+            assert_eq!(std::fs::read(tmp.path()).unwrap(), &image[..]);
+
+            let mut streamed = vec![];
+            let (size, id) = mkfs_erofs_verity(fs, version, &mut streamed).unwrap();
+            assert_eq!(size, image.len() as u64);
+            assert_eq!(streamed, &image[..]);
+            assert_eq!(id, compute_verity::<Sha256HashValue>(&image));
+        }
+    });
+}
+
+#[test]
+fn test_streaming_error() {
+    struct FailingWriter(usize);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.0 < data.len() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.0 -= data.len();
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut fs = FileSystem::<Sha256HashValue>::new(default_stat());
+    simple(&mut fs);
+    let err = mkfs_erofs_to_writer(&fs, FormatVersion::default(), FailingWriter(2000)).unwrap_err();
+    assert_eq!(err.to_string(), "disk full");
+}
+
 fn dump_image(img: &[u8]) -> String {
     let mut dump = vec![];
     debug_img(&mut dump, img).unwrap();
@@ -52,8 +52,42 @@ mount -o bind,ro "${root}" "${root}"  # see open_root_fs()
 sysroot="$(mkd "${top}/sysroot")"
 mount -o bind "${blkdev}" "${sysroot}"
 
+# with a signing key in the initramfs, the image digest needs to be signed
+openssl genpkey -algorithm ed25519 -out "${top}/key.pem"
+openssl pkey -in "${top}/key.pem" -pubout -out "${top}/pubkey.pem"
+signed_config="${top}/signed-config"
+tee "${signed_config}" <<EOF
+[signature]
+key = "${top}/pubkey.pem"
+EOF
+
+setup_signed() {
+    composefs-setup-root \
+        --config "${signed_config}" \
+        --cmdline "composefs=${imageid}" \
+        --root-fs "${root}" \
+        --sysroot "${sysroot}" \
+        ${null}
+}
+
+signatures="$(mkd "${repo}/signatures")"
+assert_fail setup_signed  # no signature
+echo 'bad' > "${signatures}/${imageid}.sig"
+assert_fail setup_signed  # invalid signature
+printf '%s' "${imageid}" > "${top}/message"
+openssl pkeyutl -sign -rawin -inkey "${top}/key.pem" -in "${top}/message" \
+    -out "${signatures}/${imageid}.sig"
+
+# the signature doesn't cover the '?' which would turn off fs-verity
+assert_fail composefs-setup-root \
+    --config "${signed_config}" \
+    --cmdline "composefs=?${imageid}" \
+    --root-fs "${root}" \
+    --sysroot "${sysroot}" \
+    ${null}
+
 composefs-setup-root \
-    --config "${config}" \
+    --config "${signed_config}" \
     --cmdline "composefs=${imageid}" \
     --root-fs "${root}" \
     --sysroot "${sysroot}" \
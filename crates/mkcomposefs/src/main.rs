@@ -70,6 +70,14 @@ struct Args {
     #[arg(long, default_value = "1")]
     max_version: u32,
 
+    /// Store files of up to 4096 bytes which compress well DEFLATE-compressed in the image.
+    ///
+    /// This implies format version 1.  Mounting the image requires Linux 6.6 or
+    /// later with EROFS DEFLATE support.  With a dumpfile as the source, only
+    /// files which are inline or in the --digest-store are compressed.
+    #[arg(long)]
+    compress: bool,
+
     /// Copy regular file content to the given object store directory.
     ///
     /// Files are stored by their fsverity digest in a content-addressed layout
@@ -116,7 +124,9 @@ fn main() -> Result<()> {
     // Note: Full Format 1.0 support (compact inodes, whiteout table) is not yet
     // implemented. Currently this only affects the composefs_version header and
     // build_time fields.
-    let format_version = if args.min_version == 0 {
+    let format_version = if args.compress {
+        FormatVersion::V1_1Compressed
+    } else if args.min_version == 0 {
         FormatVersion::V1_0
     } else {
         FormatVersion::V1_1
@@ -139,6 +149,15 @@ fn main() -> Result<()> {
     // Apply transformations based on flags
     apply_transformations(&mut fs, &args, format_version)?;
 
+    // Only inline content is compressed, so move the small files into the image
+    if format_version.compressed() {
+        fs.inline_compressible_files(|path, id| match &repo {
+            _ if !args.from_file => Ok(Some(std::fs::read(args.source.join(path))?)),
+            Some(repo) => Ok(repo.read_object(id).ok()),
+            None => Ok(None),
+        })?;
+    }
+
     // Generate EROFS image
     let image = mkfs_erofs(&fs, format_version);
 